@@ -0,0 +1,14 @@
+; Exercises everything a listing shows
+LEN:    EQU 5
+_ld:    MACRO
+        MOV A, M
+        INX H
+        ENDM
+        LXI H, _msg
+        _ld
+        IF LEN - 5
+        MVI A, 2
+        ENDIF
+        HLT
+_buf:   DS 2
+_msg:   DB 'hello', 0
@@ -0,0 +1,6 @@
+        ORG 0x0100
+_start: LXI H, _msg
+        HLT
+        ORG 0xf000
+_msg:   DB 'hi', 0
+        END _start
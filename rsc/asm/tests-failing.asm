@@ -0,0 +1,12 @@
+TEST_WRONG:
+        ;! EXPECT A = 2
+        MVI A, 1
+        HLT
+
+TEST_LOOPS:
+        ;! CYCLES 50
+_spin:  NOP
+        JMP _spin
+
+TEST_PASSES:
+        HLT
@@ -0,0 +1,12 @@
+; Tests from an included file and a macro, run with `i8080 test ./rsc/asm/tests-included.asm`
+
+        INCLUDE 'tests.asm'
+
+_seta:  MACRO val
+TEST_MACRO:
+        MVI A, val
+        ENDM
+
+        _seta 3
+        ;! EXPECT A = 3
+        HLT
@@ -0,0 +1,38 @@
+; Tests for a few small routines, run with `i8080 test ./rsc/asm/tests.asm`
+
+TEST_ADD:
+        ;! CYCLES 100
+        ;! EXPECT A = 5
+        ;! EXPECT FLAG Z = 0
+        ;! EXPECT FLAG CY = 0
+        MVI A, 2
+        ADI 3
+        HLT
+
+TEST_CALL:
+        ;! EXPECT HL = _buf
+        ;! EXPECT MEM _buf = 0x2a, 0x2a
+        LXI H, _buf
+        CALL _store
+        HLT
+
+        ;! TEST
+_prints:
+        ;! EXPECT OUT 'hi'
+        LXI H, _hello
+_loop:  MOV A, M
+        OUT 0
+        CPI 0x00
+        JZ _done
+        INX H
+        JMP _loop
+_done:  HLT
+
+_store: MVI M, 0x2a
+        INX H
+        MVI M, 0x2a
+        DCX H
+        RET
+
+_buf:   DS 2
+_hello: DB 'hi', 0
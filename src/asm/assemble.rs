@@ -4,24 +4,51 @@
 //! intended... which is nice.
 //!
 //! There's no sense of PIC here, nor is there a loader to perform similar tasks. If you wish for
-//! your code to be loaded at a particular address... you need to tell the assembler at the start.
+//! your code to be loaded at a particular address... you need to tell the assembler at the start,
+//! or assemble an object with `-c` and have `link` place it, see [Linking](#linking).
 //!
 //! # Meta-instructions
 //!
 //! Meta-instructions supported include:
 //!
-//! - `MACRO` and `ENDM` to define macros
-//! - `IF` and `ENDIF` to define pre-compilation conditional blocks of code
-//! - `DS`, `DB`, and `DW` to define storage
-//! - `ORG` to continue assembling at a specific address (with gaps zero-filled)
+//! - `MACRO` and `ENDM` to define macros, `LOCAL` and `EXITM` within them
+//! - `REPT`, `IRP` and `IRPC` to repeat a block of code
+//! - `IF`, `ELSEIF`, `ELSE` and `ENDIF` to define pre-compilation conditional blocks of code
+//! - `DS`, `DB`, `DW` and `DC` to define storage
+//! - `ORG` to continue assembling at a specific address (with gaps zero-filled, or `--fill`)
+//! - `END` to stop assembling, optionally giving the program's start address
 //! - `EQU` to immutably, and `SET` to mutably, set values
+//! - `INCLUDE` and `INCBIN` to bring in other files
+//! - `CSEG` and `DSEG` to switch between code and data, `PUBLIC` and `EXTRN` to share symbols
+//!   between objects
+//! - `ASSERT expr, 'message'` to fail when an expression is zero, the message is optional
+//! - `STRUCT name` and `ENDS` to lay out a structure, see [Structures](#structures)
 //!
 //! ## Macros
 //!
 //! Macros are short pieces of code that can be called like an instruction, the compiler generates
 //! the code for the macro with each invocation.
 //!
-//! Macro-endm pairs are supported however they cannot be nested.
+//! Macros may take named parameters, with defaults, and declare `LOCAL` labels which are unique to
+//! each expansion, see [macros](super::macros). `EXITM` leaves an expansion early, usually from
+//! within an `IF`.
+//!
+//! A macro's body is kept as written until it's called, so a `MACRO` inside another only defines
+//! its macro when the outer one is called. Macros may call others, `$` is the address of the line
+//! within the expansion.
+//!
+//! `REPT count`, `IRP sym, <a, b, c>` and `IRPC sym, 'chars'` blocks, ended by `ENDM`, are
+//! expanded in place once per iteration, so `$` and labels are those of each iteration. Each line
+//! uses the values `SET` symbols had when it was reached, so a table can be built up:
+//!
+//! ```asm
+//! N:      SET 0
+//! _squares:
+//!         REPT 16
+//!         DB N * N
+//! N:      SET N + 1
+//!         ENDM
+//! ```
 //!
 //! When macros are called upon, they must have already been defined (that is, sequentially in
 //! the source file), so:
@@ -44,6 +71,10 @@
 //!
 //! will not.
 //!
+//! `-E` writes the source as assembled instead of the program, with every macro expanded and
+//! `IF` resolved, see [preprocess]. It assembles to the same bytes, so is worth a look when a
+//! macro doesn't do what was expected.
+//!
 //! ## If Blocks
 //!
 //! Ifs can be used to control if blocks are included in the resulting binary; if the condition is
@@ -61,24 +92,145 @@
 //! ENDIF
 //! ```
 //!
-//! If-endif pairs are supported however they cannot be nested.
+//! Comparisons such as `IF SIZE GT 0x100` resolve to 0xFFFF when they hold and 0 otherwise.
+//!
+//! `ELSEIF` and `ELSE` give other branches, only the first whose condition holds is included, and
+//! blocks may be nested to any depth, see [conditional](super::conditional):
+//!
+//! ```asm
+//! IF CPM
+//! CALL 5
+//! ELSEIF ROM
+//! CALL _rom_out
+//! ELSE
+//! OUT 0
+//! ENDIF
+//! ```
+//!
+//! The condition of an IF may use labels defined further on in the source, see
+//! [Forward References](#forward-references).
+//!
+//! Conditionals in a macro are evaluated each time the macro is expanded, with `$` and any `SET`
+//! symbols as they are at that call, so one macro may produce different code at each use.
+//!
+//! Symbols can be given on the command line to build variants of a program, `-D DEBUG` defines
+//! `DEBUG` as 1 and `-D SIZE=0x100` gives a value, as if by `EQU`. `-U` takes away one defined
+//! before, such as a register definition. The source can't define them again but can give a
+//! default, `DEFINED(name)` being true for any symbol defined before it:
+//!
+//! ```asm
+//!         IF NOT DEFINED(SIZE)
+//! SIZE:   EQU 0x80
+//!         ENDIF
+//! ```
+//!
+//! ## Forward References
+//!
+//! Expressions which decide the layout of the program, those of `IF`, `ORG`, `DS`, `REPT`, `EQU`
+//! and `SET`, may use symbols defined later on. The source is parsed again with the values found
+//! by the last pass until every value is settled, or stops changing:
+//!
+//! ```asm
+//!         ORG START
+//! _buf:   DS SIZE
+//! START:  EQU 0x100
+//! SIZE:   EQU 16
+//! ```
+//!
+//! A `DS` may take its size from labels after it, as long as the size comes out the same wherever
+//! the `DS` puts them. A value which truly depends on itself, such as a `DS` whose size is the
+//! address of a label after it, is reported as circular rather than as an unknown identifier.
+//!
+//! ## Includes
+//!
+//! `INCLUDE 'path'` splices another source file in place of the line, and `INCBIN 'path'` places
+//! the raw bytes of a file as if by `DB`, optionally only `length` bytes from `offset`:
+//!
+//! ```asm
+//!         INCLUDE 'lib/print.asm'
+//! _font:  INCBIN 'font.bin', 32, 256
+//! ```
+//!
+//! Paths are looked for relative to the file doing the including, then in each `-I` directory in
+//! turn. A file may not include itself, directly or otherwise. The offset and length of `INCBIN`
+//! are worked out while files are read, before any labels exist, so must be constant.
+//!
+//! `--deps <file>` writes a Makefile rule naming the output and every file that went into it.
+//!
+//! ## Linking
+//!
+//! `DSEG` collects what follows into a data segment, placed after all of the code, and `CSEG`
+//! goes back to the code. Each segment carries on from where it was left, and an `ORG` within
+//! `DSEG` is relative to the start of the data.
+//!
+//! With `-c` the program is assembled from 0 as an object, see [object](super::object), for
+//! `i8080 link` to place alongside others. Any 16-bit value which is an address in the code or data
+//! plus or minus a constant is recorded to be moved, a byte of an address or the sum of two can't
+//! be and is an error. `PUBLIC name, ...` lets other objects use symbols, and `EXTRN name, ...`
+//! uses those of others:
+//!
+//! ```asm
+//!         EXTRN PRINT
+//!         LXI H, MSG
+//!         CALL PRINT
+//!         DSEG
+//! MSG:    DB 'hello', 0
+//! ```
+//!
+//! `--format rel` writes the object as a Microsoft M80 `.REL` module instead, see
+//! [rel], which L80 can link as well. Its names are at most 7 characters.
+//!
+//! ## ROM Images
 //!
-//! If the condition of the IF uses a label, that label must already be defined sequentially in the
-//! source file.
+//! A `bin` can be shaped for burning to a ROM, see [rom]. Only what's from `--load-at` is burnt,
+//! `--pad 8K` fills it out to that size with the `--fill` byte, `--checksum crc16 --checksum-at
+//! 0x1ffe` stores a checksum of the rest of it, and `--split 2K` also writes it as 2716 sized
+//! files `OUTPUT.0`, `OUTPUT.1`... `ASSERT` keeps the program from growing past where it should
+//! end:
+//!
+//! ```asm
+//! TOP:
+//!         ASSERT TOP <= 0x1ffe, 'code runs into the checksum'
+//! ```
 //!
 //! ## Defines
 //!
-//! `DS` takes a single expression resolving to an 8-bit (one-byte) value
+//! `DS` reserves as many bytes as its expression, up to 16-bit, filled with zeros or with a
+//! second value given, `DS 0x100, 0xff`, which has to fit in a byte.
 //!
-//! `DW` is variadic and takes multiple expressions resolving to two-byte (16-bit) values - two
-//! character strings may be also used here.
+//! `DW` is variadic and takes multiple expressions resolving to two-byte (16-bit) values. A two
+//! character string is a value as to Intel's ASM, `'AB'` being 0x4142 and stored `42 41`, a
+//! longer one is an error.
 //!
-//! The DB instruction can be given in a few forms:
+//! The DB instruction can be given in a few forms, mixed as needed:
 //!
 //! ```asm
 //! DB 0x45           ; define 1 byte of the value 0x45
 //! DB 'some-string$' ; define 12 bytes of the ASCII values of the string
 //! DB 0x45, 0x52     ; define 2 bytes of the value 0x4552
+//! DB 'n=', 'A' + 1, $ ; strings, expressions and the address of the line
+//! ```
+//!
+//! `DC` is as `DB` but sets the high bit of the last character of each string, marking where it
+//! ends without a terminator:
+//!
+//! ```asm
+//! DC 'HELP', 'EXIT' ; 'HEL', 'P' | 0x80, 'EXI', 'T' | 0x80
+//! ```
+//!
+//! ## Structures
+//!
+//! `STRUCT name` ... `ENDS` lays out fields with `DB`, `DW` and `DS`, giving each an offset
+//! symbol `NAME.FIELD` and the structure its size, as `NAME` or `SIZEOF(NAME)`. The name used as
+//! an instruction places an instance, see [structs](super::structs):
+//!
+//! ```asm
+//!         STRUCT POINT
+//! X:      DW 0
+//! Y:      DW 0
+//!         ENDS
+//! _here:  POINT 5
+//!         LHLD _here + POINT.Y
 //! ```
 //!
 //! # Examples
@@ -92,6 +244,20 @@
 //!     ./rsc/asm/hello-world.asm
 //! ```
 //!
+//! With `--listing <file>`, a listing of each line's address, bytes, cycles and source is also
+//! written, see [listing](super::listing).
+//!
+//! With `--symbols <file>`, every label, `EQU` and `SET` is written with its value and kind, as
+//! JSON if the file ends `.json`, and `--xref <file>` writes where each is defined and referenced,
+//! see [symbols].
+//!
+//! With `--format ihex`, Intel HEX is written instead of a raw binary, only covering the regions
+//! which were assembled and taking the start address from `END <expr>`.
+//!
+//! With `--format prl`, a page relocatable program is written, see [prl](crate::prl). The source
+//! is assembled at 0x0000 and again at 0x0100, and the bytes which differ by one are those moved
+//! when it's loaded. A value which differs by anything else, such as `DW $ * 2`, is an error.
+//!
 //! The flag `--register-definitions` is used to include some `EQU` statements which are fairly
 //! standard, these are
 //!
@@ -105,38 +271,251 @@
 //! M: EQU 6
 //! A: EQU 7
 //! ```
+//!
+//! With `--dialect dri`, source written for Digital Research's ASM and MAC is read as it is, with
+//! labels without colons, `*` comments and `!` between statements, see [dialect]. The registers
+//! are always defined for it.
+//!
+//! # Embedding
+//!
+//! Source doesn't have to come from disk, `with_source` assembles text held in memory and
+//! `with_resolver` takes any [SourceResolver] for virtual files. `build` assembles without
+//! printing, returning the bytes, symbol table, the address of each line, and any errors as
+//! [Diagnostic]s with a line and column.
+//!
+//! # Errors
+//!
+//! A bad line doesn't stop assembly, it is reported and skipped so every error in the source is
+//! found in one run. Each carries a code, such as `E101`, and is printed with the line at fault
+//! underlined, or with `--error-format json` as one JSON object per line for editors.
+//!
+//! Warnings are given the same way for source which assembles but likely isn't what was meant,
+//! such as a value truncated to a byte. They're turned on and off with `-W`, see
+//! [warnings].
 
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 
-use crate::cli::AssembleArgs;
+use crate::cli::{AssembleArgs, Dialect, ErrorFormat, OutputFormat};
+use crate::ihex::{self, Image};
 use crate::meta::I8080_OP_META;
+use crate::prl::{Prl, PrlError};
 use crate::util;
 
 use super::{
-    errors::{AssemblerError, CodeGenError, ParserError},
-    expressions::parser::{parse_expression, parse_expression_u16, ExprOutput},
+    conditional::{ConditionStack, Directive},
+    diagnostic::{Diagnostic, Severity},
+    dialect,
+    errors::{AssemblerError, CodeGenError, OpParseError, ParserError},
+    expressions::{
+        errors::ExpressionError,
+        parser::{parse_expression, parse_expression_u16, ExprOutput},
+    },
     find_op_code,
-    label::Label,
-    tokenizer::{self, LineMeta},
+    label::{Label, Segment},
+    listing::{self, ListingLine, ListingMark},
+    macros::{self, is_identifier, local_name, opens_block, substitute, Macro},
+    object::{Object, Relocation, Target},
+    preprocess, rel,
+    rom::{self, RomError},
+    source::{self, FsResolver, MemoryResolver, SourceResolver},
+    structs::Struct,
+    symbols,
+    tokenizer::{self, find_unquoted, LineMeta},
+    warnings::{self, Warning, Warnings},
 };
 
-#[derive(Debug, Clone, Default)]
-pub struct Macro {
-    lines: Vec<LineMeta>,
-    bytes: Vec<u8>,
-    width: usize,
-    uses_pc: bool,
+/// Where a source line ended up in the program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineAddress {
+    pub file: PathBuf,
+    pub line_no: usize,
+    pub address: u16,
+    pub width: usize,
+}
+
+/// Everything known after an assembly, whether or not it succeeded
+///
+/// `bytes` is empty if any of the diagnostics are errors.
+#[derive(Debug, Default)]
+pub struct Assembly {
+    pub bytes: Vec<u8>,
+    pub symbols: HashMap<String, Label>,
+    pub lines: Vec<LineAddress>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Assembly {
+    pub fn is_ok(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .all(|d| d.severity != Severity::Error)
+    }
+}
+
+/// How deeply macro calls may nest, reached by a macro which calls itself without end
+const MAX_MACRO_DEPTH: usize = 64;
+
+/// How many passes are made over the source before values are taken to never settle
+const MAX_PASSES: usize = 64;
+
+/// How far symbols are moved to find what a value is relative to, see
+/// [Assembler::relocation_of], odd in both bytes so that moving either is noticed
+const RELOCATION_PROBE: u16 = 0x0101;
+
+/// Where parsing has got to, shared by the source and every macro expansion in it
+#[derive(Debug, Default)]
+struct ParseState {
+    macros: HashMap<String, Macro>,
+    defining: Option<Definition>,
+    /// Structures defined so far, and the one being laid out if within `STRUCT`
+    structs: HashMap<String, Struct>,
+    record: Option<Struct>,
+    address: u16,
+    highest_address: u16,
+    /// The current value of each `SET` symbol, fixed into the lines which use it
+    sets: HashMap<String, String>,
+    /// Macro calls expanded so far, giving each its own `LOCAL` names
+    expansions: usize,
+    /// How many macro calls the current line is within
+    depth: usize,
+    /// Symbols given a value this pass, and of those, the ones whose value isn't yet settled
+    defined: HashSet<String>,
+    unsettled: HashSet<String>,
+    /// Symbols whose value was settled by the end of the last pass
+    settled_before: HashSet<String>,
+    /// Whether addresses from here on depend on a value which isn't yet settled
+    layout_unsettled: bool,
+    /// Values taken from the last pass for symbols not yet defined in this one
+    forward: Vec<(LineMeta, String, Option<u16>)>,
+    /// The first use of a symbol which wasn't known or settled, another pass is needed
+    pending: Option<(LineMeta, String)>,
+    /// Lines which couldn't be parsed this pass, reported if it's the last
+    errors: Vec<(ParserError, Option<LineMeta>)>,
+    /// Likewise for warnings
+    warnings: Vec<(Warning, Option<LineMeta>)>,
+    /// The segment being assembled, and the address and layout the other had got to
+    segment: Segment,
+    parked: (u16, bool),
+    /// Where `DSEG` starts, the end of the code in the last pass, whether it's been used and where
+    /// the code ended in this pass
+    data_base: u16,
+    data_used: bool,
+    code_end: u16,
+    /// Names given to `PUBLIC`
+    publics: Vec<(String, LineMeta)>,
+}
+
+/// A macro, or repeat block, whose `ENDM` hasn't yet been reached
+#[derive(Debug)]
+struct Definition {
+    name: String,
+    body: Macro,
+    /// Blocks within the body waiting on their own `ENDM`
+    nesting: usize,
+    conditions: ConditionStack,
+    /// For `REPT`, `IRP` and `IRPC`, expanded as soon as the body is complete
+    repeat: Option<Repeat>,
+}
+
+/// The line starting a repeat block and the substitutions for each of its iterations
+#[derive(Debug)]
+struct Repeat {
+    line: LineMeta,
+    iterations: Vec<HashMap<String, String>>,
 }
 
-impl Macro {
-    pub fn new() -> Self {
-        Default::default()
+impl ParseState {
+    /// Symbols whose value won't change in later passes
+    fn settled(&self) -> HashSet<String> {
+        self.defined.difference(&self.unsettled).cloned().collect()
+    }
+
+    /// Records a symbol given a value this pass
+    fn define(&mut self, name: &str, settled: bool) {
+        self.defined.insert(name.to_string());
+        if settled {
+            self.unsettled.remove(name);
+        } else {
+            self.unsettled.insert(name.to_string());
+        }
+    }
+
+    /// An argument as it's to be generated, with functions and `SET`s replaced by their values
+    fn resolve_arg(&self, arg: &str) -> Result<String, ParserError> {
+        Ok(substitute(&self.resolve_functions(arg)?, &self.sets))
+    }
+
+    /// `expr` with `DEFINED` and `SIZEOF` replaced by their values from what's defined so far
+    fn resolve_functions(&self, expr: &str) -> Result<String, ParserError> {
+        let expr = symbols::resolve_calls(expr, "DEFINED", |name| {
+            let defined = self.defined.contains(name);
+            Ok::<_, ParserError>(if defined { "0FFFFH" } else { "0" }.to_string())
+        })?;
+        symbols::resolve_calls(&expr, "SIZEOF", |name| match self.structs.get(name) {
+            Some(record) => Ok(record.size().to_string()),
+            None => Err(ParserError::UnknownStruct(name.to_string())),
+        })
+    }
+
+    fn wait_on(&mut self, line: Option<&LineMeta>, name: &str) {
+        if self.pending.is_none() {
+            self.pending = Some((line.cloned().unwrap_or_default(), name.to_string()));
+        }
+    }
+
+    /// Moves on to assembling `segment`, from where it was left
+    fn switch_to(&mut self, segment: Segment) {
+        if segment == self.segment {
+            return;
+        }
+        if segment == Segment::Data {
+            self.data_used = true;
+        }
+        let parked = (self.address, self.layout_unsettled);
+        (self.address, self.layout_unsettled) = self.parked;
+        self.parked = parked;
+        self.segment = segment;
+    }
+
+    /// Raises the highest address, and the end of the code, to the current address
+    fn reach(&mut self) {
+        if self.address > self.highest_address {
+            trace!("highest address set");
+            self.highest_address = self.address;
+        }
+        if self.segment == Segment::Code && self.address > self.code_end {
+            self.code_end = self.address;
+        }
+    }
+
+    /// Adds a line at the current address, moving the address on past it
+    fn place(&mut self, mut line: LineMeta, resolved_lines: &mut Vec<LineMeta>) {
+        line.address = self.address;
+        debug!("@{:<03} adding to resolved lines", line.line_no);
+        if (self.address as u32) + (line.width as u32) > 0xffff {
+            warn!("@{:<03} program wraps the address space", line.line_no);
+        }
+        self.address += line.width as u16;
+        trace!("@{:<03}+1 new address: {}", line.line_no, self.address);
+        self.reach();
+        resolved_lines.push(line);
     }
 }
 
+/// How the parser left a set of lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Continue,
+    /// `EXITM` ended the macro early
+    Exit,
+    /// `END` was reached, nothing more is assembled
+    End,
+}
+
 fn get_reg_defs() -> HashMap<String, Label> {
     let mut map = HashMap::new();
     map.insert("B".to_string(), Label::new_addr(Some(0)));
@@ -150,84 +529,303 @@ fn get_reg_defs() -> HashMap<String, Label> {
     map
 }
 
+/// A line of source as the assembler read it, see [Assembler::source_lines]
+#[derive(Debug)]
+pub struct SourceLine<'a> {
+    pub file: PathBuf,
+    /// Numbered within its own file
+    pub line_no: usize,
+    pub raw_line: &'a str,
+    pub labels: Vec<String>,
+}
+
 pub struct Assembler {
     args: AssembleArgs,
+    resolver: Box<dyn SourceResolver>,
     lines: RefCell<Vec<LineMeta>>,
     macros: RefCell<HashMap<String, Macro>>,
     labels: HashMap<String, Label>,
+    /// Symbols from `-D`, which the source can't define again
+    command_line: HashSet<String>,
     prog_width: u16,
+    start_address: Option<u16>,
+    /// Whether the last pass reached an `END`, which `-E` has to give back with or without its address
+    ended: bool,
+    source: Vec<String>,
+    files: Vec<PathBuf>,
+    origins: Vec<(usize, usize)>,
+    dependencies: Vec<PathBuf>,
+    skipped_lines: HashSet<usize>,
+    /// Lines of the `CSEG`s and `DSEG`s in the last pass
+    segment_switches: Vec<usize>,
+    definitions: HashMap<String, usize>,
     erroring_line: Option<LineMeta>,
+    /// Every error found so far, with the line it was found on
+    failures: Vec<(AssemblerError, Option<LineMeta>)>,
+    /// Which warnings are given, from the `-W` flags
+    enabled_warnings: Warnings,
+    warnings: Vec<(Warning, Option<LineMeta>)>,
+    /// Where `DSEG` was placed, if it was used
+    data_base: Option<u16>,
+    publics: Vec<(String, LineMeta)>,
+    /// Addresses of the words which move when linked, for `-c`
+    relocations: Vec<(u16, Target)>,
+    /// Which bytes move with the page a PRL is loaded at
+    page_relocated: Vec<bool>,
 }
 
 impl Assembler {
     pub fn new(args: AssembleArgs) -> Self {
         Self {
+            enabled_warnings: Warnings::from_flags(&args.warnings),
             args,
+            resolver: Box::new(FsResolver),
             lines: RefCell::new(Vec::new()),
             macros: RefCell::new(HashMap::new()),
             labels: HashMap::new(),
+            command_line: HashSet::new(),
             prog_width: 0,
+            start_address: None,
+            ended: false,
+            source: Vec::new(),
+            files: Vec::new(),
+            origins: Vec::new(),
+            dependencies: Vec::new(),
+            skipped_lines: HashSet::new(),
+            segment_switches: Vec::new(),
+            definitions: HashMap::new(),
             erroring_line: None,
+            failures: Vec::new(),
+            warnings: Vec::new(),
+            data_base: None,
+            publics: Vec::new(),
+            relocations: Vec::new(),
+            page_relocated: Vec::new(),
         }
     }
 
-    pub fn assemble(&mut self) -> Result<Vec<u8>, AssemblerError> {
-        if self.args.register_definitions {
-            self.labels = get_reg_defs();
+    /// Source files are read through `resolver` rather than from disk
+    pub fn with_resolver<R: SourceResolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Box::new(resolver);
+        self
+    }
+
+    /// Assembles `source` as if it were the contents of the input file
+    pub fn with_source<S: Into<String>>(self, source: S) -> Self {
+        let resolver = MemoryResolver::new().with_file(self.args.input.clone(), source);
+        self.with_resolver(resolver)
+    }
+
+    /// Assembles, printing any diagnostics in the format asked for
+    pub fn assemble(&mut self) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        let bytes = self.run();
+        let diagnostics = self.diagnostics();
+        for diagnostic in diagnostics.iter() {
+            match self.args.error_format {
+                ErrorFormat::Human => println!("{}", diagnostic.render()),
+                ErrorFormat::Json => println!("{}", diagnostic.to_json()),
+            }
         }
-        self.load_file()
-            .and_then(|lines| {
-                self.parse_at(lines, self.args.load_at)
-                    .map_err(|e| e.into())
-            })
-            .and_then(|_| self.gen_macros().map_err(|e| e.into()))
-            .and_then(|_| self.generate_prog().map_err(|e| e.into()))
-            .map_err(|e| {
-                self.print_err_msg(&e);
-                e
-            })
+        bytes.ok_or(diagnostics)
     }
 
-    /// Fill the macros with useful bytes
-    ///
-    /// The only unknown in epxression resolution is PC ($), if this is found in any expression,
-    /// the lines and the macro are marked as `uses_pc`
-    ///
-    /// When the real code comes to load in the macro, they should check if this flag is set and
-    /// regenerate the macro code specific to that call - a better fixup method could exist
-    /// but that seems like effort I don't have
-    fn gen_macros(&mut self) -> Result<(), CodeGenError> {
-        self.erroring_line = None;
-        for (_, _macro) in self.macros.borrow_mut().iter_mut() {
-            for line in _macro.lines.iter_mut() {
-                self.erroring_line = Some(LineMeta::erroring(line));
-                let (mut bytes, uses_pc) = self.gen_for_line(line, line.address, true)?;
-                if bytes.len() != line.width {
-                    return Err(CodeGenError::UnexpectedLength(line.width, bytes.len()));
+    /// Assembles without printing anything, errors are returned as diagnostics alongside whatever
+    /// symbols and line addresses were resolved
+    pub fn build(&mut self) -> Assembly {
+        let bytes = self.run().unwrap_or_default();
+        let diagnostics = self.diagnostics();
+        let lines = self
+            .lines
+            .borrow()
+            .iter()
+            .filter(|line| !line.label_only)
+            .map(|line| {
+                let (file, line_no) = self.origin(line.line_no);
+                LineAddress {
+                    file: file.to_path_buf(),
+                    line_no,
+                    address: line.address,
+                    width: line.width,
                 }
-                if uses_pc {
-                    line.uses_pc = true;
+            })
+            .collect();
+        Assembly {
+            bytes,
+            symbols: self.labels.clone(),
+            lines,
+            diagnostics,
+        }
+    }
+
+    /// Every error and warning found by the last assembly, in the order of the lines they're on
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        // Another dialect's lines were rewritten when read, it's the line as written that's shown
+        let written = |line: &Option<LineMeta>| -> Option<LineMeta> {
+            let mut line = line.clone()?;
+            if self.args.dialect != Dialect::Native {
+                if let Some(raw_line) = self.source.get(line.line_no.wrapping_sub(1)) {
+                    line.raw_line = raw_line.to_string();
                 }
-                _macro.bytes.append(&mut bytes);
-                if line.uses_pc {
-                    _macro.uses_pc = true;
+            }
+            Some(line)
+        };
+        let errors = self
+            .failures
+            .iter()
+            .map(|(e, line)| (Diagnostic::from_error(e, written(line).as_ref()), line));
+        let warnings = self.warnings.iter().map(|(w, line)| {
+            let error = self.enabled_warnings.error;
+            (
+                Diagnostic::from_warning(w, written(line).as_ref(), error),
+                line,
+            )
+        });
+        let mut diagnostics: Vec<(Diagnostic, &Option<LineMeta>)> =
+            errors.chain(warnings).collect();
+        diagnostics.sort_by_key(|(_, line)| line.as_ref().map_or(usize::MAX, |line| line.line_no));
+        diagnostics
+            .into_iter()
+            .map(|(diagnostic, line)| match line {
+                Some(line) => {
+                    let (file, line_no) = self.origin(line.line_no);
+                    diagnostic.at(file, line_no)
                 }
+                None => diagnostic.at(&self.args.input, 0),
+            })
+            .collect()
+    }
+
+    /// Keeps a warning if it's turned on
+    fn warn(&mut self, warning: Warning, line: Option<LineMeta>) {
+        if self.enabled_warnings.enabled(&warning) {
+            self.warnings.push((warning, line));
+        }
+    }
+
+    /// Assembles as much as possible, the bytes are only given if there were no errors
+    fn run(&mut self) -> Option<Vec<u8>> {
+        self.failures.clear();
+        self.warnings.clear();
+        self.relocations.clear();
+        self.labels = if self.args.defines_registers() {
+            get_reg_defs()
+        } else {
+            HashMap::new()
+        };
+        if let Err(e) = self.define_command_line() {
+            self.failures.push((e.into(), None));
+            return None;
+        }
+        let lines = match self.load_file() {
+            Ok(lines) => lines,
+            Err(e) => {
+                self.failures.push((e, None));
+                return None;
+            }
+        };
+        // Objects are placed by the linker, they're assembled from zero
+        let load_at = match self.args.format {
+            OutputFormat::Prl => 0x100,
+            _ if self.args.writes_object() => 0,
+            _ => self.args.load_at,
+        };
+        // What moves in a PRL is whatever differs from the program assembled a page lower
+        let at_0000 = if self.args.format == OutputFormat::Prl {
+            let predefined = self.labels.clone();
+            if !self.parse_at(lines.clone(), 0) {
+                return None;
+            }
+            let bytes = self.generate_prog();
+            if !self.failures.is_empty() {
+                return None;
+            }
+            self.warnings.clear();
+            self.labels = predefined;
+            Some(bytes)
+        } else {
+            None
+        };
+        // Code can't be generated for lines which haven't all got an address
+        if !self.parse_at(lines, load_at) {
+            return None;
+        }
+        let bytes = self.generate_prog();
+        self.check_publics();
+        if self.args.writes_object() {
+            self.relocate();
+        }
+        if let Some(at_0000) = at_0000.filter(|_| self.failures.is_empty()) {
+            self.page_relocate(&at_0000, &bytes);
+        }
+        self.check_labels();
+        self.check_fall_through();
+        let warnings_fail = self.enabled_warnings.error && !self.warnings.is_empty();
+        if self.failures.is_empty() && !warnings_fail {
+            Some(bytes)
+        } else {
+            None
+        }
+    }
+
+    /// Adds the `-D` symbols to those known before parsing, once the `-U`s are taken out
+    ///
+    /// A value may use any symbol defined before it. Names given different values, or both
+    /// defined and undefined, conflict, as does defining a register without undefining it first.
+    fn define_command_line(&mut self) -> Result<(), ParserError> {
+        self.command_line.clear();
+        let undefines: HashSet<String> = self
+            .args
+            .undefines
+            .iter()
+            .map(|name| name.trim().to_uppercase())
+            .collect();
+        // Only something known before any -D can be undefined to make way for one
+        let removed: HashSet<&String> = undefines
+            .iter()
+            .filter(|name| self.labels.remove(*name).is_some())
+            .collect();
+        for definition in self.args.defines.iter() {
+            let (name, expr) = match definition.split_once('=') {
+                Some((name, expr)) => (name.trim().to_uppercase(), expr.trim()),
+                None => (definition.trim().to_uppercase(), "1"),
+            };
+            let invalid =
+                |why: String| ParserError::InvalidSymbolDefinition(definition.clone(), why);
+            if !is_identifier(&name) {
+                return Err(invalid(format!("{} isn't a name", name)));
+            }
+            // Symbols are upper case in the source, as the tokenizer makes them
+            let upper = symbols::identifiers(expr)
+                .into_iter()
+                .map(|ident| (ident.clone(), ident))
+                .collect();
+            let (value, _) = parse_expression_u16(substitute(expr, &upper), 0, &self.labels)
+                .map_err(|e| invalid(e.to_string()))?;
+            let conflicts = (undefines.contains(&name) && !removed.contains(&name))
+                || match self.labels.get(&name) {
+                    Some(label) if self.command_line.contains(&name) => label.value != Some(value),
+                    Some(_) => true,
+                    None => false,
+                };
+            if conflicts {
+                return Err(ParserError::ConflictingSymbolDefinitions(name));
             }
+            debug!("-D {} with value {}", name, value);
+            self.labels
+                .insert(name.clone(), Label::new_equ(Some(value)));
+            self.command_line.insert(name);
         }
         Ok(())
     }
 
-    fn gen_for_line(
-        &self,
-        line: &LineMeta,
-        address: u16,
-        inside_macro: bool,
-    ) -> Result<(Vec<u8>, bool), CodeGenError> {
+    fn gen_for_line(&self, line: &LineMeta, address: u16) -> Result<(Vec<u8>, bool), CodeGenError> {
         trace!("generating code for line {}", line.line_no);
-        if line.op_code.is_some() {
-            self.gen_for_instruction(line, address)
+        if line.label_only {
+            Ok((vec![], false))
+        } else if line.op_code.is_none() || line.expansion.is_some() {
+            self.gen_for_macro_call(line)
         } else {
-            self.gen_for_macro_call(line, address, inside_macro)
+            self.gen_for_instruction(line, address)
         }
     }
 
@@ -252,9 +850,9 @@ impl Assembler {
             parsed_exprs,
         );
         match line.inst.as_ref().unwrap().as_str() {
-            "DB" => {
+            inst @ ("DB" | "DC") => {
                 let mut bytes: Vec<u8> = vec![];
-                for (expr, flags) in parsed_exprs.iter() {
+                for (arg, (expr, flags)) in line.args_list.iter().zip(parsed_exprs.iter()) {
                     if flags.string {
                         for byte in expr {
                             bytes.push(*byte);
@@ -262,17 +860,31 @@ impl Assembler {
                     } else {
                         bytes.push(expr[0]);
                     }
+                    // DC marks the end of each string by the high bit of its last character
+                    if inst == "DC" && macros::unquote(arg).is_some_and(|s| !s.is_empty()) {
+                        if let Some(last) = bytes.last_mut() {
+                            *last |= 0x80;
+                        }
+                    }
                 }
-                debug!("@{:<03} DB generated {} bytes", line.line_no, bytes.len());
+                debug!(
+                    "@{:<03} {} generated {} bytes",
+                    line.line_no,
+                    inst,
+                    bytes.len()
+                );
                 trace!("@{:<03} {:?}", line.line_no, bytes);
                 Ok((bytes, uses_pc))
             }
             "DW" => {
                 let mut bytes: Vec<u8> = vec![];
-                for (expr, _) in parsed_exprs.iter() {
-                    let pair = util::vec_u8_to_u16(expr);
-                    bytes.push(pair as u8);
-                    bytes.push((pair >> 8) as u8);
+                for (expr, flags) in parsed_exprs.iter() {
+                    // Two characters are the word of both, the first being the high byte
+                    let word = match expr[..] {
+                        [high, low] if flags.string => u16::from_be_bytes([high, low]),
+                        _ => util::vec_u8_to_u16(expr),
+                    };
+                    bytes.extend(word.to_le_bytes());
                 }
                 debug!("@{:<03} DW generated {} bytes", line.line_no, bytes.len());
                 trace!("@{:<03} {:?}", line.line_no, bytes);
@@ -280,8 +892,16 @@ impl Assembler {
             }
             "DS" => {
                 let len = util::vec_u8_to_u16(&parsed_exprs[0].0);
-                let bytes: Vec<u8> = vec![0; len as usize];
-                debug!("@{:<03} DS {} bytes", line.line_no, bytes.len());
+                let fill = match parsed_exprs.get(1) {
+                    Some((expr, _)) if warnings::truncates(util::vec_u8_to_u16(expr)) => {
+                        let arg = line.args_list[1].to_string();
+                        return Err(ParserError::InvalidArgument("DS".to_string(), arg).into());
+                    }
+                    Some((expr, _)) => expr[0],
+                    None => 0,
+                };
+                let bytes: Vec<u8> = vec![fill; len as usize];
+                debug!("@{:<03} DS {} bytes of {}", line.line_no, bytes.len(), fill);
                 Ok((bytes, uses_pc))
             }
             name => {
@@ -320,9 +940,9 @@ impl Assembler {
                             match parsed_exprs.get(idx) {
                                 Some((bytes, _)) => {
                                     if meta.argb {
-                                        inst_bytes.push(*bytes.get(0).unwrap());
+                                        inst_bytes.push(*bytes.first().unwrap());
                                     } else if meta.argw {
-                                        inst_bytes.push(*bytes.get(0).unwrap());
+                                        inst_bytes.push(*bytes.first().unwrap());
                                         inst_bytes.push(*bytes.get(1).unwrap());
                                     }
                                 }
@@ -348,472 +968,1804 @@ impl Assembler {
         }
     }
 
-    fn gen_for_macro_call(
-        &self,
-        line: &LineMeta,
-        address: u16,
-        inside_macro: bool,
-    ) -> Result<(Vec<u8>, bool), CodeGenError> {
-        let macros = self.macros.borrow();
-        let macro_name = line.inst.as_ref().unwrap();
-        let _macro = macros.get(macro_name).unwrap();
-        if _macro.uses_pc {
-            trace!("@{:<03} {} macro uses PC ($)", line.line_no, macro_name);
-            if inside_macro {
-                debug!(
-                    "@{:<03} macro called inside macro uses PC ($)",
-                    line.line_no
-                );
-                Err(CodeGenError::ParserError(
-                    ParserError::MacroCallInMacroUsesSp,
-                ))
-            } else {
-                let bytes = self.gen_for_macro_at(_macro, address)?;
-                Ok((bytes, _macro.uses_pc))
+    /// Macro calls and repeat blocks are generated from the lines they expanded to
+    fn gen_for_macro_call(&self, line: &LineMeta) -> Result<(Vec<u8>, bool), CodeGenError> {
+        let expansion = line
+            .expansion
+            .as_ref()
+            .ok_or(ParserError::MacroUseBeforeCreation)?;
+        let mut bytes = vec![];
+        let mut uses_pc = false;
+        for line in expansion.iter() {
+            let (mut line_bytes, pc) = self.gen_for_line(line, line.address)?;
+            if line_bytes.len() != line.width {
+                return Err(CodeGenError::UnexpectedLength(line.width, line_bytes.len()));
             }
-        } else {
-            trace!(
-                "@{:<03} {} macro doesn't use PC ($)",
-                line.line_no,
-                macro_name
-            );
-            Ok((_macro.bytes.clone(), _macro.uses_pc))
+            uses_pc |= pc;
+            bytes.append(&mut line_bytes);
         }
-    }
-
-    fn gen_for_macro_at(&self, _macro: &Macro, addr: u16) -> Result<Vec<u8>, CodeGenError> {
-        let mut bytes: Vec<u8> = Vec::new();
-        for line in _macro.lines.iter() {
-            let (mut _bytes, _) = self.gen_for_line(line, line.address + addr, true)?;
-            bytes.append(&mut _bytes);
-        }
-        Ok(bytes)
+        Ok((bytes, uses_pc))
     }
 
     /// Because of the ORG instruction, we preallocate the vec so cannot `.append` and must instead
     /// `[line.address]` into it
-    fn generate_prog(&mut self) -> Result<Vec<u8>, CodeGenError> {
-        self.erroring_line = None;
-        let mut bytes = vec![0; self.prog_width as usize];
-        for line in self.lines.borrow().iter() {
-            self.erroring_line = Some(LineMeta::erroring(line));
-            let (line_bytes, _) = self.gen_for_line(line, line.address, false)?;
-            if line_bytes.len() != line.width {
-                return Err(CodeGenError::UnexpectedLength(line.width, bytes.len()));
+    ///
+    /// Lines which already failed are left as zeros, as are any which fail here
+    fn generate_prog(&mut self) -> Vec<u8> {
+        let failed: HashSet<usize> = self
+            .failures
+            .iter()
+            .filter_map(|(_, line)| line.as_ref().map(|line| line.line_no))
+            .collect();
+        let mut bytes = vec![self.args.rom.fill; self.prog_width as usize];
+        // Which bytes have been assembled, to find any written over by a later ORG region
+        let mut written = vec![false; self.prog_width as usize];
+        // The region being written over, its first line, address, bytes overwritten and end
+        let mut overlap: Option<(LineMeta, usize, usize, usize)> = None;
+        let lines = self.lines.take();
+        for line in lines.iter() {
+            if failed.contains(&line.line_no) {
+                continue;
+            }
+            let line_bytes = match self.gen_for_line(line, line.address) {
+                Ok((line_bytes, _)) if line_bytes.len() == line.width => line_bytes,
+                Ok((line_bytes, _)) => {
+                    let e = CodeGenError::UnexpectedLength(line.width, line_bytes.len());
+                    self.failures
+                        .push((e.into(), Some(LineMeta::erroring(line))));
+                    continue;
+                }
+                Err(e) => {
+                    self.failures
+                        .push((e.into(), Some(LineMeta::erroring(line))));
+                    continue;
+                }
+            };
+            self.check_truncation(line);
+            let from = line.address as usize;
+            let to = from + line_bytes.len();
+            let span = &written[from..to];
+            let overwritten = span.iter().filter(|w| **w).count();
+            match overlap.as_mut() {
+                // Lines running on over the same bytes are one region
+                Some((_, _, count, end)) if overwritten > 0 && *end == from => {
+                    *count += overwritten;
+                    *end = to;
+                }
+                _ if line_bytes.is_empty() => {}
+                _ => {
+                    if let Some((first_line, at, count, _)) = overlap.take() {
+                        self.warn(Warning::Overlap(at as u16, count), Some(first_line));
+                    }
+                    if let Some(first) = span.iter().position(|w| *w) {
+                        let first_line = LineMeta::erroring(line);
+                        overlap = Some((first_line, from + first, overwritten, to));
+                    }
+                }
             }
             for (idx, byte) in line_bytes.iter().enumerate() {
-                bytes[line.address as usize + idx] = *byte;
+                bytes[from + idx] = *byte;
+                written[from + idx] = true;
             }
         }
+        if let Some((first_line, at, count, _)) = overlap {
+            self.warn(Warning::Overlap(at as u16, count), Some(first_line));
+        }
+        self.lines.replace(lines);
         if self.args.hlt {
             bytes.push(0x76);
         }
-        Ok(bytes)
+        bytes
     }
 
-    #[cfg(test)]
-    fn parse(&mut self, lines: Vec<LineMeta>) -> Result<(), ParserError> {
-        self.parse_at(lines, 0)
-    }
-
-    /// Holy shit, this does a lot. And might be the single worst function I've ever written...
-    ///
-    /// ## Notes
-    ///
-    /// `self.labels` is populated in place implying that labels cannot be used in expressions
-    /// before assignment
-    ///
-    /// IFs conditions are evaluated implying the same as before, if the condition is `false`, then
-    /// the following lines are not loaded until an ENDIF is found
-    ///
-    /// Macros are tracked, their width and the related lines are recorded, at the end of the
-    /// function, width used in vector creation in `self`
-    ///
-    /// The address is incremented by the width of the instruction or the width of the macro
-    /// where appropriate (assumes macro already exists)
-    ///
-    /// Some expressions are calculated here (earlier than I would like):
-    /// - IF, DB, DW, DS, ORG, as they are needed to control the current address
-    /// - EQU, SET, as they *may* be needed in the above
-    ///
-    /// Lines without op codes are macro invocations, these will not be be passed to the
-    /// expression parser
-    ///
-    /// ## Things Done Here
-    ///
-    /// - Addresses are calculated
-    /// - Instruction widths are known
-    /// - Macros are sized
-    /// - IFs' conditions are known
-    /// - The relevant lines are separated from the rest
-    fn parse_at(&mut self, lines: Vec<LineMeta>, load_address: u16) -> Result<(), ParserError> {
-        let mut resolved_lines: Vec<LineMeta> = Vec::new();
-
-        let mut macros: HashMap<String, Macro> = HashMap::new();
-        let mut current_macro_name: Option<&str> = None;
-
-        let mut inside_if: bool = false;
-        let mut skip_in_true_if: bool = false;
-
-        let mut address: u16 = load_address;
-        let mut macro_address: u16 = load_address;
-        let mut highest_address: u16 = load_address;
-
-        for line in lines.iter() {
-            self.erroring_line = Some(LineMeta::erroring(line));
-            // The IFs control `skip`, if an IF resolves to `false`, we skip until ENDIF
-            if let Some(inst) = &line.inst {
-                match inst.as_str() {
-                    "IF" => {
-                        if inside_if {
-                            debug!("@{:<03} IF found without previous ENDIF", line.line_no);
-                            return Err(ParserError::NestedIf);
-                        }
-                        let first_arg = line.args_list[0].to_string();
-                        let (val, _) = parse_expression_u16(first_arg, address, &self.labels)?;
-                        let cond = val > 0;
-                        debug!("@{:<03} IF condition is {}", line.line_no, cond);
-                        inside_if = true;
-                        skip_in_true_if = !cond;
-                    }
-                    "ENDIF" => {
-                        if !inside_if {
-                            debug!("@{:<03} ENDIF found without previous IF", line.line_no);
-                            return Err(ParserError::NotInIf);
-                        }
-                        debug!("@{:<03} ENDIF reached", line.line_no);
-                        inside_if = false;
-                        skip_in_true_if = false;
-                    }
-                    "END" => {
-                        debug!("@{:<03} END found, leaving parser", line.line_no);
-                        break;
-                    }
-                    _ => {}
-                }
+    /// Warns of values stored in a byte which don't fit in one, see [warnings::truncates]
+    fn check_truncation(&mut self, line: &LineMeta) {
+        if let Some(expansion) = &line.expansion {
+            for line in expansion.iter() {
+                self.check_truncation(line);
             }
+            return;
+        }
+        let op_code = match line.op_code {
+            Some(op_code) => op_code as usize,
+            None => return,
+        };
+        let meta = I8080_OP_META[op_code];
+        // Every value given to DB, only the last argument of an instruction is stored
+        let args = match meta.op {
+            "DB" | "DC" => &line.args_list[..],
+            _ if meta.argb => &line.args_list[line.args_list.len().saturating_sub(1)..],
+            _ => return,
+        };
+        for arg in args {
+            let value = match parse_expression(arg, line.address, &self.labels) {
+                Ok((bytes, flags)) if !flags.string => util::vec_u8_to_u16(&bytes),
+                _ => continue,
+            };
+            if warnings::truncates(value) {
+                let w = Warning::Truncation(arg.to_string(), value);
+                self.warn(w, Some(LineMeta::erroring(line)));
+            }
+        }
+    }
 
-            if skip_in_true_if {
+    /// Warns of address labels in the source which nothing refers to
+    fn check_labels(&mut self) {
+        let references = self.references();
+        let macros: HashSet<String> = self.macros.borrow().keys().cloned().collect();
+        let mut unused: Vec<(usize, String)> = self
+            .definitions
+            .iter()
+            .filter(|(name, _)| !references.contains_key(*name) && !macros.contains(*name))
+            .filter(|(name, _)| self.labels.get(*name).is_some_and(|l| l.is_addr))
+            .map(|(name, line_no)| (*line_no, name.to_string()))
+            .collect();
+        unused.sort();
+        for (line_no, name) in unused {
+            let raw_line = self.source[line_no - 1].to_string();
+            // A LOCAL label is named differently in each expansion, it's the one in the source
+            // which matters
+            let labelled = self
+                .tokenize_line(&raw_line)
+                .is_ok_and(|lines| lines.iter().any(|meta| meta.label.as_ref() == Some(&name)));
+            if !labelled {
                 continue;
             }
+            let line = LineMeta::from_raw(line_no, raw_line);
+            self.warn(Warning::UnusedLabel(name), Some(line));
+        }
+    }
 
-            if let Some(label) = &line.label {
-                debug!("@{:<03} contains label ({:?})", line.line_no, line.label);
+    /// Warns of `DB` data which an instruction before it would run on into
+    ///
+    /// Data after a `CSEG` or `DSEG` is somewhere else entirely, even if its address follows on.
+    fn check_fall_through(&mut self) {
+        let mut placed: Vec<LineMeta> = vec![];
+        for line in self.lines.borrow().iter() {
+            flatten(line, &mut placed);
+        }
+        for pair in placed.windows(2) {
+            let (before, data) = (&pair[0], &pair[1]);
+            let switched = self
+                .segment_switches
+                .iter()
+                .any(|line_no| (before.line_no..=data.line_no).contains(line_no));
+            let is_code = before.op_code.is_some_and(|op| op < 0x100);
+            let jumps_away = matches!(
+                before.inst.as_deref(),
+                Some("JMP") | Some("RET") | Some("PCHL") | Some("HLT")
+            );
+            if matches!(data.inst.as_deref(), Some("DB") | Some("DC"))
+                && is_code
+                && !jumps_away
+                && !switched
+                && before.address as usize + before.width == data.address as usize
+            {
+                let inst = before.inst.clone().unwrap_or_default();
+                self.warn(Warning::FallThrough(inst), Some(LineMeta::erroring(data)));
+            }
+        }
+    }
 
-                // Check if we're overwriting a label on any op but SET
-                if self.labels.contains_key(label) {
-                    debug!(
-                        "@{:<03} label ({:?}) on inst {:?} already loaded",
-                        line.line_no, line.inst, line.label
-                    );
-                    if line.inst.as_ref().unwrap().as_str() != "SET" {
-                        return Err(ParserError::LabelAlreadyDefined(
-                            label.to_string(),
-                            *self.labels.get(label).unwrap(),
-                        ));
-                    }
+    /// Errors for `PUBLIC` names which aren't defined here
+    fn check_publics(&mut self) {
+        let publics = std::mem::take(&mut self.publics);
+        for (name, line) in publics.iter() {
+            let e = match self.labels.get(name) {
+                None => ExpressionError::UnknownIdentifier(name.to_string()).into(),
+                Some(label) if label.segment == Segment::External => {
+                    ParserError::InvalidArgument("PUBLIC".to_string(), name.to_string())
                 }
+                Some(_) => continue,
+            };
+            self.failures.push((e.into(), Some(line.clone())));
+        }
+        self.publics = publics;
+    }
 
-                // Load it labels without setting a value
-                if !line.label_only {
-                    let inst = line.inst.as_ref().unwrap().as_str();
-                    match inst {
-                        // We're checking the use of EQU multiple times above
-                        "SET" | "EQU" => {
-                            let arg = line.args_list[0].to_string();
-                            let (val, _) = parse_expression_u16(arg, address, &self.labels)?;
-                            debug!(
-                                "@{:<03} label ({:?}) loaded with val ({}) for {:?}",
-                                line.line_no, line.label, val, line.inst
-                            );
-                            if inst == "EQU" {
-                                self.labels
-                                    .insert(label.to_string(), Label::new_equ(Some(val)));
-                            } else {
-                                self.labels
-                                    .insert(label.to_string(), Label::new_set(Some(val)));
-                            }
-                        }
-                        // This includes MACRO as macro labels can be used in expressions
-                        _ => {
-                            debug!(
-                                "@{:<03} label ({:?}) loaded with addr ({})",
-                                line.line_no, line.label, address,
-                            );
-                            self.labels
-                                .insert(label.to_string(), Label::new_addr(Some(address)));
-                        }
-                    };
-                } else {
-                    debug!(
-                        "@{:<03} label-only ({:?}) loaded with addr ({})",
-                        line.line_no, line.label, address,
-                    );
-                    self.labels
-                        .insert(label.to_string(), Label::new_addr(Some(address)));
+    /// Finds the bytes of a PRL which move from those of the program assembled at 0x0000
+    fn page_relocate(&mut self, at_0000: &[u8], bytes: &[u8]) {
+        match Prl::from_pair(at_0000, bytes.get(0x100..).unwrap_or_default()) {
+            Ok(prl) => self.page_relocated = prl.relocated,
+            Err(e) => {
+                let address = match e {
+                    PrlError::NotRelocatable(offset) => 0x100 + offset as u16,
+                    _ => 0x100,
+                };
+                let mut placed: Vec<LineMeta> = vec![];
+                for line in self.lines.borrow().iter() {
+                    flatten(line, &mut placed);
                 }
+                let line = placed
+                    .iter()
+                    .find(|line| {
+                        (address as usize).wrapping_sub(line.address as usize) < line.width
+                    })
+                    .map(LineMeta::erroring);
+                self.failures
+                    .push((ParserError::NotPageRelocatable(e).into(), line));
             }
+        }
+    }
 
-            if line.label_only {
-                let mut new_line = line.clone();
-                new_line.address = address;
-                new_line.width = 0;
-                if let Some(name) = current_macro_name {
-                    let m = macros.get_mut(name).unwrap();
-                    m.lines.push(new_line)
-                } else {
-                    resolved_lines.push(new_line);
-                }
-                continue;
+    /// Which segment an address of the program is in
+    fn segment_at(&self, address: u16) -> Segment {
+        match self.data_base {
+            Some(base) if address >= base => Segment::Data,
+            _ => Segment::Code,
+        }
+    }
+
+    /// What the value of an expression moves by when linked, `None` if it doesn't
+    ///
+    /// The expression is worked out again with the symbols of each segment, and each external,
+    /// moved by [RELOCATION_PROBE]. The value may move with one of them by as much, or not at all.
+    fn relocation_of(
+        &self,
+        expr: &str,
+        address: u16,
+        pc_segment: Segment,
+    ) -> Result<Option<Target>, ParserError> {
+        let (value, flags) = parse_expression_u16(expr, address, &self.labels)?;
+        let used: HashMap<String, Label> = symbols::identifiers(expr)
+            .into_iter()
+            .filter_map(|name| self.labels.get(&name).map(|label| (name, *label)))
+            .collect();
+        let target_of = |segment: Segment, name: &str| match segment {
+            Segment::Code => Some(Target::Code),
+            Segment::Data => Some(Target::Data),
+            Segment::External => Some(Target::External(name.to_string())),
+            Segment::Absolute => None,
+        };
+        let mut targets: Vec<Target> = vec![];
+        let pc_target = target_of(pc_segment, "").filter(|_| flags.pc);
+        for target in pc_target.into_iter().chain(
+            used.iter()
+                .filter_map(|(name, l)| target_of(l.segment, name)),
+        ) {
+            if !targets.contains(&target) {
+                targets.push(target);
             }
+        }
 
-            let width: usize;
+        let mut found = None;
+        for target in targets {
+            let moves =
+                |segment: Segment, name: &str| target_of(segment, name) == Some(target.clone());
+            let moved: HashMap<String, Label> = used
+                .iter()
+                .map(|(name, label)| {
+                    let mut label = *label;
+                    if moves(label.segment, name) {
+                        label.value = label.value.map(|v| v.wrapping_add(RELOCATION_PROBE));
+                    }
+                    (name.to_string(), label)
+                })
+                .collect();
+            let pc = if moves(pc_segment, "") {
+                address.wrapping_add(RELOCATION_PROBE)
+            } else {
+                address
+            };
+            let not_relocatable = || ParserError::NotRelocatable(expr.to_string());
+            let (moved, _) =
+                parse_expression_u16(expr, pc, &moved).map_err(|_| not_relocatable())?;
+            match moved.wrapping_sub(value) {
+                0 => {}
+                RELOCATION_PROBE if found.is_none() => found = Some(target),
+                _ => return Err(not_relocatable()),
+            }
+        }
+        Ok(found)
+    }
 
-            let inst_name = line.inst.as_ref().unwrap();
+    /// Finds the words which move when linked, a value which moves can't be kept in a byte
+    fn relocate(&mut self) {
+        let failed: HashSet<usize> = self
+            .failures
+            .iter()
+            .filter_map(|(_, line)| line.as_ref().map(|line| line.line_no))
+            .collect();
+        let mut placed: Vec<LineMeta> = vec![];
+        for line in self.lines.borrow().iter() {
+            if !failed.contains(&line.line_no) {
+                flatten(line, &mut placed);
+            }
+        }
+        for line in placed.iter() {
+            let op_code = match line.op_code {
+                Some(op_code) => op_code as usize,
+                None => continue,
+            };
+            let meta = I8080_OP_META[op_code];
+            // Each value kept and how far into the line its word is, bytes have none
+            let kept: Vec<(&String, Option<u16>)> = match meta.op {
+                "DB" | "DC" => line.args_list.iter().map(|arg| (arg, None)).collect(),
+                "DW" => (0..)
+                    .step_by(2)
+                    .zip(line.args_list.iter())
+                    .map(|(offset, arg)| (arg, Some(offset)))
+                    .collect(),
+                _ if op_code > 0xff => continue,
+                _ if meta.argw => line
+                    .args_list
+                    .last()
+                    .map(|arg| (arg, Some(1)))
+                    .into_iter()
+                    .collect(),
+                _ if meta.argb => line
+                    .args_list
+                    .last()
+                    .map(|arg| (arg, None))
+                    .into_iter()
+                    .collect(),
+                _ => continue,
+            };
+            let pc_segment = self.segment_at(line.address);
+            for (arg, offset) in kept {
+                let e = match (self.relocation_of(arg, line.address, pc_segment), offset) {
+                    (Ok(None), _) => continue,
+                    (Ok(Some(target)), Some(offset)) => {
+                        self.relocations.push((line.address + offset, target));
+                        continue;
+                    }
+                    (Ok(Some(_)), None) => ParserError::NotRelocatable(arg.to_string()),
+                    (Err(e), _) => e,
+                };
+                self.failures
+                    .push((e.into(), Some(LineMeta::erroring(line))));
+            }
+        }
+    }
 
-            // None of these should be in the `new_lines` vec, therefore all `continue`:
-            //
-            // - Process MACRO start and end
-            // - ORG should set the address
-            // - IF and ENDIF are processed above
-            match inst_name.as_str() {
-                "MACRO" => {
-                    let label = line.label.as_ref().unwrap();
-                    debug!("@{:<03} macro {:?} to be loaded", line.line_no, line.label);
-                    if let Some(name) = current_macro_name {
-                        debug!("@{:<03} MACRO '{}' found within MACRO", line.line_no, name);
-                        return Err(ParserError::NestedMacro);
+    #[cfg(test)]
+    fn parse(&mut self, lines: Vec<LineMeta>) -> Result<(), ParserError> {
+        self.parse_at(lines, 0);
+        match self.failures.drain(..).next() {
+            Some((AssemblerError::Parser(e), _)) => Err(e),
+            Some((e, _)) => panic!("not a parser error: {}", e),
+            None => Ok(()),
+        }
+    }
+
+    /// Works out the address and width of every line, see [Self::parse_lines], giving whether
+    /// every value settled
+    ///
+    /// Expressions which affect the layout may refer to symbols defined further on, so the lines
+    /// are parsed again with the values from the last pass until nothing is left unsettled, or
+    /// until a pass leaves every value as it was. A value can stay put because it depends only on
+    /// itself, as with `X: EQU X`, so the symbols used before their definition are then moved by
+    /// [RELOCATION_PROBE] for a pass, and if any doesn't come back, it's circular. Errors are
+    /// only kept from the last pass, earlier ones may have come from values which hadn't settled.
+    fn parse_at(&mut self, lines: Vec<LineMeta>, load_address: u16) -> bool {
+        // Anything known before parsing, such as the register definitions, is there every pass
+        let predefined = self.labels.clone();
+        let mut settled: HashSet<String> = predefined.keys().cloned().collect();
+        let mut defined = settled.clone();
+        // Errors from loading the source stand whatever happens here
+        let loaded = self.failures.len();
+        // DSEG follows the code, where that ends is only known after a pass
+        let mut data_base = load_address;
+        let mut data_base_settled = false;
+        // Every symbol's value after the last pass
+        let mut previous: HashMap<String, Option<u16>> = HashMap::new();
+        // The values a probe was made from, and whether one has shown them to be settled
+        let mut probe: Option<HashMap<String, Option<u16>>> = None;
+        let mut probed = false;
+
+        for pass in 1..=MAX_PASSES {
+            debug!("parser pass {}", pass);
+            let mut state = ParseState {
+                address: load_address,
+                highest_address: load_address,
+                defined: predefined.keys().cloned().collect(),
+                settled_before: settled.clone(),
+                segment: Segment::Code,
+                parked: (data_base, !data_base_settled),
+                data_base,
+                code_end: load_address,
+                ..Default::default()
+            };
+            self.labels.extend(predefined.clone());
+            self.definitions.clear();
+            self.skipped_lines.clear();
+            self.segment_switches.clear();
+            self.start_address = None;
+            self.ended = false;
+            let mut resolved_lines: Vec<LineMeta> = Vec::new();
+
+            if let Err(e) = self.parse_lines(&lines, &mut state, &mut resolved_lines) {
+                let line = self.erroring_line.take();
+                state.errors.push((e, line));
+            }
+
+            self.erroring_line = None;
+            if let Some(definition) = state.defining.take() {
+                debug!("@EOF MACRO '{}' without ENDM", definition.name);
+                state.errors.push((ParserError::NoEndMacro, None));
+            }
+            if let Some(record) = state.record.take() {
+                debug!("@EOF STRUCT '{}' without ENDS", record.name);
+                state.errors.push((ParserError::NoEndStruct, None));
+            }
+            for (e, line) in state.errors.drain(..) {
+                self.failures.push((e.into(), line));
+            }
+
+            // Symbols no longer defined mustn't be carried into the next pass
+            self.labels.retain(|name, _| state.defined.contains(name));
+            // A symbol used before its definition must have ended up with the value it was used at
+            let changed = state.forward.iter().find(|(_, name, value)| {
+                self.labels.get(name).map(|label| label.value) != Some(*value)
+            });
+            let base_moved = state.data_used && state.code_end != data_base;
+            data_base_settled = !base_moved;
+            data_base = state.code_end;
+            let values: HashMap<String, Option<u16>> = self
+                .labels
+                .iter()
+                .map(|(name, label)| (name.to_string(), label.value))
+                .collect();
+            let unchanged = values == previous && !base_moved;
+            let (line, symbol) = match (state.pending.take(), changed) {
+                (None, None) if !base_moved => return self.parsed(state, resolved_lines),
+                _ if unchanged && probed => return self.parsed(state, resolved_lines),
+                (Some(pending), _) => pending,
+                (None, Some((line, name, _))) => (line.clone(), name.to_string()),
+                (None, None) => (LineMeta::default(), "DSEG".to_string()),
+            };
+            debug!("pass {} left {} unsettled", pass, symbol);
+
+            if let Some(fixed) = probe.take() {
+                let moved = state
+                    .forward
+                    .iter()
+                    .any(|(_, name, _)| values.get(name) != fixed.get(name));
+                if moved {
+                    self.failures
+                        .push((ParserError::CircularReference(symbol).into(), Some(line)));
+                    return false;
+                }
+                // Back to the values which settled, for a last pass to lay the lines out with
+                for (name, label) in self.labels.iter_mut() {
+                    label.value = fixed.get(name).copied().flatten();
+                }
+                previous = fixed;
+                probed = true;
+            } else if unchanged {
+                if !self.labels.contains_key(&symbol) {
+                    let e = ExpressionError::UnknownIdentifier(symbol);
+                    self.failures
+                        .push((ParserError::ExpressionError(e).into(), Some(line)));
+                    return false;
+                }
+                let inputs: HashSet<&String> = state
+                    .forward
+                    .iter()
+                    .map(|(_, name, _)| name)
+                    .filter(|name| state.unsettled.contains(*name))
+                    .collect();
+                for name in inputs {
+                    if let Some(label) = self.labels.get_mut(name) {
+                        label.value = label.value.map(|v| v.wrapping_add(RELOCATION_PROBE));
                     }
-                    macros.insert(label.to_string(), Macro::new());
-                    current_macro_name = Some(label);
-                    continue;
                 }
-                "ENDM" => {
-                    if current_macro_name.is_none() {
-                        debug!("@{:<03} ENDM found with no MACRO", line.line_no);
-                        return Err(ParserError::NotInMacro);
+                probe = Some(values);
+            } else {
+                previous = values;
+            }
+
+            if pass == MAX_PASSES {
+                // Values still moving between the same symbols are chasing each other
+                let e = if state.defined == defined {
+                    ParserError::CircularReference(symbol)
+                } else {
+                    ParserError::UnstableValue(symbol)
+                };
+                self.failures.push((e.into(), Some(line)));
+                return false;
+            }
+            settled = state.settled();
+            defined = state.defined;
+            // Another pass is made, this one's errors may not stand
+            self.failures.truncate(loaded);
+        }
+        unreachable!("the last pass always returns")
+    }
+
+    /// Keeps what the last pass worked out, returning that it settled
+    fn parsed(&mut self, mut state: ParseState, resolved_lines: Vec<LineMeta>) -> bool {
+        for (w, line) in state.warnings.drain(..) {
+            self.warn(w, line);
+        }
+        self.data_base = Some(state.code_end).filter(|_| state.data_used);
+        self.publics = state.publics;
+        self.macros = RefCell::new(state.macros);
+        self.lines = RefCell::new(resolved_lines);
+        self.prog_width = state.highest_address;
+        true
+    }
+
+    /// The value of an expression needed while parsing, and whether it's settled
+    ///
+    /// Symbols defined further on take their value from the last pass. One not known at all is
+    /// taken to be zero for now, another pass is asked for and if it still isn't known then, the
+    /// error is given.
+    fn evaluate(&self, expr: &str, state: &mut ParseState) -> Result<(u16, bool), ParserError> {
+        let line = self.erroring_line.as_ref();
+        let expr = &state.resolve_functions(expr)?;
+        // `$` moves with the layout as much as any label
+        let mut settled = !(state.layout_unsettled && find_unquoted(expr, '$').is_some());
+        for name in symbols::identifiers(expr) {
+            let label = match self.labels.get(&name) {
+                Some(label) => label,
+                None => continue,
+            };
+            let is_settled = if state.defined.contains(&name) {
+                !state.unsettled.contains(&name)
+            } else {
+                let line = line.cloned().unwrap_or_default();
+                state.forward.push((line, name.to_string(), label.value));
+                state.settled_before.contains(&name)
+            };
+            if !is_settled {
+                settled = false;
+                state.wait_on(line, &name);
+            }
+        }
+        match parse_expression_u16(expr, state.address, &self.labels) {
+            Ok((val, _)) => Ok((val, settled)),
+            Err(ExpressionError::UnknownIdentifier(name)) => {
+                state.wait_on(line, &name);
+                Ok((0, false))
+            }
+            // Values from the last pass may overflow or divide by zero where the settled ones won't
+            Err(_) if !settled => Ok((0, false)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Like [Self::evaluate] for a value which moves the code after it
+    fn evaluate_layout(&self, expr: &str, state: &mut ParseState) -> Result<u16, ParserError> {
+        let (val, settled) = self.evaluate(expr, state)?;
+        if !settled {
+            state.layout_unsettled = true;
+        }
+        Ok(val)
+    }
+
+    /// Whether an `IF` or `ELSEIF` line's condition holds
+    fn condition_of(&self, line: &LineMeta, state: &mut ParseState) -> Result<bool, ParserError> {
+        let arg = line
+            .args_list
+            .first()
+            .ok_or(ParserError::WrongNumberOfArgs(1, 0))?;
+        // What's assembled after depends on the condition as much as on any address
+        let val = self.evaluate_layout(arg, state)?;
+        Ok(val > 0)
+    }
+
+    /// Holy shit, this does a lot. And might be the single worst function I've ever written...
+    ///
+    /// ## Notes
+    ///
+    /// `self.labels` is populated in place, symbols not yet defined in this pass have their values
+    /// from the last, see [Self::evaluate]
+    ///
+    /// IFs conditions are evaluated implying the same as before, if the condition is `false`, then
+    /// the following lines are not loaded until an ENDIF is found
+    ///
+    /// Macro bodies are recorded as written. Each call substitutes its arguments and `LOCAL`s into
+    /// the body and parses the result here in turn, at the address of the call, the lines it
+    /// resolves to are kept as the call's expansion
+    ///
+    /// Some expressions are calculated here (earlier than I would like):
+    /// - IF, DB, DW, DS, ORG, as they are needed to control the current address
+    /// - EQU, SET, as they *may* be needed in the above
+    ///
+    /// Lines without op codes are macro invocations, these will not be be passed to the
+    /// expression parser
+    ///
+    /// ## Things Done Here
+    ///
+    /// - Addresses are calculated
+    /// - Instruction widths are known
+    /// - Macros are expanded
+    /// - IFs' conditions are known
+    /// - The relevant lines are separated from the rest
+    fn parse_lines(
+        &mut self,
+        lines: &[LineMeta],
+        state: &mut ParseState,
+        resolved_lines: &mut Vec<LineMeta>,
+    ) -> Result<Flow, ParserError> {
+        let mut conditions = ConditionStack::new();
+
+        for line in lines.iter() {
+            self.erroring_line = Some(LineMeta::erroring(line));
+            match self.parse_line(line, &mut conditions, state, resolved_lines) {
+                Ok(Flow::Continue) => {}
+                Ok(flow) => return Ok(flow),
+                // The line is left out and parsing carries on to find any other errors
+                Err(e) => {
+                    let line = self.erroring_line.take();
+                    state.errors.push((e, line));
+                }
+            }
+        }
+
+        if conditions.depth() > 0 {
+            debug!("@EOF IFs without ENDIF");
+            self.erroring_line = None;
+            return Err(ParserError::NoEndIf);
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// One line of [Self::parse_lines], a flow other than [Flow::Continue] ends the lines
+    fn parse_line(
+        &mut self,
+        line: &LineMeta,
+        conditions: &mut ConditionStack,
+        state: &mut ParseState,
+        resolved_lines: &mut Vec<LineMeta>,
+    ) -> Result<Flow, ParserError> {
+        // Everything up to the matching ENDM is the body of the macro being defined
+        if let Some(definition) = state.defining.as_mut() {
+            match line.inst.as_deref() {
+                Some(inst) if opens_block(inst) => definition.nesting += 1,
+                Some("ENDM") if definition.nesting == 0 => {
+                    // Conditionals in a macro's body are only checked for balance here,
+                    // they're evaluated when the macro is expanded
+                    if definition.conditions.depth() > 0 {
+                        debug!("@{:<03} ENDM reached inside IF", line.line_no);
+                        state.defining = None;
+                        return Err(ParserError::NoEndIf);
                     }
                     debug!("@{:<03} ENDM reached", line.line_no);
-                    current_macro_name = None;
-                    macro_address = load_address;
-                    continue;
-                }
-                "ORG" => {
-                    if let Some(name) = current_macro_name {
-                        debug!("@{:<03} ORG used in MACRO '{}'", line.line_no, name);
-                        return Err(ParserError::OrigInMacro);
+                    // A label on the ENDM marks the end of each expansion
+                    if let Some(colon) = line.label.as_ref().and(line.raw_line.find(':')) {
+                        definition.body.lines.push(LineMeta {
+                            line_no: line.line_no,
+                            ..LineMeta::label_only(
+                                line.label.clone(),
+                                None,
+                                line.raw_line[..=colon].to_string(),
+                            )
+                        });
                     }
-                    let arg = &line.args_list[0];
-                    let new_address =
-                        match parse_expression_u16(arg.to_string(), address, &self.labels) {
-                            Ok((val, _)) => val,
-                            Err(e) => {
-                                debug!("@{:<03} invalid expr '{}'", line.line_no, arg);
-                                return Err(ParserError::ExpressionError(e));
+                    let definition = state.defining.take().unwrap();
+                    match definition.repeat {
+                        Some(repeat) => {
+                            let mut new_line = repeat.line.clone();
+                            let (lines, flow) = self.repeat(repeat, &definition.body, state)?;
+                            new_line.width = lines.iter().map(|l| l.width).sum();
+                            new_line.expansion = Some(lines);
+                            state.place(new_line, resolved_lines);
+                            if flow == Flow::End {
+                                return Ok(flow);
                             }
-                        };
-                    debug!("@{:<03} ORG with new address {}", line.line_no, new_address);
-                    address = new_address;
-                    if address > highest_address {
-                        highest_address = address;
+                        }
+                        None => {
+                            state.macros.insert(definition.name, definition.body);
+                        }
                     }
-                    continue;
+                    return Ok(Flow::Continue);
                 }
-                "IF" | "ENDIF" | "SET" | "EQU" => continue,
-                _ => {}
+                Some("ENDM") => definition.nesting -= 1,
+                Some("ORG") => {
+                    debug!(
+                        "@{:<03} ORG used in MACRO '{}'",
+                        line.line_no, definition.name
+                    );
+                    return Err(ParserError::OrigInMacro);
+                }
+                Some(inst) => {
+                    if let Some(directive) = Directive::from_inst(inst) {
+                        definition.conditions.apply(directive, || Ok(true))?;
+                    }
+                }
+                None => {}
+            }
+            definition.body.lines.push(line.clone());
+            return Ok(Flow::Continue);
+        }
+
+        // The conditionals control skipping, lines are skipped until a true branch or ENDIF
+        if let Some(directive) = line.inst.as_deref().and_then(Directive::from_inst) {
+            let skipping = conditions.skipping();
+            conditions.apply(directive, || {
+                let cond = self.condition_of(line, state)?;
+                debug!(
+                    "@{:<03} {:?} condition is {}",
+                    line.line_no, directive, cond
+                );
+                Ok(cond)
+            })?;
+            if skipping && conditions.skipping() {
+                self.skip(line, state);
             }
+            return Ok(Flow::Continue);
+        }
 
-            // If an op_code is present, this is a predefined instruction
-            if let Some(op_code) = &line.op_code {
-                debug!("@{:<03} {:?} is not a macro", line.line_no, line.inst);
+        if line.inst.as_deref() == Some("END") {
+            debug!("@{:<03} END found, leaving parser", line.line_no);
+            self.ended = true;
+            if let Some(arg) = line.args_list.first() {
+                let (val, _) = self.evaluate(arg, state)?;
+                debug!("@{:<03} start address is {}", line.line_no, val);
+                self.start_address = Some(val);
+            }
+            return Ok(Flow::End);
+        }
 
-                let inst_meta = I8080_OP_META[*op_code as usize];
+        if conditions.skipping() {
+            self.skip(line, state);
+            return Ok(Flow::Continue);
+        }
 
-                // Check if instructions which requires a label, has one
-                if inst_meta.labelled && line.label.is_none() {
-                    debug!(
-                        "@{:<03} {:?} requires a label but none is present",
-                        line.line_no, line.inst
-                    );
-                    return Err(ParserError::OperationRequiresLabel(inst_name.clone()));
+        match line.inst.as_deref() {
+            Some("EXITM") | Some("LOCAL") if state.depth == 0 => {
+                debug!("@{:<03} {:?} outside of a macro", line.line_no, line.inst);
+                return Err(ParserError::NotInMacro);
+            }
+            Some("EXITM") => {
+                debug!("@{:<03} EXITM leaving macro", line.line_no);
+                return Ok(Flow::Exit);
+            }
+            // These were substituted before the expansion was parsed
+            Some("LOCAL") => return Ok(Flow::Continue),
+            _ => {}
+        }
+
+        // Lines of a structure lay out its fields rather than being placed
+        if state.record.is_some() {
+            return self.struct_line(line, state);
+        }
+
+        if let Some(label) = &line.label {
+            debug!("@{:<03} contains label ({:?})", line.line_no, line.label);
+
+            if label.contains('&') {
+                return Err(ParserError::InvalidLabel(label.to_string()));
+            }
+
+            // The source can only guard against a -D, not replace it
+            if self.command_line.contains(label) {
+                return Err(ParserError::DefinedOnCommandLine(label.to_string()));
+            }
+
+            // Check if we're overwriting a label on any op but SET, or a macro being redefined
+            if state.defined.contains(label) {
+                debug!(
+                    "@{:<03} label ({:?}) on inst {:?} already loaded",
+                    line.line_no, line.inst, line.label
+                );
+                let redefinition = match line.inst.as_deref() {
+                    Some("SET") => true,
+                    Some("MACRO") => state.macros.contains_key(label),
+                    _ => false,
+                };
+                if !redefinition {
+                    return Err(ParserError::LabelAlreadyDefined(
+                        label.to_string(),
+                        *self.labels.get(label).unwrap(),
+                    ));
+                }
+                if line.inst.as_deref() == Some("SET")
+                    && self.labels.get(label).is_some_and(|l| l.is_eq)
+                {
+                    state.warnings.push((
+                        Warning::ShadowedEqu(label.to_string()),
+                        Some(LineMeta::erroring(line)),
+                    ));
                 }
+            }
 
-                // Check we have the correct number of arguments
-                if inst_meta.define {
-                    // Check varargs has at least one arg
-                    if line.args_list.is_empty() {
-                        debug!(
-                            "@{:<03} define {:?} contains no args",
-                            line.line_no, line.inst,
-                        );
-                        return Err(ParserError::NoArgsForVariadic);
-                    } else {
+            self.definitions
+                .entry(label.to_string())
+                .or_insert(line.line_no);
+
+            // Load it labels without setting a value
+            if !line.label_only {
+                let inst = line.inst.as_ref().unwrap().as_str();
+                match inst {
+                    // We're checking the use of EQU multiple times above
+                    "SET" | "EQU" => {
+                        let (val, settled) = self.evaluate(&line.args_list[0], state)?;
+                        state.define(label, settled);
+                        // A value from an address moves with it when linked
+                        let segment = if self.args.writes_object() && settled {
+                            let arg = &line.args_list[0];
+                            match self.relocation_of(arg, state.address, state.segment)? {
+                                None => Segment::Absolute,
+                                Some(Target::Code) => Segment::Code,
+                                Some(Target::Data) => Segment::Data,
+                                Some(Target::External(_) | Target::Common(_)) => {
+                                    return Err(ParserError::NotRelocatable(arg.to_string()))
+                                }
+                            }
+                        } else {
+                            Segment::Absolute
+                        };
                         debug!(
-                            "@{:<03} define {:?} with args ({:?})",
-                            line.line_no, line.inst, line.args_list,
+                            "@{:<03} label ({:?}) loaded with val ({}) for {:?}",
+                            line.line_no, line.label, val, line.inst
                         );
-                        width =
-                            self.width_of_data_storage(inst_name.to_string(), &line.args_list)?;
+                        if inst == "EQU" {
+                            let equ = Label::new_equ(Some(val)).in_segment(segment);
+                            self.labels.insert(label.to_string(), equ);
+                        } else {
+                            let set = Label::new_set(Some(val)).in_segment(segment);
+                            self.labels.insert(label.to_string(), set);
+                            state.sets.insert(label.to_string(), val.to_string());
+                        }
                     }
-                } else {
-                    // Check set args has correct #args
-                    if inst_meta.asm_arg_count != line.args_list.len() {
+                    // This includes MACRO as macro labels can be used in expressions
+                    _ => {
                         debug!(
-                            "@{:<03} {:?} expected {} args (args: {:?})",
-                            line.line_no, line.inst, inst_meta.asm_arg_count, line.args_list,
+                            "@{:<03} label ({:?}) loaded with addr ({})",
+                            line.line_no, line.label, state.address,
                         );
-                        return Err(ParserError::WrongNumberOfArgs(
-                            inst_meta.asm_arg_count,
-                            line.args_list.len(),
-                        ));
-                    } else {
-                        debug!(
-                            "@{:<03} {:?} with {} (args: {:?})",
-                            line.line_no,
-                            line.inst,
-                            inst_meta.width(),
-                            line.args_list,
+                        state.define(label, !state.layout_unsettled);
+                        self.labels.insert(
+                            label.to_string(),
+                            Label::new_addr(Some(state.address)).in_segment(state.segment),
                         );
-                        width = inst_meta.width();
                     }
-                }
+                };
+            } else {
+                debug!(
+                    "@{:<03} label-only ({:?}) loaded with addr ({})",
+                    line.line_no, line.label, state.address,
+                );
+                state.define(label, !state.layout_unsettled);
+                self.labels.insert(
+                    label.to_string(),
+                    Label::new_addr(Some(state.address)).in_segment(state.segment),
+                );
+            }
+        }
+
+        if line.label_only {
+            let mut new_line = line.clone();
+            new_line.address = state.address;
+            new_line.width = 0;
+            resolved_lines.push(new_line);
+            return Ok(Flow::Continue);
+        }
+
+        let width: usize;
+        let mut expansion: Option<Vec<LineMeta>> = None;
+        let mut flow = Flow::Continue;
+
+        let inst_name = line.inst.as_ref().unwrap();
+
+        // Directives aren't placed, they change the state of the parse
+        if let Some(flow) = self.directive(line, conditions, state)? {
+            return Ok(flow);
+        }
+
+        // If an op_code is present, this is a predefined instruction
+        if let Some(op_code) = &line.op_code {
+            debug!("@{:<03} {:?} is not a macro", line.line_no, line.inst);
+
+            let inst_meta = I8080_OP_META[*op_code as usize];
+
+            // Check if instructions which requires a label, has one
+            if inst_meta.labelled && line.label.is_none() {
+                debug!(
+                    "@{:<03} {:?} requires a label but none is present",
+                    line.line_no, line.inst
+                );
+                return Err(ParserError::OperationRequiresLabel(inst_name.clone()));
             }
-            // The only other thing it could be is a macro which should take no arguments
-            else {
-                debug!("@{:<03} {:?} should be a macro", line.line_no, line.inst);
 
-                if !line.args_list.is_empty() {
-                    debug!("@{:<03} {:?} macro has arguments", line.line_no, line.inst);
-                    return Err(ParserError::WrongNumberOfArgs(0, line.args_list.len()));
+            // Check we have the correct number of arguments
+            if inst_meta.define {
+                // Check varargs has at least one arg
+                if line.args_list.is_empty() {
+                    debug!(
+                        "@{:<03} define {:?} contains no args",
+                        line.line_no, line.inst,
+                    );
+                    return Err(ParserError::NoArgsForVariadic);
+                } else {
+                    debug!(
+                        "@{:<03} define {:?} with args ({:?})",
+                        line.line_no, line.inst, line.args_list,
+                    );
+                    width =
+                        self.width_of_data_storage(inst_name.to_string(), &line.args_list, state)?;
                 }
+            } else {
+                // Check set args has correct #args
+                if inst_meta.asm_arg_count != line.args_list.len() {
+                    debug!(
+                        "@{:<03} {:?} expected {} args (args: {:?})",
+                        line.line_no, line.inst, inst_meta.asm_arg_count, line.args_list,
+                    );
+                    return Err(ParserError::WrongNumberOfArgs(
+                        inst_meta.asm_arg_count,
+                        line.args_list.len(),
+                    ));
+                } else {
+                    debug!(
+                        "@{:<03} {:?} with {} (args: {:?})",
+                        line.line_no,
+                        line.inst,
+                        inst_meta.width(),
+                        line.args_list,
+                    );
+                    width = inst_meta.width();
+                }
+            }
+        }
+        // Or an instance of a structure, its fields are placed much as a macro's lines
+        else if state.structs.contains_key(inst_name.as_str()) {
+            let lines = self.expand_instance(inst_name, line, state)?;
+            width = lines.iter().map(|l| l.width).sum();
+            expansion = Some(lines);
+        }
+        // The only other thing it could be is a macro
+        else {
+            debug!("@{:<03} {:?} should be a macro", line.line_no, line.inst);
+            let (lines, inner_flow) = self.expand_macro(line, state)?;
+            width = lines.iter().map(|l| l.width).sum();
+            expansion = Some(lines);
+            // Only END carries on past the macro, EXITM just leaves the one it's in
+            if inner_flow == Flow::End {
+                flow = Flow::End;
+            }
+            debug!(
+                "@{:<03} macro {:?} is of length {}",
+                line.line_no, line.inst, width,
+            );
+        }
 
-                // Check that the macro call is not from within the definition of the macro
-                if let Some(name) = current_macro_name {
-                    if name == *inst_name {
-                        debug!(
-                            "@{:<03} {:?} macro is called within its definition",
-                            line.line_no, line.inst
-                        );
-                        return Err(ParserError::RecursiveMacro);
+        let mut new_line = line.clone();
+        new_line.width = width;
+        new_line.expansion = expansion;
+        // Code is generated once every line is placed, by when a SET may have moved on and
+        // anything may have been defined
+        if line.op_code.is_some() {
+            new_line.args_list = line
+                .args_list
+                .iter()
+                .map(|arg| state.resolve_arg(arg))
+                .collect::<Result<_, _>>()?;
+        }
+        state.place(new_line, resolved_lines);
+        Ok(flow)
+    }
+
+    /// A line of a directive which isn't placed, such as `MACRO` or `ORG`, or `None` if it's to
+    /// be placed
+    ///
+    /// These are kept out of [Self::parse_line] as it recurses for each macro expansion.
+    fn directive(
+        &mut self,
+        line: &LineMeta,
+        conditions: &ConditionStack,
+        state: &mut ParseState,
+    ) -> Result<Option<Flow>, ParserError> {
+        let inst_name = line.inst.as_ref().unwrap();
+        // Conditionals are processed before these
+        match inst_name.as_str() {
+            "MACRO" => {
+                let label = line.label.as_ref().unwrap();
+                debug!("@{:<03} macro {:?} to be loaded", line.line_no, line.label);
+                state.defining = Some(Definition {
+                    name: label.to_string(),
+                    body: Macro::with_params(&line.args_list)?,
+                    nesting: 0,
+                    conditions: ConditionStack::new(),
+                    repeat: None,
+                });
+                Ok(Some(Flow::Continue))
+            }
+            "REPT" | "IRP" | "IRPC" => {
+                let iterations = match inst_name.as_str() {
+                    "IRP" => macros::irp(&line.args_list)?,
+                    "IRPC" => macros::irpc(&line.args_list)?,
+                    _ => {
+                        let arg = match line.args_list.as_slice() {
+                            [arg] => arg,
+                            args => return Err(ParserError::WrongNumberOfArgs(1, args.len())),
+                        };
+                        let count = self.evaluate_layout(arg, state)?;
+                        vec![HashMap::new(); count as usize]
                     }
+                };
+                debug!(
+                    "@{:<03} {} block of {} iterations",
+                    line.line_no,
+                    inst_name,
+                    iterations.len()
+                );
+                state.defining = Some(Definition {
+                    name: inst_name.to_string(),
+                    body: Macro::default(),
+                    nesting: 0,
+                    conditions: ConditionStack::new(),
+                    repeat: Some(Repeat {
+                        line: line.clone(),
+                        iterations,
+                    }),
+                });
+                Ok(Some(Flow::Continue))
+            }
+            "ENDM" => {
+                debug!("@{:<03} ENDM found with no MACRO", line.line_no);
+                Err(ParserError::NotInMacro)
+            }
+            "ORG" => {
+                let (new_address, settled) = self.evaluate(&line.args_list[0], state)?;
+                // A settled ORG outside of any IF places what follows it whatever came before
+                if !settled {
+                    state.layout_unsettled = true;
+                } else if conditions.depth() == 0 && state.depth == 0 {
+                    state.layout_unsettled = false;
                 }
-
-                // Get the width of the macro as set by this function
-                match macros.get(inst_name) {
-                    Some(_macro) => {
-                        debug!(
-                            "@{:<03} macro {:?} is of length {}",
-                            line.line_no, line.inst, _macro.width,
-                        );
-                        width = _macro.width;
+                debug!("@{:<03} ORG with new address {}", line.line_no, new_address);
+                // Data is placed after the code, ORG within it is from where it starts
+                state.address = match state.segment {
+                    Segment::Data => state.data_base.wrapping_add(new_address),
+                    _ => new_address,
+                };
+                state.reach();
+                Ok(Some(Flow::Continue))
+            }
+            "STRUCT" => {
+                let name = match line.args_list.as_slice() {
+                    [name] if is_identifier(name) => name,
+                    [name] => {
+                        return Err(ParserError::InvalidArgument(
+                            inst_name.to_string(),
+                            name.to_string(),
+                        ))
                     }
-                    None => {
-                        debug!("@{:<03} macro {:?} not found", line.line_no, line.inst);
-                        return Err(ParserError::MacroUseBeforeCreation);
+                    args => return Err(ParserError::WrongNumberOfArgs(1, args.len())),
+                };
+                debug!("@{:<03} STRUCT {} to be laid out", line.line_no, name);
+                state.record = Some(Struct::new(name.as_str()));
+                Ok(Some(Flow::Continue))
+            }
+            "ENDS" => {
+                debug!("@{:<03} ENDS found with no STRUCT", line.line_no);
+                Err(ParserError::NotInStruct)
+            }
+            "CSEG" => {
+                self.segment_switches.push(line.line_no);
+                state.switch_to(Segment::Code);
+                Ok(Some(Flow::Continue))
+            }
+            "DSEG" => {
+                self.segment_switches.push(line.line_no);
+                state.switch_to(Segment::Data);
+                Ok(Some(Flow::Continue))
+            }
+            "PUBLIC" | "EXTRN" if line.args_list.is_empty() => Err(ParserError::NoArgsForVariadic),
+            "PUBLIC" => {
+                for name in line.args_list.iter() {
+                    if !is_identifier(name) {
+                        return Err(ParserError::InvalidArgument(
+                            inst_name.to_string(),
+                            name.to_string(),
+                        ));
                     }
+                    state
+                        .publics
+                        .push((name.to_string(), LineMeta::erroring(line)));
                 }
+                Ok(Some(Flow::Continue))
             }
-
-            let mut new_line = line.clone();
-            new_line.width = width;
-
-            if let Some(name) = current_macro_name {
-                // Cannot assign storage in a macro
-                match inst_name.as_str() {
-                    "DB" | "DW" | "DS" => {
-                        debug!("@{:<03} {} found in macro", line.line_no, inst_name);
-                        return Err(ParserError::DefineInMacro);
+            "EXTRN" => {
+                for name in line.args_list.iter() {
+                    if !self.args.writes_object() {
+                        return Err(ParserError::ExternalNotLinked(name.to_string()));
                     }
-                    _ => {}
+                    if !is_identifier(name) {
+                        return Err(ParserError::InvalidArgument(
+                            inst_name.to_string(),
+                            name.to_string(),
+                        ));
+                    }
+                    if let Some(label) = self
+                        .labels
+                        .get(name)
+                        .filter(|_| state.defined.contains(name))
+                    {
+                        return Err(ParserError::LabelAlreadyDefined(name.to_string(), *label));
+                    }
+                    state.define(name, true);
+                    self.definitions
+                        .entry(name.to_string())
+                        .or_insert(line.line_no);
+                    let label = Label::new_addr(Some(0)).in_segment(Segment::External);
+                    self.labels.insert(name.to_string(), label);
                 }
-                // Cannot use an IF/ENDIF pair in a macro (implementation, not spec)
-                if inside_if {
-                    debug!("@{:<03} IF found within macro", line.line_no);
-                    return Err(ParserError::IfAndMacroMix);
+                Ok(Some(Flow::Continue))
+            }
+            "ASSERT" => {
+                let (arg, message) = match line.args_list.as_slice() {
+                    [arg] => (arg, arg.to_string()),
+                    [arg, message] => match macros::unquote(message) {
+                        Some(message) => (arg, message.to_string()),
+                        None => {
+                            return Err(ParserError::InvalidArgument(
+                                inst_name.to_string(),
+                                message.to_string(),
+                            ))
+                        }
+                    },
+                    args => return Err(ParserError::WrongNumberOfArgs(2, args.len())),
+                };
+                // Values are only certain once settled, a later pass checks the rest
+                let (val, settled) = self.evaluate(arg, state)?;
+                if settled && val == 0 {
+                    return Err(ParserError::AssertionFailed(message));
                 }
+                Ok(Some(Flow::Continue))
+            }
+            "SET" | "EQU" => Ok(Some(Flow::Continue)),
+            _ => Ok(None),
+        }
+    }
 
-                new_line.address = macro_address;
+    /// The fields of an instance of a structure, placed from the current address
+    fn expand_instance(
+        &mut self,
+        name: &str,
+        line: &LineMeta,
+        state: &mut ParseState,
+    ) -> Result<Vec<LineMeta>, ParserError> {
+        let record = state.structs[name].clone();
+        debug!("@{:<03} instance of STRUCT {}", line.line_no, record.name);
+        let lines = record.instance(&line.args_list, line.line_no, |inst, args| {
+            self.width_of_data_storage(inst.to_string(), args, state)
+        })?;
+        let address = state.address;
+        let (lines, _) = self.expand_body(&lines, HashMap::new(), state)?;
+        state.address = address;
+        Ok(lines)
+    }
 
+    /// A line between `STRUCT` and `ENDS`, adding a field to the structure being laid out
+    fn struct_line(
+        &mut self,
+        line: &LineMeta,
+        state: &mut ParseState,
+    ) -> Result<Flow, ParserError> {
+        let inst = match line.inst.as_deref() {
+            // A label alone names the offset of the next field
+            None => {
+                let record = state.record.as_ref().unwrap();
+                if let Some(label) = &line.label {
+                    let (symbol, offset) = (record.symbol(label), record.size());
+                    self.define_struct_symbol(&symbol, offset, line, state)?;
+                }
+                return Ok(Flow::Continue);
+            }
+            Some("ENDS") => {
+                let record = state.record.take().unwrap();
                 debug!(
-                    "@{:<03} adding {} to '{}' macro width",
-                    line.line_no, width, name
+                    "@{:<03} ENDS of {} with size {}",
+                    line.line_no,
+                    record.name,
+                    record.size()
                 );
-                let m = macros.get_mut(name).unwrap();
-                m.width += width;
-                m.lines.push(new_line);
+                self.define_struct_symbol(&record.name, record.size(), line, state)?;
+                state.structs.insert(record.name.clone(), record);
+                return Ok(Flow::Continue);
+            }
+            Some(inst @ ("DB" | "DW" | "DS" | "DC")) => inst,
+            Some(inst) => return Err(ParserError::InvalidInStruct(inst.to_string())),
+        };
+        if line.args_list.is_empty() {
+            return Err(ParserError::NoArgsForVariadic);
+        }
+        let width = self.width_of_data_storage(inst.to_string(), &line.args_list, state)?;
+        let record = state.record.as_mut().unwrap();
+        let offset = record.push(line.label.clone(), inst, &line.args_list, width);
+        if let Some(label) = &line.label {
+            let symbol = record.symbol(label);
+            self.define_struct_symbol(&symbol, offset, line, state)?;
+        }
+        Ok(Flow::Continue)
+    }
 
-                macro_address += width as u16;
-                trace!(
-                    "@{:<03}+1 new macro ({}) address: {}",
-                    line.line_no,
-                    name,
-                    address
+    /// Gives a structure's size or the offset of a field to its symbol, as if by `EQU`
+    fn define_struct_symbol(
+        &mut self,
+        name: &str,
+        value: u16,
+        line: &LineMeta,
+        state: &mut ParseState,
+    ) -> Result<(), ParserError> {
+        if self.command_line.contains(name) {
+            return Err(ParserError::DefinedOnCommandLine(name.to_string()));
+        }
+        if let Some(label) = self
+            .labels
+            .get(name)
+            .filter(|_| state.defined.contains(name))
+        {
+            return Err(ParserError::LabelAlreadyDefined(name.to_string(), *label));
+        }
+        self.definitions
+            .entry(name.to_string())
+            .or_insert(line.line_no);
+        state.define(name, !state.layout_unsettled);
+        self.labels
+            .insert(name.to_string(), Label::new_equ(Some(value)));
+        Ok(())
+    }
+
+    /// Notes a line left out by a conditional, for the listing
+    fn skip(&mut self, line: &LineMeta, state: &ParseState) {
+        // Lines of a macro's body may be skipped at one call and not another
+        if state.depth == 0 {
+            self.skipped_lines.insert(line.line_no);
+        }
+    }
+
+    /// Whether the source defines a macro called `name` anywhere
+    fn defines_macro(&self, name: &str) -> bool {
+        self.source.iter().any(|raw_line| {
+            self.tokenize_line(raw_line).is_ok_and(|lines| {
+                lines.iter().any(|meta| {
+                    meta.inst.as_deref() == Some("MACRO") && meta.label.as_deref() == Some(name)
+                })
+            })
+        })
+    }
+
+    /// The lines a macro call resolves to at the current address, with its arguments and `LOCAL`
+    /// labels substituted into the body
+    fn expand_macro(
+        &mut self,
+        line: &LineMeta,
+        state: &mut ParseState,
+    ) -> Result<(Vec<LineMeta>, Flow), ParserError> {
+        let name = line.inst.as_ref().unwrap();
+        let _macro = match state.macros.get(name) {
+            Some(_macro) => _macro.clone(),
+            None if self.defines_macro(name) => {
+                debug!(
+                    "@{:<03} macro {:?} not yet defined",
+                    line.line_no, line.inst
                 );
-            } else {
-                new_line.address = address;
-                debug!("@{:<03} adding to resolved lines", line.line_no);
-                resolved_lines.push(new_line);
-                // Address doesn't need updating in a macro
-                if (address as u32) + (width as u32) > 0xffff {
-                    warn!("@{:<03} program wraps the address space", line.line_no);
-                }
-                address += width as u16;
-                trace!("@{:<03}+1 new address: {}", line.line_no, address);
-                if address > highest_address {
-                    trace!("@{:<03} highest address set", line.line_no);
-                    highest_address = address;
-                }
+                return Err(ParserError::MacroUseBeforeCreation);
             }
+            None => {
+                debug!(
+                    "@{:<03} no instruction or macro {:?}",
+                    line.line_no, line.inst
+                );
+                let e = OpParseError::NoSuchInstruction(name.to_string());
+                return Err(ParserError::NoInstructionFound(e));
+            }
+        };
+        let replacements = _macro.bind(&line.args_list)?;
+        let address = state.address;
+        let expanded = self.expand_body(&_macro.lines, replacements, state);
+        // The call's own line moves the address on by the whole expansion
+        state.address = address;
+        expanded
+    }
+
+    /// Each iteration of a repeat block in turn, until one leaves with `EXITM` or `END`
+    fn repeat(
+        &mut self,
+        repeat: Repeat,
+        body: &Macro,
+        state: &mut ParseState,
+    ) -> Result<(Vec<LineMeta>, Flow), ParserError> {
+        let address = state.address;
+        let mut expansion = vec![];
+        let mut flow = Flow::Continue;
+        for replacements in repeat.iterations {
+            let (mut lines, inner_flow) = self.expand_body(&body.lines, replacements, state)?;
+            expansion.append(&mut lines);
+            if inner_flow != Flow::Continue {
+                flow = inner_flow;
+                break;
+            }
+        }
+        // EXITM only leaves the block
+        if flow == Flow::Exit {
+            flow = Flow::Continue;
         }
+        state.address = address;
+        Ok((expansion, flow))
+    }
 
-        self.erroring_line = None;
+    /// Parses the lines of a macro or repeat block's body from the current address, after
+    /// substituting `replacements` and its `LOCAL`s
+    fn expand_body(
+        &mut self,
+        lines: &[LineMeta],
+        mut replacements: HashMap<String, String>,
+        state: &mut ParseState,
+    ) -> Result<(Vec<LineMeta>, Flow), ParserError> {
+        if state.depth >= MAX_MACRO_DEPTH {
+            debug!("macro expansions nested too deeply");
+            return Err(ParserError::RecursiveMacro);
+        }
 
-        if inside_if {
-            debug!("@EOF IFs without ENDIF");
-            Err(ParserError::NoEndIf)
-        } else if let Some(name) = current_macro_name {
-            debug!("@EOF MACRO '{}' without ENDM", name);
-            Err(ParserError::NoEndMacro)
+        state.expansions += 1;
+        // Only the body's own LOCALs, not those of any block within it
+        let mut nesting = 0;
+        for mline in lines.iter() {
+            match mline.inst.as_deref() {
+                Some(inst) if opens_block(inst) => nesting += 1,
+                Some("ENDM") => nesting -= 1,
+                Some("LOCAL") if nesting == 0 => {
+                    for local in mline.args_list.iter() {
+                        replacements.insert(local.to_string(), local_name(local, state.expansions));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let body = if replacements.is_empty() {
+            lines.to_vec()
         } else {
-            self.macros = RefCell::new(macros);
-            self.lines = RefCell::new(resolved_lines);
-            self.prog_width = highest_address;
+            let mut body = vec![];
+            for mline in lines.iter() {
+                self.erroring_line = Some(LineMeta::erroring(mline));
+                let raw_line = substitute(&mline.raw_line, &replacements);
+                if let Some(mut meta) = tokenizer::tokenize(&raw_line)? {
+                    meta.line_no = mline.line_no;
+                    body.push(meta);
+                }
+            }
+            body
+        };
+
+        let mut expansion = vec![];
+        state.depth += 1;
+        let flow = self.parse_lines(&body, state, &mut expansion);
+        state.depth -= 1;
+        Ok((expansion, flow?))
+    }
 
-            Ok(())
+    pub(crate) fn labels(&self) -> &HashMap<String, Label> {
+        &self.labels
+    }
+
+    /// Each line of the last assembly's source with `INCLUDE`s spliced in, paired with the labels
+    /// it placed, including those placed by a macro it called. Lines skipped by conditionals are
+    /// left out
+    pub(crate) fn source_lines(&self) -> Vec<SourceLine<'_>> {
+        fn add_labels(line: &LineMeta, labels: &mut Vec<String>) {
+            labels.extend(line.label.iter().cloned());
+            for line in line.expansion.iter().flatten() {
+                add_labels(line, labels);
+            }
         }
+
+        let resolved = self.lines.borrow();
+        let mut by_line: HashMap<usize, Vec<String>> = HashMap::new();
+        for line in resolved.iter() {
+            add_labels(line, by_line.entry(line.line_no).or_default());
+        }
+        self.source
+            .iter()
+            .enumerate()
+            .map(|(idx, raw_line)| (idx + 1, raw_line))
+            .filter(|(line_no, _)| !self.skipped_lines.contains(line_no))
+            .map(|(line_no, raw_line)| {
+                let (file, line) = self.origin(line_no);
+                SourceLine {
+                    file: file.to_path_buf(),
+                    line_no: line,
+                    raw_line,
+                    labels: by_line.remove(&line_no).unwrap_or_default(),
+                }
+            })
+            .collect()
     }
 
     pub fn write(&self, bytes: Vec<u8>) -> Result<(), io::Error> {
-        fs::write(&self.args.output, &bytes)
+        if self.args.rom.shapes_image()
+            && (self.args.format != OutputFormat::Bin || self.args.object)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                RomError::NotBin,
+            ));
+        }
+        match self.args.format {
+            OutputFormat::Prl => fs::write(&self.args.output, self.prl(bytes).encode()),
+            OutputFormat::Rel => {
+                let rel = rel::write(&self.object(&bytes))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                fs::write(&self.args.output, rel)
+            }
+            _ if self.args.object => fs::write(&self.args.output, self.object(&bytes).to_string()),
+            OutputFormat::Bin if self.args.rom.shapes_image() => self.write_rom(bytes),
+            OutputFormat::Bin => fs::write(&self.args.output, &bytes),
+            OutputFormat::Ihex => fs::write(&self.args.output, ihex::encode(&self.image(&bytes))),
+        }
+    }
+
+    /// The image padded and checksummed, then any chunks of it beside the whole
+    fn write_rom(&self, bytes: Vec<u8>) -> Result<(), io::Error> {
+        let rom = &self.args.rom;
+        let bytes = rom::shape(&bytes, self.args.load_at as usize, rom)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(size) = rom.split {
+            for (idx, chunk) in rom::split(&bytes, size, rom.fill).iter().enumerate() {
+                let mut path = self.args.output.clone().into_os_string();
+                path.push(format!(".{}", idx));
+                fs::write(path, chunk)?;
+            }
+        }
+        fs::write(&self.args.output, bytes)
+    }
+
+    /// The last assembly as a PRL, the program was assembled at 0x0100
+    pub fn prl(&self, bytes: Vec<u8>) -> Prl {
+        Prl {
+            code: bytes.get(0x100..).unwrap_or_default().to_vec(),
+            relocated: self.page_relocated.clone(),
+            bss: 0,
+        }
+    }
+
+    /// The last assembly as a relocatable object, for `-c`
+    ///
+    /// Code was assembled from zero with the data after it, so words relative to the data are
+    /// moved back to be from where it starts.
+    pub fn object(&self, bytes: &[u8]) -> Object {
+        let end = self.prog_width as usize;
+        let data_base = self.data_base.unwrap_or(self.prog_width);
+        let mut code = bytes[..data_base as usize].to_vec();
+        let mut data = bytes[data_base as usize..end].to_vec();
+        let offset_of = |address: u16| match self.segment_at(address) {
+            Segment::Data => (Segment::Data, address - data_base),
+            segment => (segment, address),
+        };
+
+        let mut relocations = vec![];
+        for (address, target) in self.relocations.iter() {
+            let (segment, offset) = offset_of(*address);
+            if *target == Target::Data {
+                let bytes = if segment == Segment::Data {
+                    &mut data
+                } else {
+                    &mut code
+                };
+                let idx = offset as usize;
+                let word = util::vec_u8_to_u16(&bytes[idx..idx + 2]).wrapping_sub(data_base);
+                bytes[idx..idx + 2].copy_from_slice(&util::u16_to_vec_u8(word));
+            }
+            relocations.push(Relocation {
+                segment,
+                offset,
+                target: target.clone(),
+            });
+        }
+
+        let publics = self
+            .publics
+            .iter()
+            .filter_map(|(name, _)| {
+                let label = self.labels.get(name)?;
+                let value = label.value.unwrap_or_default();
+                Some(match label.segment {
+                    Segment::Code => (name.to_string(), Segment::Code, value),
+                    Segment::Data => (name.to_string(), Segment::Data, value - data_base),
+                    _ => (name.to_string(), Segment::Absolute, value),
+                })
+            })
+            .collect();
+        let mut externs: Vec<String> = self
+            .labels
+            .iter()
+            .filter(|(_, label)| label.segment == Segment::External)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        externs.sort();
+
+        Object {
+            name: self
+                .args
+                .input
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_uppercase())
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| "MAIN".to_string()),
+            code,
+            data,
+            publics,
+            externs,
+            relocations,
+            commons: vec![],
+            start: self.start_address.map(offset_of),
+        }
+    }
+
+    /// Labels, EQUs and SETs from the source, register definitions and macro names are left out
+    pub fn symbols(&self) -> HashMap<String, Label> {
+        let reg_defs = get_reg_defs();
+        let macros = self.macros.borrow();
+        self.labels
+            .iter()
+            .filter(|(name, _)| !self.args.defines_registers() || !reg_defs.contains_key(*name))
+            .filter(|(name, _)| !macros.contains_key(*name))
+            .map(|(name, label)| (name.to_string(), *label))
+            .collect()
+    }
+
+    /// The lines of the source referring to each symbol
+    fn references(&self) -> HashMap<String, Vec<usize>> {
+        let symbols = self.symbols();
+        let mut references: HashMap<String, Vec<usize>> = HashMap::new();
+        for (idx, raw_line) in self.source.iter().enumerate() {
+            let line_no = idx + 1;
+            let mut idents = vec![];
+            for meta in self.tokenize_line(raw_line).unwrap_or_default() {
+                if meta.label_only {
+                    continue;
+                }
+                idents.extend(symbols::identifiers(&meta.args_list.join(",")));
+                // Macro calls refer to the macro's label
+                idents.extend(meta.inst.filter(|_| meta.op_code.is_none()));
+            }
+            for ident in idents {
+                if symbols.contains_key(&ident) {
+                    let lines = references.entry(ident).or_default();
+                    if lines.last() != Some(&line_no) {
+                        lines.push(line_no);
+                    }
+                }
+            }
+        }
+        references
+    }
+
+    /// Each symbol's defining line and the lines which refer to it, see [symbols]
+    pub fn cross_reference(&self) -> String {
+        let symbols = self.symbols();
+        let references = self.references();
+        let definitions = self
+            .definitions
+            .iter()
+            .map(|(name, line_no)| (name.to_string(), self.describe_line(*line_no)))
+            .collect();
+        let references = references
+            .into_iter()
+            .map(|(name, lines)| {
+                let lines = lines.iter().map(|l| self.describe_line(*l)).collect();
+                (name, lines)
+            })
+            .collect();
+        symbols::write_xref(&symbols, &definitions, &references)
+    }
+
+    /// A listing of the last successful assembly, see [listing](super::listing)
+    pub fn listing(&self) -> String {
+        let resolved = self.lines.borrow();
+        let mut by_line: HashMap<usize, Vec<&LineMeta>> = HashMap::new();
+        for line in resolved.iter() {
+            by_line.entry(line.line_no).or_default().push(line);
+        }
+
+        let mut listing = vec![];
+        // Expansions of REPT, IRP and IRPC follow their block's ENDM, with the line it's on
+        let mut after_block: Vec<(usize, Vec<ListingLine>)> = vec![];
+        for (idx, raw_line) in self.source.iter().enumerate() {
+            let line_no = idx + 1;
+            while let Some(pos) = after_block.iter().position(|(end, _)| *end < line_no) {
+                listing.append(&mut after_block.remove(pos).1);
+            }
+            let mut row = self.listing_line(line_no, raw_line);
+            if self.skipped_lines.contains(&line_no) {
+                row.mark = ListingMark::Skipped;
+                listing.push(row);
+                continue;
+            }
+            let lines = match by_line.get(&line_no) {
+                Some(lines) => lines,
+                None => {
+                    // EQU and SET lines aren't kept, but an EQU's value never changes
+                    let lines = self.tokenize_line(raw_line).unwrap_or_default();
+                    if let Some(meta) = lines.into_iter().find(|m| m.inst.as_deref() == Some("EQU"))
+                    {
+                        row.value = meta
+                            .label
+                            .and_then(|l| self.labels.get(&l))
+                            .and_then(|l| l.value);
+                    }
+                    listing.push(row);
+                    continue;
+                }
+            };
+            // Statements after the first on a line are given rows of their own
+            let mut row = Some(row);
+            for line in lines.iter() {
+                let mut row = row
+                    .take()
+                    .unwrap_or_else(|| self.listing_line(line_no, line.raw_line.trim()));
+                row.address = Some(line.address);
+                if line.expansion.is_some() {
+                    listing.push(row);
+                    match line.inst.as_deref() {
+                        Some("REPT" | "IRP" | "IRPC") => {
+                            let mut rows = vec![];
+                            self.list_macro_call(line, &mut rows);
+                            after_block.push((self.block_end(line_no), rows));
+                        }
+                        _ => self.list_macro_call(line, &mut listing),
+                    }
+                } else {
+                    self.fill_listing_line(&mut row, line);
+                    listing.push(row);
+                }
+            }
+        }
+        for (_, mut rows) in after_block {
+            listing.append(&mut rows);
+        }
+        listing::render(&listing, &self.symbols())
+    }
+
+    /// The line of the `ENDM` closing the block opened on `line_no`
+    fn block_end(&self, line_no: usize) -> usize {
+        let mut nesting = 0;
+        for (idx, raw_line) in self.source.iter().enumerate().skip(line_no) {
+            for meta in self.tokenize_line(raw_line).unwrap_or_default() {
+                match meta.inst.as_deref() {
+                    Some(inst) if opens_block(inst) => nesting += 1,
+                    Some("ENDM") if nesting == 0 => return idx + 1,
+                    Some("ENDM") => nesting -= 1,
+                    _ => {}
+                }
+            }
+        }
+        self.source.len()
+    }
+
+    /// The last assembly as source, for `-E`, see [preprocess]
+    pub fn preprocessed(&self) -> String {
+        preprocess::render(
+            &self.lines.borrow(),
+            &self.symbols(),
+            self.prog_width,
+            self.ended.then_some(self.start_address),
+        )
+    }
+
+    /// A listing row numbered by the line's own file, marked if that's an included one
+    fn listing_line(&self, line_no: usize, raw_line: &str) -> ListingLine {
+        let (file, line) = self.origin(line_no);
+        let mut row = ListingLine::source(line, raw_line);
+        if file != self.args.input {
+            row.mark = ListingMark::Included;
+        }
+        row
     }
 
-    fn width_of_data_storage(&self, inst: String, args: &[String]) -> Result<usize, ParserError> {
+    fn list_macro_call(&self, line: &LineMeta, listing: &mut Vec<ListingLine>) {
+        for mline in line.expansion.iter().flatten() {
+            let mut row = ListingLine {
+                address: Some(mline.address),
+                mark: ListingMark::Expansion,
+                ..self.listing_line(mline.line_no, &mline.raw_line)
+            };
+            if mline.expansion.is_some() {
+                listing.push(row);
+                self.list_macro_call(mline, listing);
+            } else {
+                self.fill_listing_line(&mut row, mline);
+                listing.push(row);
+            }
+        }
+    }
+
+    fn fill_listing_line(&self, row: &mut ListingLine, line: &LineMeta) {
+        let op_code = match line.op_code {
+            Some(op_code) => op_code as usize,
+            None => return,
+        };
+        let bytes = match self.gen_for_line(line, line.address) {
+            Ok((bytes, _)) => bytes,
+            Err(_) => return,
+        };
+        // The op code in the line may only be an estimate, the first byte is the real one
+        if op_code <= 0xff {
+            if let Some(inst) = bytes.first() {
+                row.cycles = Some(I8080_OP_META[*inst as usize].cycles);
+            }
+        }
+        // Reserved storage is only filler, not worth listing
+        if I8080_OP_META[op_code].op != "DS" {
+            row.bytes = bytes;
+        }
+    }
+
+    /// Only the parts of the program which were assembled, gaps left by `ORG` are dropped
+    pub fn image(&self, bytes: &[u8]) -> Image {
+        let mut spans: Vec<(usize, usize)> = self
+            .lines
+            .borrow()
+            .iter()
+            .filter(|line| line.width > 0)
+            .map(|line| (line.address as usize, line.address as usize + line.width))
+            .collect();
+        if self.args.hlt {
+            let end = bytes.len();
+            spans.push((end - 1, end));
+        }
+        spans.sort_unstable();
+
+        let mut merged: Vec<(usize, usize)> = vec![];
+        for (from, to) in spans {
+            match merged.last_mut() {
+                Some((_, end)) if from <= *end => *end = to.max(*end),
+                _ => merged.push((from, to)),
+            }
+        }
+        Image {
+            regions: merged
+                .into_iter()
+                .map(|(from, to)| (from as u16, bytes[from..to.min(bytes.len())].to_vec()))
+                .collect(),
+            start: self.start_address,
+        }
+    }
+
+    fn width_of_data_storage(
+        &self,
+        inst: String,
+        args: &[String],
+        state: &mut ParseState,
+    ) -> Result<usize, ParserError> {
         match inst.as_str() {
-            "DB" => {
+            "DB" | "DC" => {
                 let mut width = 0;
                 for arg in args {
-                    let (bytes, flags) = parse_expression(arg, 0, &self.labels)?;
-                    if flags.string {
-                        width += bytes.len();
-                    } else {
-                        width += 1;
+                    match parse_expression(arg, 0, &self.labels) {
+                        Ok((bytes, flags)) if flags.string => width += bytes.len(),
+                        // Only strings are more than a byte, a label yet to be defined isn't one
+                        Ok(_) | Err(ExpressionError::UnknownIdentifier(_)) => width += 1,
+                        Err(e) => return Err(e.into()),
                     }
                 }
                 Ok(width)
             }
-            "DW" => Ok(args.len() * 2),
+            "DW" => {
+                for arg in args {
+                    if let Ok((bytes, flags)) = parse_expression(arg, 0, &self.labels) {
+                        if flags.string && bytes.len() > 2 {
+                            return Err(ParserError::InvalidArgument(
+                                "DW".to_string(),
+                                arg.to_string(),
+                            ));
+                        }
+                    }
+                }
+                Ok(args.len() * 2)
+            }
             "DS" => {
-                let arg0 = args.get(0).unwrap();
-                let (bytes, flags) = parse_expression(arg0, 0, &self.labels)?;
-                if flags.string {
-                    Err(ParserError::InvalidArgument(
-                        "DS".to_string(),
-                        arg0.to_string(),
-                    ))
-                } else {
-                    let width = bytes.get(0).unwrap();
-                    Ok(*width as usize)
+                let arg0 = match args {
+                    [arg0] | [arg0, _] => arg0,
+                    args => return Err(ParserError::WrongNumberOfArgs(2, args.len())),
+                };
+                if let Ok((_, flags)) = parse_expression(arg0, 0, &self.labels) {
+                    if flags.string {
+                        return Err(ParserError::InvalidArgument(
+                            "DS".to_string(),
+                            arg0.to_string(),
+                        ));
+                    }
                 }
+                let width = self.evaluate_layout(arg0, state)?;
+                Ok(width as usize)
             }
             _ => Err(ParserError::UnknownDefine(inst)),
         }
@@ -821,30 +2773,216 @@ impl Assembler {
 
     fn load_file(&mut self) -> Result<Vec<LineMeta>, AssemblerError> {
         self.erroring_line = None;
+        self.source.clear();
+        self.files.clear();
+        self.origins.clear();
+        self.dependencies.clear();
+        let input = self.args.input.clone();
+        let source = self
+            .resolver
+            .read(&input)
+            .map_err(AssemblerError::FileRead)?;
         let mut line_vec: Vec<LineMeta> = vec![];
-        match util::read_lines(&self.args.input) {
-            Ok(lines) => {
-                for (line_no, line_res) in lines.enumerate() {
-                    if let Ok(line) = line_res {
-                        self.erroring_line = Some(LineMeta::from_raw(line_no, line.to_string()));
-                        let line_opt = tokenizer::tokenize(&line)?;
-                        if let Some(mut line_meta) = line_opt {
-                            line_meta.line_no = line_no + 1;
-                            line_vec.push(line_meta);
-                        }
+        self.load_source(&input, &source, &mut vec![], &mut line_vec);
+        Ok(line_vec)
+    }
+
+    /// Tokenizes each line of `source`, splicing in `INCLUDE`s and turning `INCBIN`s into `DB`s
+    ///
+    /// Line numbers are positions in the combined source of every file, [Self::origin] gives the
+    /// file and line they came from. `including` holds the files currently being read, to catch
+    /// any file which ends up including itself.
+    fn load_source(
+        &mut self,
+        path: &Path,
+        source: &str,
+        including: &mut Vec<PathBuf>,
+        line_vec: &mut Vec<LineMeta>,
+    ) {
+        let file = self.files.len();
+        self.files.push(path.to_path_buf());
+        including.push(source::normalize(path));
+        for (idx, line) in source.lines().enumerate() {
+            self.source.push(line.to_string());
+            self.origins.push((file, idx + 1));
+            let line_no = self.source.len();
+            self.erroring_line = Some(LineMeta::from_raw(line_no, line.to_string()));
+            // A line which can't be read is left out, the rest are still loaded
+            if let Err(e) = self.load_line(path, line_no, line, including, line_vec) {
+                let line = self.erroring_line.take();
+                self.failures.push((e.into(), line));
+            }
+        }
+        including.pop();
+    }
+
+    fn load_line(
+        &mut self,
+        path: &Path,
+        line_no: usize,
+        line: &str,
+        including: &mut Vec<PathBuf>,
+        line_vec: &mut Vec<LineMeta>,
+    ) -> Result<(), ParserError> {
+        for mut line_meta in self.tokenize_line(line)? {
+            line_meta.line_no = line_no;
+            match line_meta.inst.as_deref() {
+                Some("INCLUDE") => {
+                    let name = quoted_path("INCLUDE", line_meta.args_list.first())?;
+                    let (found, text) = self.find_include(path, &name, |r, p| r.read(p))?;
+                    if including.contains(&source::normalize(&found)) {
+                        debug!("@{:<03} {} includes itself", line_no, found.display());
+                        return Err(ParserError::IncludeCycle(name));
                     }
+                    debug!("@{:<03} including {}", line_no, found.display());
+                    if let Some(label) = line_meta.label {
+                        line_vec.push(LineMeta {
+                            line_no,
+                            ..LineMeta::label_only(Some(label), None, line_meta.raw_line)
+                        });
+                    }
+                    self.load_source(&found, &text, including, line_vec);
+                }
+                Some("INCBIN") => {
+                    let bytes = self.include_binary(path, &line_meta.args_list)?;
+                    debug!("@{:<03} INCBIN of {} bytes", line_no, bytes.len());
+                    if bytes.is_empty() {
+                        line_meta.label_only = true;
+                    } else {
+                        line_meta.inst = Some("DB".to_string());
+                        line_meta.op_code = Some(0x100);
+                        line_meta.args_list = bytes.iter().map(|b| b.to_string()).collect();
+                    }
+                    line_vec.push(line_meta);
                 }
-                Ok(line_vec)
+                _ => line_vec.push(line_meta),
             }
-            Err(e) => Err(AssemblerError::FileRead(e)),
         }
+        Ok(())
     }
 
-    fn print_err_msg(&mut self, e: &AssemblerError) {
-        println!("{}", e);
-        if let Some(line) = &self.erroring_line {
-            println!("\n  {: <3}| {}", line.line_no, line.raw_line);
+    /// The statements of a line of source, in the syntax of `--dialect`
+    fn tokenize_line(&self, raw_line: &str) -> Result<Vec<LineMeta>, ParserError> {
+        let mut lines = vec![];
+        for statement in dialect::statements(raw_line, self.args.dialect) {
+            lines.extend(tokenizer::tokenize(&statement)?);
         }
+        Ok(lines)
+    }
+
+    /// Reads the first of `name` next to `from` or in an include directory which exists
+    fn find_include<T, F>(
+        &mut self,
+        from: &Path,
+        name: &str,
+        read: F,
+    ) -> Result<(PathBuf, T), ParserError>
+    where
+        F: Fn(&dyn SourceResolver, &Path) -> io::Result<T>,
+    {
+        let relative = from.parent().unwrap_or_else(|| Path::new(""));
+        let candidates = std::iter::once(relative.join(name))
+            .chain(self.args.include_dirs.iter().map(|dir| dir.join(name)));
+        for candidate in candidates {
+            if let Ok(contents) = read(self.resolver.as_ref(), &candidate) {
+                if !self.dependencies.contains(&candidate) {
+                    self.dependencies.push(candidate.clone());
+                }
+                return Ok((candidate, contents));
+            }
+        }
+        Err(ParserError::IncludeNotFound(name.to_string()))
+    }
+
+    fn include_binary(&mut self, from: &Path, args: &[String]) -> Result<Vec<u8>, ParserError> {
+        if args.is_empty() || args.len() > 3 {
+            return Err(ParserError::WrongNumberOfArgs(3, args.len()));
+        }
+        let name = quoted_path("INCBIN", args.first())?;
+        let (_, bytes) = self.find_include(from, &name, |r, p| r.read_bytes(p))?;
+        let offset = match args.get(1) {
+            Some(arg) => parse_expression_u16(arg, 0, &self.labels)?.0 as usize,
+            None => 0,
+        };
+        if offset > bytes.len() {
+            return Err(ParserError::InvalidArgument(
+                "INCBIN".to_string(),
+                args[1].to_string(),
+            ));
+        }
+        let length = match args.get(2) {
+            Some(arg) => parse_expression_u16(arg, 0, &self.labels)?.0 as usize,
+            None => bytes.len() - offset,
+        };
+        match bytes.get(offset..offset + length) {
+            Some(slice) => Ok(slice.to_vec()),
+            None => Err(ParserError::InvalidArgument(
+                "INCBIN".to_string(),
+                args[2].to_string(),
+            )),
+        }
+    }
+
+    /// The file and line within it that a line number of the combined source came from
+    fn origin(&self, line_no: usize) -> (&Path, usize) {
+        match line_no.checked_sub(1).and_then(|idx| self.origins.get(idx)) {
+            Some((file, line)) => (&self.files[*file], *line),
+            None => (&self.args.input, line_no),
+        }
+    }
+
+    /// How a line is referred to for the user, included files are named
+    fn describe_line(&self, line_no: usize) -> String {
+        match self.origin(line_no) {
+            (file, line) if file == self.args.input => line.to_string(),
+            (file, line) => format!("{}:{}", file.display(), line),
+        }
+    }
+
+    /// Files brought in by `INCLUDE` and `INCBIN`, in the order they were first used
+    pub fn dependencies(&self) -> &[PathBuf] {
+        &self.dependencies
+    }
+
+    /// A Makefile rule making the output depend on the input and all its dependencies, with an
+    /// empty rule for each dependency so make doesn't fail when one is removed
+    pub fn dependency_rule(&self) -> String {
+        let escape = |p: &Path| p.display().to_string().replace(' ', "\\ ");
+        let mut rule = format!(
+            "{}: {}",
+            escape(&self.args.output),
+            escape(&self.args.input)
+        );
+        for dep in self.dependencies.iter() {
+            rule.push(' ');
+            rule.push_str(&escape(dep));
+        }
+        rule.push('\n');
+        for dep in self.dependencies.iter() {
+            rule.push_str(&format!("\n{}:\n", escape(dep)));
+        }
+        rule
+    }
+}
+
+/// The lines which take up space, with macro calls and repeat blocks replaced by their expansions
+fn flatten(line: &LineMeta, placed: &mut Vec<LineMeta>) {
+    match &line.expansion {
+        Some(expansion) => expansion.iter().for_each(|line| flatten(line, placed)),
+        None if line.width > 0 => placed.push(line.clone()),
+        None => {}
+    }
+}
+
+/// The path from the quoted first argument of `INCLUDE` or `INCBIN`
+fn quoted_path(inst: &str, arg: Option<&String>) -> Result<String, ParserError> {
+    let arg = arg.ok_or(ParserError::WrongNumberOfArgs(1, 0))?;
+    match macros::unquote(arg) {
+        Some(path) if !path.is_empty() => Ok(path.to_string()),
+        _ => Err(ParserError::InvalidArgument(
+            inst.to_string(),
+            arg.to_string(),
+        )),
     }
 }
 
@@ -852,66 +2990,67 @@ impl Assembler {
 mod tests {
     use super::*;
     use std::borrow::Borrow;
-    use std::path::PathBuf;
-
-    impl AssembleArgs {
-        fn new() -> Self {
-            Self {
-                input: PathBuf::new(),
-                output: PathBuf::new(),
-                load_at: 0,
-                register_definitions: false,
-                hlt: false,
-            }
-        }
-    }
+    use std::path::PathBuf;
 
     #[test]
     fn width_of_vararg_db() {
-        let ass = Assembler::new(AssembleArgs::new());
+        let ass = Assembler::new(AssembleArgs::default());
 
         let args = vec!["13".to_string()];
         let width = ass
-            .width_of_data_storage("DB".to_owned(), &args)
+            .width_of_data_storage("DB".to_owned(), &args, &mut ParseState::default())
             .expect("should parse db arg");
         assert_eq!(width, 1, "a byte of any value should be width 1");
 
         let args = vec!["13".to_string(), "12".to_string()];
         let width = ass
-            .width_of_data_storage("DB".to_owned(), &args)
+            .width_of_data_storage("DB".to_owned(), &args, &mut ParseState::default())
             .expect("should parse db arg");
         assert_eq!(width, 2, "a list of bytes should return the length");
 
         let args = vec!["'this-string'".to_string()];
         let width = ass
-            .width_of_data_storage("DB".to_owned(), &args)
+            .width_of_data_storage("DB".to_owned(), &args, &mut ParseState::default())
             .expect("should parse db arg");
         assert_eq!(width, 11, "'this-string' is eleven chars");
     }
 
     #[test]
     fn width_of_vararg_dw() {
-        let ass = Assembler::new(AssembleArgs::new());
+        let ass = Assembler::new(AssembleArgs::default());
 
         let args = vec!["13".to_string(), "fish".to_string()];
         let width = ass
-            .width_of_data_storage("DW".to_owned(), &args)
+            .width_of_data_storage("DW".to_owned(), &args, &mut ParseState::default())
             .expect("should parse db arg");
         assert_eq!(width, 4, "double the numbers of args");
+
+        let args = vec!["'ab'".to_string(), "$".to_string()];
+        let width = ass
+            .width_of_data_storage("DW".to_owned(), &args, &mut ParseState::default())
+            .expect("should parse dw arg");
+        assert_eq!(width, 4, "a string is a word");
+
+        let args = vec!["1".to_string(), "'ABC'".to_string()];
+        let width = ass.width_of_data_storage("DW".to_owned(), &args, &mut ParseState::default());
+        assert!(
+            matches!(width, Err(ParserError::InvalidArgument(_, s)) if s == "'ABC'"),
+            "a word holds two characters"
+        );
     }
 
     #[test]
     fn width_of_vararg_ds() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let args = vec!["13".to_string()];
         let width = ass
-            .width_of_data_storage("DS".to_owned(), &args)
+            .width_of_data_storage("DS".to_owned(), &args, &mut ParseState::default())
             .expect("should parse db arg");
         assert_eq!(width, 13, "the result of the expression");
 
         let args = vec!["'hello'".to_string()];
-        let width = ass.width_of_data_storage("DS".to_owned(), &args);
+        let width = ass.width_of_data_storage("DS".to_owned(), &args, &mut ParseState::default());
         assert!(width.is_err(), "should parse db arg");
         let e = width.unwrap_err();
         assert!(matches!(e, ParserError::InvalidArgument(_, _)));
@@ -923,9 +3062,18 @@ mod tests {
             .insert("_ident".to_string(), Label::new_addr(Some(2)));
         let args = vec!["_ident".to_string()];
         let width = ass
-            .width_of_data_storage("DS".to_owned(), &args)
+            .width_of_data_storage("DS".to_owned(), &args, &mut ParseState::default())
             .expect("should parse labels");
         assert_eq!(width, 2, "the result of the expression");
+
+        let args = vec!["0x1000".to_string(), "0xff".to_string()];
+        let width = ass
+            .width_of_data_storage("DS".to_owned(), &args, &mut ParseState::default())
+            .expect("should parse a fill");
+        assert_eq!(width, 0x1000, "wider than a byte");
+        let args = vec!["1".to_string(), "2".to_string(), "3".to_string()];
+        let width = ass.width_of_data_storage("DS".to_owned(), &args, &mut ParseState::default());
+        assert!(matches!(width, Err(ParserError::WrongNumberOfArgs(2, 3))));
     }
 
     fn line_meta_for_parse(inst: &str, op: u16, args: Vec<&str>, label: Option<&str>) -> LineMeta {
@@ -940,7 +3088,7 @@ mod tests {
 
     #[test]
     fn parse_no_meta() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("MOV", 0x40, vec!["A", "B"], Some("_l1")),
@@ -957,7 +3105,7 @@ mod tests {
         let resolved_lines = ass.lines.borrow();
         assert_eq!(resolved_lines.len(), len, "no meta instructions");
 
-        let l0 = resolved_lines.get(0).unwrap();
+        let l0 = resolved_lines.first().unwrap();
         assert_eq!(l0.width, 1, "MOV A, B is one byte");
         assert_eq!(l0.address, 0, "MOV A, B address");
 
@@ -980,7 +3128,7 @@ mod tests {
 
     #[test]
     fn parse_if_endif() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("MOV", 0x40, vec!["A", "B"], Some("_l1")),
@@ -1000,7 +3148,7 @@ mod tests {
 
         assert_eq!(resolved_lines.len(), 3, "meta not included");
 
-        let l0 = resolved_lines.get(0).unwrap();
+        let l0 = resolved_lines.first().unwrap();
         assert_eq!(l0.address, 0, "MOV A, B address");
 
         let l1 = resolved_lines.get(1).unwrap();
@@ -1016,24 +3164,63 @@ mod tests {
     }
 
     #[test]
-    fn parse_nested_if_fails() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+    fn parse_nested_if() {
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("IF", 0x107, vec!["2"], None),
             line_meta_for_parse("IF", 0x107, vec!["0"], None),
             line_meta_for_parse("SHDL", 0x22, vec!["0x1111"], None),
             line_meta_for_parse("ENDIF", 0x108, vec![], None),
+            line_meta_for_parse("SHDL", 0x22, vec!["0x2222"], None),
             line_meta_for_parse("ENDIF", 0x108, vec![], None),
         ];
 
-        let e = ass.parse(raw_lines).expect_err("nested IFs should fail");
-        assert!(matches!(e, ParserError::NestedIf));
+        ass.parse(raw_lines).expect("nested IFs should parse");
+        let resolved_lines = ass.lines.borrow();
+        assert_eq!(resolved_lines.len(), 1, "only the outer IF is true");
+        assert_eq!(resolved_lines[0].args_list, vec!["0x2222".to_string()]);
+    }
+
+    #[test]
+    fn parse_if_elseif_else() {
+        let lines_for = |cond0: &str, cond1: &str| {
+            vec![
+                line_meta_for_parse("IF", 0x107, vec![cond0], None),
+                line_meta_for_parse("SHDL", 0x22, vec!["0"], None),
+                line_meta_for_parse("ELSEIF", 0x10d, vec![cond1], None),
+                line_meta_for_parse("IF", 0x107, vec!["0"], None),
+                line_meta_for_parse("SHDL", 0x22, vec!["1"], None),
+                line_meta_for_parse("ELSE", 0x10e, vec![], None),
+                line_meta_for_parse("SHDL", 0x22, vec!["2"], None),
+                line_meta_for_parse("ENDIF", 0x108, vec![], None),
+                line_meta_for_parse("ELSE", 0x10e, vec![], None),
+                line_meta_for_parse("SHDL", 0x22, vec!["3"], None),
+                line_meta_for_parse("ENDIF", 0x108, vec![], None),
+            ]
+        };
+        for (cond0, cond1, taken) in [("1", "1", "0"), ("0", "1", "2"), ("0", "0", "3")] {
+            let mut ass = Assembler::new(AssembleArgs::default());
+            ass.parse(lines_for(cond0, cond1)).expect("should parse");
+            let resolved_lines = ass.lines.borrow();
+            assert_eq!(resolved_lines.len(), 1);
+            assert_eq!(resolved_lines[0].args_list, vec![taken.to_string()]);
+        }
+
+        let mut ass = Assembler::new(AssembleArgs::default());
+        let raw_lines = vec![
+            line_meta_for_parse("IF", 0x107, vec!["1"], None),
+            line_meta_for_parse("ELSE", 0x10e, vec![], None),
+            line_meta_for_parse("ELSE", 0x10e, vec![], None),
+            line_meta_for_parse("ENDIF", 0x108, vec![], None),
+        ];
+        let e = ass.parse(raw_lines).expect_err("two ELSEs should fail");
+        assert!(matches!(e, ParserError::ElseAfterElse));
     }
 
     #[test]
     fn parse_no_if_for_endif() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![line_meta_for_parse("ENDIF", 0x108, vec![], None)];
 
@@ -1045,7 +3232,7 @@ mod tests {
 
     #[test]
     fn parse_no_endif_for_if() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![line_meta_for_parse("IF", 0x107, vec!["2"], None)];
 
@@ -1057,7 +3244,7 @@ mod tests {
 
     #[test]
     fn parse_macro_endm() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("MOV", 0x40, vec!["A", "B"], None),
@@ -1074,7 +3261,7 @@ mod tests {
 
         assert_eq!(resolved_lines.len(), 2, "meta not included");
 
-        let l0 = resolved_lines.get(0).unwrap();
+        let l0 = resolved_lines.first().unwrap();
         assert_eq!(l0.address, 0, "MOV A, B address");
 
         let l1 = resolved_lines.get(1).unwrap();
@@ -1083,7 +3270,7 @@ mod tests {
 
     #[test]
     fn call_a_macro() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("MACRO", 0x109, vec![], Some("_m1")),
@@ -1105,7 +3292,7 @@ mod tests {
 
         assert_eq!(resolved_lines.len(), 2, "macro not included");
 
-        let l0 = resolved_lines.get(0).unwrap();
+        let l0 = resolved_lines.first().unwrap();
         assert_eq!(l0.address, 0, "should be first instruction");
         assert_eq!(l0.width, 5, "should replace with macro width");
 
@@ -1115,7 +3302,7 @@ mod tests {
 
     #[test]
     fn load_address_with_macro() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("MACRO", 0x109, vec![], Some("_m1")),
@@ -1130,14 +3317,14 @@ mod tests {
             line_meta_for_parse("SHDL", 0x22, vec!["0x1111"], None),
         ];
 
-        ass.parse_at(raw_lines, 200).expect("should parse");
+        assert!(ass.parse_at(raw_lines, 200), "should parse");
         assert_eq!(ass.prog_width, 208);
 
         let resolved_lines = ass.lines.borrow();
 
         assert_eq!(resolved_lines.len(), 2, "macro not included");
 
-        let l0 = resolved_lines.get(0).unwrap();
+        let l0 = resolved_lines.first().unwrap();
         assert_eq!(l0.address, 200, "should be first instruction");
         assert_eq!(l0.width, 5, "should replace with macro width");
 
@@ -1146,8 +3333,8 @@ mod tests {
     }
 
     #[test]
-    fn parse_nested_macro_fails() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+    fn parse_nested_macro() {
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("MACRO", 0x109, vec![], Some("_m1")),
@@ -1156,14 +3343,29 @@ mod tests {
             line_meta_for_parse("ENDM", 0x10a, vec![], None),
             line_meta_for_parse("ENDM", 0x10a, vec![], None),
         ];
+        ass.parse(raw_lines.clone())
+            .expect("nested MACRO should parse");
+        assert!(
+            !ass.macros.borrow().contains_key("_m2"),
+            "inner macro only exists once the outer is called"
+        );
 
-        let e = ass.parse(raw_lines).expect_err("nested IFs should fail");
-        assert!(matches!(e, ParserError::NestedMacro));
+        let mut ass = Assembler::new(AssembleArgs::default());
+        let mut lines = raw_lines;
+        for inst in ["_m1", "_m2"] {
+            lines.push(LineMeta {
+                inst: Some(inst.to_string()),
+                ..Default::default()
+            });
+        }
+        ass.parse(lines)
+            .expect("calling the inner macro should parse");
+        assert_eq!(ass.prog_width, 3);
     }
 
     #[test]
     fn parse_no_macro_for_endm() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![line_meta_for_parse("ENDM", 0x10a, vec![], None)];
 
@@ -1175,7 +3377,7 @@ mod tests {
 
     #[test]
     fn parse_no_endm_for_macro() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
         let raw_lines = vec![line_meta_for_parse("MACRO", 0x109, vec![], Some("_m1"))];
         let e = ass
             .parse(raw_lines)
@@ -1185,7 +3387,7 @@ mod tests {
 
     #[test]
     fn parse_use_of_org_in_macro() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("MACRO", 0x109, vec![], Some("_m1")),
@@ -1201,7 +3403,7 @@ mod tests {
 
     #[test]
     fn parse_org_sets_the_address() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("MOV", 0x40, vec!["A", "B"], None),
@@ -1214,7 +3416,7 @@ mod tests {
         let resolved_lines = ass.lines.borrow();
         assert_eq!(resolved_lines.len(), 2, "no meta instructions");
 
-        let l0 = resolved_lines.get(0).unwrap();
+        let l0 = resolved_lines.first().unwrap();
         assert_eq!(l0.width, 1, "MOV A, B is one byte");
         assert_eq!(l0.address, 0, "MOV A, B address");
 
@@ -1225,7 +3427,7 @@ mod tests {
 
     #[test]
     fn parse_label_repeats() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("MOV", 0x40, vec!["A", "B"], Some("_l1")),
@@ -1242,7 +3444,7 @@ mod tests {
 
     #[test]
     fn parse_equ_no_repeat() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("EQU", 0x103, vec!["200"], Some("label")),
@@ -1259,7 +3461,7 @@ mod tests {
 
     #[test]
     fn parse_set_can_be_repeated() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("SET", 0x104, vec!["200"], Some("label")),
@@ -1279,7 +3481,7 @@ mod tests {
 
     #[test]
     fn use_of_eq_and_set() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let raw_lines = vec![
             line_meta_for_parse("EQU", 0x103, vec!["200"], Some("EQ200")),
@@ -1327,7 +3529,7 @@ mod tests {
     #[test]
     fn gen_for_instruction_no_args() {
         let line = line_meta_for_gen("MOV", 0x40, vec!["1", "2"], None, 0, 1);
-        let ass = Assembler::new(AssembleArgs::new());
+        let ass = Assembler::new(AssembleArgs::default());
         let (bytes, pc) = ass.gen_for_instruction(&line, 0).expect("should generate");
         assert!(!pc, "pc ($) was not used");
         assert_eq!(bytes, vec![0x4a]);
@@ -1338,7 +3540,7 @@ mod tests {
         // The line will be loaded with the first MVI (0x06) but it should then resolve to
         // the true OP after expression parsing (0x2e)
         let line = line_meta_for_gen("MVI", 0x06, vec!["5", "0X12"], None, 0, 2);
-        let ass = Assembler::new(AssembleArgs::new());
+        let ass = Assembler::new(AssembleArgs::default());
         let (bytes, pc) = ass.gen_for_instruction(&line, 0).expect("should generate");
         assert!(!pc, "pc ($) was not used");
         assert_eq!(bytes, vec![0x2e, 0x12]);
@@ -1347,7 +3549,7 @@ mod tests {
     #[test]
     fn gen_for_instruction_argw() {
         let line = line_meta_for_gen("JMP", 0xc3, vec!["0X1234"], None, 0, 3);
-        let ass = Assembler::new(AssembleArgs::new());
+        let ass = Assembler::new(AssembleArgs::default());
         let (bytes, pc) = ass.gen_for_instruction(&line, 0).expect("should generate");
         assert!(!pc, "pc ($) was not used");
         assert_eq!(bytes, vec![0xc3, 0x34, 0x12]);
@@ -1356,7 +3558,7 @@ mod tests {
     #[test]
     fn gen_for_instruction_argb_with_sp() {
         let line = line_meta_for_gen("IN", 0xdb, vec!["$ + 2"], None, 0x212, 2);
-        let ass = Assembler::new(AssembleArgs::new());
+        let ass = Assembler::new(AssembleArgs::default());
         let (bytes, pc) = ass
             .gen_for_instruction(&line, 0x212)
             .expect("should generate");
@@ -1367,7 +3569,7 @@ mod tests {
     #[test]
     fn gen_for_instruction_db() {
         let line = line_meta_for_gen("DB", 0x100, vec!["0X10", "'ABAB'", "$"], None, 0, 4);
-        let ass = Assembler::new(AssembleArgs::new());
+        let ass = Assembler::new(AssembleArgs::default());
         let (bytes, pc) = ass.gen_for_instruction(&line, 0).expect("should generate");
         assert!(pc, "pc ($) not used");
         assert_eq!(
@@ -1378,87 +3580,55 @@ mod tests {
 
     #[test]
     fn gen_for_instruction_dw() {
-        let line = line_meta_for_gen("DW", 0x101, vec!["0X1234", "0X10", "'ABPP'"], None, 0, 6);
-        let ass = Assembler::new(AssembleArgs::new());
+        let line = line_meta_for_gen("DW", 0x101, vec!["0X1234", "0X10", "'AB'"], None, 0, 6);
+        let ass = Assembler::new(AssembleArgs::default());
         let (bytes, pc) = ass.gen_for_instruction(&line, 0).expect("should generate");
         assert!(!pc, "pc ($) was not used");
-        assert_eq!(bytes, vec![0x34, 0x12, 0x10, 0x00, b'A', b'B']);
+        assert_eq!(bytes, vec![0x34, 0x12, 0x10, 0x00, b'B', b'A']);
     }
 
     #[test]
     fn gen_for_instruction_ds() {
         let line = line_meta_for_gen("DS", 0x102, vec!["10"], None, 0, 10);
-        let ass = Assembler::new(AssembleArgs::new());
+        let ass = Assembler::new(AssembleArgs::default());
         let (bytes, pc) = ass.gen_for_instruction(&line, 0).expect("should generate");
         assert!(!pc, "pc ($) was not used");
         assert_eq!(bytes, vec![0x00; 10]);
-    }
 
-    fn macro_no_pc() -> Macro {
-        Macro {
-            lines: vec![
-                line_meta_for_gen("MOV", 0x40, vec!["0", "0"], None, 0x00, 1),
-                line_meta_for_gen("MOV", 0x40, vec!["0", "1"], None, 0x01, 1),
-                line_meta_for_gen("SHDL", 0x22, vec!["0X1111"], None, 0x02, 3),
-            ],
-            bytes: vec![],
-            width: 5,
-            uses_pc: false,
-        }
-    }
+        let line = line_meta_for_gen("DS", 0x102, vec!["0x200", "0xff"], None, 0, 0x200);
+        let (bytes, _) = ass.gen_for_instruction(&line, 0).expect("should generate");
+        assert_eq!(bytes, vec![0xff; 0x200]);
 
-    #[test]
-    fn gen_macro_no_pc() {
-        let mut macros = HashMap::new();
-        macros.insert("_m1".to_string(), macro_no_pc());
-        let mut ass = Assembler::new(AssembleArgs::new());
-        ass.macros = RefCell::new(macros);
-        ass.gen_macros().expect("macro should compile");
-        let ass_macros = ass.macros.borrow();
-        let _m1 = ass_macros
-            .get(&"_m1".to_string())
-            .expect("macro should exist");
-        assert_eq!(_m1.bytes, vec![0x40, 0x41, 0x22, 0x11, 0x11]);
-        assert!(!_m1.uses_pc, "shouldn't use PC");
-    }
-
-    fn macro_with_pc() -> Macro {
-        Macro {
-            lines: vec![
-                line_meta_for_gen("MOV", 0x40, vec!["0", "0"], None, 0x00, 1),
-                line_meta_for_gen("MOV", 0x40, vec!["0", "1"], None, 0x01, 1),
-                line_meta_for_gen("SHDL", 0x22, vec!["$"], None, 0x02, 3),
-            ],
-            bytes: vec![],
-            width: 5,
-            uses_pc: false,
-        }
+        let line = line_meta_for_gen("DS", 0x102, vec!["2", "0 - 1"], None, 0, 2);
+        let (bytes, _) = ass.gen_for_instruction(&line, 0).expect("should generate");
+        assert_eq!(bytes, vec![0xff; 2]);
+
+        let line = line_meta_for_gen("DS", 0x102, vec!["2", "0x100"], None, 0, 2);
+        let e = ass.gen_for_instruction(&line, 0).unwrap_err();
+        assert_eq!(e.code(), "E204", "a fill is a byte");
     }
 
     #[test]
-    fn gen_macro_with_pc() {
-        let mut macros = HashMap::new();
-        macros.insert("_m1".to_string(), macro_with_pc());
-        let mut ass = Assembler::new(AssembleArgs::new());
-        ass.macros = RefCell::new(macros);
-        ass.gen_macros().expect("macro should compile");
-        let ass_macros = ass.macros.borrow();
-        let _m1 = ass_macros
-            .get(&"_m1".to_string())
-            .expect("macro should exist");
-        assert_eq!(_m1.bytes, vec![0x40, 0x41, 0x22, 0x02, 0x00]);
-        assert!(_m1.uses_pc, "shouldn't use PC");
+    fn gen_for_instruction_dc() {
+        let line = line_meta_for_gen("DC", 0x11b, vec!["'HI'", "'A'", "0X41", "''"], None, 0, 4);
+        let ass = Assembler::new(AssembleArgs::default());
+        let (bytes, _) = ass.gen_for_instruction(&line, 0).expect("should generate");
+        assert_eq!(bytes, vec![b'H', b'I' | 0x80, b'A' | 0x80, b'A']);
     }
 
-    #[test]
-    fn gen_prog_with_macro_no_pc() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+    /// Defines `_m1` as two MOVs and an SHDL of `arg`
+    fn define_macro(arg: &str) -> Vec<LineMeta> {
+        vec![
+            line_meta_for_parse("MACRO", 0x109, vec![], Some("_m1")),
+            line_meta_for_parse("MOV", 0x40, vec!["0", "0"], None),
+            line_meta_for_parse("MOV", 0x40, vec!["0", "1"], None),
+            line_meta_for_parse("SHDL", 0x22, vec![arg], None),
+            line_meta_for_parse("ENDM", 0x10a, vec![], None),
+        ]
+    }
 
-        let mut macros = HashMap::new();
-        let mut _m1 = macro_no_pc();
-        macros.insert("_m1".to_string(), _m1);
-        ass.macros = RefCell::new(macros);
-        ass.gen_macros().expect("macro should compile");
+    fn gen_prog_with_macro(arg: &str) -> Vec<u8> {
+        let mut ass = Assembler::new(AssembleArgs::default());
 
         let mut labels = HashMap::new();
         labels.insert("B".to_string(), Label::new_equ(Some(0)));
@@ -1466,63 +3636,1101 @@ mod tests {
         labels.insert("D".to_string(), Label::new_equ(Some(2)));
         ass.labels = labels;
 
-        let lines = vec![
-            line_meta_for_gen("MOV", 0x40, vec!["B", "C"], None, 0x00, 1),
+        let mut lines = define_macro(arg);
+        lines.append(&mut vec![
+            line_meta_for_parse("MOV", 0x40, vec!["B", "C"], None),
             LineMeta {
                 inst: Some("_m1".to_string()),
-                width: 5,
-                address: 0x01,
                 ..Default::default()
             },
-            line_meta_for_gen("SHDL", 0x22, vec!["$"], None, 0x06, 3),
-        ];
-        ass.lines = RefCell::new(lines);
-        ass.prog_width = 9;
+            line_meta_for_parse("SHDL", 0x22, vec!["$"], None),
+        ]);
+        ass.parse(lines).expect("should parse");
+        assert_eq!(ass.prog_width, 9);
+
+        let bytes = ass.generate_prog();
+        assert!(ass.failures.is_empty(), "should compile");
+        bytes
+    }
 
-        let bytes = ass.generate_prog().expect("should compile");
+    #[test]
+    fn gen_prog_with_macro_no_pc() {
         assert_eq!(
-            bytes,
+            gen_prog_with_macro("0X1111"),
             vec![0x41, 0x40, 0x41, 0x22, 0x11, 0x11, 0x22, 0x06, 0x00]
         );
     }
 
     #[test]
     fn gen_prog_with_macro_with_pc() {
-        let mut ass = Assembler::new(AssembleArgs::new());
+        assert_eq!(
+            gen_prog_with_macro("$"),
+            vec![
+                0x41, 0x40, 0x41, 0x22,
+                // The SHDL in the macro is after MOV, MOV, MOV, so 0x03
+                0x03, 0x00, 0x22, 0x06, 0x00
+            ]
+        );
+    }
 
-        let mut macros = HashMap::new();
-        let mut _m1 = macro_with_pc();
-        macros.insert("_m1".to_string(), _m1);
-        ass.macros = RefCell::new(macros);
-        ass.gen_macros().expect("macro should compile");
+    #[test]
+    fn build_from_source() {
+        let source = "_start: MVI A, 0x12\n        JMP _start\n_end:   HLT\n";
+        let args = AssembleArgs {
+            register_definitions: true,
+            ..Default::default()
+        };
+        let mut ass = Assembler::new(args).with_source(source);
 
-        let mut labels = HashMap::new();
-        labels.insert("B".to_string(), Label::new_equ(Some(0)));
-        labels.insert("C".to_string(), Label::new_equ(Some(1)));
-        labels.insert("D".to_string(), Label::new_equ(Some(2)));
-        ass.labels = labels;
+        let assembly = ass.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assert_eq!(assembly.bytes, vec![0x3e, 0x12, 0xc3, 0x00, 0x00, 0x76]);
+        assert_eq!(assembly.symbols.get("_END").unwrap().value, Some(5));
+        assert_eq!(
+            assembly.lines,
+            vec![
+                LineAddress {
+                    file: PathBuf::new(),
+                    line_no: 1,
+                    address: 0,
+                    width: 2
+                },
+                LineAddress {
+                    file: PathBuf::new(),
+                    line_no: 2,
+                    address: 2,
+                    width: 3
+                },
+                LineAddress {
+                    file: PathBuf::new(),
+                    line_no: 3,
+                    address: 5,
+                    width: 1
+                },
+            ]
+        );
+    }
 
-        let lines = vec![
-            line_meta_for_gen("MOV", 0x40, vec!["B", "C"], None, 0x00, 1),
-            LineMeta {
-                inst: Some("_m1".to_string()),
-                width: 5,
-                address: 0x01,
+    #[test]
+    fn build_reports_diagnostics() {
+        let source = "        NOP\n        MVI A, _nope\n";
+        let args = AssembleArgs {
+            register_definitions: true,
+            ..Default::default()
+        };
+        let mut ass = Assembler::new(args).with_source(source);
+
+        let assembly = ass.build();
+        assert!(assembly.bytes.is_empty());
+        assert_eq!(assembly.diagnostics.len(), 1);
+        let d = &assembly.diagnostics[0];
+        assert_eq!((d.line, d.column), (2, 16));
+        assert!(d.message.contains("_NOPE"), "{}", d.message);
+    }
+
+    #[test]
+    fn build_recovers_from_errors() {
+        let source = "\
+        MVI A, _nope
+1bad:   NOP
+_dup:   NOP
+_dup:   NOP
+        MOV A, B, C
+        IF 1
+        MOV M, M
+        JMP _dup
+";
+        let assembly = build_source(source);
+        let found: Vec<(usize, &str)> = assembly
+            .diagnostics
+            .iter()
+            .map(|d| (d.line, d.code))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (1, "E101"),
+                (2, "E206"),
+                (4, "E207"),
+                (5, "E202"),
+                (7, "E208"),
+                (0, "E232")
+            ],
+            "{:?}",
+            assembly.diagnostics
+        );
+        assert!(assembly.bytes.is_empty());
+        assert!(
+            !assembly.diagnostics[4].message.is_empty(),
+            "no instruction found has a message"
+        );
+        // The lines after each error were still placed
+        assert_eq!(assembly.symbols["_DUP"].value, Some(2));
+    }
+
+    fn include_args(include_dirs: Vec<&str>) -> AssembleArgs {
+        AssembleArgs {
+            input: PathBuf::from("main.asm"),
+            output: PathBuf::from("main bin"),
+            register_definitions: true,
+            include_dirs: include_dirs.into_iter().map(PathBuf::from).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn include_files() {
+        let resolver = MemoryResolver::new()
+            .with_file(
+                "main.asm",
+                "        INCLUDE 'lib/util.asm'\n        CALL _util\n",
+            )
+            .with_file(
+                "lib/util.asm",
+                "        INCLUDE 'consts.asm'\n_util:  MVI A, LEN\n",
+            )
+            .with_file(
+                "lib/consts.asm",
+                "        INCLUDE 'shared.asm'\nLEN:    EQU 5\n",
+            )
+            .with_file("inc/shared.asm", "        NOP\n");
+        let mut ass = Assembler::new(include_args(vec!["inc"])).with_resolver(resolver);
+
+        let assembly = ass.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assert_eq!(assembly.bytes, vec![0x00, 0x3e, 0x05, 0xcd, 0x01, 0x00]);
+        let origins: Vec<(PathBuf, usize)> = assembly
+            .lines
+            .iter()
+            .map(|line| (line.file.clone(), line.line_no))
+            .collect();
+        assert_eq!(
+            origins,
+            vec![
+                (PathBuf::from("inc/shared.asm"), 1),
+                (PathBuf::from("lib/util.asm"), 2),
+                (PathBuf::from("main.asm"), 2),
+            ]
+        );
+        assert_eq!(
+            ass.dependency_rule(),
+            "main\\ bin: main.asm lib/util.asm lib/consts.asm inc/shared.asm\n\n\
+             lib/util.asm:\n\nlib/consts.asm:\n\ninc/shared.asm:\n"
+        );
+    }
+
+    #[test]
+    fn include_errors() {
+        let resolver = MemoryResolver::new()
+            .with_file("main.asm", "        NOP\n        INCLUDE 'a.asm'\n")
+            .with_file("a.asm", "        NOP\n        INCLUDE './main.asm'\n");
+        let mut ass = Assembler::new(include_args(vec![])).with_resolver(resolver);
+        let d = &ass.build().diagnostics[0];
+        assert_eq!(
+            (d.file.as_path(), d.line, d.column),
+            (Path::new("a.asm"), 2, 18)
+        );
+        assert!(d.message.contains("includes itself"), "{}", d.message);
+
+        let resolver = MemoryResolver::new()
+            .with_file("main.asm", "        INCLUDE 'a.asm'\n")
+            .with_file("a.asm", "\n        MVI A, _nope\n");
+        let mut ass = Assembler::new(include_args(vec![])).with_resolver(resolver);
+        let d = &ass.build().diagnostics[0];
+        assert_eq!(
+            (d.file.as_path(), d.line, d.column),
+            (Path::new("a.asm"), 2, 16)
+        );
+
+        let resolver = MemoryResolver::new().with_file("main.asm", "INCLUDE 'gone.asm'\n");
+        let mut ass = Assembler::new(include_args(vec![])).with_resolver(resolver);
+        let d = &ass.build().diagnostics[0];
+        assert_eq!(
+            d.to_string(),
+            "main.asm:1:10: Included file not found: gone.asm"
+        );
+    }
+
+    #[test]
+    fn include_binary() {
+        let source = "_all:   INCBIN 'data.bin'\n\
+                      _part:  INCBIN 'data.bin', 1, 2\n\
+                      _tail:  INCBIN 'data.bin', 4\n\
+                      _none:  INCBIN 'data.bin', 5\n\
+                      \x20       DW _none\n";
+        let resolver = MemoryResolver::new()
+            .with_file("main.asm", source)
+            .with_file("data.bin", "ABCDE");
+        let mut ass = Assembler::new(include_args(vec![])).with_resolver(resolver);
+        let assembly = ass.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assert_eq!(assembly.bytes, b"ABCDEBCE\x08\x00".to_vec());
+        assert_eq!(ass.dependencies(), [PathBuf::from("data.bin")]);
+
+        let resolver = MemoryResolver::new()
+            .with_file("main.asm", "INCBIN 'data.bin', 2, 4\n")
+            .with_file("data.bin", "ABCDE");
+        let mut ass = Assembler::new(include_args(vec![])).with_resolver(resolver);
+        let d = &ass.build().diagnostics[0];
+        assert!(
+            d.message.contains("Invalid arg for INCBIN"),
+            "{}",
+            d.message
+        );
+    }
+
+    #[test]
+    fn macro_conditions_at_expansion() {
+        let source = "\
+FAST:   SET 1
+_inc:   MACRO
+        IF FAST
+        INR A
+        ELSE
+        ADI 1
+        ENDIF
+        ENDM
+_two:   MACRO
+        _inc
+        IF $ - 1
+        NOP
+        ENDIF
+        ENDM
+        _two
+FAST:   SET 0
+        _two
+";
+        let args = AssembleArgs {
+            register_definitions: true,
+            ..Default::default()
+        };
+        let mut ass = Assembler::new(args).with_source(source);
+
+        let assembly = ass.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        // The first NOP is left out as `$ - 1` is zero after an INR at 0
+        assert_eq!(assembly.bytes, vec![0x3c, 0xc6, 0x01, 0x00]);
+
+        let listing = ass.listing();
+        let rows: Vec<String> = listing
+            .lines()
+            .map(|row| row.split_whitespace().collect::<Vec<&str>>().join(" "))
+            .collect();
+        assert!(rows.contains(&"4+ 0000 3C 5 INR A".to_string()));
+        assert!(rows.contains(&"6+ 0001 C6 01 7 ADI 1".to_string()));
+        assert!(rows.contains(&"12+ 0003 00 4 NOP".to_string()));
+    }
+
+    fn build_source(source: &str) -> Assembly {
+        let args = AssembleArgs {
+            register_definitions: true,
+            ..Default::default()
+        };
+        Assembler::new(args).with_source(source).build()
+    }
+
+    #[test]
+    fn macro_parameters_and_locals() {
+        let source = "\
+_store: MACRO addr, reg=A
+        LOCAL skip
+        JZ skip
+        MOV M, reg
+        STA addr
+skip:   ENDM
+        _store 0x2000
+        _store 0x2002, B
+        _store
+";
+        let assembly = build_source(source);
+        assert!(
+            matches!(
+                &assembly.diagnostics[0].message,
+                m if m.contains("macro parameter ADDR")
+            ),
+            "{:?}",
+            assembly.diagnostics
+        );
+
+        let assembly = build_source(&source.replace("        _store\n", ""));
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0xca, 0x07, 0x00, 0x77, 0x32, 0x00, 0x20, // first call
+                0xca, 0x0e, 0x00, 0x70, 0x32, 0x02, 0x20, // second call, with B
+            ]
+        );
+        assert_eq!(assembly.symbols["__SKIP_0001"].value, Some(0x07));
+        assert_eq!(assembly.symbols["__SKIP_0002"].value, Some(0x0e));
+    }
+
+    #[test]
+    fn nested_macros_and_exitm() {
+        let source = "\
+_outer: MACRO value
+_inner: MACRO
+        DW $
+        ENDM
+        _inner
+        IF value
+        EXITM
+        ENDIF
+        DB value
+        ENDM
+        NOP
+        _outer 0
+        _outer 1
+        _inner
+";
+        let assembly = build_source(source);
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        // The second call redefines `_inner` and leaves before its DB, `$` is always the DW's own
+        assert_eq!(
+            assembly.bytes,
+            vec![0x00, 0x01, 0x00, 0x00, 0x04, 0x00, 0x06, 0x00]
+        );
+
+        let assembly = build_source("        EXITM\n");
+        assert!(matches!(
+            &assembly.diagnostics[0].message,
+            m if m.contains("outside of a MACRO")
+        ));
+
+        let assembly = build_source("_loop:  MACRO\n        _loop\n        ENDM\n        _loop\n");
+        assert!(matches!(
+            &assembly.diagnostics[0].message,
+            m if m.contains("nested too deeply")
+        ));
+    }
+
+    #[test]
+    fn repeat_blocks() {
+        let source = "\
+N:      SET 1
+_table: REPT 3
+        DB N * 2
+N:      SET N + 1
+        ENDM
+        IRP reg, <B, D>
+        LOCAL here
+here:   LXI reg, here
+        ENDM
+        IRPC c, '12'
+        DB c, $
+        ENDM
+        REPT 4
+        IF $ / 15
+        EXITM
+        ENDIF
+        NOP
+        ENDM
+";
+        let assembly = build_source(source);
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0x02, 0x04, 0x06, // REPT with SET
+                0x01, 0x03, 0x00, 0x11, 0x06, 0x00, // IRP with a LOCAL label
+                0x01, 0x09, 0x02, 0x0b, // IRPC with `$`
+                0x00, 0x00, // REPT left by EXITM
+            ]
+        );
+        assert_eq!(assembly.symbols["_TABLE"].value, Some(0));
+        assert_eq!(assembly.symbols["N"].value, Some(4));
+
+        let assembly = build_source("        REPT 2\n        NOP\n");
+        assert!(matches!(
+            &assembly.diagnostics[0].message,
+            m if m.contains("No ENDM")
+        ));
+    }
+
+    #[test]
+    fn joined_parameters_and_block_listing() {
+        let source = "\
+        IRPC ch, 'xy'
+ch&_at: DB '&ch'
+        ENDM
+        DW X_AT, Y_AT
+";
+        let mut assembler = Assembler::new(AssembleArgs {
+            register_definitions: true,
+            ..Default::default()
+        })
+        .with_source(source);
+        let assembly = assembler.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assert_eq!(assembly.bytes, vec![b'x', b'y', 0x00, 0x00, 0x01, 0x00]);
+
+        // The body is listed once as written, then each iteration
+        let rows: Vec<String> = assembler
+            .listing()
+            .lines()
+            .skip(1)
+            .take(5)
+            .map(|row| row.split_whitespace().collect::<Vec<&str>>().join(" "))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                "1 0000 IRPC ch, 'xy'",
+                "2 ch&_at: DB '&ch'",
+                "3 ENDM",
+                "2+ 0000 78 x_at: DB 'x'",
+                "2+ 0001 79 y_at: DB 'y'",
+            ]
+        );
+
+        let assembly = build_source("a&b:    NOP\n");
+        assert_eq!(assembly.diagnostics[0].code, "E206");
+    }
+
+    #[test]
+    fn forward_references() {
+        let source = "\
+        ORG START
+        IF BIG
+        DS SIZE
+        ENDIF
+_end:   DB _end - START
+START:  EQU 0x10
+SIZE:   EQU LEN * 2
+LEN:    EQU 8
+BIG:    EQU 1
+";
+        let assembly = build_source(source);
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assert_eq!(assembly.symbols["SIZE"].value, Some(0x10));
+        assert_eq!(assembly.symbols["_END"].value, Some(0x20));
+        assert_eq!(assembly.bytes.len(), 0x21);
+        assert_eq!(assembly.bytes[0x20], 0x10);
+
+        // The DS moves `_end` which decides how big the DS is
+        let assembly = build_source(&source.replace("LEN:    EQU 8", "LEN:    EQU _end - START"));
+        assert!(matches!(
+            &assembly.diagnostics[0].message,
+            m if m.contains("Value of SIZE can't be worked out")
+        ));
+    }
+
+    #[test]
+    fn references_settling_at_a_fixed_point() {
+        // LEN keeps its value once the DS has moved the table after it
+        let source = "\
+        DS LEN
+START1: DB 1, 2, 3
+END1:
+LEN:    EQU END1 - START1
+";
+        let assembly = build_source(source);
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assert_eq!(assembly.symbols["LEN"].value, Some(3));
+        assert_eq!(assembly.symbols["START1"].value, Some(3));
+        assert_eq!(assembly.bytes, vec![0, 0, 0, 1, 2, 3]);
+
+        // Any value would do for X, which is no value at all
+        let assembly = build_source("X:      EQU X\n        DS X\n");
+        assert_eq!(assembly.diagnostics[0].code, "E209");
+        let assembly = build_source("START1: DS LEN\nLEN:    EQU $ - START1\n");
+        assert_eq!(assembly.diagnostics[0].code, "E209");
+    }
+
+    #[test]
+    fn redefinitions_and_unknown_instructions() {
+        let message = |source: &str| build_source(source).diagnostics[0].message.to_string();
+        assert_eq!(
+            message("X:      EQU 200\nX:      EQU 201\n"),
+            "Label (X) already defined as 0x00c8"
+        );
+        assert_eq!(
+            message("        NOP\nX:      NOP\nX:      NOP\n"),
+            "Label (X) already defined at 0x0001"
+        );
+
+        let codes = |source: &str| -> Vec<&str> {
+            build_source(source)
+                .diagnostics
+                .iter()
+                .map(|d| d.code)
+                .collect()
+        };
+        assert_eq!(codes("        FOO 1\n"), vec!["E208"]);
+        assert_eq!(
+            codes("        _nop\n_nop:   MACRO\n        NOP\n        ENDM\n"),
+            vec!["E222"]
+        );
+    }
+
+    #[test]
+    fn high_low_and_comparisons() {
+        let source = "\
+        MVI H, HIGH _data
+        MVI L, LOW _data
+        IF _data - $ LT 0X100
+        NOP
+        ELSEIF _data MOD 2 EQ 0
+        DB 0FFH
+        ENDIF
+        ORG 0X1234
+_data:  DB 1
+";
+        let assembly = build_source(source);
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assert_eq!(&assembly.bytes[..5], &[0x26, 0x12, 0x2e, 0x34, 0xff]);
+
+        let assembly = build_source("        MVI A, 1 / (2 - 2)\n");
+        assert!(matches!(
+            &assembly.diagnostics[0].message,
+            m if m.contains("division by zero")
+        ));
+    }
+
+    #[test]
+    fn unsettled_references() {
+        let message = |source: &str| build_source(source).diagnostics[0].to_string();
+
+        assert_eq!(
+            message("X:      EQU Y\nY:      EQU X + 1\n"),
+            "1:13: Value of Y can't be worked out, it depends on itself or on where code is \
+             placed which in turn depends on it"
+        );
+        assert!(message("        DS _end\n_end:   NOP\n").contains("_END can't be worked out"));
+        assert!(message("        DS NOPE\n").contains("unknown identifier 'NOPE'"));
+        assert!(message(
+            "        IF FLAG\n        NOP\n        ELSE\nFLAG:   EQU 1\n        ENDIF\n"
+        )
+        .contains("Value of FLAG kept changing"));
+    }
+
+    #[test]
+    fn warnings() {
+        let source = "\
+LEN:    EQU 2
+LEN:    SET 3
+start:  MVI A, 0X1234
+        MVI B, 0 - 1
+        CALL print
+        DB 0X100, 'AB', 0
+print:  RET
+unused: HLT
+        ORG 1
+        DB 0
+";
+        let warned = |flags: &[&str]| {
+            let args = AssembleArgs {
+                register_definitions: true,
+                warnings: flags.iter().map(|f| f.to_string()).collect(),
                 ..Default::default()
-            },
-            line_meta_for_gen("SHDL", 0x22, vec!["$"], None, 0x06, 3),
+            };
+            let assembly = Assembler::new(args).with_source(source).build();
+            let codes: Vec<(usize, &str)> = assembly
+                .diagnostics
+                .iter()
+                .map(|d| (d.line, d.code))
+                .collect();
+            (assembly.is_ok(), assembly.bytes.is_empty(), codes)
+        };
+
+        let (ok, empty, codes) = warned(&[]);
+        assert!(ok && !empty);
+        assert_eq!(
+            codes,
+            vec![
+                (2, "W003"),
+                (3, "W001"),
+                (6, "W001"),
+                (6, "W004"),
+                (10, "W005")
+            ]
+        );
+
+        let (_, _, codes) = warned(&["no-truncation", "unused-label"]);
+        assert_eq!(
+            codes,
+            vec![
+                (2, "W003"),
+                (3, "W002"),
+                (6, "W004"),
+                (8, "W002"),
+                (10, "W005")
+            ]
+        );
+
+        let (ok, empty, _) = warned(&["error"]);
+        assert!(!ok && empty);
+        let (ok, empty, codes) = warned(&["error", "no-shadowed-equ", "no-truncation"]);
+        assert_eq!(codes.len(), 2);
+        assert!(!ok && empty);
+    }
+
+    #[test]
+    fn overlapping_regions() {
+        let source = "\
+        DS 4, 1
+        ORG 1
+        DB 2
+        DB 3, 4
+        ORG 0
+        DB 5
+";
+        let assembly = build_source(source);
+        assert_eq!(assembly.bytes, vec![5, 2, 3, 4]);
+        let warned: Vec<(usize, &str)> = assembly
+            .diagnostics
+            .iter()
+            .map(|d| (d.line, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            warned,
+            vec![
+                (
+                    3,
+                    "3 bytes from 0x0001 were already assembled and are overwritten"
+                ),
+                (
+                    6,
+                    "1 byte at 0x0000 was already assembled and is overwritten"
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn page_relocatable() {
+        let args = AssembleArgs {
+            register_definitions: true,
+            format: OutputFormat::Prl,
+            ..Default::default()
+        };
+        let source = "_x:     LXI H, _x\n        MVI A, HIGH _x\n        DW _x * 2\n";
+        let assembly = Assembler::new(args).with_source(source).build();
+        assert_eq!(assembly.diagnostics.len(), 1);
+        assert_eq!(assembly.diagnostics[0].code, "E252");
+        assert_eq!(assembly.diagnostics[0].line, 3);
+    }
+
+    #[test]
+    fn preprocessed_reassembles() {
+        let source = "\
+LEN:    EQU 3
+COUNT:  SET 0
+_store: MACRO addr, reg=A
+        LOCAL skip
+        JZ skip
+        MOV M, reg
+skip:   INX H
+        ENDM
+        LXI H, buf
+        IF LEN > 2
+        _store buf
+        ELSE
+        NOP
+        ENDIF
+        REPT LEN
+COUNT:  SET COUNT + 1
+        DB COUNT
+        ENDM
+        IRP reg, <B, C>
+        _store buf, reg
+        ENDM
+        MVI A, COUNT
+        ORG 0x40
+buf:    DS LEN
+        END 0x40
+";
+        let args = AssembleArgs {
+            register_definitions: true,
+            ..Default::default()
+        };
+        let mut assembler = Assembler::new(args).with_source(source);
+        let assembly = assembler.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        let preprocessed = assembler.preprocessed();
+        assert!(!preprocessed.contains("MACRO"), "{}", preprocessed);
+        assert!(!preprocessed.contains("NOP"), "{}", preprocessed);
+        assert!(preprocessed.contains("MVI A, 3"), "{}", preprocessed);
+
+        let again = build_source(&preprocessed);
+        assert!(again.is_ok(), "{:?}\n{}", again.diagnostics, preprocessed);
+        assert_eq!(again.bytes, assembly.bytes);
+        assert!(
+            preprocessed.ends_with("        END 0x0040\n"),
+            "{}",
+            preprocessed
+        );
+    }
+
+    #[test]
+    fn preprocessed_end() {
+        let source = "\
+        LXI H, buf
+        JMP go
+buf:    DS LEN
+go:     HLT
+LEN:    EQU 4
+        END go
+";
+        let args = AssembleArgs {
+            register_definitions: true,
+            ..Default::default()
+        };
+        let mut assembler = Assembler::new(args).with_source(source);
+        let assembly = assembler.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        let preprocessed = assembler.preprocessed();
+        assert!(
+            preprocessed.ends_with("        END 0x000A\n"),
+            "{}",
+            preprocessed
+        );
+        let args = AssembleArgs {
+            register_definitions: true,
+            ..Default::default()
+        };
+        let mut again = Assembler::new(args).with_source(preprocessed.as_str());
+        let reassembled = again.build();
+        assert!(reassembled.is_ok(), "{}", preprocessed);
+        assert_eq!(again.image(&reassembled.bytes).start, Some(0x0A));
+
+        let mut assembler = Assembler::new(AssembleArgs::default())
+            .with_source("        NOP\n        END\n        HLT\n");
+        assert!(assembler.build().is_ok());
+        let preprocessed = assembler.preprocessed();
+        assert!(preprocessed.ends_with("        END\n"), "{}", preprocessed);
+        assert!(!preprocessed.contains("HLT"), "{}", preprocessed);
+    }
+
+    #[test]
+    fn preprocessed_sets() {
+        let source = "\
+        MVI C, FLAG
+FLAG:   SET 1
+        MVI A, FLAG
+        IF FLAG
+        NOP
+        ENDIF
+FLAG:   SET 0
+        MVI B, FLAG
+        IF FLAG
+        HLT
+        ENDIF
+";
+        let args = AssembleArgs {
+            register_definitions: true,
+            ..Default::default()
+        };
+        let mut assembler = Assembler::new(args).with_source(source);
+        let assembly = assembler.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        let preprocessed = assembler.preprocessed();
+        assert!(!preprocessed.contains("SET"), "{}", preprocessed);
+        assert!(
+            preprocessed.contains("MVI C, 0x0000") && preprocessed.contains("FLAG=0x0000"),
+            "{}",
+            preprocessed
+        );
+
+        let again = build_source(&preprocessed);
+        assert!(again.is_ok(), "{:?}\n{}", again.diagnostics, preprocessed);
+        assert_eq!(again.bytes, assembly.bytes);
+    }
+
+    #[test]
+    fn command_line_symbols() {
+        let build = |source: &str, defines: &[&str], undefines: &[&str]| {
+            let args = AssembleArgs {
+                register_definitions: true,
+                defines: defines.iter().map(|d| d.to_string()).collect(),
+                undefines: undefines.iter().map(|u| u.to_string()).collect(),
+                ..Default::default()
+            };
+            Assembler::new(args).with_source(source).build()
+        };
+        let codes = |assembly: Assembly| -> Vec<&str> {
+            assembly.diagnostics.iter().map(|d| d.code).collect()
+        };
+        let source = "\
+        IF NOT DEFINED(size)
+SIZE:   EQU 4
+        ENDIF
+        IF DEFINED(DEBUG)
+        MVI A, SIZE
+        ENDIF
+        DB SIZE, DEFINED(_end)
+_end:   DB DEFINED(_end)
+";
+        // Only what's defined before counts
+        assert_eq!(build(source, &[], &[]).bytes, vec![4, 0, 0xff]);
+        assert_eq!(
+            build(source, &["debug", "SIZE=debug * 6"], &[]).bytes,
+            vec![0x3e, 6, 6, 0, 0xff]
+        );
+
+        let assembly = build("SIZE:   EQU 4\n", &["SIZE=2"], &[]);
+        assert_eq!(codes(assembly), vec!["E272"]);
+        for defines in [&["X=1", "X=2"][..], &["A"], &["1X"]] {
+            let assembly = build("        NOP\n", defines, &[]);
+            let code = if defines[0] == "1X" { "E270" } else { "E271" };
+            assert_eq!(codes(assembly), vec![code], "{:?}", defines);
+        }
+        assert_eq!(codes(build("        NOP\n", &["X"], &["X"])), vec!["E271"]);
+        // The same value twice is no conflict, nor is a register given a value once undefined
+        let assembly = build("        DB A\n", &["X=1", "X=1", "A=7"], &["A"]);
+        assert_eq!(assembly.bytes, vec![7]);
+    }
+
+    #[test]
+    fn structs() {
+        let source = "\
+        STRUCT POINT
+X:      DW 0
+Y:      DW 0xffff
+COLOUR:
+        DB 7, 0
+        DS 1
+        ENDS
+        DB POINT.Y, POINT.COLOUR, SIZEOF(POINT), POINT
+_p:     POINT 0x1234, , 'A', 2
+        POINT
+";
+        let assembly = build_source(source);
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assert_eq!(
+            assembly.bytes,
+            vec![2, 4, 7, 7, 0x34, 0x12, 0xff, 0xff, b'A', 0, 2, 0, 0, 0xff, 0xff, 7, 0, 0]
+        );
+        assert_eq!(assembly.symbols["_P"].value, Some(4));
+
+        let args = AssembleArgs {
+            register_definitions: true,
+            ..Default::default()
+        };
+        let mut assembler = Assembler::new(args).with_source(source);
+        assembler.build();
+        let preprocessed = assembler.preprocessed();
+        let assembly = build_source(&preprocessed);
+        assert_eq!(assembly.bytes, build_source(source).bytes);
+
+        for (source, code) in [
+            ("        ENDS\n", "E280"),
+            ("        STRUCT S\nA:      DB 1\n", "E281"),
+            ("        STRUCT S\n        NOP\n        ENDS\n", "E282"),
+            ("        DB SIZEOF(S)\n", "E283"),
+            (
+                "        STRUCT S\nA:      DB 1\n        ENDS\n        S 'AB'\n",
+                "E204",
+            ),
+        ] {
+            let assembly = build_source(source);
+            let codes: Vec<&str> = assembly.diagnostics.iter().map(|d| d.code).collect();
+            assert_eq!(codes, vec![code], "{}", source);
+        }
+    }
+
+    #[test]
+    fn data_directives() {
+        let source = "\
+        DB 'a;b', ':', $, 'A' + 1
+        DC 'OK', 'A'
+        DW 'AB', _end, 0FFFFH
+_buf:   DS 0x120, 0E5H
+_end:
+";
+        let assembly = build_source(source);
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        let mut bytes = vec![
+            b'a',
+            b';',
+            b'b',
+            b':',
+            0,
+            b'B',
+            b'O',
+            b'K' | 0x80,
+            b'A' | 0x80,
         ];
-        ass.lines = RefCell::new(lines);
-        ass.prog_width = 9;
+        bytes.extend([b'B', b'A', 0x2f, 0x01, 0xff, 0xff]);
+        bytes.extend([0xe5; 0x120]);
+        assert_eq!(assembly.bytes, bytes);
+
+        // Addresses in words after a string are still moved when linked
+        let args = AssembleArgs {
+            object: true,
+            ..Default::default()
+        };
+        let source = "        DW 'AB', _end\n        DW 0, _end\n_end:\n";
+        let mut assembler = Assembler::new(args).with_source(source);
+        let assembly = assembler.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        let object = assembler.object(&assembly.bytes);
+        let offsets: Vec<u16> = object.relocations.iter().map(|r| r.offset).collect();
+        assert_eq!(offsets, vec![2, 6]);
+
+        // A word has room for two characters and a fill is a byte, neither is cut to fit
+        for source in [
+            "        DW 1, 'ABC'\n",
+            "        DS 2, 100H\n",
+            "        DS 2, _f + 0FEH\n_f:\n",
+        ] {
+            let assembly = build_source(source);
+            let codes: Vec<&str> = assembly.diagnostics.iter().map(|d| d.code).collect();
+            assert_eq!(codes, vec!["E204"], "{}", source);
+        }
+    }
+
+    #[test]
+    fn dri_dialect() {
+        let source = "\
+* Print a message through the BDOS
+bdos    equ 5
+msg$len equ 5
+start   lxi d, msg ! mvi c, 9
+        call bdos
+done:   jmp done    ; don't return
+msg     db 'hi!$', msg$len
+";
+        let args = AssembleArgs {
+            dialect: Dialect::Dri,
+            ..Default::default()
+        };
+        let mut assembler = Assembler::new(args).with_source(source);
+        let assembly = assembler.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        let native = build_source(
+            "\
+BDOS:   EQU 5
+START:  LXI D, MSG
+        MVI C, 9
+        CALL BDOS
+DONE:   JMP DONE
+MSG:    DB 'hi!$', 5
+",
+        );
+        assert_eq!(assembly.bytes, native.bytes);
+        assert_eq!(assembly.symbols["MSGLEN"].value, Some(5));
+
+        // Each statement of a line is listed
+        let listing = assembler.listing();
+        assert!(listing.contains("4   0003  0E 09"), "{}", listing);
+
+        let args = AssembleArgs {
+            dialect: Dialect::Dri,
+            ..Default::default()
+        };
+        let assembly = Assembler::new(args).with_source("x  mvi q, 1\n").build();
+        assert_eq!(assembly.diagnostics[0].source, "x  mvi q, 1");
+        assert_eq!(assembly.diagnostics[0].column, 8);
+    }
 
-        let bytes = ass.generate_prog().expect("should compile");
+    #[test]
+    fn assertions() {
+        // Checked once the forward reference is known
+        let source = "        ASSERT TOP <= 4, 'over the ROM'\n        ORG 2\n        DB 1\nTOP:\n";
+        let assembly = build_source(source);
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        let assembly = build_source(&source.replace("ORG 2", "ORG 4"));
+        assert_eq!(assembly.diagnostics.len(), 1);
+        assert_eq!(assembly.diagnostics[0].code, "E260");
         assert_eq!(
-            bytes,
+            assembly.diagnostics[0].message,
+            "Assertion failed: over the ROM"
+        );
+
+        let assembly = build_source("        ASSERT 1 - 1\n        ASSERT 1, MSG\n");
+        let codes: Vec<&str> = assembly.diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, vec!["E260", "E204"]);
+        assert_eq!(assembly.diagnostics[0].message, "Assertion failed: 1 - 1");
+
+        let mut args = AssembleArgs::default();
+        args.rom.fill = 0xff;
+        let assembly = Assembler::new(args)
+            .with_source("        DB 1\n        ORG 3\n        DB 2\n")
+            .build();
+        assert_eq!(assembly.bytes, vec![1, 0xff, 0xff, 2]);
+    }
+
+    #[test]
+    fn fall_through_across_segments() {
+        // The data follows straight on from the LXI, but it's never run into
+        let source = "\
+        MVI A, 1
+        DB 2
+        LXI H, MSG
+        DSEG
+MSG:    DB 'hi'
+";
+        for object in [false, true] {
+            let args = AssembleArgs {
+                register_definitions: true,
+                object,
+                ..Default::default()
+            };
+            let assembly = Assembler::new(args).with_source(source).build();
+            let codes: Vec<(usize, &str)> = assembly
+                .diagnostics
+                .iter()
+                .map(|d| (d.line, d.code))
+                .collect();
+            assert_eq!(codes, vec![(2, "W004")], "object: {}", object);
+        }
+    }
+
+    #[test]
+    fn segments() {
+        let source = "\
+        LXI H, MSG
+        DSEG
+MSG:    DB 'hi'
+        CSEG
+        JMP $
+        DSEG
+        DW MSG
+";
+        let assembly = build_source(source);
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assert_eq!(
+            assembly.bytes,
+            vec![0x21, 0x06, 0x00, 0xc3, 0x03, 0x00, b'h', b'i', 0x06, 0x00]
+        );
+
+        let assembly = build_source("        EXTRN PRINT\n        CALL PRINT\n");
+        assert_eq!(assembly.diagnostics[0].code, "E251");
+
+        let args = AssembleArgs {
+            register_definitions: true,
+            object: true,
+            ..Default::default()
+        };
+        let mut assembler = Assembler::new(args).with_source(source);
+        let assembly = assembler.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        let object = assembler.object(&assembly.bytes);
+        assert_eq!(object.code, vec![0x21, 0x00, 0x00, 0xc3, 0x03, 0x00]);
+        assert_eq!(object.data, vec![b'h', b'i', 0x00, 0x00]);
+        let targets: Vec<(Segment, u16, Target)> = object
+            .relocations
+            .into_iter()
+            .map(|r| (r.segment, r.offset, r.target))
+            .collect();
+        assert_eq!(
+            targets,
             vec![
-                0x41, 0x40, 0x41, 0x22,
-                // The SHDL in the macro is now MOV, MOV, MOV, SHDL, so 0x03
-                0x03, 0x00, 0x22, 0x06, 0x00
+                (Segment::Code, 1, Target::Data),
+                (Segment::Code, 4, Target::Code),
+                (Segment::Data, 2, Target::Data)
             ]
         );
+
+        for source in ["_x:     MVI A, _x\n", "_x:     DW _x + _x\n"] {
+            let args = AssembleArgs {
+                register_definitions: true,
+                object: true,
+                ..Default::default()
+            };
+            let assembly = Assembler::new(args).with_source(source).build();
+            assert_eq!(assembly.diagnostics[0].code, "E250", "{}", source);
+        }
     }
 }
@@ -0,0 +1,192 @@
+//! Conditional assembly
+//!
+//! `IF`, `ELSEIF`, `ELSE` and `ENDIF` may be nested to any depth. Each `IF` pushes a frame which
+//! tracks whether its current branch is being assembled and whether any branch already has been,
+//! so only the first true branch of a block is assembled. Conditions inside a block which is being
+//! skipped are never evaluated, they may refer to labels which don't exist.
+
+use super::errors::ParserError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Directive {
+    If,
+    ElseIf,
+    Else,
+    EndIf,
+}
+
+impl Directive {
+    pub fn from_inst(inst: &str) -> Option<Self> {
+        match inst {
+            "IF" => Some(Self::If),
+            "ELSEIF" => Some(Self::ElseIf),
+            "ELSE" => Some(Self::Else),
+            "ENDIF" => Some(Self::EndIf),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    active: bool,
+    taken: bool,
+    seen_else: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConditionStack {
+    frames: Vec<Frame>,
+}
+
+impl ConditionStack {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Whether lines are currently being left out
+    pub fn skipping(&self) -> bool {
+        self.frames.iter().any(|frame| !frame.active)
+    }
+
+    /// How many `IF`s are waiting on an `ENDIF`
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Applies a directive, `condition` is only called if the directive's condition matters
+    pub fn apply<F>(&mut self, directive: Directive, condition: F) -> Result<(), ParserError>
+    where
+        F: FnOnce() -> Result<bool, ParserError>,
+    {
+        match directive {
+            Directive::If => {
+                let frame = if self.skipping() {
+                    // No branch of a block inside a skipped one can be taken
+                    Frame {
+                        active: false,
+                        taken: true,
+                        seen_else: false,
+                    }
+                } else {
+                    let cond = condition()?;
+                    Frame {
+                        active: cond,
+                        taken: cond,
+                        seen_else: false,
+                    }
+                };
+                self.frames.push(frame);
+            }
+            Directive::ElseIf => {
+                let frame = self.frames.last_mut().ok_or(ParserError::NotInIf)?;
+                if frame.seen_else {
+                    return Err(ParserError::ElseAfterElse);
+                }
+                if frame.taken {
+                    frame.active = false;
+                } else {
+                    let cond = condition()?;
+                    frame.active = cond;
+                    frame.taken = cond;
+                }
+            }
+            Directive::Else => {
+                let frame = self.frames.last_mut().ok_or(ParserError::NotInIf)?;
+                if frame.seen_else {
+                    return Err(ParserError::ElseAfterElse);
+                }
+                frame.active = !frame.taken;
+                frame.taken = true;
+                frame.seen_else = true;
+            }
+            Directive::EndIf => {
+                self.frames.pop().ok_or(ParserError::NotInIf)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Which of the lines between directives would be assembled
+    fn run(directives: &[(Directive, bool)]) -> Vec<bool> {
+        let mut stack = ConditionStack::new();
+        directives
+            .iter()
+            .map(|(directive, cond)| {
+                stack.apply(*directive, || Ok(*cond)).unwrap();
+                !stack.skipping()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn first_true_branch_only() {
+        use Directive::*;
+        assert_eq!(
+            run(&[
+                (If, false),
+                (ElseIf, true),
+                (ElseIf, true),
+                (Else, false),
+                (EndIf, false)
+            ]),
+            vec![false, true, false, false, true]
+        );
+        assert_eq!(
+            run(&[(If, true), (Else, false), (EndIf, false)]),
+            vec![true, false, true]
+        );
+    }
+
+    #[test]
+    fn nested_in_skipped_block() {
+        use Directive::*;
+        assert_eq!(
+            run(&[
+                (If, false),
+                (If, true),
+                (Else, false),
+                (EndIf, false),
+                (EndIf, false)
+            ]),
+            vec![false, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn conditions_not_evaluated_when_skipping() {
+        let mut stack = ConditionStack::new();
+        stack.apply(Directive::If, || Ok(true)).unwrap();
+        stack
+            .apply(Directive::ElseIf, || panic!("branch already taken"))
+            .unwrap();
+        stack
+            .apply(Directive::If, || panic!("inside skipped"))
+            .unwrap();
+        assert_eq!(stack.depth(), 2);
+    }
+
+    #[test]
+    fn unbalanced() {
+        let mut stack = ConditionStack::new();
+        assert!(matches!(
+            stack.apply(Directive::EndIf, || Ok(true)),
+            Err(ParserError::NotInIf)
+        ));
+        assert!(matches!(
+            stack.apply(Directive::Else, || Ok(true)),
+            Err(ParserError::NotInIf)
+        ));
+        stack.apply(Directive::If, || Ok(true)).unwrap();
+        stack.apply(Directive::Else, || Ok(true)).unwrap();
+        assert!(matches!(
+            stack.apply(Directive::ElseIf, || Ok(true)),
+            Err(ParserError::ElseAfterElse)
+        ));
+    }
+}
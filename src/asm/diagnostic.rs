@@ -0,0 +1,353 @@
+//! Errors and warnings reported against a position in the source
+//!
+//! Lines and columns both count from one, a column of zero means the whole line is at fault or
+//! that no line is known at all. The span is how many characters from the column are at fault.
+//!
+//! Diagnostics are rendered for people with the line at fault underlined,
+//!
+//! ```text
+//! error[E101]: Expression evaluation error: unknown identifier '_NOPE'
+//!  --> prog.asm:3:12
+//!   |
+//! 3 |     MVI A, _nope
+//!   |            ^^^^^
+//! ```
+//!
+//! or for editors as a JSON object per line, see [Diagnostic::to_json]. Warnings, see
+//! [warnings](super::warnings), are given the same way.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    errors::{AssemblerError, CodeGenError, OpParseError, ParserError},
+    expressions::errors::ExpressionError,
+    tokenizer::LineMeta,
+    warnings::Warning,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub span: usize,
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    /// The text of the line at fault, empty if there isn't one
+    pub source: String,
+}
+
+impl Diagnostic {
+    pub fn new<S: Into<String>>(line: usize, column: usize, message: S) -> Self {
+        Self {
+            file: PathBuf::new(),
+            line,
+            column,
+            span: 0,
+            severity: Severity::Error,
+            code: "",
+            message: message.into(),
+            source: String::new(),
+        }
+    }
+
+    /// Places the error on the line it was raised for, pointing at the offending text if the error
+    /// names some, otherwise at the start of the statement
+    pub fn from_error(e: &AssemblerError, line: Option<&LineMeta>) -> Self {
+        let mut diagnostic = Self::on_line(e.to_string(), culprit(e), line);
+        diagnostic.code = e.code();
+        diagnostic
+    }
+
+    /// Like [Self::from_error], `error` is set when warnings are being taken as errors
+    pub fn from_warning(w: &Warning, line: Option<&LineMeta>, error: bool) -> Self {
+        let mut diagnostic = Self::on_line(w.to_string(), w.culprit(), line);
+        diagnostic.code = w.code();
+        if !error {
+            diagnostic.severity = Severity::Warning;
+        }
+        diagnostic
+    }
+
+    fn on_line(message: String, culprit: Option<&str>, line: Option<&LineMeta>) -> Self {
+        match line {
+            Some(line) => {
+                let (column, span) = match culprit {
+                    Some(text) => {
+                        column_of(&line.raw_line, text).map(|c| (c, text.chars().count()))
+                    }
+                    None => None,
+                }
+                .or_else(|| statement_column(&line.raw_line))
+                .unwrap_or((0, 0));
+                Self {
+                    span,
+                    source: line.raw_line.to_string(),
+                    ..Self::new(line.line_no, column, message)
+                }
+            }
+            None => Self::new(0, 0, message),
+        }
+    }
+
+    /// Moves the diagnostic to `line` of `file`, for lines which came from an included file
+    pub fn at<P: AsRef<Path>>(mut self, file: P, line: usize) -> Self {
+        self.file = file.as_ref().to_path_buf();
+        self.line = line;
+        self
+    }
+}
+
+impl Diagnostic {
+    /// The diagnostic with the line at fault and a caret under the part of it at fault
+    pub fn render(&self) -> String {
+        let mut out = self.severity.to_string();
+        if !self.code.is_empty() {
+            out.push_str(&format!("[{}]", self.code));
+        }
+        out.push_str(&format!(": {}\n", self.message));
+        if self.line == 0 {
+            // Only the file is known, if even that
+            if !self.file.as_os_str().is_empty() {
+                out.push_str(&format!(" --> {}\n", self.file.display()));
+            }
+            return out;
+        }
+
+        let gutter = " ".repeat(self.line.to_string().len());
+        let mut location = String::new();
+        if !self.file.as_os_str().is_empty() {
+            location.push_str(&format!("{}:", self.file.display()));
+        }
+        location.push_str(&self.line.to_string());
+        if self.column > 0 {
+            location.push_str(&format!(":{}", self.column));
+        }
+        out.push_str(&format!("{}--> {}\n", gutter, location));
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", self.line, self.source));
+        if self.column > 0 && self.span > 0 {
+            // Tabs are kept so the caret lines up however they're shown
+            let indent: String = self
+                .source
+                .chars()
+                .take(self.column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            out.push_str(&format!(
+                "{} | {}{}\n",
+                gutter,
+                indent,
+                "^".repeat(self.span)
+            ));
+        }
+        out
+    }
+
+    /// The diagnostic as a single line JSON object, `end_column` is one past the span
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"file\": {}, \"line\": {}, \"column\": {}, \"end_column\": {}, \
+             \"severity\": \"{}\", \"code\": \"{}\", \"message\": {}}}",
+            json_string(&self.file.display().to_string()),
+            self.line,
+            self.column,
+            self.column + self.span,
+            self.severity,
+            self.code,
+            json_string(&self.message)
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.file.as_os_str().is_empty() {
+            write!(f, "{}:", self.file.display())?;
+        }
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+fn culprit(e: &AssemblerError) -> Option<&str> {
+    let e = match e {
+        AssemblerError::Parser(e) => e,
+        AssemblerError::CodeGen(CodeGenError::ParserError(e)) => e,
+        _ => return None,
+    };
+    match e {
+        ParserError::ExpressionError(ExpressionError::UnknownIdentifier(s))
+        | ParserError::ExpressionError(ExpressionError::UnknownUnary(s))
+        | ParserError::UnknownDefine(s)
+        | ParserError::OperationRequiresLabel(s)
+        | ParserError::InvalidArgument(_, s)
+        | ParserError::UnterminatedString(s)
+        | ParserError::InvalidLabel(s)
+        | ParserError::LabelAlreadyDefined(s, _)
+        | ParserError::CircularReference(s)
+        | ParserError::UnstableValue(s)
+        | ParserError::IncludeNotFound(s)
+        | ParserError::IncludeCycle(s)
+        | ParserError::NotRelocatable(s)
+        | ParserError::ExternalNotLinked(s)
+        | ParserError::DefinedOnCommandLine(s)
+        | ParserError::InvalidInStruct(s)
+        | ParserError::UnknownStruct(s)
+        | ParserError::NoInstructionFound(OpParseError::NoSuchInstruction(s)) => Some(s),
+        _ => None,
+    }
+}
+
+fn column_of(raw_line: &str, text: &str) -> Option<usize> {
+    if text.is_empty() {
+        return None;
+    }
+    let code = raw_line.split(';').next().unwrap_or_default();
+    let upper = code.to_ascii_uppercase();
+    let is_ident = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    // A whole word is wanted, `A` shouldn't be found in `_START`
+    upper
+        .match_indices(&text.to_ascii_uppercase())
+        .find(|(idx, m)| {
+            !is_ident(upper[..*idx].chars().last())
+                && !is_ident(upper[idx + m.len()..].chars().next())
+        })
+        .map(|(idx, _)| code[..idx].chars().count() + 1)
+}
+
+/// Where the statement starts and how long it is, leaving out any comment
+fn statement_column(raw_line: &str) -> Option<(usize, usize)> {
+    let code = raw_line.split(';').next().unwrap_or_default().trim_end();
+    code.chars()
+        .position(|c| !c.is_whitespace())
+        .map(|idx| (idx + 1, code.chars().count() - idx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(line_no: usize, raw_line: &str) -> LineMeta {
+        LineMeta::from_raw(line_no, raw_line.to_string())
+    }
+
+    #[test]
+    fn points_at_culprit() {
+        let e = AssemblerError::Parser(ParserError::ExpressionError(
+            ExpressionError::UnknownIdentifier("_NOPE".to_string()),
+        ));
+        let d = Diagnostic::from_error(&e, Some(&line(3, "    MVI A, _nope ; _nope")));
+        assert_eq!(d.line, 3);
+        assert_eq!(d.column, 12);
+        assert_eq!(d.span, 5);
+        assert_eq!(d.code, "E101");
+
+        let d = Diagnostic::from_error(&e, Some(&line(3, "_nope_: MVI A, _nope")));
+        assert_eq!(d.column, 16);
+    }
+
+    #[test]
+    fn points_at_statement() {
+        let e = AssemblerError::Parser(ParserError::ElseAfterElse);
+        let d = Diagnostic::from_error(&e, Some(&line(7, "  ELSE  ; extra")));
+        assert_eq!((d.line, d.column, d.span), (7, 3, 4));
+        assert_eq!(d.to_string(), "7:3: ELSE or ELSEIF found after ELSE");
+
+        let d = Diagnostic::from_error(&e, None);
+        assert_eq!((d.line, d.column), (0, 0));
+
+        let d = Diagnostic::from_error(&e, Some(&line(7, "  ELSE"))).at("lib.asm", 2);
+        assert_eq!(
+            d.to_string(),
+            "lib.asm:2:3: ELSE or ELSEIF found after ELSE"
+        );
+    }
+
+    #[test]
+    fn rendered_with_caret() {
+        let e = AssemblerError::Parser(ParserError::ExpressionError(
+            ExpressionError::UnknownIdentifier("_NOPE".to_string()),
+        ));
+        let d = Diagnostic::from_error(&e, Some(&line(3, "\tMVI A, _nope"))).at("prog.asm", 12);
+        assert_eq!(
+            d.render(),
+            "error[E101]: Expression evaluation error: unknown identifier '_NOPE'\n\
+             \x20 --> prog.asm:12:9\n\
+             \x20  |\n\
+             12 | \tMVI A, _nope\n\
+             \x20  | \t       ^^^^^\n"
+        );
+
+        let d = Diagnostic::from_error(&AssemblerError::Parser(ParserError::NoEndIf), None);
+        assert_eq!(d.render(), "error[E232]: No ENDIF found\n");
+        assert_eq!(
+            d.at("prog.asm", 0).render(),
+            "error[E232]: No ENDIF found\n --> prog.asm\n"
+        );
+    }
+
+    #[test]
+    fn warnings() {
+        let w = Warning::ShadowedEqu("LEN".to_string());
+        let d = Diagnostic::from_warning(&w, Some(&line(4, "len: SET 2")), false);
+        assert_eq!((d.column, d.span), (1, 3));
+        assert_eq!(d.severity, Severity::Warning);
+        assert!(d
+            .render()
+            .starts_with("warning[W003]: SET of LEN which was defined by EQU\n"));
+
+        let d = Diagnostic::from_warning(&w, None, true);
+        assert_eq!(d.severity, Severity::Error);
+    }
+
+    #[test]
+    fn json() {
+        let e = AssemblerError::Parser(ParserError::InvalidArgument(
+            "INCLUDE".to_string(),
+            "\"A\"".to_string(),
+        ));
+        let d = Diagnostic::from_error(&e, Some(&line(2, "INCLUDE \"a\""))).at("a.asm", 2);
+        assert_eq!(
+            d.to_json(),
+            "{\"file\": \"a.asm\", \"line\": 2, \"column\": 9, \"end_column\": 12, \
+             \"severity\": \"error\", \"code\": \"E204\", \
+             \"message\": \"Invalid arg for INCLUDE, \\\"A\\\"\"}"
+        );
+    }
+}
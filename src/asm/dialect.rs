@@ -0,0 +1,163 @@
+//! Other assemblers' syntax, read by rewriting each line into the assembler's own
+//!
+//! With `--dialect dri`, source written for Digital Research's ASM and MAC assembles unmodified:
+//!
+//! - A name starting in column 1 is a label without a colon, unless it's an instruction, as is
+//!   the name before `EQU`, `SET` or `MACRO` wherever it starts
+//! - A `*` in column 1 makes the line a comment
+//! - `!` separates statements on one line, but for the `!=` operator
+//! - `$` within a name or number is ignored, so `MAX$LEN` is `MAXLEN` and `0FF$FFH` is `0FFFFH`
+//!
+//! ```asm
+//! * Print the string at DE
+//! BDOS    EQU 5
+//! PRINT   MVI C, 9 ! CALL BDOS
+//!         RET
+//! ```
+//!
+//! Mnemonics and symbols are case-insensitive in either dialect. Diagnostics and listings show
+//! the source as written, `-E` writes it in the assembler's own syntax.
+
+use super::{errors::OpParseError, find_op_code, tokenizer::find_unquoted};
+use crate::{cli::Dialect, meta::I8080_OP_META};
+
+/// The statements of a line of source in the assembler's own syntax
+pub fn statements(raw_line: &str, dialect: Dialect) -> Vec<String> {
+    match dialect {
+        Dialect::Native => vec![raw_line.to_string()],
+        Dialect::Dri => dri(raw_line),
+    }
+}
+
+fn dri(raw_line: &str) -> Vec<String> {
+    if let Some(comment) = raw_line.strip_prefix('*') {
+        return vec![format!(";{}", comment)];
+    }
+    let (mut code, comment) = match find_unquoted(raw_line, ';') {
+        Some(idx) => raw_line.split_at(idx),
+        None => (raw_line, ""),
+    };
+    let mut statements = vec![];
+    while let Some(idx) = separator(code) {
+        statements.push(&code[..idx]);
+        code = &code[idx + 1..];
+    }
+    statements.push(code);
+
+    let mut lines: Vec<String> = statements
+        .iter()
+        .enumerate()
+        // Only the first statement can start in column 1
+        .map(|(idx, statement)| labelled(&without_separators(statement), idx == 0))
+        .collect();
+    if let Some(last) = lines.last_mut() {
+        last.push_str(comment);
+    }
+    lines
+}
+
+/// Where the first `!` between statements is, one starting `!=` compares
+fn separator(code: &str) -> Option<usize> {
+    let mut from = 0;
+    while let Some(idx) = find_unquoted(&code[from..], '!') {
+        let at = from + idx;
+        if !code[at + 1..].starts_with('=') {
+            return Some(at);
+        }
+        from = at + 1;
+    }
+    None
+}
+
+/// The statement with a colon after its label, if it has one without
+fn labelled(statement: &str, first: bool) -> String {
+    if find_unquoted(statement, ':').is_some() {
+        return statement.to_string();
+    }
+    let trimmed = statement.trim_start();
+    let mut words = trimmed.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return statement.to_string(),
+    };
+    let in_column_one = first && trimmed.len() == statement.len() && !is_instruction(name);
+    if !in_column_one && !words.next().is_some_and(takes_label) {
+        return statement.to_string();
+    }
+    let end = statement.len() - trimmed.len() + name.len();
+    format!("{}:{}", &statement[..end], &statement[end..])
+}
+
+fn is_instruction(word: &str) -> bool {
+    !matches!(
+        find_op_code::from_args(&word.to_uppercase(), 0, 0),
+        Err(OpParseError::NoSuchInstruction(_))
+    )
+}
+
+/// Whether an instruction names its label, as `EQU`
+fn takes_label(word: &str) -> bool {
+    find_op_code::from_args(&word.to_uppercase(), 0, 0).is_ok_and(|op| I8080_OP_META[op].labelled)
+}
+
+/// The statement without any `$` between the characters of a name or number
+fn without_separators(statement: &str) -> String {
+    let mut text = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = statement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == '$' => {
+                let is_word = |c: &char| c.is_alphanumeric() || *c == '_';
+                if text.chars().last().is_some_and(|c| is_word(&c))
+                    && chars.peek().is_some_and(is_word)
+                {
+                    continue;
+                }
+            }
+            None => {}
+        }
+        text.push(c);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dri(line: &str) -> Vec<String> {
+        statements(line, Dialect::Dri)
+    }
+
+    #[test]
+    fn rewrites_dri() {
+        assert_eq!(dri("* a comment"), vec!["; a comment"]);
+        assert_eq!(dri("BDOS EQU 5"), vec!["BDOS: EQU 5"]);
+        assert_eq!(dri("    bdos equ 5"), vec!["    bdos: equ 5"]);
+        assert_eq!(dri("LOOP:   DCR C"), vec!["LOOP:   DCR C"]);
+        assert_eq!(dri("mvi a, 1"), vec!["mvi a, 1"]);
+        assert_eq!(
+            dri("PRINT MVI C, '!' ! CALL BDOS ; print!"),
+            vec!["PRINT: MVI C, '!' ", " CALL BDOS ; print!"]
+        );
+        assert_eq!(
+            dri("MAX$LEN EQU 0FF$FFH ! DB '$', $ + 1, MAX$LEN"),
+            vec!["MAXLEN: EQU 0FFFFH ", " DB '$', $ + 1, MAXLEN"]
+        );
+        assert_eq!(
+            dri("        IF X != 1 ! NOP"),
+            vec!["        IF X != 1 ", " NOP"]
+        );
+        assert_eq!(dri("        IF X!=1"), vec!["        IF X!=1"]);
+        // Only the first statement starts in column 1
+        assert_eq!(dri("NOP ! X DB 1"), vec!["NOP ", " X DB 1"]);
+        assert_eq!(
+            statements("X DB 1 ! NOP", Dialect::Native),
+            vec!["X DB 1 ! NOP"]
+        );
+    }
+}
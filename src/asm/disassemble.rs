@@ -26,6 +26,9 @@
 //! MOV H, H
 //! ```
 //!
+//! Labels can be given with `--symbols`, reading a symbol file written by the assembler, see
+//! [symbols](super::symbols).
+//!
 //! # Examples
 //!
 //! To disassemble a file to a specific output file
@@ -38,6 +41,8 @@
 //! HLT
 //! ```
 
+use std::collections::HashMap;
+
 use crate::meta::I8080_OP_META;
 use crate::util::vec_u8_to_u16;
 
@@ -45,7 +50,7 @@ use super::errors::DisassembleError;
 
 pub fn disassemble_instruction(v: &[u8], from: usize) -> Result<(String, usize), DisassembleError> {
     let v = &v[from..];
-    let inst = v.get(0);
+    let inst = v.first();
     if inst.is_none() {
         return Err(DisassembleError::NoRemainingBytes(from));
     }
@@ -70,10 +75,31 @@ pub fn disassemble_instruction(v: &[u8], from: usize) -> Result<(String, usize),
 }
 
 pub fn disassemble_vec(v: &[u8]) -> Result<Vec<String>, DisassembleError> {
+    disassemble_vec_at(v, 0, &HashMap::new())
+}
+
+/// Disassembles `v` as if loaded at `base`, placing a label before any instruction at a labelled
+/// address and using label names for 16-bit operands which match one
+pub fn disassemble_vec_at(
+    v: &[u8],
+    base: u16,
+    labels: &HashMap<u16, String>,
+) -> Result<Vec<String>, DisassembleError> {
     let mut strings = Vec::new();
     let mut from = 0;
     while from < v.len() {
-        let (s, width) = disassemble_instruction(v, from)?;
+        let address = base.wrapping_add(from as u16);
+        if let Some(name) = labels.get(&address) {
+            strings.push(format!("{}:", name));
+        }
+        let (mut s, width) = disassemble_instruction(v, from)?;
+        if I8080_OP_META[v[from] as usize].argw {
+            let word = vec_u8_to_u16(&v[from + 1..]);
+            if let Some(name) = labels.get(&word) {
+                s.truncate(s.len() - format!("{:#06x}", word).len());
+                s.push_str(name);
+            }
+        }
         strings.push(s);
         // Shouldn't happen but peace of mind is nice
         if width == 0 {
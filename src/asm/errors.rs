@@ -2,7 +2,7 @@
 
 use std::{fmt, io};
 
-use crate::asm::label::Label;
+use crate::{asm::label::Label, prl::PrlError};
 
 use super::expressions::errors::ExpressionError;
 
@@ -25,6 +25,17 @@ impl fmt::Display for AssemblerError {
     }
 }
 
+impl AssemblerError {
+    /// A short code identifying the kind of error, for looking up or filtering diagnostics
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::FileRead(_) => "E001",
+            Self::Parser(e) => e.code(),
+            Self::CodeGen(e) => e.code(),
+        }
+    }
+}
+
 impl From<ParserError> for AssemblerError {
     fn from(e: ParserError) -> Self {
         AssemblerError::Parser(e)
@@ -54,21 +65,37 @@ pub enum ParserError {
 
     LabelAlreadyDefined(String, Label),
     NoInstructionFound(OpParseError),
+    CircularReference(String),
+    UnstableValue(String),
 
     OrigInMacro,
-    DefineInMacro,
     NotInMacro,
-    NestedMacro,
-    MacroCallInMacroUsesSp,
     MacroUseBeforeCreation,
+    MissingMacroArgument(String),
     RecursiveMacro,
     NoEndMacro,
 
-    IfAndMacroMix,
-
-    NestedIf,
+    ElseAfterElse,
     NotInIf,
     NoEndIf,
+
+    IncludeNotFound(String),
+    IncludeCycle(String),
+
+    NotRelocatable(String),
+    ExternalNotLinked(String),
+    NotPageRelocatable(PrlError),
+
+    AssertionFailed(String),
+
+    InvalidSymbolDefinition(String, String),
+    ConflictingSymbolDefinitions(String),
+    DefinedOnCommandLine(String),
+
+    NotInStruct,
+    NoEndStruct,
+    InvalidInStruct(String),
+    UnknownStruct(String),
 }
 
 impl std::error::Error for ParserError {}
@@ -76,7 +103,7 @@ impl std::error::Error for ParserError {}
 impl fmt::Display for ParserError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Self::ExpressionError(e) => write!(f, "Expression evaluation error: {:?}", e),
+            Self::ExpressionError(e) => write!(f, "Expression evaluation error: {}", e),
             Self::UnknownDefine(s) => write!(f, "Unknown vararg: {}", s),
 
             Self::NoArgsForVariadic => write!(f, "No arguments given for variadic instruction"),
@@ -92,28 +119,129 @@ impl fmt::Display for ParserError {
             Self::UnterminatedString(s) => write!(f, "Unterminated string: [{}]", s),
             Self::InvalidLabel(s) => write!(f, "Invalid label: [{}]", s),
 
-            Self::LabelAlreadyDefined(s, l) => {
-                write!(f, "Label ({}) already defined at {:?}", s, l)
+            Self::LabelAlreadyDefined(s, l) => match l.value {
+                Some(value) if l.is_addr => {
+                    write!(f, "Label ({}) already defined at {:#06x}", s, value)
+                }
+                Some(value) => write!(f, "Label ({}) already defined as {:#06x}", s, value),
+                None => write!(f, "Label ({}) already defined", s),
+            },
+            Self::NoInstructionFound(e) => write!(f, "No instruction found: {}", e),
+            Self::CircularReference(s) => write!(
+                f,
+                "Value of {} can't be worked out, it depends on itself or on where code is placed \
+                 which in turn depends on it",
+                s
+            ),
+            Self::UnstableValue(s) => {
+                write!(f, "Value of {} kept changing between passes", s)
             }
-            Self::NoInstructionFound(_) => write!(f, ""),
 
             Self::OrigInMacro => write!(f, "ORIG used in macro"),
-            Self::DefineInMacro => write!(f, "Cannot define (DB DW DB) in macro"),
-            Self::NotInMacro => write!(f, "ENDM found before MACRO"),
-            Self::NestedMacro => write!(f, "Nested MACRO not permitted"),
-            Self::MacroCallInMacroUsesSp => write!(
+            Self::NotInMacro => write!(f, "ENDM, EXITM or LOCAL found outside of a MACRO"),
+            Self::MacroUseBeforeCreation => write!(f, "Macro used before its definition"),
+            Self::MissingMacroArgument(s) => {
+                write!(f, "No argument given for macro parameter {}", s)
+            }
+            Self::RecursiveMacro => write!(
                 f,
-                "Use of a macro which uses SP ('$') inside another macro is not permitted"
+                "Macro calls nested too deeply, a macro may be calling itself"
             ),
-            Self::MacroUseBeforeCreation => write!(f, "Macro used before its definition"),
-            Self::RecursiveMacro => write!(f, "Use of macro from within macro definition"),
             Self::NoEndMacro => write!(f, "No ENDM found"),
 
-            Self::IfAndMacroMix => write!(f, "This assembler does not support mixing MACRO and IF"),
-
-            Self::NestedIf => write!(f, "Nested IF not permitted"),
-            Self::NotInIf => write!(f, "ENDIF found before IF"),
+            Self::ElseAfterElse => write!(f, "ELSE or ELSEIF found after ELSE"),
+            Self::NotInIf => write!(f, "ENDIF, ELSE or ELSEIF found before IF"),
             Self::NoEndIf => write!(f, "No ENDIF found"),
+
+            Self::IncludeNotFound(s) => write!(f, "Included file not found: {}", s),
+            Self::IncludeCycle(s) => write!(f, "File includes itself: {}", s),
+
+            Self::NotRelocatable(s) => write!(
+                f,
+                "{} can't be relocated, only a 16-bit value of one address plus or minus a \
+                 constant can",
+                s
+            ),
+            Self::ExternalNotLinked(s) => write!(
+                f,
+                "EXTRN {} is only resolved by linking, assemble with -c to write an object",
+                s
+            ),
+            Self::NotPageRelocatable(e) => write!(f, "{}", e),
+
+            Self::AssertionFailed(s) => write!(f, "Assertion failed: {}", s),
+
+            Self::InvalidSymbolDefinition(s, why) => write!(f, "Invalid -D {}, {}", s, why),
+            Self::ConflictingSymbolDefinitions(s) => {
+                write!(
+                    f,
+                    "{} is defined in conflicting ways on the command line",
+                    s
+                )
+            }
+            Self::DefinedOnCommandLine(s) => write!(
+                f,
+                "{} is defined on the command line, guard this with IF NOT DEFINED({})",
+                s, s
+            ),
+
+            Self::NotInStruct => write!(f, "ENDS found outside of a STRUCT"),
+            Self::NoEndStruct => write!(f, "STRUCT without ENDS"),
+            Self::InvalidInStruct(s) => {
+                write!(f, "Only DB, DW and DS can lay out a STRUCT, not {}", s)
+            }
+            Self::UnknownStruct(s) => write!(f, "No STRUCT named {} defined before", s),
+        }
+    }
+}
+
+impl ParserError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ExpressionError(e) => e.code(),
+            Self::UnknownDefine(_) => "E200",
+
+            Self::NoArgsForVariadic => "E201",
+            Self::WrongNumberOfArgs(_, _) => "E202",
+            Self::OperationRequiresLabel(_) => "E203",
+
+            Self::InvalidArgument(_, _) => "E204",
+            Self::UnterminatedString(_) => "E205",
+            Self::InvalidLabel(_) => "E206",
+
+            Self::LabelAlreadyDefined(_, _) => "E207",
+            Self::NoInstructionFound(_) => "E208",
+            Self::CircularReference(_) => "E209",
+            Self::UnstableValue(_) => "E210",
+
+            Self::OrigInMacro => "E220",
+            Self::NotInMacro => "E221",
+            Self::MacroUseBeforeCreation => "E222",
+            Self::MissingMacroArgument(_) => "E223",
+            Self::RecursiveMacro => "E224",
+            Self::NoEndMacro => "E225",
+
+            Self::ElseAfterElse => "E230",
+            Self::NotInIf => "E231",
+            Self::NoEndIf => "E232",
+
+            Self::IncludeNotFound(_) => "E240",
+            Self::IncludeCycle(_) => "E241",
+
+            Self::NotRelocatable(_) => "E250",
+            Self::ExternalNotLinked(_) => "E251",
+            Self::NotPageRelocatable(_) => "E252",
+
+            Self::AssertionFailed(_) => "E260",
+
+            Self::InvalidSymbolDefinition(_, _) => "E270",
+            Self::ConflictingSymbolDefinitions(_) => "E271",
+            Self::DefinedOnCommandLine(_) => "E272",
+
+            Self::NotInStruct => "E280",
+            Self::NoEndStruct => "E281",
+            Self::InvalidInStruct(_) => "E282",
+            Self::UnknownStruct(_) => "E283",
         }
     }
 }
@@ -135,7 +263,7 @@ impl std::error::Error for CodeGenError {}
 impl fmt::Display for CodeGenError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            CodeGenError::ParserError(e) => write!(f, "Expression error: {}", e),
+            CodeGenError::ParserError(e) => write!(f, "{}", e),
             CodeGenError::UnexpectedLength(exp, act) => write!(
                 f,
                 "Byte length generate ({}) differs from expected ({})",
@@ -145,6 +273,15 @@ impl fmt::Display for CodeGenError {
     }
 }
 
+impl CodeGenError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            CodeGenError::ParserError(e) => e.code(),
+            CodeGenError::UnexpectedLength(_, _) => "E300",
+        }
+    }
+}
+
 impl From<ParserError> for CodeGenError {
     fn from(e: ParserError) -> Self {
         CodeGenError::ParserError(e)
@@ -14,10 +14,33 @@ pub enum ExpressionError {
     UnknownUnary(String),
     NotANumber(Token),
     MetaUsedInCalculation(String),
+    Overflow(String),
+    DivisionByZero,
+    InvalidDefined,
 }
 
 impl std::error::Error for ExpressionError {}
 
+impl ExpressionError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnprocessableChar(_) => "E100",
+            Self::UnknownIdentifier(_) => "E101",
+            Self::NumberParseError(_) => "E102",
+            Self::UnmatchedQuote(_) => "E103",
+            Self::UnmatchedParens => "E104",
+            Self::UnknownEscape(_) => "E105",
+            Self::CalculationError(_) => "E106",
+            Self::UnknownUnary(_) => "E107",
+            Self::NotANumber(_) => "E108",
+            Self::MetaUsedInCalculation(_) => "E109",
+            Self::Overflow(_) => "E110",
+            Self::DivisionByZero => "E111",
+            Self::InvalidDefined => "E112",
+        }
+    }
+}
+
 impl fmt::Display for ExpressionError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -31,6 +54,9 @@ impl fmt::Display for ExpressionError {
             Self::UnknownUnary(s) => write!(f, "unknown function '{}'", s),
             Self::NotANumber(t) => write!(f, "calculation yielded NaN: {:?}", t),
             Self::MetaUsedInCalculation(s) => write!(f, "meta arg used in calculation: {}", s),
+            Self::Overflow(s) => write!(f, "{} doesn't fit in 16 bits", s),
+            Self::DivisionByZero => write!(f, "division by zero"),
+            Self::InvalidDefined => write!(f, "DEFINED takes a name in brackets, DEFINED(name)"),
         }
     }
 }
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
-use std::isize;
 use std::iter;
+use std::num::IntErrorKind;
 use std::str;
 
 use crate::asm::label::Label;
@@ -28,17 +28,50 @@ impl ExprFlags {
     }
 }
 
+// Precedences follow the Intel manual, from loosest to tightest: `OR XOR`, `AND`, `NOT`,
+// the relational operators, `+ -`, then `* / MOD SHL SHR`. `HIGH`, `LOW` and `NEG` bind tighter
+// than any operator.
 fn functions(ident: String) -> Option<Token> {
     match ident.as_str() {
-        "XOR" => Some(Token::Operator('^', LEFT_ASSOC, 4)),
-        "AND" => Some(Token::Operator('&', LEFT_ASSOC, 4)),
-        "OR" => Some(Token::Operator('|', LEFT_ASSOC, 4)),
-        "NOT" => Some(Token::Unary(ident)),
-        "NEG" => Some(Token::Unary(ident)),
+        "XOR" => Some(Token::Operator("XOR", LEFT_ASSOC, 1)),
+        "OR" => Some(Token::Operator("OR", LEFT_ASSOC, 1)),
+        "AND" => Some(Token::Operator("AND", LEFT_ASSOC, 2)),
+        "EQ" => Some(Token::Operator("EQ", LEFT_ASSOC, 4)),
+        "NE" => Some(Token::Operator("NE", LEFT_ASSOC, 4)),
+        "LT" => Some(Token::Operator("LT", LEFT_ASSOC, 4)),
+        "LE" => Some(Token::Operator("LE", LEFT_ASSOC, 4)),
+        "GT" => Some(Token::Operator("GT", LEFT_ASSOC, 4)),
+        "GE" => Some(Token::Operator("GE", LEFT_ASSOC, 4)),
+        "MOD" => Some(Token::Operator("MOD", LEFT_ASSOC, 6)),
+        "SHL" => Some(Token::Operator("SHL", LEFT_ASSOC, 6)),
+        "SHR" => Some(Token::Operator("SHR", LEFT_ASSOC, 6)),
+        "NOT" | "NEG" | "HIGH" | "LOW" => Some(Token::Unary(ident)),
         _ => None,
     }
 }
 
+// The symbolic form of an operator starting with `c`, and whether it's two chars long
+fn symbol(c: char, next: Option<char>) -> Option<(Token, bool)> {
+    let op = |name| Token::Operator(name, LEFT_ASSOC, 4);
+    Some(match (c, next) {
+        ('<', Some('<')) => (Token::Operator("SHL", LEFT_ASSOC, 6), true),
+        ('>', Some('>')) => (Token::Operator("SHR", LEFT_ASSOC, 6), true),
+        ('<', Some('>')) | ('!', Some('=')) => (op("NE"), true),
+        ('<', Some('=')) => (op("LE"), true),
+        ('>', Some('=')) => (op("GE"), true),
+        ('=', Some('=')) => (op("EQ"), true),
+        ('<', _) => (op("LT"), false),
+        ('>', _) => (op("GT"), false),
+        ('=', _) => (op("EQ"), false),
+        ('+', _) => (Token::Operator("+", LEFT_ASSOC, 5), false),
+        ('-', _) => (Token::Operator("-", LEFT_ASSOC, 5), false),
+        ('*', _) => (Token::Operator("*", LEFT_ASSOC, 6), false),
+        ('/', _) => (Token::Operator("/", LEFT_ASSOC, 6), false),
+        ('%', _) => (Token::Operator("MOD", LEFT_ASSOC, 6), false),
+        _ => return None,
+    })
+}
+
 pub struct Lexer<'a> {
     iter: iter::Peekable<str::Chars<'a>>,
     address: u16,
@@ -70,17 +103,33 @@ impl<'a> Lexer<'a> {
                 true
             } else if c.is_numeric() {
                 let (number, radix) = self.consume_number();
-                match isize::from_str_radix(&number, radix) {
+                match u16::from_str_radix(&number, radix) {
                     Ok(val) => {
-                        tokens.push(Token::Number(val as u16));
+                        tokens.push(Token::Number(val));
+                    }
+                    Err(e) if *e.kind() == IntErrorKind::PosOverflow => {
+                        return Err(ExpressionError::Overflow(number))
                     }
                     Err(e) => return Err(ExpressionError::NumberParseError(e)),
                 }
                 false
+            } else if c == '\'' || c == '"' {
+                let s = self.consume_string(c)?;
+                if s.chars().count() == 1 {
+                    tokens.push(Token::Number(s.chars().next().unwrap() as u16))
+                } else {
+                    flags.string = true;
+                    tokens.push(Token::String(s));
+                }
+                false
             } else if c.is_alphabetic() || c == '_' {
                 let ident = self.consume_identifier();
                 if let Some(operator) = functions(ident.to_string()) {
                     tokens.push(operator);
+                } else if ident == "DEFINED" {
+                    let name = self.consume_defined()?;
+                    let defined = labels.contains_key(&name);
+                    tokens.push(Token::Number(if defined { 0xffff } else { 0 }));
                 }
                 // These should come before the label check as someone might use an SP label
                 else if ident == "PSW" {
@@ -97,28 +146,21 @@ impl<'a> Lexer<'a> {
                 false
             } else {
                 match c {
-                    '\'' => {
-                        let s = self.consume_string()?;
-                        if s.len() == 1 {
-                            tokens.push(Token::Number(s.chars().next().unwrap() as u16))
-                        } else {
-                            flags.string = true;
-                            tokens.push(Token::String(s));
-                        }
-                    }
                     '$' => {
                         flags.pc = true;
                         tokens.push(Token::Number(self.address));
                     }
-                    '+' | '-' => {
-                        tokens.push(Token::Operator(c, LEFT_ASSOC, 2));
-                    }
-                    '*' | '/' => {
-                        tokens.push(Token::Operator(c, LEFT_ASSOC, 3));
-                    }
                     '(' => tokens.push(Token::LParen),
                     ')' => tokens.push(Token::RParen),
-                    _ => return Err(ExpressionError::UnprocessableChar(c)),
+                    _ => {
+                        self.iter.next();
+                        let (operator, long) = symbol(c, self.iter.peek().copied())
+                            .ok_or(ExpressionError::UnprocessableChar(c))?;
+                        tokens.push(operator);
+                        if !long {
+                            continue;
+                        }
+                    }
                 }
                 true
             };
@@ -131,21 +173,22 @@ impl<'a> Lexer<'a> {
         Ok((tokens, flags))
     }
 
-    fn consume_string(&mut self) -> Result<String, ExpressionError> {
-        self.iter.next(); // Consume '\''
+    // Consumes a string between `quote`s, a quote inside it is either doubled or escaped
+    fn consume_string(&mut self, quote: char) -> Result<String, ExpressionError> {
+        self.iter.next(); // Consume the opening quote
 
         let mut escaped = false;
         let mut quote_matched = false;
 
         let mut s = String::new();
 
-        for c in self.iter.by_ref() {
-            // while let Some(c) = self.iter.next() {
+        while let Some(c) = self.iter.next() {
             if escaped {
                 escaped = false;
                 let escaped_char: char = match c {
                     '\\' => '\\',
                     '\'' => '\'',
+                    '"' => '"',
                     'n' => '\n',
                     'r' => '\r',
                     't' => '\t',
@@ -153,7 +196,12 @@ impl<'a> Lexer<'a> {
                 };
                 s.push(escaped_char);
             } else {
-                if c == '\'' {
+                if c == quote {
+                    if self.iter.peek() == Some(&quote) {
+                        self.iter.next();
+                        s.push(quote);
+                        continue;
+                    }
                     quote_matched = true;
                     break;
                 }
@@ -172,35 +220,49 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    // Consumes a number, giving its digits and radix. The radix is either a prefix, `0X`, `0B` or
+    // `0O`, or an Intel suffix, `H`, `B`, `O`, `Q` or `D`. A hex number must start with a digit,
+    // `0FFH` rather than `FFH` which is an identifier.
     fn consume_number(&mut self) -> (String, u32) {
         let mut s = String::new();
-        let mut radix: u32 = 10;
         while let Some(&c) = self.iter.peek() {
-            if c.is_numeric() || (radix == 16 && c >= 'A' && c <= 'F') {
-                s.push(c);
-            } else if s.len() == 1 && s.starts_with('0') {
-                if c == 'X' {
-                    radix = 16;
-                } else if c == 'B' {
-                    radix = 2;
-                } else if c == 'O' {
-                    radix = 8;
-                } else {
-                    break;
-                }
-            } else {
+            if !c.is_alphanumeric() {
                 break;
             }
+            s.push(c.to_ascii_uppercase());
             self.iter.next();
         }
+        if let Some(digits) = s.strip_prefix("0X") {
+            return (digits.to_string(), 16);
+        }
+        if let Some(digits) = s.strip_suffix('H') {
+            return (digits.to_string(), 16);
+        }
+        // `0B` alone is a binary zero by suffix
+        if s.len() > 2 {
+            if let Some(digits) = s.strip_prefix("0B") {
+                return (digits.to_string(), 2);
+            }
+            if let Some(digits) = s.strip_prefix("0O") {
+                return (digits.to_string(), 8);
+            }
+        }
+        let radix = match s.chars().last() {
+            Some('B') => 2,
+            Some('O') | Some('Q') => 8,
+            Some('D') => 10,
+            _ => return (s, 10),
+        };
+        s.pop();
         (s, radix)
     }
 
-    // Consumes an identifier until we don't have any other letters available
+    // Consumes an identifier until we don't have any other letters available, a `.` within one
+    // joins a structure's name to a field's
     fn consume_identifier(&mut self) -> String {
         let mut s = String::new();
         while let Some(&c) = self.iter.peek() {
-            if c.is_alphabetic() || c == '_' || c.is_numeric() {
+            if c.is_alphabetic() || c == '_' || c.is_numeric() || (c == '.' && !s.is_empty()) {
                 s.push(c);
             } else {
                 break;
@@ -210,6 +272,25 @@ impl<'a> Lexer<'a> {
         s
     }
 
+    // Consumes the `(name)` after `DEFINED`, giving the name
+    fn consume_defined(&mut self) -> Result<String, ExpressionError> {
+        self.skip_whitespace();
+        if self.iter.next() != Some('(') {
+            return Err(ExpressionError::InvalidDefined);
+        }
+        self.skip_whitespace();
+        let name = self.consume_identifier();
+        self.skip_whitespace();
+        if name.is_empty() || name.starts_with(char::is_numeric) || self.iter.next() != Some(')') {
+            return Err(ExpressionError::InvalidDefined);
+        }
+        Ok(name)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.iter.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
     #[cfg(test)]
     fn set_input(&mut self, raw_str: &'a str, address: u16) {
         self.iter = raw_str.chars().peekable();
@@ -255,36 +336,61 @@ mod tests {
         let next = lexer.iter.next();
 
         assert!(next.is_none(), "numerics consumed too");
+
+        lexer.set_input("POINT.X+1", 0);
+        assert_eq!(lexer.consume_identifier(), "POINT.X");
     }
 
     #[test]
     fn strings() {
         let mut lexer = Lexer::new();
 
-        let s = "'hello' 'hello\\ngoodbye' 'split\\'up' 'this\\\\that'";
+        let s = "'hello' 'hello\\ngoodbye' 'split\\'up' 'this\\\\that' 'it''s' \"say \"\"hi\"\"\"";
         lexer.set_input(s, 0);
 
-        let out = lexer.consume_string();
+        let out = lexer.consume_string('\'');
         assert!(out.is_ok(), "'hello' should be a valid string");
         assert_eq!(out.unwrap(), "hello");
 
         lexer.iter.next(); // Whitespace
 
-        let out = lexer.consume_string();
+        let out = lexer.consume_string('\'');
         assert!(out.is_ok(), "'hello\\ngoodbye' should be a valid string");
         assert_eq!(out.unwrap(), "hello\ngoodbye");
 
         lexer.iter.next(); // Whitespace
 
-        let out = lexer.consume_string();
+        let out = lexer.consume_string('\'');
         assert!(out.is_ok(), "'split\\'up' should be a valid string");
         assert_eq!(out.unwrap(), "split'up");
 
         lexer.iter.next(); // Whitespace
 
-        let out = lexer.consume_string();
+        let out = lexer.consume_string('\'');
         assert!(out.is_ok(), "'this\\\\that' should be a valid string");
         assert_eq!(out.unwrap(), "this\\that");
+
+        lexer.iter.next(); // Whitespace
+
+        let out = lexer.consume_string('\'');
+        assert_eq!(out.unwrap(), "it's", "doubled quote");
+
+        lexer.iter.next(); // Whitespace
+
+        let out = lexer.consume_string('"');
+        assert_eq!(out.unwrap(), "say \"hi\"", "double quoted");
+    }
+
+    #[test]
+    fn character_constants() {
+        let mut lexer = Lexer::new();
+
+        let (tokens, flags) = lexer.lex("\"A\"+''''", 0, &HashMap::new()).unwrap();
+        assert!(!flags.string, "single characters are numbers");
+        assert_eq!(tokens.len(), 3);
+        is_number_of_value(tokens.first().unwrap(), b'A' as u16, "double quoted char");
+        is_op_of_code(tokens.get(1).unwrap(), "+");
+        is_number_of_value(tokens.get(2).unwrap(), b'\'' as u16, "doubled quote");
     }
 
     fn is_number_of_value(t: &Token, exp: u16, mes: &str) {
@@ -302,17 +408,43 @@ mod tests {
 
         assert!(out.is_ok(), "lexing failed: {}", out.unwrap_err());
         let (tokens, flags) = out.unwrap();
-        assert_eq!(tokens.len(), 5, "should be four tokens");
+        assert_eq!(tokens.len(), 5, "should be five tokens");
         assert_eq!(flags, ExprFlags::new());
 
-        is_number_of_value(tokens.get(0).unwrap(), 2, "binary parse");
+        is_number_of_value(tokens.first().unwrap(), 2, "binary parse");
         is_number_of_value(tokens.get(1).unwrap(), 8, "octal parse");
         is_number_of_value(tokens.get(2).unwrap(), 10, "decimal parse");
         is_number_of_value(tokens.get(3).unwrap(), 16, "hexadecimal parse");
         is_number_of_value(tokens.get(4).unwrap(), 0xff, "hexadecimal parse");
     }
 
-    fn is_op_of_code(t: &Token, exp: char) {
+    #[test]
+    fn suffixed_numerics() {
+        let mut lexer = Lexer::new();
+
+        let out = lexer.lex("10H 0FFh 10000B 20O 20Q 16D 0BH 0B", 0, &HashMap::new());
+        assert!(out.is_ok(), "lexing failed: {}", out.unwrap_err());
+        let (tokens, _) = out.unwrap();
+        let expected = [16, 0xff, 16, 16, 16, 16, 0x0b, 0];
+        assert_eq!(tokens.len(), expected.len());
+        for (t, exp) in tokens.iter().zip(expected) {
+            is_number_of_value(t, exp, "suffix parse");
+        }
+
+        assert!(
+            matches!(
+                lexer.lex("FFH", 0, &HashMap::new()),
+                Err(ExpressionError::UnknownIdentifier(s)) if s == "FFH"
+            ),
+            "hex must start with a digit"
+        );
+        assert!(matches!(
+            lexer.lex("12B", 0, &HashMap::new()),
+            Err(ExpressionError::NumberParseError(_))
+        ));
+    }
+
+    fn is_op_of_code(t: &Token, exp: &str) {
         assert!(matches!(t, Token::Operator(_, _, _)));
         if let Token::Operator(op, _, _) = t {
             assert_eq!(*op, exp);
@@ -339,14 +471,14 @@ mod tests {
         _flags.string = true;
         assert_eq!(flags, _flags);
 
-        let t0 = tokens.get(0).unwrap();
+        let t0 = tokens.first().unwrap();
         assert!(matches!(t0, Token::String(_)));
         if let Token::String(s) = t0 {
             assert_eq!(s, "hello");
         }
 
-        is_op_of_code(tokens.get(1).unwrap(), '^');
-        is_op_of_code(tokens.get(2).unwrap(), '+');
+        is_op_of_code(tokens.get(1).unwrap(), "XOR");
+        is_op_of_code(tokens.get(2).unwrap(), "+");
         is_number_of_value(tokens.get(3).unwrap(), 17, "");
         is_number_of_value(tokens.get(4).unwrap(), label_val, "");
     }
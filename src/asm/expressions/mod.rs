@@ -8,32 +8,58 @@
 //!
 //! ## Operations
 //!
-//! Some operators are given as text, namely the bitwise ones:
+//! Some operators are given as text:
 //!
 //! - `XOR` is a binary operation for bitwise-exclusive-or
 //! - `AND` is a binary operation for bitwise-and
 //! - `OR` is a binary operation for bitwise-or
 //! - `NOT` is a unary operation for bitwise-not
 //! - `NEG` is a unary operation for negation
+//! - `MOD` (or `%`) is a binary operation for the remainder of a division
+//! - `SHL` and `SHR` (or `<<` and `>>`) shift left and right
+//! - `HIGH` and `LOW` are unary operations giving the high and low byte
+//! - `EQ NE LT LE GT GE` (or `= <> < <= > >=`) compare, giving 0xFFFF for true and 0 for false
+//! - `DEFINED(name)` is 0xFFFF if there's a symbol of that name and 0 if not
 //!
 //! `NEG` is not in the original spec for the language, however it is quite useful.
 //!
+//! Operators bind as in the Intel manual, from tightest to loosest:
+//!
+//! | Operators                  |
+//! | :--                        |
+//! | `HIGH LOW NEG`             |
+//! | `* / MOD SHL SHR`          |
+//! | `+ -`                      |
+//! | `EQ NE LT LE GT GE`        |
+//! | `NOT`                      |
+//! | `AND`                      |
+//! | `OR XOR`                   |
+//!
+//! Sums wrap around at 16 bits as they would on the 8080, a number or product which doesn't fit in
+//! 16 bits is an error as is division by zero.
+//!
 //! ## Numerics
 //!
-//! Another thing away from the standard spec is the specification of numeric-literals with radices
-//! other than ten.
+//! Numeric-literals with radices other than ten may be given either as in the spec, with a suffix,
+//! or with a prefix as is more common these days.
+//!
+//! For the value 16:
+//!
+//! | Radix | By-the-spec     | Prefixed |
+//! | :--   | :--             | :--      |
+//! | 2     | 10000B          | 0b10000  |
+//! | 8     | 20O or 20Q      | 0o20     |
+//! | 10    | 16 or 16D       | 16       |
+//! | 16    | 10H             | 0x10     |
 //!
-//! If you wish to provide the value 16 as hex, the spec would have you give `10H`, however this is
-//! harder to parse and honestly less common these days - this expression parser would prefer `0x10`
+//! A hex number given by suffix must start with a digit, `0FFH` rather than `FFH` which would be
+//! taken as an identifier.
 //!
-//! Similarly (for the value 16):
+//! ## Strings
 //!
-//! | Radix | By-the-spec | This Parser |
-//! | :--   | :--         | :--         |
-//! | 2     | 10000B      | 0b10000     |
-//! | 8     | 20O         | 0o20        |
-//! | 10    | 16          | 16          |
-//! | 16    | 10H         | 0x10        |
+//! Strings and character constants are given between either `'` or `"`. The quote itself is
+//! written inside the string doubled, `'it''s'`, or escaped, `'it\'s'`. A string of a single
+//! character is its ASCII value, so `'A'` and `"A"` are both 65.
 //!
 //! ## Examples
 //!
@@ -44,7 +70,8 @@
 //!
 //! ```
 //! 254 + 12    ; becomes
-//! 0xfe + 0o14
+//! 0xfe + 0o14  ; or
+//! 0FEH + 14Q
 //! ```
 //!
 //! ```
@@ -28,7 +28,7 @@ pub fn parse_expression<S: Into<String>>(
     let exp = exp.into();
     let mut lexer = Lexer::new();
 
-    let (tokens, flags) = lexer.lex(exp.as_str(), addr, labels).unwrap();
+    let (tokens, flags) = lexer.lex(exp.as_str(), addr, labels)?;
 
     let out: Vec<u8> = match tokens.len() {
         0 => vec![],
@@ -128,6 +128,73 @@ mod tests {
         is_valid_and_vec("12 XOR NOT (0XF12D + 2)", vec![0xdc, 0x0e]);
     }
 
+    #[test]
+    fn shifts_mod_and_bytes() {
+        is_valid_and_vec("17 MOD 5", vec![0x02, 0x00]);
+        is_valid_and_vec("17 % 5", vec![0x02, 0x00]);
+        is_valid_and_vec("1 SHL 4 + 1", vec![0x11, 0x00]);
+        is_valid_and_vec("1 << 16", vec![0x00, 0x00]);
+        is_valid_and_vec("0X1234 SHR 4", vec![0x23, 0x01]);
+        is_valid_and_vec("0X1234 >> 4", vec![0x23, 0x01]);
+        is_valid_and_vec("HIGH 0X1234", vec![0x12, 0x00]);
+        is_valid_and_vec("LOW 0X1234 + 1", vec![0x35, 0x00]);
+        is_valid_and_vec("HIGH (0X1234 + 0X100)", vec![0x13, 0x00]);
+    }
+
+    #[test]
+    fn comparisons() {
+        for exp in ["2 EQ 2", "2 = 2", "2 == 2", "1 NE 2", "1 <> 2", "1 != 2"] {
+            is_valid_and_vec(exp, vec![0xff, 0xff]);
+        }
+        for exp in [
+            "1 LT 2", "1 < 2", "2 LE 2", "2 <= 2", "3 GT 2", "3 > 2", "2 GE 2",
+        ] {
+            is_valid_and_vec(exp, vec![0xff, 0xff]);
+        }
+        for exp in ["2 EQ 3", "2 LT 2", "3 <= 2", "2 > 2", "1 >= 2"] {
+            is_valid_and_vec(exp, vec![0x00, 0x00]);
+        }
+    }
+
+    #[test]
+    fn intel_precedence() {
+        // Relational operators bind looser than arithmetic
+        is_valid_and_vec("1 + 1 EQ 2", vec![0xff, 0xff]);
+        // NOT applies to the whole comparison
+        is_valid_and_vec("NOT 1 EQ 2", vec![0xff, 0xff]);
+        is_valid_and_vec("NOT 0 + 1", vec![0xfe, 0xff]);
+        // AND before OR and XOR, and both after arithmetic
+        is_valid_and_vec("1 OR 2 AND 3", vec![0x03, 0x00]);
+        is_valid_and_vec("3 AND 1 + 1", vec![0x02, 0x00]);
+        is_valid_and_vec("1 EQ 1 AND 2 GT 1", vec![0xff, 0xff]);
+        is_valid_and_vec("2 + 3 * 4 MOD 5", vec![0x04, 0x00]);
+    }
+
+    #[test]
+    fn overflow_and_division_by_zero() {
+        let e = parse_expression("1 / 0", 0, &HashMap::new()).unwrap_err();
+        assert!(matches!(e, ExpressionError::DivisionByZero));
+        let e = parse_expression("1 MOD (2 - 2)", 0, &HashMap::new()).unwrap_err();
+        assert!(matches!(e, ExpressionError::DivisionByZero));
+        let e = parse_expression("0X100 * 0X100", 0, &HashMap::new()).unwrap_err();
+        assert!(matches!(e, ExpressionError::Overflow(_)));
+        let e = parse_expression("70000", 0, &HashMap::new()).unwrap_err();
+        assert!(matches!(e, ExpressionError::Overflow(s) if s == "70000"));
+        is_valid_and_vec("0 - 1", vec![0xff, 0xff]);
+    }
+
+    #[test]
+    fn defined() {
+        let mut labels = HashMap::new();
+        labels.insert("DEBUG".to_string(), Label::new_equ(Some(0)));
+        let r = parse_expression("DEFINED(DEBUG) AND NOT DEFINED ( TRACE )", 0, &labels);
+        assert_eq!(r.unwrap().0, vec![0xff, 0xff]);
+        for exp in ["DEFINED DEBUG", "DEFINED()", "DEFINED(1)", "DEFINED(DEBUG"] {
+            let e = parse_expression(exp, 0, &labels).unwrap_err();
+            assert!(matches!(e, ExpressionError::InvalidDefined), "{}", exp);
+        }
+    }
+
     #[test]
     fn all_together() {
         let mut labels = HashMap::new();
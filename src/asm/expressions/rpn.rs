@@ -13,7 +13,7 @@ pub fn calculate(input_queue: &[Token]) -> Result<u16, ExpressionError> {
                 let left = stack.pop();
                 match (left, right) {
                     (Some(Token::Number(n1)), Some(Token::Number(n2))) => {
-                        stack.push(Token::Number(operate(*o, n1, n2)))
+                        stack.push(Token::Number(operate(o, n1, n2)?))
                     }
                     _ => break,
                 }
@@ -33,6 +33,20 @@ pub fn calculate(input_queue: &[Token]) -> Result<u16, ExpressionError> {
                         stack.push(Token::Number(0_u16.wrapping_sub(n)));
                     }
                 }
+                "HIGH" => {
+                    let arg = stack.pop();
+
+                    if let Some(Token::Number(n)) = arg {
+                        stack.push(Token::Number(n >> 8));
+                    }
+                }
+                "LOW" => {
+                    let arg = stack.pop();
+
+                    if let Some(Token::Number(n)) = arg {
+                        stack.push(Token::Number(n & 0xff));
+                    }
+                }
                 _ => return Err(ExpressionError::UnknownUnary(unary.to_string())),
             },
             _ => (),
@@ -49,15 +63,33 @@ pub fn calculate(input_queue: &[Token]) -> Result<u16, ExpressionError> {
     }
 }
 
-fn operate(operator: char, left: u16, right: u16) -> u16 {
-    match operator {
-        '+' => left.wrapping_add(right),
-        '-' => left.wrapping_sub(right),
-        '*' => left.wrapping_mul(right),
-        '/' => left.wrapping_div(right),
-        '&' => left & right,
-        '^' => left ^ right,
-        '|' => left | right,
+/// Applies a binary operator, sums wrap around as they would on the 8080 but a product which
+/// doesn't fit in 16 bits is an error. Comparisons give 0xFFFF for true and 0 for false.
+fn operate(operator: &str, left: u16, right: u16) -> Result<u16, ExpressionError> {
+    let truth = |b: bool| if b { 0xffff } else { 0 };
+    Ok(match operator {
+        "+" => left.wrapping_add(right),
+        "-" => left.wrapping_sub(right),
+        "*" => left
+            .checked_mul(right)
+            .ok_or_else(|| ExpressionError::Overflow(format!("{} * {}", left, right)))?,
+        "/" => left
+            .checked_div(right)
+            .ok_or(ExpressionError::DivisionByZero)?,
+        "MOD" => left
+            .checked_rem(right)
+            .ok_or(ExpressionError::DivisionByZero)?,
+        "SHL" => left.checked_shl(right as u32).unwrap_or(0),
+        "SHR" => left.checked_shr(right as u32).unwrap_or(0),
+        "EQ" => truth(left == right),
+        "NE" => truth(left != right),
+        "LT" => truth(left < right),
+        "LE" => truth(left <= right),
+        "GT" => truth(left > right),
+        "GE" => truth(left >= right),
+        "AND" => left & right,
+        "XOR" => left ^ right,
+        "OR" => left | right,
         _ => 0,
-    }
+    })
 }
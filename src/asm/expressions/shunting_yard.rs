@@ -24,7 +24,9 @@ pub fn transform(input_queue: Vec<Token>) -> Result<Vec<Token>, ExpressionError>
                                 break;
                             }
                         }
-                        Some(&Token::Unary(_)) => {
+                        Some(Token::Unary(unary))
+                            if token::unary_precedence(unary) >= o1_precedence =>
+                        {
                             output_queue.push(stack.pop().unwrap());
                         }
                         _ => break,
@@ -1,6 +1,6 @@
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
-    Operator(char, u32, u32), // Operator, associativity, precedence
+    Operator(&'static str, u32, u32), // Operator, associativity, precedence
     String(String),
     Number(u16),
     MetaIdentifier(String),
@@ -11,3 +11,11 @@ pub enum Token {
 
 pub const LEFT_ASSOC: u32 = 1;
 pub const RIGHT_ASSOC: u32 = 2;
+
+/// How tightly a unary operation binds, on the same scale as the precedence of an operator
+pub fn unary_precedence(unary: &str) -> u32 {
+    match unary {
+        "NOT" => 3,
+        _ => 7,
+    }
+}
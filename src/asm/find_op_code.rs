@@ -100,6 +100,6 @@ fn with_word_regs(start: u16, arg0: u16, extra: bool) -> Result<usize, OpParseEr
     } else {
         return Err(OpParseError::InvalidRegister);
     };
-    let idx = start + (offset as u16 * 0x10);
+    let idx = start + (offset * 0x10);
     Ok(idx as usize)
 }
@@ -6,6 +6,22 @@ pub struct Label {
     pub is_addr: bool,
     pub is_eq: bool,
     pub is_set: bool,
+    /// What the value moves with when the program is linked
+    pub segment: Segment,
+}
+
+/// Where a value lies, for relocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Segment {
+    /// A fixed value, or an address which doesn't move
+    #[default]
+    Absolute,
+    /// In `CSEG`, the default
+    Code,
+    /// In `DSEG`
+    Data,
+    /// From `EXTRN`, only known once linked
+    External,
 }
 
 impl Label {
@@ -15,6 +31,7 @@ impl Label {
             is_addr: true,
             is_eq: false,
             is_set: false,
+            segment: Segment::Absolute,
         }
     }
 
@@ -24,6 +41,7 @@ impl Label {
             is_addr: false,
             is_eq: true,
             is_set: false,
+            segment: Segment::Absolute,
         }
     }
 
@@ -33,6 +51,23 @@ impl Label {
             is_addr: false,
             is_eq: false,
             is_set: true,
+            segment: Segment::Absolute,
+        }
+    }
+
+    pub fn in_segment(mut self, segment: Segment) -> Self {
+        self.segment = segment;
+        self
+    }
+
+    /// Short name for the kind of label, `ADDR`, `EQU` or `SET`
+    pub fn kind(&self) -> &'static str {
+        if self.is_eq {
+            "EQU"
+        } else if self.is_set {
+            "SET"
+        } else {
+            "ADDR"
         }
     }
 }
@@ -0,0 +1,172 @@
+//! Assembly listings
+//!
+//! Each source line is shown with the address it was assembled at, the bytes it became and how
+//! many cycles the instruction takes, followed by a table of every symbol.
+//!
+//! ```text
+//!  LINE   ADDR  BYTES        CYC  SOURCE
+//!     2  =0005                    LEN:    EQU 5
+//!     3                           _ld:    MACRO
+//!     4                                   MOV A, M
+//!     5                                   ENDM
+//!     6   0000  21 07 00       4          LXI H, _msg
+//!     7   0003                            _ld
+//!     4+  0003  7E             7          MOV A, M
+//!     8                                   IF LEN - 5
+//!     9-  ----                            MVI A, 2
+//!    10                                   ENDIF
+//!    11   0004  76             7          HLT
+//!    12   0005                    _buf:   DS 2
+//!    13   0007  68 65 6C 6C       _msg:   DB 'hello', 0
+//!         000B  6F 00
+//! ```
+//!
+//! The marker after the line number is `+` for lines expanded from a macro, which carry the line
+//! number of the macro's definition and follow the call, or the `ENDM` of a `REPT`, `IRP` or
+//! `IRPC` block, `-` for lines skipped by a false `IF`, and `C` for lines from
+//! an `INCLUDE`d file, numbered within that file. `EQU` lines show their value in place of an
+//! address, and the bytes of `DS` aren't shown as they're only filler.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::label::Label;
+
+const BYTES_PER_ROW: usize = 4;
+const HEADER: &str = " LINE   ADDR  BYTES        CYC  SOURCE\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListingMark {
+    Source,
+    Expansion,
+    Skipped,
+    Included,
+}
+
+/// A line of the listing before formatting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingLine {
+    pub line_no: usize,
+    pub address: Option<u16>,
+    pub value: Option<u16>,
+    pub bytes: Vec<u8>,
+    pub cycles: Option<u8>,
+    pub mark: ListingMark,
+    pub source: String,
+}
+
+impl ListingLine {
+    pub fn source<S: Into<String>>(line_no: usize, source: S) -> Self {
+        Self {
+            line_no,
+            address: None,
+            value: None,
+            bytes: vec![],
+            cycles: None,
+            mark: ListingMark::Source,
+            source: source.into(),
+        }
+    }
+}
+
+pub fn render(lines: &[ListingLine], symbols: &HashMap<String, Label>) -> String {
+    let mut out = String::new();
+    out.push_str(HEADER);
+    for line in lines.iter() {
+        let mark = match line.mark {
+            ListingMark::Source => ' ',
+            ListingMark::Expansion => '+',
+            ListingMark::Skipped => '-',
+            ListingMark::Included => 'C',
+        };
+        let addr = match (line.value, line.address, line.mark) {
+            (Some(val), _, _) => format!("={:04X}", val),
+            (_, Some(addr), _) => format!("{:04X}", addr),
+            (_, _, ListingMark::Skipped) => "----".to_string(),
+            _ => String::new(),
+        };
+        let mut rows = line.bytes.chunks(BYTES_PER_ROW);
+        let first = rows.next().map(hex_bytes).unwrap_or_default();
+        let cycles = line.cycles.map(|c| c.to_string()).unwrap_or_default();
+        let row = format!(
+            "{:>5}{} {:>5}  {:<12}{:>4}  {}",
+            line.line_no, mark, addr, first, cycles, line.source
+        );
+        // Lines without source would otherwise be left with trailing whitespace
+        let _ = writeln!(out, "{}", row.trim_end());
+
+        let base = line.address.unwrap_or(0);
+        for (idx, row) in rows.enumerate() {
+            let addr = base.wrapping_add(((idx + 1) * BYTES_PER_ROW) as u16);
+            let _ = writeln!(out, "{:>12}  {}", format!("{:04X}", addr), hex_bytes(row));
+        }
+    }
+
+    out.push_str("\nSYMBOLS\n\n");
+    let mut names: Vec<&String> = symbols.keys().collect();
+    names.sort();
+    for name in names {
+        let label = symbols[name];
+        let value = label
+            .value
+            .map(|v| format!("{:04X}", v))
+            .unwrap_or_else(|| "????".to_string());
+        let _ = writeln!(out, "{:<24} {}  {}", name, value, label.kind());
+    }
+    out
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_lines_and_symbols() {
+        let mut symbols = HashMap::new();
+        symbols.insert("_MSG".to_string(), Label::new_addr(Some(0x0a)));
+        symbols.insert("LEN".to_string(), Label::new_equ(Some(5)));
+
+        let lines = vec![
+            ListingLine {
+                address: Some(0),
+                bytes: vec![0x21, 0x0a, 0x00],
+                cycles: Some(10),
+                ..ListingLine::source(1, "LXI H, _msg")
+            },
+            ListingLine {
+                value: Some(5),
+                ..ListingLine::source(2, "LEN: EQU 5")
+            },
+            ListingLine {
+                mark: ListingMark::Skipped,
+                ..ListingLine::source(3, "NOP")
+            },
+            ListingLine::source(4, ""),
+            ListingLine {
+                address: Some(0x0a),
+                bytes: b"hello".to_vec(),
+                ..ListingLine::source(5, "_msg: DB 'hello'")
+            },
+        ];
+        let out = render(&lines, &symbols);
+        let rows: Vec<&str> = out.lines().collect();
+        assert_eq!(rows[0], " LINE   ADDR  BYTES        CYC  SOURCE");
+        assert_eq!(rows[1], "    1   0000  21 0A 00      10  LXI H, _msg");
+        assert_eq!(rows[2], "    2  =0005                    LEN: EQU 5");
+        assert_eq!(rows[3], "    3-  ----                    NOP");
+        assert_eq!(rows[4], "    4");
+        assert_eq!(rows[5], "    5   000A  68 65 6C 6C       _msg: DB 'hello'");
+        assert_eq!(rows[6], "        000E  6F");
+        assert!(out.ends_with(
+            "LEN                      0005  EQU\n_MSG                     000A  ADDR\n"
+        ));
+    }
+}
@@ -0,0 +1,360 @@
+//! Macro definitions and their expansion
+//!
+//! A macro is the lines between `MACRO` and `ENDM`, kept as written and expanded textually at
+//! each call. Parameters are named after `MACRO`, optionally with a default for when a call leaves
+//! them out:
+//!
+//! ```asm
+//! _store: MACRO addr, reg=A
+//!         LOCAL _skip
+//!         JZ _skip
+//!         MOV M, reg
+//!         STA addr
+//! _skip:
+//!         ENDM
+//!         _store 0x2000
+//!         _store 0x2002, B
+//! ```
+//!
+//! Each whole word of the body matching a parameter or a `LOCAL` name is replaced before the line
+//! is assembled, comments are left alone. `&` joins a parameter to the text around it and is
+//! dropped, so `LBL&N:` is `LBL3:` when `N` is 3. Inside strings, as with M80, only parameters
+//! joined by an `&` are replaced, so `DB '&CH'` gives the character but `DB 'CH'` stays as it is.
+//! `LOCAL` names become unique to each expansion, so labels in a macro don't clash when it's used
+//! twice.
+//!
+//! `REPT`, `IRP` and `IRPC` blocks are unnamed macros, also ended by `ENDM`, which are expanded
+//! where they're written, once per iteration:
+//!
+//! ```asm
+//!         REPT 4          ; four RLCs
+//!         RLC
+//!         ENDM
+//!         IRP reg, <B, C, D>
+//!         PUSH reg
+//!         ENDM
+//!         IRPC digit, '0123'
+//!         DB digit * 2, '&digit'
+//!         ENDM
+//! ```
+
+use std::collections::HashMap;
+
+use super::{errors::ParserError, tokenizer::LineMeta};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroParam {
+    pub name: String,
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Macro {
+    pub params: Vec<MacroParam>,
+    pub lines: Vec<LineMeta>,
+}
+
+impl Macro {
+    /// A macro taking the parameters listed after its `MACRO`, as `name` or `name=default`
+    pub fn with_params(args: &[String]) -> Result<Self, ParserError> {
+        let mut params = vec![];
+        for arg in args.iter() {
+            let (name, default) = match arg.split_once('=') {
+                Some((name, default)) => (name.trim(), Some(default.trim().to_string())),
+                None => (arg.trim(), None),
+            };
+            if !is_identifier(name) {
+                return Err(ParserError::InvalidArgument(
+                    "MACRO".to_string(),
+                    arg.to_string(),
+                ));
+            }
+            params.push(MacroParam {
+                name: name.to_uppercase(),
+                default,
+            });
+        }
+        Ok(Self {
+            params,
+            lines: vec![],
+        })
+    }
+
+    /// The value of each parameter for a call with `args`, an empty argument takes the default
+    pub fn bind(&self, args: &[String]) -> Result<HashMap<String, String>, ParserError> {
+        if args.len() > self.params.len() {
+            return Err(ParserError::WrongNumberOfArgs(
+                self.params.len(),
+                args.len(),
+            ));
+        }
+        let mut bound = HashMap::new();
+        for (idx, param) in self.params.iter().enumerate() {
+            let value = match args.get(idx).filter(|arg| !arg.is_empty()) {
+                Some(arg) => arg.to_string(),
+                None => param
+                    .default
+                    .clone()
+                    .ok_or_else(|| ParserError::MissingMacroArgument(param.name.to_string()))?,
+            };
+            bound.insert(param.name.to_string(), value);
+        }
+        Ok(bound)
+    }
+}
+
+/// Whether an instruction starts a block ended by `ENDM`
+pub fn opens_block(inst: &str) -> bool {
+    matches!(inst, "MACRO" | "REPT" | "IRP" | "IRPC")
+}
+
+/// The symbol of an `IRP` or `IRPC` and what follows it
+fn iterated_symbol<'a>(
+    inst: &str,
+    args: &'a [String],
+) -> Result<(String, &'a [String]), ParserError> {
+    match args.split_first() {
+        Some((symbol, rest)) if !rest.is_empty() => {
+            if !is_identifier(symbol) {
+                return Err(ParserError::InvalidArgument(
+                    inst.to_string(),
+                    symbol.to_string(),
+                ));
+            }
+            Ok((symbol.to_uppercase(), rest))
+        }
+        _ => Err(ParserError::WrongNumberOfArgs(2, args.len())),
+    }
+}
+
+/// The substitutions for each iteration of `IRP sym, <a, b, c>`
+pub fn irp(args: &[String]) -> Result<Vec<HashMap<String, String>>, ParserError> {
+    let (symbol, rest) = iterated_symbol("IRP", args)?;
+    let list = rest.join(", ");
+    let values = list
+        .strip_prefix('<')
+        .and_then(|l| l.strip_suffix('>'))
+        .ok_or_else(|| ParserError::InvalidArgument("IRP".to_string(), list.to_string()))?;
+    if values.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    Ok(rest
+        .iter()
+        .enumerate()
+        .map(|(idx, value)| {
+            let mut value = value.as_str();
+            if idx == 0 {
+                value = &value[1..];
+            }
+            if idx == rest.len() - 1 {
+                value = &value[..value.len() - 1];
+            }
+            HashMap::from([(symbol.to_string(), value.trim().to_string())])
+        })
+        .collect())
+}
+
+/// The substitutions for each iteration of `IRPC sym, 'chars'`
+pub fn irpc(args: &[String]) -> Result<Vec<HashMap<String, String>>, ParserError> {
+    let (symbol, rest) = iterated_symbol("IRPC", args)?;
+    if rest.len() > 1 {
+        return Err(ParserError::WrongNumberOfArgs(2, args.len()));
+    }
+    let chars = unquote(&rest[0]).unwrap_or(&rest[0]);
+    Ok(chars
+        .chars()
+        .map(|c| HashMap::from([(symbol.to_string(), c.to_string())]))
+        .collect())
+}
+
+/// The text between the quotes of a string written as `'text'` or `"text"`
+pub fn unquote(s: &str) -> Option<&str> {
+    ['\'', '"']
+        .into_iter()
+        .find_map(|q| s.strip_prefix(q).and_then(|s| s.strip_suffix(q)))
+}
+
+pub(crate) fn is_identifier(s: &str) -> bool {
+    s.chars()
+        .next()
+        .is_some_and(|c| c == '_' || c.is_alphabetic())
+        && s.chars().all(|c| c == '_' || c.is_alphanumeric())
+}
+
+/// The name a `LOCAL` takes in one expansion
+pub fn local_name(name: &str, expansion: usize) -> String {
+    format!("__{}_{:04}", name, expansion)
+}
+
+/// Replaces each whole word of `raw_line` found in `replacements`, ignoring case, leaving
+/// comments alone. Inside strings only words joined to an `&` are replaced, and an `&` next to a
+/// replaced word is dropped so it can be joined to other text
+pub fn substitute(raw_line: &str, replacements: &HashMap<String, String>) -> String {
+    let mut out = String::with_capacity(raw_line.len());
+    let mut text = String::new();
+    let mut chars = raw_line.chars();
+    while let Some(c) = chars.next() {
+        if c == ';' {
+            out.push_str(&substitute_words(&text, replacements, false));
+            text.clear();
+            out.push(c);
+            out.extend(chars.by_ref());
+        } else if c == '\'' || c == '"' {
+            out.push_str(&substitute_words(&text, replacements, false));
+            text.clear();
+            let quote = c;
+            let mut escaped = false;
+            for c in chars.by_ref() {
+                if !escaped && c == quote {
+                    break;
+                }
+                escaped = !escaped && c == '\\';
+                text.push(c);
+            }
+            out.push(quote);
+            out.push_str(&substitute_words(&text, replacements, true));
+            out.push(quote);
+            text.clear();
+        } else {
+            text.push(c);
+        }
+    }
+    out.push_str(&substitute_words(&text, replacements, false));
+    out
+}
+
+/// Replaces the words of `text` as [substitute], only those joined to an `&` if `joined_only`
+fn substitute_words(
+    text: &str,
+    replacements: &HashMap<String, String>,
+    joined_only: bool,
+) -> String {
+    let is_word = |c: char| c == '_' || c.is_alphanumeric();
+    let mut parts: Vec<&str> = vec![];
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let len = if is_word(c) {
+            rest.find(|c| !is_word(c)).unwrap_or(rest.len())
+        } else {
+            c.len_utf8()
+        };
+        parts.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    let joined = |idx: usize| {
+        (idx > 0 && parts[idx - 1] == "&") || parts.get(idx + 1).is_some_and(|p| *p == "&")
+    };
+    let replaced: Vec<Option<&String>> = parts
+        .iter()
+        .enumerate()
+        .map(|(idx, part)| {
+            replacements
+                .get(&part.to_uppercase())
+                .filter(|_| !joined_only || joined(idx))
+        })
+        .collect();
+
+    let mut out = String::with_capacity(text.len());
+    for (idx, part) in parts.iter().enumerate() {
+        match replaced[idx] {
+            Some(replacement) => out.push_str(replacement),
+            None if *part == "&"
+                && ((idx > 0 && replaced[idx - 1].is_some())
+                    || replaced.get(idx + 1).is_some_and(|r| r.is_some())) => {}
+            None => out.push_str(part),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn params_and_defaults() {
+        let m = Macro::with_params(&strings(&["ADDR", "REG=A"])).unwrap();
+        let bound = m.bind(&strings(&["0x2000"])).unwrap();
+        assert_eq!(bound["ADDR"], "0x2000");
+        assert_eq!(bound["REG"], "A");
+
+        let bound = m.bind(&strings(&["1", "B"])).unwrap();
+        assert_eq!(bound["REG"], "B");
+
+        assert!(matches!(
+            m.bind(&[]),
+            Err(ParserError::MissingMacroArgument(p)) if p == "ADDR"
+        ));
+        assert!(matches!(
+            m.bind(&strings(&["1", "2", "3"])),
+            Err(ParserError::WrongNumberOfArgs(2, 3))
+        ));
+        assert!(Macro::with_params(&strings(&["1X"])).is_err());
+    }
+
+    #[test]
+    fn irp_and_irpc_iterations() {
+        let values = |iterations: Vec<HashMap<String, String>>| {
+            iterations
+                .into_iter()
+                .map(|mut i| i.remove("X").unwrap())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            values(irp(&strings(&["X", "<1", "'A, B'", "3>"])).unwrap()),
+            vec!["1", "'A, B'", "3"]
+        );
+        assert_eq!(values(irp(&strings(&["X", "<7>"])).unwrap()), vec!["7"]);
+        assert!(irp(&strings(&["X", "<>"])).unwrap().is_empty());
+        assert!(irp(&strings(&["X", "1, 2"])).is_err());
+        assert_eq!(
+            values(irpc(&strings(&["x", "'aB'"])).unwrap()),
+            vec!["a", "B"]
+        );
+        assert_eq!(
+            values(irpc(&strings(&["X", "\"12\""])).unwrap()),
+            vec!["1", "2"]
+        );
+        assert!(matches!(
+            irpc(&strings(&["X"])),
+            Err(ParserError::WrongNumberOfArgs(2, 1))
+        ));
+    }
+
+    #[test]
+    fn substitutes_whole_words() {
+        let mut replacements = HashMap::new();
+        replacements.insert("REG".to_string(), "B".to_string());
+        replacements.insert("SKIP".to_string(), local_name("SKIP", 3));
+        assert_eq!(
+            substitute("skip: MOV reg, REGS ; reg", &replacements),
+            "__SKIP_0003: MOV B, REGS ; reg"
+        );
+        assert_eq!(substitute("DB 'reg', reg", &replacements), "DB 'reg', B");
+        assert_eq!(
+            substitute("DB \"reg's\", reg", &replacements),
+            "DB \"reg's\", B"
+        );
+    }
+
+    #[test]
+    fn substitutes_joined_words() {
+        let mut replacements = HashMap::new();
+        replacements.insert("N".to_string(), "3".to_string());
+        replacements.insert("CH".to_string(), "x".to_string());
+        assert_eq!(substitute("LBL&N: DB N&0H", &replacements), "LBL3: DB 30H");
+        assert_eq!(
+            substitute("DB '&CH', 'CH', \"<&ch&>\" ; &CH", &replacements),
+            "DB 'x', 'CH', \"<x>\" ; &CH"
+        );
+        assert_eq!(
+            substitute("DB 'A&B', X & N", &replacements),
+            "DB 'A&B', X & 3"
+        );
+    }
+}
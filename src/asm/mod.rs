@@ -1,38 +1,91 @@
 //! Contains the assembler and disassembler
 
 pub mod assemble;
+pub mod conditional;
+pub mod diagnostic;
+pub mod dialect;
 pub mod disassemble;
+pub mod listing;
+pub mod macros;
+pub mod object;
+pub mod preprocess;
+pub mod rel;
+pub mod rom;
+pub mod source;
+pub mod structs;
+pub mod symbols;
+pub mod warnings;
 
 mod errors;
-mod expressions;
 mod find_op_code;
-mod label;
-mod tokenizer;
 
-use std::fs::File;
+pub(crate) mod expressions;
+pub(crate) mod label;
+pub(crate) mod tokenizer;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
 use std::io::Write;
+use std::path::PathBuf;
 
 use crate::{
     cli::{AssembleArgs, DisassembleArgs},
     ecodes::{E_ASSEMBLER, E_DISASSEMBLER, E_IO_ERROR, E_SUCCESS},
+    ihex::{self, Image},
     util::read_file_to_vec_u8,
 };
 
-use self::{assemble::Assembler, disassemble::disassemble_vec};
+use self::{
+    assemble::Assembler, disassemble::disassemble_vec_at, errors::DisassembleError, rom::RomError,
+};
 
 pub fn run_assembler(args: AssembleArgs) -> i32 {
     let output = args.output.clone();
+    let listing = args.listing.clone();
+    let symbol_file = args.symbols.clone();
+    let xref = args.xref.clone();
+    let deps = args.deps.clone();
+    let preprocess = args.preprocess;
     let mut assembler = Assembler::new(args);
-    match assembler.assemble() {
-        Ok(bytes) => match assembler.write(bytes) {
-            Ok(_) => E_SUCCESS,
-            Err(e) => {
-                println!("{}\n {}", e, output.as_path().display(),);
-                E_IO_ERROR
+    let bytes = match assembler.assemble() {
+        Ok(bytes) => bytes,
+        Err(_) => return E_ASSEMBLER,
+    };
+    let written = if preprocess {
+        fs::write(&output, assembler.preprocessed())
+    } else {
+        assembler.write(bytes)
+    };
+    if let Err(e) = written {
+        println!("{}\n {}", e, output.as_path().display(),);
+        // A ROM image which can't be shaped is the program's fault, not the disk's
+        return match e.get_ref() {
+            Some(e) if e.is::<RomError>() => E_ASSEMBLER,
+            _ => E_IO_ERROR,
+        };
+    }
+
+    let extras: [(Option<PathBuf>, &dyn Fn() -> String); 4] = [
+        (listing, &|| assembler.listing()),
+        (symbol_file.clone(), &|| {
+            let table = assembler.symbols();
+            match &symbol_file {
+                Some(path) if symbols::is_json_file(path) => symbols::write_json(&table),
+                _ => symbols::write_text(&table),
+            }
+        }),
+        (xref, &|| assembler.cross_reference()),
+        (deps, &|| assembler.dependency_rule()),
+    ];
+    for (path, content) in extras.iter() {
+        if let Some(path) = path {
+            if let Err(e) = fs::write(path, content()) {
+                println!("{}\n {}", e, path.as_path().display(),);
+                return E_IO_ERROR;
             }
-        },
-        Err(_) => E_ASSEMBLER,
+        }
     }
+    E_SUCCESS
 }
 
 macro_rules! ok_or_return {
@@ -48,8 +101,21 @@ macro_rules! ok_or_return {
 }
 
 pub fn run_disassmbler(args: DisassembleArgs) -> i32 {
-    let v_u8 = ok_or_return!(read_file_to_vec_u8(args.input), E_IO_ERROR);
-    let v_strings = ok_or_return!(disassemble_vec(&v_u8), E_DISASSEMBLER);
+    let labels = match &args.symbols {
+        Some(path) => {
+            let text = ok_or_return!(fs::read_to_string(path), E_IO_ERROR);
+            symbols::addresses(&ok_or_return!(symbols::read_text(&text), E_IO_ERROR))
+        }
+        None => HashMap::new(),
+    };
+    let v_strings = if ihex::is_hex_file(&args.input) {
+        let text = ok_or_return!(fs::read_to_string(&args.input), E_IO_ERROR);
+        let image = ok_or_return!(ihex::decode(&text), E_IO_ERROR);
+        ok_or_return!(disassemble_image(&image, &labels), E_DISASSEMBLER)
+    } else {
+        let v_u8 = ok_or_return!(read_file_to_vec_u8(args.input), E_IO_ERROR);
+        ok_or_return!(disassemble_vec_at(&v_u8, 0, &labels), E_DISASSEMBLER)
+    };
     let content = v_strings.join("\n");
     if let Some(filename) = args.output {
         let mut f = ok_or_return!(File::create(filename), E_IO_ERROR);
@@ -60,6 +126,25 @@ pub fn run_disassmbler(args: DisassembleArgs) -> i32 {
     E_SUCCESS
 }
 
+/// Each region is preceded by an `ORG` so the output reassembles to the same addresses
+fn disassemble_image(
+    image: &Image,
+    labels: &HashMap<u16, String>,
+) -> Result<Vec<String>, DisassembleError> {
+    let mut strings = vec![];
+    for (addr, bytes) in image.regions.iter() {
+        strings.push(format!("ORG {:#06x}", addr));
+        strings.append(&mut disassemble_vec_at(bytes, *addr, labels)?);
+    }
+    if let Some(start) = image.start {
+        match labels.get(&start) {
+            Some(name) => strings.push(format!("END {}", name)),
+            None => strings.push(format!("END {:#06x}", start)),
+        }
+    }
+    Ok(strings)
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -86,8 +171,8 @@ mod tests {
             input: util::test::rsc(infile),
             output: output.clone(),
             hlt: halt,
-            load_at: 0,
             register_definitions: true,
+            ..Default::default()
         });
         assert_eq!(r, E_SUCCESS);
         let out = read_to_v8(output).expect("file should exist");
@@ -142,9 +227,159 @@ mod tests {
         let r = run_disassmbler(cli::DisassembleArgs {
             input: util::test::rsc("bin/simple.bin"),
             output: Some(output.clone()),
+            symbols: None,
         });
         assert_eq!(r, E_SUCCESS);
         let out = read_to_v_string(output).expect("file should exist");
         assert_eq!(out, vec!["MVI A, 0xde", "MVI B, 0xad", "ADD B", "HLT",]);
     }
+
+    #[test]
+    fn regions_to_ihex_and_back() {
+        let output = util::test::rsc("aux/regions.hex");
+        let r = run_assembler(cli::AssembleArgs {
+            input: util::test::rsc("asm/regions.asm"),
+            output: output.clone(),
+            format: cli::OutputFormat::Ihex,
+            register_definitions: true,
+            ..Default::default()
+        });
+        assert_eq!(r, E_SUCCESS);
+
+        let image = ihex::decode(&fs::read_to_string(&output).unwrap()).unwrap();
+        assert_eq!(
+            image.regions,
+            vec![
+                (0x0100, vec![0x21, 0x00, 0xf0, 0x76]),
+                (0xf000, b"hi\0".to_vec())
+            ]
+        );
+        assert_eq!(image.start, Some(0x0100));
+
+        let dis_output = util::test::rsc("aux/regions.asm");
+        let r = run_disassmbler(cli::DisassembleArgs {
+            input: output,
+            output: Some(dis_output.clone()),
+            symbols: None,
+        });
+        assert_eq!(r, E_SUCCESS);
+        let out = read_to_v_string(dis_output).expect("file should exist");
+        assert_eq!(out[..3], ["ORG 0x0100", "LXI H, 0xf000", "HLT"]);
+        assert_eq!(out[3], "ORG 0xf000");
+        assert_eq!(out.last().unwrap(), "END 0x0100");
+    }
+
+    #[test]
+    fn hello_world_prl() {
+        let output = util::test::rsc("aux/hello-world.prl");
+        let r = run_assembler(cli::AssembleArgs {
+            input: util::test::rsc("asm/hello-world.asm"),
+            output: output.clone(),
+            format: cli::OutputFormat::Prl,
+            register_definitions: true,
+            ..Default::default()
+        });
+        assert_eq!(r, E_SUCCESS);
+
+        let prl = crate::prl::Prl::decode(&fs::read(&output).unwrap()).unwrap();
+        assert_eq!(prl.code.len(), 28);
+        let moved: Vec<usize> = (0..prl.code.len()).filter(|i| prl.relocated[*i]).collect();
+        // The high bytes of _hello, _done and _do
+        assert_eq!(moved, vec![2, 10, 14]);
+        let image = prl.relocate(0x4000).unwrap();
+        assert_eq!(&image.regions[0].1[..3], &[0x21, 0x10, 0x40]);
+    }
+
+    #[test]
+    fn listing() {
+        let output = util::test::rsc("aux/listing.bin");
+        let listing = util::test::rsc("aux/listing.lst");
+        let r = run_assembler(cli::AssembleArgs {
+            input: util::test::rsc("asm/listing.asm"),
+            output,
+            listing: Some(listing.clone()),
+            register_definitions: true,
+            ..Default::default()
+        });
+        assert_eq!(r, E_SUCCESS);
+
+        let out = read_to_v_string(listing).expect("file should exist");
+        let row = |n: usize| out[n].split_whitespace().collect::<Vec<&str>>().join(" ");
+        assert_eq!(row(2), "2 =0005 LEN: EQU 5");
+        assert_eq!(row(7), "7 0000 21 08 00 4 LXI H, _msg");
+        assert_eq!(row(8), "8 0003 _ld");
+        assert_eq!(row(9), "4+ 0003 7E 7 MOV A, M");
+        assert_eq!(row(10), "5+ 0004 23 5 INX H");
+        assert_eq!(row(11), "9 IF LEN - 5");
+        assert_eq!(row(12), "10- ---- MVI A, 2");
+        assert_eq!(row(15), "13 0006 _buf: DS 2");
+        assert_eq!(row(16), "14 0008 68 65 6C 6C _msg: DB 'hello', 0");
+        assert_eq!(row(17), "000C 6F 00");
+        assert!(out.contains(&"_MSG                     0008  ADDR".to_string()));
+    }
+
+    #[test]
+    fn symbols_and_xref() {
+        let output = util::test::rsc("aux/symbols.bin");
+        let sym = util::test::rsc("aux/symbols.sym");
+        let json = util::test::rsc("aux/symbols.json");
+        let xref = util::test::rsc("aux/symbols.xref");
+        let args = |symbols: &PathBuf| cli::AssembleArgs {
+            input: util::test::rsc("asm/listing.asm"),
+            output: output.clone(),
+            symbols: Some(symbols.clone()),
+            xref: Some(xref.clone()),
+            register_definitions: true,
+            ..Default::default()
+        };
+        assert_eq!(run_assembler(args(&sym)), E_SUCCESS);
+        assert_eq!(run_assembler(args(&json)), E_SUCCESS);
+
+        let text = fs::read_to_string(&sym).unwrap();
+        assert_eq!(text, "0005 LEN EQU\n0006 _BUF ADDR\n0008 _MSG ADDR\n");
+        let read = symbols::read_text(&text).unwrap();
+        assert_eq!(read["_MSG"].value, Some(8));
+        assert!(fs::read_to_string(&json)
+            .unwrap()
+            .contains("{\"name\": \"LEN\", \"value\": 5, \"kind\": \"EQU\"}"));
+
+        let out = read_to_v_string(&xref).expect("file should exist");
+        let row = |n: usize| out[n].split_whitespace().collect::<Vec<&str>>().join(" ");
+        assert_eq!(row(1), "LEN 0005 EQU 2 9");
+        assert_eq!(row(3), "_MSG 0008 ADDR 14 7");
+
+        let dis_output = util::test::rsc("aux/symbols.asm");
+        let r = run_disassmbler(cli::DisassembleArgs {
+            input: output,
+            output: Some(dis_output.clone()),
+            symbols: Some(sym),
+        });
+        assert_eq!(r, E_SUCCESS);
+        let out = read_to_v_string(dis_output).expect("file should exist");
+        assert_eq!(out[0], "LXI H, _MSG");
+        assert_eq!(out[4..7], ["_BUF:", "NOP", "NOP"]);
+        assert_eq!(out[7], "_MSG:");
+    }
+
+    #[test]
+    fn load_address_on_the_command_line() {
+        use crate::cli::{Cli, Commands};
+        use clap::Parser;
+
+        let parse = |load_at: &str| {
+            Cli::try_parse_from(["i8080", "asm", "rom.asm", "--load-at", load_at]).map(|cli| {
+                match cli.command {
+                    Commands::Assemble(args) => args.load_at,
+                    _ => unreachable!(),
+                }
+            })
+        };
+        assert_eq!(parse("0xF800").unwrap(), 0xf800);
+        assert_eq!(parse("62K").unwrap(), 0xf800);
+        assert_eq!(parse("256").unwrap(), 0x100);
+        assert!(parse("64K").is_err());
+
+        let cli = Cli::try_parse_from(["i8080", "run", "prog.bin", "-l", "0x100"]).unwrap();
+        assert!(matches!(cli.command, Commands::Run(args) if args.load_at == Some(0x100)));
+    }
 }
@@ -0,0 +1,358 @@
+//! Relocatable objects, written by `asm -c` and combined by `link`
+//!
+//! An object is text, a header then a record per line, with values and offsets in hex:
+//!
+//! ```text
+//! I8080 OBJECT 1
+//! MODULE PRINT
+//! CODE 0000 2A0000CD0000C9
+//! DATA 0000 0000
+//! PUBLIC PRINT CODE 0000
+//! EXTRN PUTC
+//! RELOC CODE 0001 DATA
+//! EXTREF CODE 0004 PUTC
+//! START CODE 0000
+//! ```
+//!
+//! - `CODE` and `DATA` give the bytes of each segment from an offset, a long segment is split over
+//!   several lines
+//! - `PUBLIC` gives a symbol other modules may refer to, as an offset into its segment or as an
+//!   `ABS` value which doesn't move
+//! - `EXTRN` names a symbol another module must give
+//! - `RELOC` has the word at an offset of a segment moved by where the `CODE` or `DATA` segment of
+//!   this module is placed, `EXTREF` has it added to the address of an external symbol
+//! - `START` is where the program starts, from the `END` of the module
+//! - `COMMON` declares a named block of a size shared with other modules, `BLOCK` gives bytes it
+//!   starts with and `COMREF` has a word moved by where the block is placed
+//!
+//! Lines starting `;` are ignored.
+
+use std::fmt;
+
+use super::label::Segment;
+
+const HEADER: &str = "I8080 OBJECT 1";
+
+/// Bytes written per `CODE` or `DATA` line
+const BYTES_PER_LINE: usize = 32;
+
+#[derive(Debug)]
+pub enum ObjectError {
+    NoHeader,
+    InvalidLine(usize, String),
+    InvalidValue(usize, String),
+    OutOfSegment(String),
+}
+
+impl std::error::Error for ObjectError {}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoHeader => write!(f, "Not an object, expected [{}]", HEADER),
+            Self::InvalidLine(l, s) => write!(f, "Line {}: unknown record [{}]", l, s),
+            Self::InvalidValue(l, s) => write!(f, "Line {}: invalid value [{}]", l, s),
+            Self::OutOfSegment(s) => write!(f, "Relocation outside of its segment [{}]", s),
+        }
+    }
+}
+
+/// What a relocated word is moved by
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    Code,
+    Data,
+    External(String),
+    Common(String),
+}
+
+/// A word in a segment which changes with where things are placed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub segment: Segment,
+    pub offset: u16,
+    pub target: Target,
+}
+
+/// A named block shared between modules, placed once however many declare it
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Common {
+    pub name: String,
+    pub size: u16,
+    /// What the module gives the start of the block, empty if nothing
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Object {
+    pub name: String,
+    pub code: Vec<u8>,
+    pub data: Vec<u8>,
+    /// Names with their segment and offset, or value if absolute
+    pub publics: Vec<(String, Segment, u16)>,
+    pub externs: Vec<String>,
+    pub relocations: Vec<Relocation>,
+    pub commons: Vec<Common>,
+    pub start: Option<(Segment, u16)>,
+}
+
+impl Object {
+    pub fn segment(&self, segment: Segment) -> &[u8] {
+        match segment {
+            Segment::Data => &self.data,
+            _ => &self.code,
+        }
+    }
+
+    /// The block of a name, declaring it if it isn't already
+    pub fn common(&mut self, name: &str) -> &mut Common {
+        let idx = match self.commons.iter().position(|c| c.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.commons.push(Common {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                self.commons.len() - 1
+            }
+        };
+        &mut self.commons[idx]
+    }
+
+    pub fn read(text: &str) -> Result<Self, ObjectError> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with(';'));
+        match lines.next() {
+            Some((_, line)) if line == HEADER => {}
+            _ => return Err(ObjectError::NoHeader),
+        }
+
+        let mut object = Object::default();
+        for (line_no, line) in lines {
+            let invalid_line = || ObjectError::InvalidLine(line_no, line.to_string());
+            let value = |s: &str| {
+                u16::from_str_radix(s, 16)
+                    .map_err(|_| ObjectError::InvalidValue(line_no, s.to_string()))
+            };
+            let segment = |s: &str| match s {
+                "CODE" => Ok(Segment::Code),
+                "DATA" => Ok(Segment::Data),
+                _ => Err(ObjectError::InvalidValue(line_no, s.to_string())),
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["MODULE", name] => object.name = name.to_string(),
+                [kind @ ("CODE" | "DATA"), offset, bytes] => {
+                    let offset = value(offset)? as usize;
+                    let bytes = hex_bytes(bytes)
+                        .ok_or_else(|| ObjectError::InvalidValue(line_no, bytes.to_string()))?;
+                    let into = if *kind == "CODE" {
+                        &mut object.code
+                    } else {
+                        &mut object.data
+                    };
+                    load(into, offset, &bytes);
+                }
+                ["COMMON", name, size] => {
+                    let size = value(size)?;
+                    let common = object.common(name);
+                    common.size = common.size.max(size);
+                }
+                ["BLOCK", name, offset, bytes] => {
+                    let offset = value(offset)? as usize;
+                    let bytes = hex_bytes(bytes)
+                        .ok_or_else(|| ObjectError::InvalidValue(line_no, bytes.to_string()))?;
+                    load(&mut object.common(name).bytes, offset, &bytes);
+                }
+                ["PUBLIC", name, "ABS", val] => {
+                    object
+                        .publics
+                        .push((name.to_string(), Segment::Absolute, value(val)?));
+                }
+                ["PUBLIC", name, seg, offset] => {
+                    object
+                        .publics
+                        .push((name.to_string(), segment(seg)?, value(offset)?));
+                }
+                ["EXTRN", name] => object.externs.push(name.to_string()),
+                [kind @ ("RELOC" | "EXTREF" | "COMREF"), seg, offset, target] => {
+                    let target = match (*kind, *target) {
+                        ("RELOC", "CODE") => Target::Code,
+                        ("RELOC", "DATA") => Target::Data,
+                        ("EXTREF", name) => Target::External(name.to_string()),
+                        ("COMREF", name) => Target::Common(name.to_string()),
+                        _ => return Err(invalid_line()),
+                    };
+                    object.relocations.push(Relocation {
+                        segment: segment(seg)?,
+                        offset: value(offset)?,
+                        target,
+                    });
+                }
+                ["START", seg, offset] => object.start = Some((segment(seg)?, value(offset)?)),
+                _ => return Err(invalid_line()),
+            }
+        }
+
+        // Segments are complete once every line is read
+        for relocation in object.relocations.iter() {
+            let len = object.segment(relocation.segment).len();
+            if relocation.offset as usize + 2 > len {
+                return Err(ObjectError::OutOfSegment(format!(
+                    "{} {:04X}",
+                    segment_name(relocation.segment),
+                    relocation.offset
+                )));
+            }
+        }
+        Ok(object)
+    }
+}
+
+/// Places bytes at an offset, growing what they're placed in to fit
+pub(crate) fn load(into: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+    if into.len() < offset + bytes.len() {
+        into.resize(offset + bytes.len(), 0);
+    }
+    into[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+fn segment_name(segment: Segment) -> &'static str {
+    match segment {
+        Segment::Data => "DATA",
+        Segment::Code => "CODE",
+        _ => "ABS",
+    }
+}
+
+fn hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(s.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "MODULE {}", self.name)?;
+        for (kind, bytes) in [("CODE", &self.code), ("DATA", &self.data)] {
+            for (idx, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+                let hex: String = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+                writeln!(f, "{} {:04X} {}", kind, idx * BYTES_PER_LINE, hex)?;
+            }
+        }
+        for common in self.commons.iter() {
+            writeln!(f, "COMMON {} {:04X}", common.name, common.size)?;
+            for (idx, chunk) in common.bytes.chunks(BYTES_PER_LINE).enumerate() {
+                let hex: String = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+                let offset = idx * BYTES_PER_LINE;
+                writeln!(f, "BLOCK {} {:04X} {}", common.name, offset, hex)?;
+            }
+        }
+        for (name, segment, value) in self.publics.iter() {
+            writeln!(
+                f,
+                "PUBLIC {} {} {:04X}",
+                name,
+                segment_name(*segment),
+                value
+            )?;
+        }
+        for name in self.externs.iter() {
+            writeln!(f, "EXTRN {}", name)?;
+        }
+        for relocation in self.relocations.iter() {
+            let segment = segment_name(relocation.segment);
+            match &relocation.target {
+                Target::Code => writeln!(f, "RELOC {} {:04X} CODE", segment, relocation.offset)?,
+                Target::Data => writeln!(f, "RELOC {} {:04X} DATA", segment, relocation.offset)?,
+                Target::External(name) => {
+                    writeln!(f, "EXTREF {} {:04X} {}", segment, relocation.offset, name)?
+                }
+                Target::Common(name) => {
+                    writeln!(f, "COMREF {} {:04X} {}", segment, relocation.offset, name)?
+                }
+            }
+        }
+        if let Some((segment, offset)) = self.start {
+            writeln!(f, "START {} {:04X}", segment_name(segment), offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let object = Object {
+            name: "PRINT".to_string(),
+            code: (0..40).collect(),
+            data: vec![0, 0],
+            publics: vec![
+                ("PRINT".to_string(), Segment::Code, 0),
+                ("WIDTH".to_string(), Segment::Absolute, 80),
+            ],
+            externs: vec!["PUTC".to_string()],
+            relocations: vec![
+                Relocation {
+                    segment: Segment::Code,
+                    offset: 1,
+                    target: Target::Data,
+                },
+                Relocation {
+                    segment: Segment::Data,
+                    offset: 0,
+                    target: Target::External("PUTC".to_string()),
+                },
+                Relocation {
+                    segment: Segment::Code,
+                    offset: 4,
+                    target: Target::Common("BUF".to_string()),
+                },
+            ],
+            commons: vec![Common {
+                name: "BUF".to_string(),
+                size: 0x80,
+                bytes: vec![1, 2],
+            }],
+            start: Some((Segment::Code, 0)),
+        };
+        let text = object.to_string();
+        assert!(text.starts_with("I8080 OBJECT 1\nMODULE PRINT\nCODE 0000 000102"));
+        assert!(text.contains("\nCODE 0020 2021222324252627\n"));
+        assert!(text.contains("\nPUBLIC WIDTH ABS 0050\n"));
+        assert!(text.contains("\nEXTREF DATA 0000 PUTC\n"));
+        assert!(text.contains("\nCOMMON BUF 0080\nBLOCK BUF 0000 0102\n"));
+        assert_eq!(Object::read(&text).unwrap(), object);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(
+            Object::read("MODULE X\n"),
+            Err(ObjectError::NoHeader)
+        ));
+        assert!(matches!(
+            Object::read("I8080 OBJECT 1\n; a comment\nCODE 0000 ABC\n"),
+            Err(ObjectError::InvalidValue(3, _))
+        ));
+        assert!(matches!(
+            Object::read("I8080 OBJECT 1\nLINK ME\n"),
+            Err(ObjectError::InvalidLine(2, _))
+        ));
+        assert!(matches!(
+            Object::read("I8080 OBJECT 1\nCODE 0000 00\nRELOC CODE 0000 CODE\n"),
+            Err(ObjectError::OutOfSegment(_))
+        ));
+    }
+}
@@ -0,0 +1,219 @@
+//! Preprocessed source, from `asm -E`
+//!
+//! The program as it was assembled, written back out as source: `IF` blocks are resolved, macro
+//! and repeat blocks are replaced by their expansions, which follow the call as a comment, and
+//! `INCLUDE`s are spliced in. Each line is commented with its address and the values of any
+//! `EQU` or `SET` it uses.
+//!
+//! ```text
+//! LEN:    EQU 0x0005
+//!         LXI H, _MSG             ; 0000
+//! ; _ld
+//!         MOV A, M                ; 0003
+//!         MVI B, LEN              ; 0004  LEN=0x0005
+//! ```
+//!
+//! Symbols which aren't labels of a line, as the `EQU`s, are given first with their final values,
+//! and an `ORG` is put wherever the address jumps, so the source reassembles to the same bytes
+//! with the same flags, but for `-D`s which are among the `EQU`s. `DEFINED` has already been
+//! replaced by its value at each use.
+//!
+//! `SET`s aren't written at all, as one value at the top would be wrong for every line before a
+//! redefinition. A use after a `SET` already has the value it saw, and one before the first `SET`
+//! is given the last value, as it was when assembled. The note on the line names the symbol.
+//!
+//! Segments and `PUBLIC`s aren't kept, it's the program rather than an object.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use super::{
+    label::{Label, Segment},
+    macros::substitute,
+    symbols,
+    tokenizer::LineMeta,
+};
+
+const CODE_WIDTH: usize = 32;
+
+/// The lines placed by the assembler as source
+///
+/// `end` is how far the program reaches, which may be past the last line after an `ORG`, and
+/// `end_statement` the program's `END` if it had one, with the address given to it.
+pub fn render(
+    lines: &[LineMeta],
+    symbols: &HashMap<String, Label>,
+    end: u16,
+    end_statement: Option<Option<u16>>,
+) -> String {
+    let mut labelled = HashSet::new();
+    labels_of(lines, &mut labelled);
+    let mut names: Vec<(&String, &Label)> = symbols
+        .iter()
+        .filter(|(name, label)| !labelled.contains(*name) && label.segment != Segment::External)
+        .collect();
+    names.sort_by_key(|(name, _)| *name);
+
+    let mut out = String::new();
+    let mut sets = HashMap::new();
+    for (name, label) in names {
+        match label.value {
+            Some(value) if label.is_set => {
+                sets.insert(name.to_string(), format!("{:#06X}", value));
+            }
+            Some(value) => {
+                let _ = writeln!(out, "{:<7} EQU {:#06X}", format!("{}:", name), value);
+            }
+            None => {}
+        }
+    }
+    let mut address = 0;
+    let mut reached = 0;
+    write_lines(lines, symbols, &sets, &mut address, &mut reached, &mut out);
+    if reached < end {
+        let _ = writeln!(out, "        ORG {:#06X}", end);
+    }
+    match end_statement {
+        Some(Some(start)) => {
+            let _ = writeln!(out, "        END {:#06X}", start);
+        }
+        Some(None) => {
+            let _ = writeln!(out, "        END");
+        }
+        None => {}
+    }
+    out
+}
+
+fn labels_of(lines: &[LineMeta], labelled: &mut HashSet<String>) {
+    for line in lines.iter() {
+        labelled.extend(line.label.clone());
+        labels_of(line.expansion.as_deref().unwrap_or_default(), labelled);
+    }
+}
+
+fn write_lines(
+    lines: &[LineMeta],
+    symbols: &HashMap<String, Label>,
+    sets: &HashMap<String, String>,
+    address: &mut u16,
+    reached: &mut u16,
+    out: &mut String,
+) {
+    for line in lines.iter() {
+        if line.address != *address {
+            let _ = writeln!(out, "        ORG {:#06X}", line.address);
+            *address = line.address;
+        }
+        match &line.expansion {
+            Some(expansion) => {
+                if let Some(label) = &line.label {
+                    let _ = writeln!(out, "{}", annotate(format!("{}:", label), line, &[]));
+                }
+                let _ = writeln!(out, "; {}", line.raw_line.trim());
+                write_lines(expansion, symbols, sets, address, reached, out);
+            }
+            None => {
+                let _ = writeln!(
+                    out,
+                    "{}",
+                    annotate(code(line, sets), line, &notes(line, symbols))
+                );
+                *address = address.wrapping_add(line.width as u16);
+                *reached = (*reached).max(*address);
+            }
+        }
+    }
+}
+
+/// The label, instruction and arguments of a line, with the value of any `SET` still in them
+fn code(line: &LineMeta, sets: &HashMap<String, String>) -> String {
+    let mut text = match &line.label {
+        Some(label) => format!("{:<7} ", format!("{}:", label)),
+        None => " ".repeat(8),
+    };
+    if let Some(inst) = line.inst.as_ref().filter(|_| !line.label_only) {
+        text.push_str(inst);
+        if !line.args_list.is_empty() {
+            text.push(' ');
+            text.push_str(&substitute(&line.args_list.join(", "), sets));
+        }
+    }
+    text.trim_end().to_string()
+}
+
+/// Values of the `EQU`s and `SET`s a line uses
+fn notes(line: &LineMeta, symbols: &HashMap<String, Label>) -> Vec<String> {
+    let mut notes = vec![];
+    for ident in symbols::identifiers(&line.args_list.join(",")) {
+        let value = symbols
+            .get(&ident)
+            .filter(|label| label.is_eq || label.is_set)
+            .and_then(|label| label.value);
+        let note = match value {
+            Some(value) => format!("{}={:#06X}", ident, value),
+            None => continue,
+        };
+        if !notes.contains(&note) {
+            notes.push(note);
+        }
+    }
+    notes
+}
+
+fn annotate(code: String, line: &LineMeta, notes: &[String]) -> String {
+    let mut text = format!(
+        "{:<width$} ; {:04X}",
+        code,
+        line.address,
+        width = CODE_WIDTH - 1
+    );
+    for note in notes.iter().chain(line.comment.iter()) {
+        text.push_str("  ");
+        text.push_str(note);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::tokenizer::tokenize;
+
+    fn line(raw: &str, address: u16, width: usize) -> LineMeta {
+        LineMeta {
+            address,
+            width,
+            ..tokenize(raw).unwrap().unwrap()
+        }
+    }
+
+    #[test]
+    fn renders() {
+        let mut call = line("_CALL:  _ld", 2, 1);
+        call.expansion = Some(vec![line("        MOV A, M", 2, 1)]);
+        let lines = vec![
+            line("        MVI B, LEN ; length", 0, 2),
+            call,
+            line("_END:", 0x10, 0),
+        ];
+        let symbols = HashMap::from([
+            ("LEN".to_string(), Label::new_equ(Some(5))),
+            ("_END".to_string(), Label::new_addr(Some(0x10))),
+        ]);
+        assert_eq!(
+            render(&lines, &symbols, 0x12, Some(Some(0))),
+            "\
+LEN:    EQU 0x0005
+        MVI B, LEN              ; 0000  LEN=0x0005  length
+_CALL:                          ; 0002
+; _CALL:  _ld
+        MOV A, M                ; 0002
+        ORG 0x0010
+_END:                           ; 0010
+        ORG 0x0012
+        END 0x0000
+"
+        );
+    }
+}
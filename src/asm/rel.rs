@@ -0,0 +1,636 @@
+//! Microsoft M80 relocatable modules, `.REL` files
+//!
+//! A `.REL` file is a stream of bits, most significant first, rather than bytes. Each item starts
+//! with a bit saying whether it's a plain byte:
+//!
+//! - `0` and 8 bits is a byte loaded as is
+//! - `1`, a 2-bit address type and 16 bits, low byte first, is a word moved by where its segment is
+//!   placed, the types being `00` absolute, `01` program (code), `10` data and `11` common
+//! - `1 00`, a 4-bit control and its fields is a special link item
+//!
+//! Special link items take an A field, an address type and 16-bit value, and/or a B field, a 3-bit
+//! length and that many 8-bit characters of a name:
+//!
+//! | Control | Fields | Item                                                     |
+//! |---------|--------|----------------------------------------------------------|
+//! | 0       | B      | entry symbol, a name the module gives                    |
+//! | 1       | B      | select the COMMON block common words refer to             |
+//! | 2       | B      | program name                                             |
+//! | 3       | B      | request a library search, ignored                        |
+//! | 5       | A B    | size of a COMMON block                                   |
+//! | 6       | A B    | chain of references to an external, from the last        |
+//! | 7       | A B    | value of an entry symbol                                 |
+//! | 8, 9    | A      | minus or plus an offset to the external at the location  |
+//! | 10      | A      | size of the data segment                                 |
+//! | 11      | A      | set the location counter, and segment, bytes load at     |
+//! | 12      | A      | chain of references to the location counter             |
+//! | 13      | A      | size of the program (code) segment                       |
+//! | 14      | A      | end of the module, giving where it starts                |
+//! | 15      |        | end of the file                                          |
+//!
+//! Each reference to an external holds the address of the one before, the first holding an
+//! absolute 0, so only the last need be given. An offset from an external can't be held in the
+//! chain so has its own item just before the word.
+//!
+//! Modules are read into, and written from, [Object]s. Absolute segments (`ASEG`) and relocated
+//! words within a COMMON block aren't supported, nor are extension link items.
+
+use std::{collections::HashMap, fmt};
+
+use super::{
+    label::Segment,
+    object::{self, Object, Relocation, Target},
+};
+
+/// Longest name a B field can hold
+const NAME_LENGTH: usize = 7;
+
+const ABSOLUTE: u16 = 0;
+const PROGRAM: u16 = 1;
+const DATA: u16 = 2;
+const COMMON: u16 = 3;
+
+#[derive(Debug)]
+pub enum RelError {
+    Truncated,
+    NameTooLong(String),
+    Unsupported(&'static str),
+    /// The external whose chain of references is broken
+    BadChain(String),
+}
+
+impl std::error::Error for RelError {}
+
+impl fmt::Display for RelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "REL module ends part way through"),
+            Self::NameTooLong(s) => write!(
+                f,
+                "Name {} is too long for a REL module, {} characters at most",
+                s, NAME_LENGTH
+            ),
+            Self::Unsupported(s) => write!(f, "REL modules with {} aren't supported", s),
+            Self::BadChain(s) => write!(f, "Chain of references to {} is broken", s),
+        }
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: u16, width: u8) {
+        for bit in (0..width).rev() {
+            if self.bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if (value >> bit) & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+            }
+            self.bits += 1;
+        }
+    }
+
+    fn word(&mut self, value: u16) {
+        self.push(value & 0xff, 8);
+        self.push(value >> 8, 8);
+    }
+
+    fn align(&mut self) {
+        self.bits = self.bytes.len() * 8;
+    }
+
+    fn special(&mut self, control: u16, a: Option<(u16, u16)>, b: Option<&str>) {
+        self.push(0b100, 3);
+        self.push(control, 4);
+        if let Some((kind, value)) = a {
+            self.push(kind, 2);
+            self.word(value);
+        }
+        if let Some(name) = b {
+            self.push(name.len() as u16 % 8, 3);
+            for c in name.bytes() {
+                self.push(c as u16, 8);
+            }
+        }
+    }
+}
+
+fn checked(name: &str) -> Result<&str, RelError> {
+    match name.len() {
+        1..=NAME_LENGTH => Ok(name),
+        _ => Err(RelError::NameTooLong(name.to_string())),
+    }
+}
+
+fn address_type(segment: Segment) -> u16 {
+    match segment {
+        Segment::Code => PROGRAM,
+        Segment::Data => DATA,
+        _ => ABSOLUTE,
+    }
+}
+
+/// An object as a module on its own in a `.REL` file
+pub fn write(object: &Object) -> Result<Vec<u8>, RelError> {
+    let mut out = BitWriter {
+        bytes: vec![],
+        bits: 0,
+    };
+    let name: String = object.name.chars().take(NAME_LENGTH).collect();
+    out.special(2, None, Some(&name));
+    for (name, _, _) in object.publics.iter() {
+        out.special(0, None, Some(checked(name)?));
+    }
+    for common in object.commons.iter() {
+        out.special(
+            5,
+            Some((ABSOLUTE, common.size)),
+            Some(checked(&common.name)?),
+        );
+    }
+    out.special(10, Some((ABSOLUTE, object.data.len() as u16)), None);
+    out.special(13, Some((PROGRAM, object.code.len() as u16)), None);
+
+    // Each reference to an external holds where the one before it is
+    let mut words: HashMap<(Segment, u16), &Target> = HashMap::new();
+    let mut links: HashMap<(Segment, u16), Option<(Segment, u16)>> = HashMap::new();
+    let mut chains: Vec<(&String, Option<(Segment, u16)>)> =
+        object.externs.iter().map(|name| (name, None)).collect();
+    for relocation in object.relocations.iter() {
+        let at = (relocation.segment, relocation.offset);
+        words.insert(at, &relocation.target);
+        if let Target::External(name) = &relocation.target {
+            let chain = match chains.iter_mut().find(|(n, _)| *n == name) {
+                Some(chain) => chain,
+                None => return Err(RelError::BadChain(name.to_string())),
+            };
+            links.insert(at, chain.1.replace(at));
+        }
+    }
+
+    let mut selected: Option<&str> = None;
+    for (segment, bytes) in [(Segment::Code, &object.code), (Segment::Data, &object.data)] {
+        if bytes.is_empty() {
+            continue;
+        }
+        out.special(11, Some((address_type(segment), 0)), None);
+        let mut idx = 0;
+        while idx < bytes.len() {
+            let word =
+                || bytes[idx] as u16 | (bytes.get(idx + 1).copied().unwrap_or(0) as u16) << 8;
+            match words.get(&(segment, idx as u16)) {
+                Some(Target::External(_)) => {
+                    if word() != 0 {
+                        out.special(9, Some((ABSOLUTE, word())), None);
+                    }
+                    match links[&(segment, idx as u16)] {
+                        Some((segment, offset)) => {
+                            out.push(0b1, 1);
+                            out.push(address_type(segment), 2);
+                            out.word(offset);
+                        }
+                        None => {
+                            out.push(0, 9);
+                            out.push(0, 9);
+                        }
+                    }
+                }
+                Some(Target::Common(name)) => {
+                    if selected != Some(name) {
+                        out.special(1, None, Some(checked(name)?));
+                        selected = Some(name);
+                    }
+                    out.push(0b111, 3);
+                    out.word(word());
+                }
+                Some(target) => {
+                    let kind = if **target == Target::Code {
+                        PROGRAM
+                    } else {
+                        DATA
+                    };
+                    out.push(0b100 | kind, 3);
+                    out.word(word());
+                }
+                None => {
+                    out.push(bytes[idx] as u16, 9);
+                    idx += 1;
+                    continue;
+                }
+            }
+            idx += 2;
+        }
+    }
+    for common in object.commons.iter().filter(|c| !c.bytes.is_empty()) {
+        out.special(1, None, Some(&common.name));
+        out.special(11, Some((COMMON, 0)), None);
+        for byte in common.bytes.iter() {
+            out.push(*byte as u16, 9);
+        }
+    }
+
+    for (name, last) in chains.iter() {
+        let head = last.map_or((ABSOLUTE, 0), |(s, o)| (address_type(s), o));
+        out.special(6, Some(head), Some(checked(name)?));
+    }
+    for (name, segment, value) in object.publics.iter() {
+        out.special(7, Some((address_type(*segment), *value)), Some(name));
+    }
+    let start = object
+        .start
+        .map_or((ABSOLUTE, 0), |(s, o)| (address_type(s), o));
+    out.special(14, Some(start), None);
+    out.align();
+    out.special(15, None, None);
+    Ok(out.bytes)
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn take(&mut self, width: u8) -> Result<u16, RelError> {
+        let mut value = 0;
+        for _ in 0..width {
+            let byte = self.bytes.get(self.at / 8).ok_or(RelError::Truncated)?;
+            value = value << 1 | ((byte >> (7 - self.at % 8)) & 1) as u16;
+            self.at += 1;
+        }
+        Ok(value)
+    }
+
+    fn word(&mut self) -> Result<u16, RelError> {
+        Ok(self.take(8)? | self.take(8)? << 8)
+    }
+
+    fn a_field(&mut self) -> Result<(u16, u16), RelError> {
+        Ok((self.take(2)?, self.word()?))
+    }
+
+    fn b_field(&mut self) -> Result<String, RelError> {
+        let len = match self.take(3)? {
+            0 => 8,
+            len => len,
+        };
+        (0..len).map(|_| Ok(self.take(8)? as u8 as char)).collect()
+    }
+
+    fn align(&mut self) {
+        self.at = self.at.div_ceil(8) * 8;
+    }
+
+    /// Whether nothing but CP/M's end of file padding is left
+    fn at_end(&self) -> bool {
+        self.bytes[(self.at / 8).min(self.bytes.len())..]
+            .iter()
+            .all(|b| *b == 0x1a || *b == 0)
+    }
+}
+
+/// A module being read, words are kept by where they are until the module ends
+#[derive(Default)]
+struct Module {
+    object: Object,
+    /// Segment loaded into and the location counter of each
+    segment: u16,
+    locations: [u16; 4],
+    common: String,
+    words: HashMap<(Segment, u16), (u16, u16)>,
+    relocations: HashMap<(Segment, u16), Target>,
+    offsets: Vec<((Segment, u16), u16)>,
+}
+
+impl Module {
+    /// The segment being loaded, words in a COMMON block can't be relocated
+    fn segment(&self) -> Result<Segment, RelError> {
+        match self.segment {
+            PROGRAM => Ok(Segment::Code),
+            DATA => Ok(Segment::Data),
+            _ => Err(RelError::Unsupported("relocated words in COMMON")),
+        }
+    }
+
+    fn load(&mut self, bytes: &[u8]) -> Result<(), RelError> {
+        let offset = self.locations[self.segment as usize] as usize;
+        let into = match self.segment {
+            COMMON => &mut self.object.common(&self.common.clone()).bytes,
+            DATA => &mut self.object.data,
+            _ => &mut self.object.code,
+        };
+        object::load(into, offset, bytes);
+        let location = &mut self.locations[self.segment as usize];
+        *location = location.wrapping_add(bytes.len() as u16);
+        Ok(())
+    }
+
+    fn load_word(&mut self, kind: u16, value: u16) -> Result<(), RelError> {
+        let at = (self.segment()?, self.locations[self.segment as usize]);
+        let target = match kind {
+            PROGRAM => Target::Code,
+            DATA => Target::Data,
+            _ => Target::Common(self.common.to_string()),
+        };
+        self.words.insert(at, (kind, value));
+        self.relocations.insert(at, target);
+        self.load(&[value as u8, (value >> 8) as u8])
+    }
+
+    fn segment_of(kind: u16) -> Option<Segment> {
+        match kind {
+            PROGRAM => Some(Segment::Code),
+            DATA => Some(Segment::Data),
+            _ => None,
+        }
+    }
+
+    /// Every word in a chain from its last, each is cleared
+    fn chain(
+        &mut self,
+        (kind, value): (u16, u16),
+        name: &str,
+    ) -> Result<Vec<(Segment, u16)>, RelError> {
+        let mut at = Module::segment_of(kind).map(|s| (s, value));
+        if at.is_none() && value != 0 {
+            return Err(RelError::BadChain(name.to_string()));
+        }
+        let mut chain = vec![];
+        while let Some((segment, offset)) = at {
+            let bytes = match segment {
+                Segment::Data => &mut self.object.data,
+                _ => &mut self.object.code,
+            };
+            let idx = offset as usize;
+            if idx + 2 > bytes.len() || chain.len() > bytes.len() {
+                return Err(RelError::BadChain(name.to_string()));
+            }
+            let link = bytes[idx] as u16 | (bytes[idx + 1] as u16) << 8;
+            bytes[idx..idx + 2].fill(0);
+            at = match self.words.remove(&(segment, offset)) {
+                Some((kind, _)) => Module::segment_of(kind).map(|s| (s, link)),
+                None if link == 0 => None,
+                None => return Err(RelError::BadChain(name.to_string())),
+            };
+            self.relocations.remove(&(segment, offset));
+            chain.push((segment, offset));
+        }
+        Ok(chain)
+    }
+
+    fn end(mut self, (kind, value): (u16, u16)) -> Object {
+        for ((segment, offset), by) in self.offsets.iter() {
+            let bytes = match segment {
+                Segment::Data => &mut self.object.data,
+                _ => &mut self.object.code,
+            };
+            let idx = *offset as usize;
+            if let Some(word) = bytes.get_mut(idx..idx + 2) {
+                let value = (word[0] as u16 | (word[1] as u16) << 8).wrapping_add(*by);
+                word.copy_from_slice(&[value as u8, (value >> 8) as u8]);
+            }
+        }
+        let mut relocations: Vec<Relocation> = self
+            .relocations
+            .into_iter()
+            .map(|((segment, offset), target)| Relocation {
+                segment,
+                offset,
+                target,
+            })
+            .collect();
+        relocations.sort_by_key(|r| (r.segment == Segment::Data, r.offset));
+        self.object.relocations = relocations;
+        self.object.start = match (kind, value) {
+            (ABSOLUTE, 0) => None,
+            (kind, value) => Some((Module::segment_of(kind).unwrap_or(Segment::Absolute), value)),
+        };
+        self.object
+    }
+}
+
+/// Every module in a `.REL` file, a library may hold many
+pub fn read(bytes: &[u8]) -> Result<Vec<Object>, RelError> {
+    let mut stream = BitReader { bytes, at: 0 };
+    let mut objects = vec![];
+    let mut module = Module {
+        segment: PROGRAM,
+        ..Default::default()
+    };
+    loop {
+        if stream.take(1)? == 0 {
+            let byte = stream.take(8)? as u8;
+            module.load(&[byte])?;
+            continue;
+        }
+        let kind = stream.take(2)?;
+        if kind != ABSOLUTE {
+            let value = stream.word()?;
+            module.load_word(kind, value)?;
+            continue;
+        }
+        match stream.take(4)? {
+            0 | 3 => {
+                stream.b_field()?;
+            }
+            1 => module.common = stream.b_field()?,
+            2 => module.object.name = stream.b_field()?,
+            4 => return Err(RelError::Unsupported("extension link items")),
+            5 => {
+                let (_, size) = stream.a_field()?;
+                let name = stream.b_field()?;
+                let common = module.object.common(&name);
+                common.size = common.size.max(size);
+            }
+            6 => {
+                let head = stream.a_field()?;
+                let name = stream.b_field()?;
+                for (segment, offset) in module.chain(head, &name)? {
+                    let target = Target::External(name.to_string());
+                    module.relocations.insert((segment, offset), target);
+                }
+                module.object.externs.push(name);
+            }
+            7 => {
+                let (kind, value) = stream.a_field()?;
+                let name = stream.b_field()?;
+                let segment = match kind {
+                    COMMON => return Err(RelError::Unsupported("entry symbols in COMMON")),
+                    kind => Module::segment_of(kind).unwrap_or(Segment::Absolute),
+                };
+                module.object.publics.push((name, segment, value));
+            }
+            control @ (8 | 9) => {
+                let (_, value) = stream.a_field()?;
+                let by = if control == 8 {
+                    value.wrapping_neg()
+                } else {
+                    value
+                };
+                let at = (module.segment()?, module.locations[module.segment as usize]);
+                module.offsets.push((at, by));
+            }
+            10 => {
+                let (_, size) = stream.a_field()?;
+                let size = (size as usize).max(module.object.data.len());
+                module.object.data.resize(size, 0);
+            }
+            11 => {
+                let (kind, value) = stream.a_field()?;
+                if kind == ABSOLUTE {
+                    return Err(RelError::Unsupported("absolute segments"));
+                }
+                module.segment = kind;
+                module.locations[kind as usize] = value;
+            }
+            12 => {
+                let head = stream.a_field()?;
+                let segment = module.segment()?;
+                let location = module.locations[module.segment as usize];
+                let target = match segment {
+                    Segment::Data => Target::Data,
+                    _ => Target::Code,
+                };
+                for (at_segment, offset) in module.chain(head, "$")? {
+                    let bytes = match at_segment {
+                        Segment::Data => &mut module.object.data,
+                        _ => &mut module.object.code,
+                    };
+                    let idx = offset as usize;
+                    bytes[idx..idx + 2].copy_from_slice(&[location as u8, (location >> 8) as u8]);
+                    module
+                        .relocations
+                        .insert((at_segment, offset), target.clone());
+                }
+            }
+            13 => {
+                let (_, size) = stream.a_field()?;
+                let size = (size as usize).max(module.object.code.len());
+                module.object.code.resize(size, 0);
+            }
+            14 => {
+                let start = stream.a_field()?;
+                stream.align();
+                let ended = std::mem::replace(
+                    &mut module,
+                    Module {
+                        segment: PROGRAM,
+                        ..Default::default()
+                    },
+                );
+                objects.push(ended.end(start));
+                if stream.at_end() {
+                    return Ok(objects);
+                }
+            }
+            _ => return Ok(objects),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::object::Common;
+
+    fn object() -> Object {
+        Object {
+            name: "PRINT".to_string(),
+            // LXI H, MSG; CALL PUTC; CALL PUTC + 3; JMP 0; LDA BUF + 4
+            code: vec![
+                0x21, 0x00, 0x00, 0xcd, 0x00, 0x00, 0xcd, 0x03, 0x00, 0xc3, 0x00, 0x00, 0x3a, 0x04,
+                0x00,
+            ],
+            data: vec![b'h', b'i', 0x00, 0x09, 0x00],
+            publics: vec![
+                ("PRINT".to_string(), Segment::Code, 0),
+                ("MSG".to_string(), Segment::Data, 0),
+                ("WIDTH".to_string(), Segment::Absolute, 80),
+            ],
+            externs: vec!["PUTC".to_string()],
+            relocations: vec![
+                Relocation {
+                    segment: Segment::Code,
+                    offset: 1,
+                    target: Target::Data,
+                },
+                Relocation {
+                    segment: Segment::Code,
+                    offset: 4,
+                    target: Target::External("PUTC".to_string()),
+                },
+                Relocation {
+                    segment: Segment::Code,
+                    offset: 7,
+                    target: Target::External("PUTC".to_string()),
+                },
+                Relocation {
+                    segment: Segment::Code,
+                    offset: 10,
+                    target: Target::Code,
+                },
+                Relocation {
+                    segment: Segment::Code,
+                    offset: 13,
+                    target: Target::Common("BUF".to_string()),
+                },
+                Relocation {
+                    segment: Segment::Data,
+                    offset: 3,
+                    target: Target::Code,
+                },
+            ],
+            commons: vec![Common {
+                name: "BUF".to_string(),
+                size: 0x10,
+                bytes: vec![],
+            }],
+            start: Some((Segment::Code, 0)),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let object = object();
+        let rel = write(&object).unwrap();
+        // Program name, 5 characters
+        assert_eq!(&rel[..3], &[0b1000_0101, 0b0101_0100, 0b0001_0100]);
+        assert_eq!(read(&rel).unwrap(), vec![object]);
+    }
+
+    #[test]
+    fn libraries() {
+        let mut rel = write(&object()).unwrap();
+        let mut other = object();
+        other.name = "OTHER".to_string();
+        other.commons[0].bytes = vec![1, 2, 3];
+        rel.pop();
+        rel.append(&mut write(&other).unwrap());
+        rel.extend([0x1a; 4]);
+        let objects = read(&rel).unwrap();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[1].commons[0].bytes, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut object = object();
+        object.publics[0].0 = "LONGNAME".to_string();
+        assert!(matches!(write(&object), Err(RelError::NameTooLong(_))));
+
+        let rel = write(&self::object()).unwrap();
+        assert!(matches!(
+            read(&rel[..rel.len() - 4]),
+            Err(RelError::Truncated)
+        ));
+        // Set location counter, absolute 0100
+        assert!(matches!(
+            read(&[0b1001_0110, 0b0000_0000, 0b0100_0000, 0b0000_0000]),
+            Err(RelError::Unsupported(_))
+        ));
+    }
+}
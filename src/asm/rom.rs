@@ -0,0 +1,169 @@
+//! Shaping a `bin` into what's burnt to a ROM, see [RomArgs]
+//!
+//! The image is the program from `--load-at`, so a ROM at 0xF800 is padded and split from there
+//! and its checksum is at an address from 0xF800 up. It's padded first, then the checksum is put
+//! in, then it's split, so the checksum covers the padding and is found in whichever chunk holds
+//! its address.
+//!
+//! Sizes and addresses are read by [parse_size](crate::cli::parse_size).
+//!
+//! The checksum is of every byte of the image but its own, which are taken as zero.
+
+use std::fmt;
+
+use crate::cli::{Checksum, RomArgs};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RomError {
+    /// Size of the program and what it was to be padded to
+    TooLarge(usize, usize),
+    /// Address of the checksum, where the image starts and its size
+    ChecksumOutside(usize, usize, usize),
+    /// These only make sense for a raw image
+    NotBin,
+}
+
+impl std::error::Error for RomError {}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TooLarge(len, pad) => write!(
+                f,
+                "Program is {:#06X} bytes, larger than the {:#06X} it's padded to",
+                len, pad
+            ),
+            Self::ChecksumOutside(at, from, len) => write!(
+                f,
+                "Checksum at {:#06X} isn't within the {:#06X} byte image from {:#06X}",
+                at, len, from
+            ),
+            Self::NotBin => write!(f, "Padding, checksums and splitting need --format bin"),
+        }
+    }
+}
+
+/// The image from `load_at` of the program's `bytes`, with padding and checksum
+pub fn shape(bytes: &[u8], load_at: usize, args: &RomArgs) -> Result<Vec<u8>, RomError> {
+    let mut bytes = bytes.get(load_at..).unwrap_or_default().to_vec();
+    if let Some(pad) = args.pad {
+        if bytes.len() > pad {
+            return Err(RomError::TooLarge(bytes.len(), pad));
+        }
+        bytes.resize(pad, args.fill);
+    }
+    if let (Some(kind), Some(at)) = (args.checksum, args.checksum_at) {
+        let width = match kind {
+            Checksum::Sum8 => 1,
+            Checksum::Sum16 | Checksum::Crc16 => 2,
+        };
+        let outside = || RomError::ChecksumOutside(at, load_at, bytes.len());
+        let at = at.checked_sub(load_at).ok_or_else(outside)?;
+        if at + width > bytes.len() {
+            return Err(outside());
+        }
+        bytes[at..at + width].fill(0);
+        let sum = checksum(kind, &bytes);
+        bytes[at..at + width].copy_from_slice(&sum.to_le_bytes()[..width]);
+    }
+    Ok(bytes)
+}
+
+pub fn checksum(kind: Checksum, bytes: &[u8]) -> u16 {
+    match kind {
+        Checksum::Sum8 | Checksum::Sum16 => bytes
+            .iter()
+            .fold(0_u16, |sum, b| sum.wrapping_add(*b as u16)),
+        Checksum::Crc16 => bytes.iter().fold(0xffff_u16, |crc, b| {
+            (0..8).fold(crc ^ (*b as u16) << 8, |crc, _| {
+                if crc & 0x8000 != 0 {
+                    crc << 1 ^ 0x1021
+                } else {
+                    crc << 1
+                }
+            })
+        }),
+    }
+}
+
+/// The image in chunks of a size, the last padded to it
+pub fn split(bytes: &[u8], size: usize, fill: u8) -> Vec<Vec<u8>> {
+    bytes
+        .chunks(size)
+        .map(|chunk| {
+            let mut chunk = chunk.to_vec();
+            chunk.resize(size, fill);
+            chunk
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(checksum(Checksum::Crc16, b"123456789"), 0x29b1);
+        assert_eq!(checksum(Checksum::Sum16, &[0xff, 0xff, 0x02]), 0x0200);
+
+        let args = RomArgs {
+            fill: 0xff,
+            pad: Some(8),
+            checksum: Some(Checksum::Sum8),
+            checksum_at: Some(7),
+            split: None,
+        };
+        let bytes = shape(&[0x01, 0x02], 0, &args).unwrap();
+        assert_eq!(bytes, vec![0x01, 0x02, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]);
+        // The old checksum isn't counted
+        assert_eq!(shape(&bytes, 0, &args).unwrap(), bytes);
+    }
+
+    #[test]
+    fn invalid() {
+        let args = RomArgs {
+            pad: Some(2),
+            checksum: Some(Checksum::Crc16),
+            checksum_at: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(shape(&[0; 3], 0, &args), Err(RomError::TooLarge(3, 2)));
+        assert_eq!(
+            shape(&[0; 2], 0, &args),
+            Err(RomError::ChecksumOutside(1, 0, 2))
+        );
+        // Below where the image starts
+        let args = RomArgs {
+            pad: Some(4),
+            checksum: Some(Checksum::Sum8),
+            checksum_at: Some(0xff),
+            ..Default::default()
+        };
+        assert_eq!(
+            shape(&[0; 0x102], 0x100, &args),
+            Err(RomError::ChecksumOutside(0xff, 0x100, 4))
+        );
+    }
+
+    #[test]
+    fn from_load_address() {
+        let args = RomArgs {
+            fill: 0xff,
+            pad: Some(4),
+            checksum: Some(Checksum::Sum8),
+            checksum_at: Some(0xf803),
+            split: None,
+        };
+        let mut program = vec![0; 0xf800];
+        program.extend([0x01, 0x02]);
+        let bytes = shape(&program, 0xf800, &args).unwrap();
+        assert_eq!(bytes, vec![0x01, 0x02, 0xff, 0x02]);
+    }
+
+    #[test]
+    fn splits() {
+        let chunks = split(&[1, 2, 3, 4, 5], 2, 0xff);
+        assert_eq!(chunks, vec![vec![1, 2], vec![3, 4], vec![5, 0xff]]);
+    }
+}
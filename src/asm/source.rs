@@ -0,0 +1,104 @@
+//! Where the assembler gets its source text from
+//!
+//! By default files are read from disk, but anything implementing [SourceResolver] may be given to
+//! the assembler, such as a [MemoryResolver] holding source that was never written to a file.
+//!
+//! `INCLUDE` and `INCBIN` go through the same resolver, so virtual files may include one another.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+pub trait SourceResolver {
+    /// Returns the full text of the file at `path`
+    fn read(&self, path: &Path) -> io::Result<String>;
+
+    /// Returns the raw contents of the file at `path`, for `INCBIN`
+    fn read_bytes(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.read(path).map(String::into_bytes)
+    }
+}
+
+/// Removes `.` and folds `..` into its parent without touching the filesystem, so the same file
+/// reached by two routes compares equal
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normal.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normal.pop();
+                }
+                _ => normal.push(".."),
+            },
+            c => normal.push(c),
+        }
+    }
+    normal
+}
+
+/// Reads files from disk
+#[derive(Debug, Default)]
+pub struct FsResolver;
+
+impl SourceResolver for FsResolver {
+    fn read(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn read_bytes(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+}
+
+/// Virtual files held in memory, keyed by their [normalize]d path
+#[derive(Debug, Default)]
+pub struct MemoryResolver {
+    files: HashMap<PathBuf, String>,
+}
+
+impl MemoryResolver {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_file<P: Into<PathBuf>, S: Into<String>>(mut self, path: P, source: S) -> Self {
+        self.files.insert(normalize(&path.into()), source.into());
+        self
+    }
+}
+
+impl SourceResolver for MemoryResolver {
+    fn read(&self, path: &Path) -> io::Result<String> {
+        self.files.get(&normalize(path)).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such virtual file: {}", path.display()),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_resolver() {
+        let resolver = MemoryResolver::new().with_file("main.asm", "NOP");
+        assert_eq!(resolver.read(Path::new("main.asm")).unwrap(), "NOP");
+        assert_eq!(resolver.read(Path::new("./main.asm")).unwrap(), "NOP");
+        let e = resolver.read(Path::new("other.asm")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize(Path::new("a/./b/../c.asm")), Path::new("a/c.asm"));
+        assert_eq!(normalize(Path::new("../a/b/../../c")), Path::new("../c"));
+        assert_eq!(normalize(Path::new("./c")), Path::new("c"));
+    }
+}
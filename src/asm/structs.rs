@@ -0,0 +1,173 @@
+//! Structures, laid out by `STRUCT name` ... `ENDS`
+//!
+//! Each line of a structure is a `DB`, `DW`, `DC` or `DS` giving the size of a field, and its
+//! label names the field. Fields aren't placed, rather each gives a symbol of its offset,
+//! `NAME.FIELD`, and the structure's name is its size, as is `SIZEOF(NAME)`:
+//!
+//! ```asm
+//!         STRUCT POINT
+//! X:      DW 0
+//! Y:      DW 0
+//! COLOUR: DB 7
+//!         ENDS
+//!
+//!         LXI D, POINT.Y      ; 2
+//!         MVI C, SIZEOF(POINT) ; 5
+//! ```
+//!
+//! Giving the name of a structure as an instruction places an instance of it, each field as it
+//! was defined unless given a value in its place. A value shorter than its field is padded as by
+//! `DS`, one which is longer is an error:
+//!
+//! ```asm
+//! _origin: POINT              ; 0, 0, 7
+//! _corner: POINT 320, , 2     ; 320, 0, 2
+//! ```
+
+use super::{
+    errors::ParserError,
+    tokenizer::{self, LineMeta},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: Option<String>,
+    /// The directive laying out the field and its arguments, which are its default value
+    pub inst: String,
+    pub args: Vec<String>,
+    pub offset: u16,
+    pub width: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+impl Struct {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            fields: vec![],
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        self.fields.iter().map(|f| f.width).sum::<usize>() as u16
+    }
+
+    /// The symbol giving the offset of a field
+    pub fn symbol(&self, field: &str) -> String {
+        format!("{}.{}", self.name, field)
+    }
+
+    /// Adds a field after the others, giving its offset
+    pub fn push(&mut self, name: Option<String>, inst: &str, args: &[String], width: usize) -> u16 {
+        let offset = self.size();
+        self.fields.push(Field {
+            name,
+            inst: inst.to_string(),
+            args: args.to_vec(),
+            offset,
+            width,
+        });
+        offset
+    }
+
+    /// The lines of an instance, each field its default or else the value given in its place
+    ///
+    /// `width` is how many bytes a directive would place, to pad values shorter than their field.
+    pub fn instance(
+        &self,
+        values: &[String],
+        line_no: usize,
+        mut width: impl FnMut(&str, &[String]) -> Result<usize, ParserError>,
+    ) -> Result<Vec<LineMeta>, ParserError> {
+        if values.len() > self.fields.len() {
+            return Err(ParserError::WrongNumberOfArgs(
+                self.fields.len(),
+                values.len(),
+            ));
+        }
+        let mut lines = vec![];
+        for (idx, field) in self.fields.iter().enumerate() {
+            let value = match values.get(idx).filter(|v| !v.is_empty()) {
+                Some(value) => value,
+                None => {
+                    lines.push(line(&field.inst, &field.args, line_no)?);
+                    continue;
+                }
+            };
+            let inst = if field.inst == "DS" {
+                "DB"
+            } else {
+                &field.inst
+            };
+            let args = [value.to_string()];
+            let value_width = width(inst, &args)?;
+            if value_width > field.width {
+                let name = match &field.name {
+                    Some(name) => self.symbol(name),
+                    None => self.name.clone(),
+                };
+                return Err(ParserError::InvalidArgument(name, value.to_string()));
+            }
+            lines.push(line(inst, &args, line_no)?);
+            if value_width < field.width {
+                let pad = [(field.width - value_width).to_string()];
+                lines.push(line("DS", &pad, line_no)?);
+            }
+        }
+        Ok(lines)
+    }
+}
+
+fn line(inst: &str, args: &[String], line_no: usize) -> Result<LineMeta, ParserError> {
+    let raw_line = format!("        {} {}", inst, args.join(", "));
+    let mut line = tokenizer::tokenize(&raw_line)?.unwrap_or_default();
+    line.line_no = line_no;
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instances() {
+        let mut point = Struct::new("POINT");
+        assert_eq!(point.push(Some("X".into()), "DW", &["0".into()], 2), 0);
+        assert_eq!(point.push(None, "DS", &["2".into()], 2), 2);
+        assert_eq!(
+            point.push(Some("C".into()), "DB", &["7".into(), "8".into()], 2),
+            4
+        );
+        assert_eq!(point.size(), 6);
+        assert_eq!(point.symbol("C"), "POINT.C");
+
+        let width = |inst: &str, args: &[String]| match inst {
+            "DW" => Ok(2),
+            _ => Ok(args[0].len()),
+        };
+        let values = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let text = |lines: Vec<LineMeta>| {
+            lines
+                .iter()
+                .map(|l| format!("{} {}", l.inst.as_ref().unwrap(), l.args_list.join(",")))
+                .collect::<Vec<_>>()
+        };
+        let lines = point.instance(&values(&["", "1"]), 4, width).unwrap();
+        assert_eq!(text(lines.clone()), vec!["DW 0", "DB 1", "DS 1", "DB 7,8"]);
+        assert!(lines.iter().all(|l| l.line_no == 4));
+
+        assert!(matches!(
+            point.instance(&values(&["1", "2", "3", "4"]), 4, width),
+            Err(ParserError::WrongNumberOfArgs(3, 4))
+        ));
+        assert!(matches!(
+            point.instance(&values(&["", "", "'abc'"]), 4, width),
+            Err(ParserError::InvalidArgument(field, _)) if field == "POINT.C"
+        ));
+    }
+}
@@ -0,0 +1,367 @@
+//! Symbol tables and cross-references
+//!
+//! The text `.sym` format has one symbol per line, its value in hex, name and kind:
+//!
+//! ```text
+//! 0005 LEN EQU
+//! 0008 _MSG ADDR
+//! ```
+//!
+//! Lines may leave out the kind (`ADDR` is assumed) so classic two-column symbol files can be read
+//! too. Blank lines and those starting `;` are ignored.
+//!
+//! Files ending `.json` are written as a JSON array of `{"name", "value", "kind"}` objects instead.
+
+use std::{collections::HashMap, fmt, path::Path};
+
+use super::label::Label;
+
+#[derive(Debug)]
+pub enum SymbolError {
+    InvalidLine(usize, String),
+    InvalidValue(usize, String),
+    UnknownKind(usize, String),
+}
+
+impl std::error::Error for SymbolError {}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidLine(l, s) => write!(f, "Line {}: expected a value and name [{}]", l, s),
+            Self::InvalidValue(l, s) => write!(f, "Line {}: invalid value [{}]", l, s),
+            Self::UnknownKind(l, s) => write!(f, "Line {}: unknown kind [{}]", l, s),
+        }
+    }
+}
+
+pub fn is_json_file<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+}
+
+/// Symbols ordered by value then name
+fn sorted(symbols: &HashMap<String, Label>) -> Vec<(&String, &Label)> {
+    let mut sorted: Vec<(&String, &Label)> = symbols.iter().collect();
+    sorted.sort_by_key(|(name, label)| (label.value, *name));
+    sorted
+}
+
+pub fn write_text(symbols: &HashMap<String, Label>) -> String {
+    sorted(symbols)
+        .into_iter()
+        .map(|(name, label)| {
+            format!(
+                "{:04X} {} {}\n",
+                label.value.unwrap_or_default(),
+                name,
+                label.kind()
+            )
+        })
+        .collect()
+}
+
+pub fn write_json(symbols: &HashMap<String, Label>) -> String {
+    let entries: Vec<String> = sorted(symbols)
+        .into_iter()
+        .map(|(name, label)| {
+            format!(
+                "  {{\"name\": \"{}\", \"value\": {}, \"kind\": \"{}\"}}",
+                name.replace('\\', "\\\\").replace('"', "\\\""),
+                label.value.unwrap_or_default(),
+                label.kind()
+            )
+        })
+        .collect();
+    if entries.is_empty() {
+        "[]\n".to_string()
+    } else {
+        format!("[\n{}\n]\n", entries.join(",\n"))
+    }
+}
+
+pub fn read_text(text: &str) -> Result<HashMap<String, Label>, SymbolError> {
+    let mut symbols = HashMap::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line_no = idx + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            return Err(SymbolError::InvalidLine(line_no, line.to_string()));
+        }
+        let value = u16::from_str_radix(parts[0], 16)
+            .map_err(|_| SymbolError::InvalidValue(line_no, parts[0].to_string()))?;
+        let label = match parts.get(2).map(|k| k.to_ascii_uppercase()).as_deref() {
+            None | Some("ADDR") => Label::new_addr(Some(value)),
+            Some("EQU") => Label::new_equ(Some(value)),
+            Some("SET") => Label::new_set(Some(value)),
+            Some(kind) => return Err(SymbolError::UnknownKind(line_no, kind.to_string())),
+        };
+        symbols.insert(parts[1].to_uppercase(), label);
+    }
+    Ok(symbols)
+}
+
+/// Names of address labels by their value, for showing where code is
+///
+/// If several labels share an address, the first alphabetically is used.
+pub fn addresses(symbols: &HashMap<String, Label>) -> HashMap<u16, String> {
+    let mut addresses: HashMap<u16, String> = HashMap::new();
+    for (name, label) in symbols.iter().filter(|(_, l)| l.is_addr) {
+        if let Some(value) = label.value {
+            let entry = addresses.entry(value).or_insert_with(|| name.to_string());
+            if name < entry {
+                *entry = name.to_string();
+            }
+        }
+    }
+    addresses
+}
+
+/// Each symbol with the line defining it and every line referring to it
+///
+/// Lines are given as they should be shown, such as `12` or `lib.asm:3` for an included file.
+pub fn write_xref(
+    symbols: &HashMap<String, Label>,
+    definitions: &HashMap<String, String>,
+    references: &HashMap<String, Vec<String>>,
+) -> String {
+    let mut names: Vec<&String> = symbols.keys().collect();
+    names.sort();
+    let mut out = format!(
+        "{:<24} {:<5} {:<5} {:>7}  REFERENCES\n",
+        "SYMBOL", "VALUE", "KIND", "DEFINED"
+    );
+    for name in names {
+        let label = symbols[name];
+        let defined = definitions.get(name).map(String::as_str).unwrap_or("-");
+        let refs = references
+            .get(name)
+            .map(|lines| lines.join(" "))
+            .unwrap_or_default();
+        let row = format!(
+            "{:<24} {:04X}  {:<5} {:>7}  {}",
+            name,
+            label.value.unwrap_or_default(),
+            label.kind(),
+            defined,
+            refs
+        );
+        out.push_str(row.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Identifiers used in some source text, uppercased, ignoring strings and numbers
+pub fn identifiers(text: &str) -> Vec<String> {
+    let mut idents = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\'' || c == '"' {
+            let quote = c;
+            let mut escaped = false;
+            for c in chars.by_ref() {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == quote {
+                    break;
+                }
+            }
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut word = c.to_string();
+            while let Some(&c) = chars.peek() {
+                if !is_word_char(c) {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            if !word.starts_with(|c: char| c.is_numeric()) {
+                idents.push(word.to_uppercase());
+            }
+        }
+    }
+    idents
+}
+
+/// `text` with each call of `function`, such as `DEFINED(name)`, outside of strings replaced by
+/// what `value` gives for the name
+///
+/// Any not followed by a bracketed name are left for the expression parser to complain about.
+pub fn resolve_calls<E>(
+    text: &str,
+    function: &str,
+    mut value: impl FnMut(&str) -> Result<String, E>,
+) -> Result<String, E> {
+    if !text.to_uppercase().contains(function) {
+        return Ok(text.to_string());
+    }
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        out.push(c);
+        if c == '\'' || c == '"' {
+            let quote = c;
+            let mut escaped = false;
+            for c in chars.by_ref() {
+                out.push(c);
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == quote {
+                    break;
+                }
+            }
+        } else if is_word_char(c) {
+            let mut word = c.to_string();
+            while let Some(&c) = chars.peek().filter(|c| is_word_char(**c)) {
+                word.push(c);
+                out.push(c);
+                chars.next();
+            }
+            if !word.eq_ignore_ascii_case(function) {
+                continue;
+            }
+            let rest: String = chars.clone().collect();
+            let name = rest
+                .trim_start()
+                .strip_prefix('(')
+                .and_then(|rest| rest.split_once(')'))
+                .map(|(name, _)| name.trim())
+                .filter(|name| !name.starts_with(char::is_numeric))
+                .filter(|name| !name.is_empty() && name.chars().all(is_word_char));
+            if let Some(name) = name {
+                out.truncate(out.len() - word.len());
+                out.push_str(&value(&name.to_uppercase())?);
+                // Past the closing bracket
+                for c in chars.by_ref() {
+                    if c == ')' {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Whether a character may be part of a symbol, `.` joining a structure's name to a field's
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> HashMap<String, Label> {
+        let mut symbols = HashMap::new();
+        symbols.insert("_MSG".to_string(), Label::new_addr(Some(0x08)));
+        symbols.insert("LEN".to_string(), Label::new_equ(Some(5)));
+        symbols.insert("COUNT".to_string(), Label::new_set(Some(0x100)));
+        symbols
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let text = write_text(&symbols());
+        assert_eq!(text, "0005 LEN EQU\n0008 _MSG ADDR\n0100 COUNT SET\n");
+
+        let read = read_text(&text).unwrap();
+        assert_eq!(read.len(), 3);
+        assert!(read["LEN"].is_eq);
+        assert!(read["COUNT"].is_set);
+        assert_eq!(read["_MSG"].value, Some(8));
+    }
+
+    #[test]
+    fn reads_classic_files() {
+        let read = read_text("; comment\n\n0100 start\n").unwrap();
+        assert!(read["START"].is_addr);
+        assert_eq!(read["START"].value, Some(0x100));
+
+        assert!(matches!(
+            read_text("0100"),
+            Err(SymbolError::InvalidLine(1, _))
+        ));
+        assert!(matches!(
+            read_text("zz START"),
+            Err(SymbolError::InvalidValue(1, _))
+        ));
+        assert!(matches!(
+            read_text("0100 START WHAT"),
+            Err(SymbolError::UnknownKind(1, _))
+        ));
+    }
+
+    #[test]
+    fn json() {
+        let json = write_json(&symbols());
+        assert_eq!(
+            json,
+            "[\n  {\"name\": \"LEN\", \"value\": 5, \"kind\": \"EQU\"},\n  \
+             {\"name\": \"_MSG\", \"value\": 8, \"kind\": \"ADDR\"},\n  \
+             {\"name\": \"COUNT\", \"value\": 256, \"kind\": \"SET\"}\n]\n"
+        );
+        assert_eq!(write_json(&HashMap::new()), "[]\n");
+    }
+
+    #[test]
+    fn finds_identifiers() {
+        assert_eq!(
+            identifiers("_msg + 0x10, 'a_b', LEN AND 12H, point.x"),
+            vec!["_MSG", "LEN", "AND", "POINT.X"]
+        );
+    }
+
+    #[test]
+    fn resolves_calls() {
+        let defined = |name: &str| Ok::<_, ()>((name == "DEBUG").to_string());
+        assert_eq!(
+            resolve_calls("DEFINED(DEBUG) AND NOT defined ( _x )", "DEFINED", defined),
+            Ok("true AND NOT false".to_string())
+        );
+        assert_eq!(
+            resolve_calls("'DEFINED(DEBUG)', UNDEFINED, DEFINED", "DEFINED", defined),
+            Ok("'DEFINED(DEBUG)', UNDEFINED, DEFINED".to_string())
+        );
+        let size = |name: &str| match name {
+            "POINT" => Ok("4".to_string()),
+            _ => Err(name.to_string()),
+        };
+        assert_eq!(
+            resolve_calls("POINT.X + SIZEOF(point)", "SIZEOF", size),
+            Ok("POINT.X + 4".to_string())
+        );
+        assert_eq!(
+            resolve_calls("SIZEOF(LINE)", "SIZEOF", size),
+            Err("LINE".to_string())
+        );
+    }
+
+    #[test]
+    fn xref() {
+        let mut definitions = HashMap::new();
+        definitions.insert("_MSG".to_string(), "14".to_string());
+        let mut references = HashMap::new();
+        references.insert(
+            "_MSG".to_string(),
+            vec!["7".to_string(), "lib.asm:9".to_string()],
+        );
+        let out = write_xref(&symbols(), &definitions, &references);
+        let rows: Vec<&str> = out.lines().collect();
+        assert_eq!(rows[1], "COUNT                    0100  SET         -");
+        assert_eq!(
+            rows[3],
+            "_MSG                     0008  ADDR       14  7 lib.asm:9"
+        );
+    }
+}
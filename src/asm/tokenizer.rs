@@ -15,7 +15,8 @@ pub struct LineMeta {
     pub label_only: bool,
     pub address: u16,
     pub width: usize,
-    pub uses_pc: bool,
+    /// For macro calls, the lines of the macro as expanded at this call
+    pub expansion: Option<Vec<LineMeta>>,
 }
 
 impl LineMeta {
@@ -48,13 +49,31 @@ impl LineMeta {
     }
 }
 
+/// The first `c` in `line` which isn't within quotes
+pub(crate) fn find_unquoted(line: &str, c: char) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (idx, ch) in line.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if ch == '\\' => escaped = true,
+            Some(q) if ch == q => quote = None,
+            Some(_) => {}
+            None if ch == '\'' || ch == '"' => quote = Some(ch),
+            None if ch == c => return Some(idx),
+            None => {}
+        }
+    }
+    None
+}
+
 // A more robust system wouldn't be a bad idea, but as the syntax is fairly simple might as
 // well do it fairly simply
 pub fn tokenize(raw_line: &str) -> Result<Option<LineMeta>, ParserError> {
     let mut line = raw_line.trim();
     let mut comment: Option<String> = None;
-    // Check for a comment in the line
-    if let Some(comment_idx) = line.find(';') {
+    // Check for a comment in the line, a `;` in a string is just a character
+    if let Some(comment_idx) = find_unquoted(line, ';') {
         if line.len() > comment_idx {
             comment = Some(line[comment_idx + 1..].trim().to_string());
         }
@@ -68,7 +87,7 @@ pub fn tokenize(raw_line: &str) -> Result<Option<LineMeta>, ParserError> {
 
     let mut label: Option<String> = None;
     // Check if some label precedes the instruction
-    if let Some(label_idx) = line.find(':') {
+    if let Some(label_idx) = find_unquoted(line, ':') {
         let label_str = line[..label_idx].trim().to_uppercase();
         if label_str.is_empty() {
             return Err(ParserError::InvalidLabel(label_str));
@@ -77,7 +96,9 @@ pub fn tokenize(raw_line: &str) -> Result<Option<LineMeta>, ParserError> {
             if idx == 0 && c != '_' && !c.is_alphabetic() {
                 return Err(ParserError::InvalidLabel(label_str));
             }
-            if c != '_' && !c.is_alphabetic() && !c.is_numeric() {
+            // A `.` is only in the symbols of a structure's fields, an `&` joins a macro's
+            // parameter to the rest of a label and is gone once it's expanded
+            if !matches!(c, '_' | '.' | '&') && !c.is_alphabetic() && !c.is_numeric() {
                 return Err(ParserError::InvalidLabel(label_str));
             }
         }
@@ -108,14 +129,20 @@ pub fn tokenize(raw_line: &str) -> Result<Option<LineMeta>, ParserError> {
     let mut args_list: Vec<String> = vec![];
     // If any args exists...
     if let Some(args) = raw_args {
-        let mut in_quotes: bool = false;
+        let mut quote: Option<char> = None;
         let mut char_escaped: bool = false;
         let mut this_arg: String = String::new();
         for (idx, c) in args.chars().enumerate() {
-            // Start or stop "being" in a string unless escaped
-            if c == '\'' && !char_escaped {
-                in_quotes = !in_quotes;
+            // Start or stop "being" in a string unless escaped, a doubled quote closes and opens
+            // the string again
+            if (c == '\'' || c == '"') && !char_escaped {
+                match quote {
+                    None => quote = Some(c),
+                    Some(q) if q == c => quote = None,
+                    Some(_) => {}
+                }
             }
+            let in_quotes = quote.is_some();
             // If we are escaping this character, unset it
             if char_escaped {
                 char_escaped = false;
@@ -137,7 +164,7 @@ pub fn tokenize(raw_line: &str) -> Result<Option<LineMeta>, ParserError> {
                 }
             }
             if idx == args.len() - 1 {
-                if in_quotes {
+                if quote.is_some() {
                     return Err(ParserError::UnterminatedString(args));
                 }
                 args_list.push(this_arg.trim().to_string());
@@ -270,6 +297,19 @@ mod tests {
         assert_eq!(meta.args_list, vec!["'fish, other fish'", "B"], "args");
     }
 
+    #[test]
+    fn double_quoted_arg_with_quotes() {
+        let line = "DB \"it's, fish\", 'don''t, b', b".to_string();
+        let meta = tokenize(&line).expect("tokenizer failed");
+        assert!(meta.is_some(), "should be a valid line");
+        let meta = meta.unwrap();
+        assert_eq!(
+            meta.args_list,
+            vec!["\"it's, fish\"", "'don''t, b'", "B"],
+            "args"
+        );
+    }
+
     #[test]
     fn string_arg_with_other_phrases() {
         let line = "MOV XOR 'fish' + 1, B".to_string();
@@ -295,4 +335,17 @@ mod tests {
     }
 
     // strings
+
+    #[test]
+    fn comments_and_labels_outside_strings() {
+        let meta = tokenize("_s: DB 'a;b:c', ';', 'it''s' ; a note")
+            .unwrap()
+            .unwrap();
+        assert_eq!(meta.label, Some("_S".to_string()));
+        assert_eq!(meta.args_list, vec!["'a;b:c'", "';'", "'it''s'"]);
+        assert_eq!(meta.comment, Some("a note".to_string()));
+
+        let meta = tokenize("        DB ':'").unwrap().unwrap();
+        assert_eq!(meta.label, None);
+    }
 }
@@ -0,0 +1,217 @@
+//! Warnings for source which assembles but is almost certainly not what was meant
+//!
+//! Each warning has a name to turn it on or off with `-W`:
+//!
+//! | Name           | Code | Given for                                                     |
+//! |----------------|------|---------------------------------------------------------------|
+//! | `truncation`   | W001 | a value too wide for the byte it's stored in, by `MVI` or `DB` |
+//! | `unused-label` | W002 | an address label nothing refers to, off unless asked for      |
+//! | `shadowed-equ` | W003 | a `SET` of a symbol already defined by `EQU`                  |
+//! | `fall-through` | W004 | an instruction which runs straight on into `DB` data          |
+//! | `overlap`      | W005 | an `ORG` region writing over bytes already assembled          |
+//!
+//! `-W name` turns one on and `-W no-name` off, `-W all` turns them all on. `-W error` makes every
+//! warning given an error, so nothing is written.
+//!
+//! Values from `0xFF80` up are taken to be negative bytes, `MVI A, 0 - 1` isn't truncated.
+
+use std::{collections::HashSet, fmt};
+
+/// Names of the warnings, as given to `-W`
+pub const NAMES: [&str; 5] = [
+    "truncation",
+    "unused-label",
+    "shadowed-equ",
+    "fall-through",
+    "overlap",
+];
+
+/// Everything `-W` accepts
+pub const FLAGS: &[&str] = &[
+    "all",
+    "error",
+    "truncation",
+    "no-truncation",
+    "unused-label",
+    "no-unused-label",
+    "shadowed-equ",
+    "no-shadowed-equ",
+    "fall-through",
+    "no-fall-through",
+    "overlap",
+    "no-overlap",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// The expression and the value it had
+    Truncation(String, u16),
+    UnusedLabel(String),
+    ShadowedEqu(String),
+    /// The instruction which runs on into the data
+    FallThrough(String),
+    /// The first address written over and how many bytes were
+    Overlap(u16, usize),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Truncation(s, v) => write!(
+                f,
+                "{} is {:#06X}, only the low byte {:#04X} is kept",
+                s, v, *v as u8
+            ),
+            Self::UnusedLabel(s) => write!(f, "Label {} is never used", s),
+            Self::ShadowedEqu(s) => write!(f, "SET of {} which was defined by EQU", s),
+            Self::FallThrough(s) => write!(f, "Execution runs on from {} into data", s),
+            Self::Overlap(a, 1) => write!(
+                f,
+                "1 byte at {:#06X} was already assembled and is overwritten",
+                a
+            ),
+            Self::Overlap(a, n) => write!(
+                f,
+                "{} bytes from {:#06X} were already assembled and are overwritten",
+                n, a
+            ),
+        }
+    }
+}
+
+impl Warning {
+    /// The name which turns the warning on and off
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Truncation(..) => NAMES[0],
+            Self::UnusedLabel(_) => NAMES[1],
+            Self::ShadowedEqu(_) => NAMES[2],
+            Self::FallThrough(_) => NAMES[3],
+            Self::Overlap(..) => NAMES[4],
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Truncation(..) => "W001",
+            Self::UnusedLabel(_) => "W002",
+            Self::ShadowedEqu(_) => "W003",
+            Self::FallThrough(_) => "W004",
+            Self::Overlap(..) => "W005",
+        }
+    }
+
+    /// The part of the line at fault, if the warning names one
+    pub fn culprit(&self) -> Option<&str> {
+        match self {
+            Self::Truncation(s, _) | Self::UnusedLabel(s) | Self::ShadowedEqu(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a value stored in a byte loses anything
+pub fn truncates(value: u16) -> bool {
+    value > 0xff && value < 0xff80
+}
+
+/// Which warnings are given, and whether they're errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warnings {
+    enabled: HashSet<&'static str>,
+    pub error: bool,
+}
+
+impl Default for Warnings {
+    fn default() -> Self {
+        Self {
+            enabled: NAMES
+                .iter()
+                .copied()
+                .filter(|name| *name != "unused-label")
+                .collect(),
+            error: false,
+        }
+    }
+}
+
+impl Warnings {
+    /// The defaults changed by each `-W` flag in turn, unknown flags are ignored
+    pub fn from_flags<S: AsRef<str>>(flags: &[S]) -> Self {
+        let mut warnings = Self::default();
+        for flag in flags.iter().map(|f| f.as_ref()) {
+            match flag {
+                "all" => warnings.enabled.extend(NAMES),
+                "error" => warnings.error = true,
+                flag => {
+                    let (name, on) = match flag.strip_prefix("no-") {
+                        Some(name) => (name, false),
+                        None => (flag, true),
+                    };
+                    if let Some(name) = NAMES.iter().find(|n| **n == name) {
+                        if on {
+                            warnings.enabled.insert(name);
+                        } else {
+                            warnings.enabled.remove(name);
+                        }
+                    }
+                }
+            }
+        }
+        warnings
+    }
+
+    pub fn enabled(&self, warning: &Warning) -> bool {
+        self.enabled.contains(warning.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags() {
+        let unused = Warning::UnusedLabel("L".to_string());
+        let truncation = Warning::Truncation("0X100".to_string(), 0x100);
+
+        let warnings = Warnings::default();
+        assert!(!warnings.enabled(&unused));
+        assert!(warnings.enabled(&truncation));
+        assert!(!warnings.error);
+
+        let warnings = Warnings::from_flags(&["unused-label", "no-truncation", "error"]);
+        assert!(warnings.enabled(&unused));
+        assert!(!warnings.enabled(&truncation));
+        assert!(warnings.error);
+
+        let warnings = Warnings::from_flags(&["no-truncation", "all"]);
+        assert!(NAMES.iter().all(|name| warnings.enabled.contains(name)));
+        assert!(NAMES.iter().all(|name| FLAGS.contains(name)));
+    }
+
+    #[test]
+    fn truncation() {
+        assert!(!truncates(0xff));
+        assert!(truncates(0x100));
+        assert!(truncates(0xff7f));
+        assert!(!truncates(0xff80));
+        assert!(!truncates(0xffff));
+        assert_eq!(
+            Warning::Truncation("0X1234".to_string(), 0x1234).to_string(),
+            "0X1234 is 0x1234, only the low byte 0x34 is kept"
+        );
+    }
+
+    #[test]
+    fn overlap() {
+        assert_eq!(
+            Warning::Overlap(0, 1).to_string(),
+            "1 byte at 0x0000 was already assembled and is overwritten"
+        );
+        assert_eq!(
+            Warning::Overlap(0x10, 3).to_string(),
+            "3 bytes from 0x0010 were already assembled and are overwritten"
+        );
+    }
+}
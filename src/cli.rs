@@ -5,12 +5,16 @@
 //! - `run` to access the emulator
 //! - `assemble` to access the assembler
 //! - `disassemble` to access the disassembler
+//! - `test` to run the tests written in an ASM file
+//! - `link` to combine objects written by `assemble -c` into a program
 //!
 //! Use the `--help` option for each subcommand to find out more...
 
 use std::path::PathBuf;
 
-use clap::{self, Args, Parser, Subcommand};
+use clap::{self, ArgEnum, Args, Parser, Subcommand};
+
+use crate::asm::warnings;
 
 #[derive(Debug, Parser)]
 #[clap(name = "i8080", about = "An I8080 emulator", long_about = None)]
@@ -26,6 +30,8 @@ pub enum Commands {
     Assemble(AssembleArgs),
     #[clap(visible_alias = "dis")]
     Disassemble(DisassembleArgs),
+    Test(TestArgs),
+    Link(LinkArgs),
 }
 
 #[derive(Debug, Args)]
@@ -33,7 +39,12 @@ pub enum Commands {
 pub struct RunArgs {
     #[clap(help = "File to load into memory")]
     pub file: PathBuf,
-    #[clap(short, long, help = "Load program at given address")]
+    #[clap(
+        short,
+        long,
+        parse(try_from_str = parse_address),
+        help = "Load program at given address"
+    )]
     pub load_at: Option<u16>,
     #[clap(short, long, help = "Randomize registers and memory")]
     pub randomize: bool,
@@ -43,12 +54,55 @@ pub struct RunArgs {
     pub assemble: bool,
     #[clap(long, help = "Disable the console device")]
     pub no_console: bool,
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "buffered",
+        help = "How the console device displays output"
+    )]
+    pub console: ConsoleMode,
+    #[clap(
+        long,
+        help = "Print the virtual terminal's screen at halt instead of drawing it live"
+    )]
+    pub dump_screen: bool,
     #[clap(long, help = "Sleep occasionally to match 2HZ")]
     pub emulate_clock_speed: bool,
+    #[clap(
+        long,
+        conflicts_with = "no-console",
+        help = "Write console output to a file"
+    )]
+    pub console_out: Option<PathBuf>,
+    #[clap(
+        long,
+        conflicts_with = "no-console",
+        help = "Compare console output against a file, failing if they differ"
+    )]
+    pub expect_output: Option<PathBuf>,
+    #[clap(long, help = "Symbol file naming addresses in interactive mode")]
+    pub symbols: Option<PathBuf>,
 }
 
-#[derive(Debug, Args)]
-#[clap(about = "Assemble a file into a bin")]
+/// Display modes of the console device
+///
+/// - `buffered` prints text when a NUL or ETB is received
+/// - `stream` prints each character as it arrives
+/// - `vt52` and `vt100` interpret escape sequences on a virtual 80x24 screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum ConsoleMode {
+    Buffered,
+    Stream,
+    Vt52,
+    Vt100,
+}
+
+#[derive(Debug, Args, Default)]
+#[clap(
+    about = "Assemble a file into a bin",
+    after_help = "Sizes and addresses may be given in decimal, in hex with 0x or in KiB with a K \
+                  suffix, so a 2716 is --split 2K."
+)]
 pub struct AssembleArgs {
     #[clap(help = "ASM file to assemble")]
     pub input: PathBuf,
@@ -56,10 +110,45 @@ pub struct AssembleArgs {
     pub output: PathBuf,
     #[clap(long, help = "Add a HLT instruction at the end of the program")]
     pub hlt: bool,
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "bin",
+        help = "Format of the output file"
+    )]
+    pub format: OutputFormat,
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "native",
+        help = "Syntax of the source"
+    )]
+    pub dialect: Dialect,
+    #[clap(long, help = "Write a listing of the assembled program")]
+    pub listing: Option<PathBuf>,
+    #[clap(long, help = "Write the symbol table, as JSON if the file ends .json")]
+    pub symbols: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Write a cross-reference of where symbols are defined and used"
+    )]
+    pub xref: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Write a Makefile rule for the output and every file it was built from"
+    )]
+    pub deps: Option<PathBuf>,
+    #[clap(
+        short = 'I',
+        long = "include-dir",
+        help = "Directory to search for INCLUDE and INCBIN files"
+    )]
+    pub include_dirs: Vec<PathBuf>,
 
     #[clap(
         long,
         default_value = "0",
+        parse(try_from_str = parse_address),
         help = "Address at which the file will be loaded"
     )]
     pub load_at: u16,
@@ -70,6 +159,214 @@ pub struct AssembleArgs {
         help = "Include register EQU statements"
     )]
     pub register_definitions: bool,
+
+    #[clap(
+        short = 'D',
+        long = "define",
+        value_name = "NAME[=EXPR]",
+        help = "Define a symbol before assembling, as 1 if no value is given"
+    )]
+    pub defines: Vec<String>,
+
+    #[clap(
+        short = 'U',
+        long = "undefine",
+        value_name = "NAME",
+        help = "Remove a symbol defined before assembling, such as a register definition"
+    )]
+    pub undefines: Vec<String>,
+
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "human",
+        help = "How errors are printed"
+    )]
+    pub error_format: ErrorFormat,
+
+    #[clap(
+        short = 'W',
+        long = "warn",
+        value_name = "WARNING",
+        possible_values = warnings::FLAGS,
+        help = "Turn a warning on, or off as no-WARNING, all turns every one on and error makes \
+                them errors"
+    )]
+    pub warnings: Vec<String>,
+
+    #[clap(
+        short = 'c',
+        long,
+        help = "Write a relocatable object, to be linked by `link`, rather than a program"
+    )]
+    pub object: bool,
+
+    #[clap(
+        short = 'E',
+        long,
+        conflicts_with = "object",
+        help = "Write the source with IFs resolved and macros expanded rather than the program"
+    )]
+    pub preprocess: bool,
+
+    #[clap(flatten)]
+    pub rom: RomArgs,
+}
+
+impl AssembleArgs {
+    /// Whether a relocatable object is written rather than a program
+    pub fn writes_object(&self) -> bool {
+        self.object || self.format == OutputFormat::Rel
+    }
+
+    /// Whether the registers are symbols, as they always are to DRI's assemblers
+    pub fn defines_registers(&self) -> bool {
+        self.register_definitions || self.dialect == Dialect::Dri
+    }
+}
+
+// Shaping a `bin` into the image burnt to a ROM, a plain comment as clap would take a doc comment
+// as the about of `asm`
+#[derive(Debug, Args, Default)]
+pub struct RomArgs {
+    #[clap(
+        long,
+        value_name = "BYTE",
+        default_value = "0",
+        parse(try_from_str = parse_byte),
+        help = "Byte filling gaps between ORG regions and any padding"
+    )]
+    pub fill: u8,
+    #[clap(
+        long,
+        value_name = "SIZE",
+        parse(try_from_str = parse_size),
+        help = "Pad the image from --load-at to this many bytes"
+    )]
+    pub pad: Option<usize>,
+    #[clap(
+        long,
+        arg_enum,
+        requires = "checksum-at",
+        help = "Checksum of the image to insert"
+    )]
+    pub checksum: Option<Checksum>,
+    #[clap(
+        long,
+        value_name = "ADDR",
+        requires = "checksum",
+        parse(try_from_str = parse_size),
+        help = "Address of the checksum within the image, little-endian if two bytes"
+    )]
+    pub checksum_at: Option<usize>,
+    #[clap(
+        long,
+        value_name = "SIZE",
+        parse(try_from_str = parse_chunk_size),
+        help = "Also write the image as files of this many bytes, OUTPUT.0, OUTPUT.1..."
+    )]
+    pub split: Option<usize>,
+}
+
+impl RomArgs {
+    /// Whether any option beyond the fill byte changes the image
+    pub fn shapes_image(&self) -> bool {
+        self.pad.is_some() || self.checksum.is_some() || self.split.is_some()
+    }
+}
+
+/// Checksums which can be put in a ROM image
+///
+/// - `sum8` is the low byte of the sum of every byte
+/// - `sum16` is the sum of every byte, in two bytes
+/// - `crc16` is CRC-16/CCITT-FALSE, polynomial 0x1021 starting from 0xFFFF
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Checksum {
+    Sum8,
+    Sum16,
+    Crc16,
+}
+
+/// A size or address, decimal, `0x` hex or a number of KiB such as `4K`
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let invalid = || format!("invalid size '{}'", s);
+    if let Some(kib) = s.strip_suffix(['K', 'k']) {
+        return kib
+            .parse::<usize>()
+            .map(|k| k * 1024)
+            .map_err(|_| invalid());
+    }
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| invalid())
+}
+
+fn parse_chunk_size(s: &str) -> Result<usize, String> {
+    match parse_size(s)? {
+        0 => Err("chunks can't be empty".to_string()),
+        size => Ok(size),
+    }
+}
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let value = parse_size(s)?;
+    u8::try_from(value).map_err(|_| format!("{} doesn't fit in a byte", s))
+}
+
+fn parse_address(s: &str) -> Result<u16, String> {
+    let value = parse_size(s)?;
+    u16::try_from(value).map_err(|_| format!("{} is outside the address space", s))
+}
+
+/// Formats the assembler can write
+///
+/// - `bin` is a raw image, gaps between `ORG` regions are zero-filled unless `--fill` is given
+/// - `ihex` is Intel HEX covering only the assembled regions
+/// - `rel` is a Microsoft M80 relocatable module, an object as with `-c` which L80 can also link
+/// - `prl` is a CP/M or MP/M page relocatable program, assembled at 0x0100 whatever `--load-at`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Default)]
+pub enum OutputFormat {
+    #[default]
+    Bin,
+    Ihex,
+    Rel,
+    Prl,
+}
+
+/// Formats the linker can write
+///
+/// - `bin` is a raw image from the lowest address placed, gaps are zero-filled
+/// - `ihex` is Intel HEX covering only the code and data
+/// - `com` is a CP/M program, code is placed from 0x100 where it must start
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum LinkFormat {
+    Bin,
+    Ihex,
+    Com,
+}
+
+/// Syntaxes the assembler reads, see [dialect](crate::asm::dialect)
+///
+/// - `native` is the assembler's own, labels end with a colon
+/// - `dri` is that of Digital Research's ASM and MAC for CP/M
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Default)]
+pub enum Dialect {
+    #[default]
+    Native,
+    Dri,
+}
+
+/// How the assembler prints its diagnostics
+///
+/// - `human` shows each with the line at fault and the offending part underlined
+/// - `json` gives each as a JSON object on a line of its own, for editors to read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Default)]
+pub enum ErrorFormat {
+    #[default]
+    Human,
+    Json,
 }
 
 #[derive(Debug, Args)]
@@ -79,4 +376,72 @@ pub struct DisassembleArgs {
     pub input: PathBuf,
     #[clap(short, long, help = "Output filename")]
     pub output: Option<PathBuf>,
+    #[clap(long, help = "Symbol file naming addresses")]
+    pub symbols: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+#[clap(about = "Run the tests in an ASM file")]
+pub struct TestArgs {
+    #[clap(help = "ASM file containing tests")]
+    pub input: PathBuf,
+    #[clap(
+        long,
+        default_value = "1000000",
+        help = "Cycles a test may use before it fails"
+    )]
+    pub cycles: u64,
+    #[clap(long, help = "Only run tests whose names contain this")]
+    pub filter: Option<String>,
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "native",
+        help = "Syntax of the source"
+    )]
+    pub dialect: Dialect,
+    #[clap(long, help = "Write a JUnit XML report")]
+    pub junit: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+#[clap(about = "Link relocatable objects into a program")]
+pub struct LinkArgs {
+    #[clap(
+        required = true,
+        help = "Object files written by `assemble -c`, or M80 .REL files"
+    )]
+    pub inputs: Vec<PathBuf>,
+    #[clap(
+        short = 'l',
+        long = "library",
+        help = "Object or .REL file of modules only linked when they give a symbol needed"
+    )]
+    pub libraries: Vec<PathBuf>,
+    #[clap(short, long, default_value = "a.out", help = "Output filename")]
+    pub output: PathBuf,
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "bin",
+        help = "Format of the output file"
+    )]
+    pub format: LinkFormat,
+    #[clap(
+        long,
+        parse(try_from_str = parse_address),
+        help = "Address at which code is placed, 0 if not given or 256 for com"
+    )]
+    pub code_at: Option<u16>,
+    #[clap(
+        long,
+        parse(try_from_str = parse_address),
+        help = "Address at which data is placed, after the code if not given"
+    )]
+    pub data_at: Option<u16>,
+    #[clap(
+        long,
+        help = "Where to write the map of modules and symbols, the output with .map if not given"
+    )]
+    pub map: Option<PathBuf>,
 }
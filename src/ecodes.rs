@@ -4,3 +4,6 @@ pub const E_SUCCESS: i32 = 0;
 pub const E_ASSEMBLER: i32 = 1;
 pub const E_DISASSEMBLER: i32 = 2;
 pub const E_IO_ERROR: i32 = 3;
+pub const E_OUTPUT_MISMATCH: i32 = 4;
+pub const E_TEST_FAILURE: i32 = 5;
+pub const E_LINKER: i32 = 6;
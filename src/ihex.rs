@@ -0,0 +1,220 @@
+//! Intel HEX images
+//!
+//! Only the record types an 8080 has any use for are supported: data (`00`), end of file (`01`)
+//! and start segment address (`03`), whose IP half is taken as the start address. Data records
+//! hold at most 16 bytes.
+
+use std::{fmt, path::Path};
+
+const RECORD_DATA: u8 = 0x00;
+const RECORD_EOF: u8 = 0x01;
+const RECORD_START_SEGMENT: u8 = 0x03;
+const BYTES_PER_RECORD: usize = 16;
+
+#[derive(Debug)]
+pub enum IhexError {
+    MissingColon(usize),
+    InvalidHex(usize),
+    BadLength(usize),
+    BadChecksum(usize, u8, u8),
+    UnsupportedRecord(usize, u8),
+    NoEndOfFile,
+}
+
+impl std::error::Error for IhexError {}
+
+impl fmt::Display for IhexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingColon(l) => write!(f, "Line {}: record doesn't start with ':'", l),
+            Self::InvalidHex(l) => write!(f, "Line {}: record isn't valid hex", l),
+            Self::BadLength(l) => write!(f, "Line {}: record length doesn't match", l),
+            Self::BadChecksum(l, exp, act) => write!(
+                f,
+                "Line {}: checksum is {:#04x}, expected {:#04x}",
+                l, act, exp
+            ),
+            Self::UnsupportedRecord(l, t) => {
+                write!(f, "Line {}: unsupported record type {:#04x}", l, t)
+            }
+            Self::NoEndOfFile => write!(f, "No end of file record found"),
+        }
+    }
+}
+
+/// Blocks of bytes at their load addresses, along with where execution should begin
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    pub regions: Vec<(u16, Vec<u8>)>,
+    pub start: Option<u16>,
+}
+
+impl Image {
+    /// Lowest address of any region
+    pub fn base(&self) -> Option<u16> {
+        self.regions.iter().map(|(addr, _)| *addr).min()
+    }
+
+    /// The regions as one binary from the lowest address, gaps between them zero-filled
+    pub fn flatten(&self) -> Vec<u8> {
+        let base = self.base().unwrap_or_default() as usize;
+        let mut bytes = vec![];
+        for (addr, region) in self.regions.iter() {
+            let from = *addr as usize - base;
+            if bytes.len() < from + region.len() {
+                bytes.resize(from + region.len(), 0);
+            }
+            bytes[from..from + region.len()].copy_from_slice(region);
+        }
+        bytes
+    }
+}
+
+/// Whether a file should be treated as Intel HEX, going by its extension
+pub fn is_hex_file<P: AsRef<Path>>(path: P) -> bool {
+    matches!(
+        path.as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .as_deref(),
+        Some("hex") | Some("ihx")
+    )
+}
+
+pub fn encode(image: &Image) -> String {
+    let mut out = String::new();
+    for (addr, bytes) in image.regions.iter() {
+        for (idx, chunk) in bytes.chunks(BYTES_PER_RECORD).enumerate() {
+            let at = addr.wrapping_add((idx * BYTES_PER_RECORD) as u16);
+            out.push_str(&record(RECORD_DATA, at, chunk));
+        }
+    }
+    if let Some(start) = image.start {
+        let mut data = vec![0, 0];
+        data.extend_from_slice(&start.to_be_bytes());
+        out.push_str(&record(RECORD_START_SEGMENT, 0, &data));
+    }
+    out.push_str(&record(RECORD_EOF, 0, &[]));
+    out
+}
+
+fn record(kind: u8, addr: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&addr.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    bytes.push(checksum(&bytes));
+    let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(":{}\n", hex)
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |acc, b| acc.wrapping_add(*b))
+        .wrapping_neg()
+}
+
+/// Reads data records into regions, records which follow on from one another are joined
+pub fn decode(text: &str) -> Result<Image, IhexError> {
+    let mut image = Image::default();
+    for (idx, line) in text.lines().enumerate() {
+        let line_no = idx + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let hex = line
+            .strip_prefix(':')
+            .ok_or(IhexError::MissingColon(line_no))?;
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(IhexError::InvalidHex(line_no));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| IhexError::InvalidHex(line_no))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(IhexError::BadLength(line_no));
+        }
+        let (body, sum) = bytes.split_at(bytes.len() - 1);
+        if checksum(body) != sum[0] {
+            return Err(IhexError::BadChecksum(line_no, checksum(body), sum[0]));
+        }
+
+        let addr = u16::from_be_bytes([body[1], body[2]]);
+        let data = &body[4..];
+        match body[3] {
+            RECORD_DATA => match image.regions.last_mut() {
+                Some((at, region)) if at.wrapping_add(region.len() as u16) == addr => {
+                    region.extend_from_slice(data)
+                }
+                _ => image.regions.push((addr, data.to_vec())),
+            },
+            RECORD_EOF => return Ok(image),
+            RECORD_START_SEGMENT if data.len() == 4 => {
+                image.start = Some(u16::from_be_bytes([data[2], data[3]]))
+            }
+            kind => return Err(IhexError::UnsupportedRecord(line_no, kind)),
+        }
+    }
+    Err(IhexError::NoEndOfFile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_records() {
+        let image = Image {
+            regions: vec![(0x0100, vec![0x3e, 0x12, 0x76])],
+            start: Some(0x0100),
+        };
+        assert_eq!(
+            encode(&image),
+            ":030100003E127636\n:0400000300000100F8\n:00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn round_trip_regions() {
+        let image = Image {
+            regions: vec![(0x0000, (0..40).collect()), (0xf000, vec![0xaa, 0xbb])],
+            start: None,
+        };
+        let decoded = decode(&encode(&image)).unwrap();
+        assert_eq!(decoded, image);
+        assert_eq!(decoded.base(), Some(0));
+    }
+
+    #[test]
+    fn bad_records() {
+        assert!(matches!(
+            decode("030100003E127636\n"),
+            Err(IhexError::MissingColon(1))
+        ));
+        assert!(matches!(
+            decode(":030100003E127637\n"),
+            Err(IhexError::BadChecksum(1, 0x36, 0x37))
+        ));
+        assert!(matches!(
+            decode(":020100003E127636\n"),
+            Err(IhexError::BadLength(1))
+        ));
+        assert!(matches!(
+            decode(":030100003E127636\n"),
+            Err(IhexError::NoEndOfFile)
+        ));
+    }
+
+    #[test]
+    fn hex_extensions() {
+        assert!(is_hex_file("prog.hex"));
+        assert!(is_hex_file("PROG.IHX"));
+        assert!(!is_hex_file("prog.bin"));
+        assert!(!is_hex_file("hex"));
+    }
+}
@@ -0,0 +1,584 @@
+//! Linker for relocatable objects
+//!
+//! Objects written by `asm -c`, see [object](crate::asm::object), or Microsoft M80 `.REL`
+//! modules, see [rel], are combined into a program. The code of each object is
+//! placed one after another from `--code-at`, in the order given, then their data from
+//! `--data-at`, or straight after the code if that isn't given. Each COMMON block is placed once
+//! after the data, as large as the largest declaring it.
+//!
+//! Each word an object marks as relocated is moved by where its code, data or COMMON block was
+//! placed, or has the address of the `PUBLIC` symbol it refers to added to it. A symbol given by
+//! more than one object, or refered to and not given by any, is an error.
+//!
+//! A `.REL` file may hold many modules, as may a library given with `-l`, of which only those
+//! giving a symbol needed by what's already linked are used.
+//!
+//! The program is written as a binary from its lowest address, load it there with
+//! `run --load-at`, as Intel HEX, or as a CP/M `.COM` which must start at 0x100. It starts at the
+//! `END` of the first object to give one.
+//!
+//! A map of where everything was placed is written alongside:
+//!
+//! ```text
+//! MODULE           CODE        DATA
+//! MAIN             0100-0115   011F-0125
+//! PRINT            0116-011E   0126-012A
+//!
+//! SYMBOL           ADDRESS     MODULE
+//! START            0100        MAIN
+//! PRINT            0116        PRINT
+//! MSG              011F        MAIN
+//! COUNT            0126        PRINT
+//! ```
+
+use std::{collections::HashMap, fmt, fs, path::PathBuf};
+
+use crate::{
+    asm::{
+        label::Segment,
+        object::{Object, ObjectError, Target},
+        rel,
+    },
+    cli::{LinkArgs, LinkFormat},
+    ecodes::{E_IO_ERROR, E_LINKER, E_SUCCESS},
+    ihex::{self, Image},
+    util,
+};
+
+#[derive(Debug)]
+pub enum LinkError {
+    /// The module whose code or data went past the end of memory
+    Overflow(String),
+    /// The symbol and the modules giving it
+    DuplicateSymbol(String, String, String),
+    /// The symbol and the module refering to it
+    UndefinedSymbol(String, String),
+    /// Where the code and data overlap
+    Overlap(u16),
+    /// Where a `.COM` program would start, or be placed, other than 0x100
+    NotCom(u16),
+}
+
+impl std::error::Error for LinkError {}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Overflow(s) => write!(f, "Module {} doesn't fit below 0xFFFF", s),
+            Self::DuplicateSymbol(s, a, b) => {
+                write!(f, "Symbol {} is given by both {} and {}", s, a, b)
+            }
+            Self::UndefinedSymbol(s, m) => {
+                write!(f, "Symbol {} used by {} isn't given by any module", s, m)
+            }
+            Self::Overlap(a) => write!(f, "Code and data overlap at {:#06X}", a),
+            Self::NotCom(a) => write!(
+                f,
+                "A .COM program is placed and started at 0x0100, not {:#06X}",
+                a
+            ),
+        }
+    }
+}
+
+/// Where a module's code and data were placed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+    pub name: String,
+    pub code: u16,
+    pub code_len: usize,
+    pub data: u16,
+    pub data_len: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Linked {
+    pub image: Image,
+    pub modules: Vec<Placement>,
+    /// Every `PUBLIC` symbol with its address and the index of the module giving it
+    pub symbols: Vec<(String, u16, usize)>,
+    /// Each COMMON block with where it was placed and its size
+    pub commons: Vec<(String, u16, usize)>,
+}
+
+/// Places `len` bytes from `address`, giving where the next bytes go
+fn place(address: usize, len: usize, name: &str) -> Result<usize, LinkError> {
+    let end = address + len;
+    if end > 0x10000 {
+        return Err(LinkError::Overflow(name.to_string()));
+    }
+    Ok(end)
+}
+
+pub fn link(objects: &[Object], code_at: u16, data_at: Option<u16>) -> Result<Linked, LinkError> {
+    let mut modules = vec![];
+    let mut address = code_at as usize;
+    for object in objects.iter() {
+        let code = address;
+        address = place(address, object.code.len(), &object.name)?;
+        modules.push(Placement {
+            name: object.name.to_string(),
+            code: code as u16,
+            code_len: object.code.len(),
+            data: 0,
+            data_len: object.data.len(),
+        });
+    }
+    let code_end = address;
+    let data_start = data_at.map_or(code_end, |a| a as usize);
+    let mut address = data_start;
+    for (object, module) in objects.iter().zip(modules.iter_mut()) {
+        module.data = address as u16;
+        address = place(address, object.data.len(), &object.name)?;
+    }
+    let mut commons: Vec<(String, u16, usize)> = vec![];
+    for common in objects.iter().flat_map(|object| object.commons.iter()) {
+        let size = (common.size as usize).max(common.bytes.len());
+        match commons.iter_mut().find(|(name, _, _)| *name == common.name) {
+            Some(placed) => placed.2 = placed.2.max(size),
+            None => commons.push((common.name.to_string(), 0, size)),
+        }
+    }
+    for common in commons.iter_mut() {
+        common.1 = address as u16;
+        address = place(address, common.2, &format!("/{}/", common.0))?;
+    }
+    let data_end = address;
+    let (code_at, data_at) = (code_at as usize, data_start);
+    if code_at < code_end && data_at < data_end && code_at < data_end && data_at < code_end {
+        return Err(LinkError::Overlap(code_at.max(data_at) as u16));
+    }
+
+    let mut symbols: Vec<(String, u16, usize)> = vec![];
+    let mut by_name: HashMap<String, (u16, usize)> = HashMap::new();
+    for (idx, (object, module)) in objects.iter().zip(modules.iter()).enumerate() {
+        for (name, segment, value) in object.publics.iter() {
+            let address = match segment {
+                Segment::Code => module.code.wrapping_add(*value),
+                Segment::Data => module.data.wrapping_add(*value),
+                _ => *value,
+            };
+            if let Some((_, other)) = by_name.get(name) {
+                return Err(LinkError::DuplicateSymbol(
+                    name.to_string(),
+                    modules[*other].name.to_string(),
+                    module.name.to_string(),
+                ));
+            }
+            by_name.insert(name.to_string(), (address, idx));
+            symbols.push((name.to_string(), address, idx));
+        }
+    }
+
+    let mut code: Vec<u8> = vec![];
+    let mut data: Vec<u8> = vec![];
+    for (object, module) in objects.iter().zip(modules.iter()) {
+        let undefined =
+            |name: &str| LinkError::UndefinedSymbol(name.to_string(), object.name.to_string());
+        if let Some(name) = object
+            .externs
+            .iter()
+            .find(|name| !by_name.contains_key(*name))
+        {
+            return Err(undefined(name));
+        }
+        let mut segments = (object.code.clone(), object.data.clone());
+        for relocation in object.relocations.iter() {
+            let by = match &relocation.target {
+                Target::Code => module.code,
+                Target::Data => module.data,
+                Target::External(name) => by_name.get(name).ok_or_else(|| undefined(name))?.0,
+                Target::Common(name) => match commons.iter().find(|(n, _, _)| n == name) {
+                    Some((_, address, _)) => *address,
+                    None => return Err(undefined(&format!("/{}/", name))),
+                },
+            };
+            let bytes = match relocation.segment {
+                Segment::Data => &mut segments.1,
+                _ => &mut segments.0,
+            };
+            let idx = relocation.offset as usize;
+            let word = util::vec_u8_to_u16(&bytes[idx..idx + 2]).wrapping_add(by);
+            bytes[idx..idx + 2].copy_from_slice(&util::u16_to_vec_u8(word));
+        }
+        code.append(&mut segments.0);
+        data.append(&mut segments.1);
+    }
+    // Blocks are given their initial bytes by each module in turn
+    data.resize(data_end - data_at, 0);
+    for common in objects.iter().flat_map(|object| object.commons.iter()) {
+        if let Some((_, address, _)) = commons.iter().find(|(n, _, _)| *n == common.name) {
+            let idx = *address as usize - data_at;
+            data[idx..idx + common.bytes.len()].copy_from_slice(&common.bytes);
+        }
+    }
+
+    let start = objects
+        .iter()
+        .zip(modules.iter())
+        .find_map(|(object, module)| match object.start? {
+            (Segment::Code, offset) => Some(module.code.wrapping_add(offset)),
+            (Segment::Data, offset) => Some(module.data.wrapping_add(offset)),
+            (_, address) => Some(address),
+        });
+    let mut regions: Vec<(u16, Vec<u8>)> = [(code_at as u16, code), (data_at as u16, data)]
+        .into_iter()
+        .filter(|(_, bytes)| !bytes.is_empty())
+        .collect();
+    regions.sort_by_key(|(addr, _)| *addr);
+    Ok(Linked {
+        image: Image { regions, start },
+        modules,
+        symbols,
+        commons,
+    })
+}
+
+/// The objects given, then any from a library giving a symbol they need, until none do
+pub fn search(mut objects: Vec<Object>, mut library: Vec<Object>) -> Vec<Object> {
+    loop {
+        let given: Vec<&String> = objects
+            .iter()
+            .flat_map(|object| object.publics.iter().map(|(name, _, _)| name))
+            .collect();
+        let needed = |name: &String| {
+            !given.contains(&name) && objects.iter().any(|object| object.externs.contains(name))
+        };
+        let found = library
+            .iter()
+            .position(|module| module.publics.iter().any(|(name, _, _)| needed(name)));
+        match found {
+            Some(idx) => objects.push(library.remove(idx)),
+            None => return objects,
+        }
+    }
+}
+
+impl Linked {
+    /// Where each module and symbol was placed, symbols are in order of address
+    pub fn map(&self) -> String {
+        let range = |from: u16, len: usize| match len {
+            0 => "-".to_string(),
+            len => format!("{:04X}-{:04X}", from, from as usize + len - 1),
+        };
+        let mut out = format!("{:<16} {:<11} {}\n", "MODULE", "CODE", "DATA");
+        for module in self.modules.iter() {
+            out.push_str(&format!(
+                "{:<16} {:<11} {}\n",
+                module.name,
+                range(module.code, module.code_len),
+                range(module.data, module.data_len)
+            ));
+        }
+        for (name, address, size) in self.commons.iter() {
+            out.push_str(&format!(
+                "{:<16} {:<11} {}\n",
+                format!("/{}/", name),
+                "-",
+                range(*address, *size)
+            ));
+        }
+        out.push_str(&format!(
+            "\n{:<16} {:<11} {}\n",
+            "SYMBOL", "ADDRESS", "MODULE"
+        ));
+        let mut symbols: Vec<&(String, u16, usize)> = self.symbols.iter().collect();
+        symbols.sort_by_key(|(name, address, _)| (*address, name.to_string()));
+        for (name, address, module) in symbols {
+            out.push_str(&format!(
+                "{:<16} {:<11} {}\n",
+                name,
+                format!("{:04X}", address),
+                self.modules[*module].name
+            ));
+        }
+        out
+    }
+}
+
+/// Every module in a file, which is either an object or a `.REL` file
+fn read_objects(path: &PathBuf) -> Result<Vec<Object>, i32> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            println!("{}\n {}", e, path.display());
+            return Err(E_IO_ERROR);
+        }
+    };
+    let read = match std::str::from_utf8(&bytes).map(Object::read) {
+        Ok(Err(ObjectError::NoHeader)) | Err(_) => rel::read(&bytes).map_err(|e| e.to_string()),
+        Ok(object) => object.map(|object| vec![object]).map_err(|e| e.to_string()),
+    };
+    read.map_err(|e| {
+        println!("{}: {}", path.display(), e);
+        E_LINKER
+    })
+}
+
+/// Links a program, a `.COM` must be placed and start at 0x100
+fn link_as(objects: &[Object], args: &LinkArgs) -> Result<Linked, LinkError> {
+    if args.format != LinkFormat::Com {
+        return link(objects, args.code_at.unwrap_or(0), args.data_at);
+    }
+    let linked = link(objects, args.code_at.unwrap_or(0x100), args.data_at)?;
+    let lowest = linked
+        .image
+        .regions
+        .first()
+        .map_or(0x100, |(addr, _)| *addr);
+    match linked.image.start {
+        _ if lowest != 0x100 => Err(LinkError::NotCom(lowest)),
+        Some(start) if start != 0x100 => Err(LinkError::NotCom(start)),
+        _ => Ok(linked),
+    }
+}
+
+pub fn run_linker(args: LinkArgs) -> i32 {
+    let mut objects = vec![];
+    for path in args.inputs.iter() {
+        match read_objects(path) {
+            Ok(mut modules) => objects.append(&mut modules),
+            Err(code) => return code,
+        }
+    }
+    for path in args.libraries.iter() {
+        match read_objects(path) {
+            Ok(library) => objects = search(objects, library),
+            Err(code) => return code,
+        }
+    }
+
+    let linked = match link_as(&objects, &args) {
+        Ok(linked) => linked,
+        Err(e) => {
+            println!("{}", e);
+            return E_LINKER;
+        }
+    };
+    let output = match args.format {
+        LinkFormat::Bin | LinkFormat::Com => linked.image.flatten(),
+        LinkFormat::Ihex => ihex::encode(&linked.image).into_bytes(),
+    };
+    let map = args
+        .map
+        .clone()
+        .unwrap_or_else(|| args.output.with_extension("map"));
+    let writes: [(&PathBuf, Vec<u8>); 2] =
+        [(&args.output, output), (&map, linked.map().into_bytes())];
+    for (path, content) in writes.iter() {
+        if let Err(e) = fs::write(path, content) {
+            println!("{}\n {}", e, path.display());
+            return E_IO_ERROR;
+        }
+    }
+    E_SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble::Assembler;
+    use crate::asm::object::{Common, Relocation};
+    use crate::cli::AssembleArgs;
+    use crate::util::test::rsc;
+
+    fn object(name: &str, source: &str) -> Object {
+        let mut assembler = Assembler::new(AssembleArgs {
+            input: PathBuf::from(format!("{}.asm", name)),
+            register_definitions: true,
+            object: true,
+            ..Default::default()
+        })
+        .with_source(source);
+        let assembly = assembler.build();
+        assert!(assembly.is_ok(), "{:?}", assembly.diagnostics);
+        assembler.object(&assembly.bytes)
+    }
+
+    const MAIN: &str = "\
+        EXTRN PRINT
+        PUBLIC MSG
+START:  LXI H, MSG
+        CALL PRINT
+        JMP $
+        DSEG
+MSG:    DB 'hi', 0
+        END START
+";
+
+    const PRINT: &str = "\
+        PUBLIC PRINT
+        EXTRN MSG
+PRINT:  MOV A, M
+        ORA A
+        RZ
+        OUT 0
+        INX H
+        JMP PRINT
+        DSEG
+LAST:   DW MSG + 1, LAST
+";
+
+    #[test]
+    fn links_objects() {
+        let main = object("main", MAIN);
+        assert_eq!(main.code, vec![0x21, 0, 0, 0xcd, 0, 0, 0xc3, 0x06, 0x00]);
+        let print = object("print", PRINT);
+
+        let linked = link(&[main, print], 0x100, None).unwrap();
+        let (code_at, code) = &linked.image.regions[0];
+        assert_eq!(*code_at, 0x100);
+        assert_eq!(
+            &code[..9],
+            &[0x21, 0x12, 0x01, 0xcd, 0x09, 0x01, 0xc3, 0x06, 0x01]
+        );
+        // JMP PRINT is moved by where the print module's code was placed
+        assert_eq!(&code[15..], &[0xc3, 0x09, 0x01]);
+        let (data_at, data) = &linked.image.regions[1];
+        assert_eq!(*data_at, 0x112);
+        assert_eq!(data, &[b'h', b'i', 0, 0x13, 0x01, 0x15, 0x01]);
+        assert_eq!(linked.image.start, Some(0x100));
+
+        assert_eq!(
+            linked.map(),
+            "MODULE           CODE        DATA\n\
+             MAIN             0100-0108   0112-0114\n\
+             PRINT            0109-0111   0115-0118\n\
+             \n\
+             SYMBOL           ADDRESS     MODULE\n\
+             PRINT            0109        PRINT\n\
+             MSG              0112        MAIN\n"
+        );
+    }
+
+    #[test]
+    fn addresses_on_the_command_line() {
+        use crate::cli::{Cli, Commands};
+        use clap::Parser;
+
+        let parse = |code_at: &str| {
+            Cli::try_parse_from(["i8080", "link", "main.obj", "--code-at", code_at]).map(|cli| {
+                match cli.command {
+                    Commands::Link(args) => args.code_at,
+                    _ => unreachable!(),
+                }
+            })
+        };
+        assert_eq!(parse("0x100").unwrap(), Some(0x100));
+        assert_eq!(parse("256").unwrap(), Some(0x100));
+        assert_eq!(parse("32K").unwrap(), Some(0x8000));
+        assert!(parse("0x10000").is_err());
+    }
+
+    #[test]
+    fn data_placed_apart() {
+        let linked = link(
+            &[object("main", MAIN), object("print", PRINT)],
+            0,
+            Some(0x8000),
+        )
+        .unwrap();
+        let flat = linked.image.flatten();
+        assert_eq!(flat.len(), 0x8000 + 7);
+        assert_eq!(&flat[1..3], &[0x00, 0x80]);
+
+        let e = link(&[object("main", MAIN), object("print", PRINT)], 0, Some(4)).unwrap_err();
+        assert!(matches!(e, LinkError::Overlap(4)));
+    }
+
+    #[test]
+    fn unresolved_symbols() {
+        let e = link(&[object("main", MAIN)], 0, None).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Symbol PRINT used by MAIN isn't given by any module"
+        );
+
+        let e = link(&[object("print", PRINT), object("print", PRINT)], 0, None).unwrap_err();
+        assert!(matches!(e, LinkError::DuplicateSymbol(s, _, _) if s == "PRINT"));
+
+        let e = link(
+            &[object("main", MAIN), object("print", PRINT)],
+            0xfff0,
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(e, LinkError::Overflow(s) if s == "PRINT"));
+    }
+
+    #[test]
+    fn rel_modules() {
+        let objects = [object("main", MAIN), object("print", PRINT)];
+        let rels: Vec<Vec<u8>> = objects.iter().map(|o| rel::write(o).unwrap()).collect();
+        let read: Vec<Object> = rels.iter().flat_map(|r| rel::read(r).unwrap()).collect();
+        let by_rel = link(&read, 0x100, None).unwrap();
+        assert_eq!(by_rel.image, link(&objects, 0x100, None).unwrap().image);
+
+        let paths = [rsc("aux/main.rel"), rsc("aux/print.rel")];
+        for (path, rel) in paths.iter().zip(rels.iter()) {
+            fs::write(path, rel).unwrap();
+        }
+        let args = |inputs: &[PathBuf], code_at| LinkArgs {
+            inputs: inputs.to_vec(),
+            libraries: vec![],
+            output: rsc("aux/prog.com"),
+            format: LinkFormat::Com,
+            code_at,
+            data_at: None,
+            map: None,
+        };
+        assert_eq!(run_linker(args(&paths, None)), E_SUCCESS);
+        let com = fs::read(rsc("aux/prog.com")).unwrap();
+        assert_eq!(com, by_rel.image.flatten());
+        assert!(fs::read_to_string(rsc("aux/prog.map"))
+            .unwrap()
+            .starts_with("MODULE"));
+        assert_eq!(run_linker(args(&paths, Some(0))), E_LINKER);
+        assert_eq!(run_linker(args(&[rsc("aux/none.rel")], None)), E_IO_ERROR);
+    }
+
+    const COMMON_MAIN: &str = "\
+        EXTRN PRINT
+        PUBLIC MSG
+        CALL PRINT
+        LDA 2
+        DSEG
+MSG:    DB 0
+";
+
+    #[test]
+    fn commons_and_libraries() {
+        let mut main = object("main", COMMON_MAIN);
+        main.relocations.push(Relocation {
+            segment: Segment::Code,
+            offset: 4,
+            target: Target::Common("BUF".to_string()),
+        });
+        main.commons.push(Common {
+            name: "BUF".to_string(),
+            size: 4,
+            bytes: vec![],
+        });
+        let mut print = object("print", PRINT);
+        print.commons.push(Common {
+            name: "BUF".to_string(),
+            size: 8,
+            bytes: vec![0xaa, 0xbb],
+        });
+        let unused = object("unused", "        PUBLIC UNUSED\nUNUSED: RET\n");
+
+        let objects = search(vec![main], vec![unused, print]);
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[1].name, "PRINT");
+
+        let linked = link(&objects, 0, None).unwrap();
+        // Code of 6 and 9 bytes, then 1 and 4 of data, then the block
+        assert_eq!(linked.commons, vec![("BUF".to_string(), 0x14, 8)]);
+        let flat = linked.image.flatten();
+        assert_eq!(&flat[3..6], &[0x3a, 0x16, 0x00]);
+        assert_eq!(&flat[0x14..], &[0xaa, 0xbb, 0, 0, 0, 0, 0, 0]);
+        assert!(linked
+            .map()
+            .contains("\n/BUF/            -           0014-001B\n"));
+    }
+}
@@ -24,11 +24,37 @@
 //!
 //! If assembling and running in one, register definitions are included and a `HLT` instruction is
 //! placed at the end of the program.
+//!
+//! Routines can also be tested, see the [tester] module for how tests are written
+//!
+//! ```sh
+//! $ i8080 test ./rsc/asm/tests.asm
+//! ```
+//!
+//! Routines may be assembled on their own into relocatable objects, with `PUBLIC` and `EXTRN`
+//! symbols, then linked into a program, see the [link] module
+//!
+//! ```sh
+//! $ i8080 asm -c --reg-defs main.asm -o main.obj
+//! $ i8080 asm -c --reg-defs print.asm -o print.obj
+//! $ i8080 link main.obj print.obj --code-at 256 -o prog.bin
+//! ```
+//!
+//! Microsoft M80 `.REL` modules and libraries may be linked in too, and a CP/M `.COM` written
+//!
+//! ```sh
+//! $ i8080 asm --format rel --reg-defs main.asm -o main.rel
+//! $ i8080 link main.rel -l forlib.rel --format com -o prog.com
+//! ```
 
 pub mod asm;
 pub mod cli;
 pub mod ecodes;
+pub mod ihex;
+pub mod link;
+pub mod prl;
 pub mod sys;
+pub mod tester;
 
 mod meta;
 mod util;
@@ -40,7 +66,9 @@ use clap::Parser;
 
 use asm::{run_assembler, run_disassmbler};
 use cli::{Cli, Commands};
+use link::run_linker;
 use sys::run_system;
+use tester::run_tests;
 
 fn main() {
     env_logger::init();
@@ -51,5 +79,7 @@ fn main() {
         Commands::Run(subargs) => run_system(subargs),
         Commands::Assemble(subargs) => run_assembler(subargs),
         Commands::Disassemble(subargs) => run_disassmbler(subargs),
+        Commands::Test(subargs) => run_tests(subargs),
+        Commands::Link(subargs) => run_linker(subargs),
     });
 }
@@ -91,10 +91,10 @@ impl OpMeta {
     }
 }
 
-pub const I8080_OP_META: [OpMeta; 0x10b] = load_op_meta();
+pub const I8080_OP_META: [OpMeta; 0x11c] = load_op_meta();
 
-const fn load_op_meta() -> [OpMeta; 0x10b] {
-    let mut set: [OpMeta; 0x10b] = [OpMeta::new_no_args("", 0, 0); 0x10b];
+const fn load_op_meta() -> [OpMeta; 0x11c] {
+    let mut set: [OpMeta; 0x11c] = [OpMeta::new_no_args("", 0, 0); 0x11c];
 
     // ------------------------------------------ MOV
 
@@ -197,16 +197,16 @@ const fn load_op_meta() -> [OpMeta; 0x10b] {
     set[0xfc] = OpMeta::new_argw("CM", 1, 11);
 
     // Rxx
-    set[0xc9] = OpMeta::new_no_args("RET", 1, 10);
+    set[0xc9] = OpMeta::new_no_args("RET", 0, 10);
     // If the condition is matched, 6 cycles are added
-    set[0xc0] = OpMeta::new_no_args("RNZ", 1, 5);
-    set[0xc8] = OpMeta::new_no_args("RZ", 1, 5);
-    set[0xd0] = OpMeta::new_no_args("RNC", 1, 5);
-    set[0xd8] = OpMeta::new_no_args("RC", 1, 5);
-    set[0xe0] = OpMeta::new_no_args("RPO", 1, 5);
-    set[0xe8] = OpMeta::new_no_args("RPE", 1, 5);
-    set[0xf0] = OpMeta::new_no_args("RP", 1, 5);
-    set[0xf8] = OpMeta::new_no_args("RM", 1, 5);
+    set[0xc0] = OpMeta::new_no_args("RNZ", 0, 5);
+    set[0xc8] = OpMeta::new_no_args("RZ", 0, 5);
+    set[0xd0] = OpMeta::new_no_args("RNC", 0, 5);
+    set[0xd8] = OpMeta::new_no_args("RC", 0, 5);
+    set[0xe0] = OpMeta::new_no_args("RPO", 0, 5);
+    set[0xe8] = OpMeta::new_no_args("RPE", 0, 5);
+    set[0xf0] = OpMeta::new_no_args("RP", 0, 5);
+    set[0xf8] = OpMeta::new_no_args("RM", 0, 5);
 
     // ------------------------------------------ IMMEDIATE
 
@@ -432,8 +432,8 @@ const fn load_op_meta() -> [OpMeta; 0x10b] {
 
     set[0x100] = OpMeta::new_define("DB", 0);
     set[0x101] = OpMeta::new_define("DW", 0);
-    set[0x102] = OpMeta::new_define("DS", 1);
-    set[0x102].argb = true;
+    set[0x102] = OpMeta::new_define("DS", 2);
+    set[0x102].argw = true;
     set[0x103] = OpMeta::new_labelled("EQU", 1);
     set[0x103].argw = true;
     set[0x104] = OpMeta::new_labelled("SET", 1);
@@ -444,6 +444,23 @@ const fn load_op_meta() -> [OpMeta; 0x10b] {
     set[0x108] = OpMeta::new_no_args("ENDIF", 0, 0);
     set[0x109] = OpMeta::new_labelled("MACRO", 0);
     set[0x10a] = OpMeta::new_labelled("ENDM", 0);
+    set[0x10b] = OpMeta::new_no_args("INCLUDE", 1, 0);
+    set[0x10c] = OpMeta::new_no_args("INCBIN", 1, 0);
+    set[0x10d] = OpMeta::new_argb("ELSEIF", 1, 0);
+    set[0x10e] = OpMeta::new_no_args("ELSE", 0, 0);
+    set[0x10f] = OpMeta::new_no_args("LOCAL", 1, 0);
+    set[0x110] = OpMeta::new_no_args("EXITM", 0, 0);
+    set[0x111] = OpMeta::new_no_args("REPT", 1, 0);
+    set[0x112] = OpMeta::new_no_args("IRP", 2, 0);
+    set[0x113] = OpMeta::new_no_args("IRPC", 2, 0);
+    set[0x114] = OpMeta::new_no_args("PUBLIC", 1, 0);
+    set[0x115] = OpMeta::new_no_args("EXTRN", 1, 0);
+    set[0x116] = OpMeta::new_no_args("CSEG", 0, 0);
+    set[0x117] = OpMeta::new_no_args("DSEG", 0, 0);
+    set[0x118] = OpMeta::new_no_args("ASSERT", 1, 0);
+    set[0x119] = OpMeta::new_no_args("STRUCT", 1, 0);
+    set[0x11a] = OpMeta::new_no_args("ENDS", 0, 0);
+    set[0x11b] = OpMeta::new_define("DC", 0);
 
     set
 }
@@ -468,4 +485,13 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn returns_take_no_operands() {
+        for op in [0xc9, 0xc0, 0xc8, 0xd0, 0xd8, 0xe0, 0xe8, 0xf0, 0xf8] {
+            let meta = I8080_OP_META[op];
+            assert_eq!(meta.asm_arg_count, 0, "{} has no operands", meta.op);
+            assert_eq!(meta.width(), 1, "{} is one byte", meta.op);
+        }
+    }
 }
@@ -0,0 +1,209 @@
+//! Page relocatable images, CP/M and MP/M `.PRL` files
+//!
+//! A PRL is a page of header, the program assembled at 0x0100, then a bitmap with a bit for each
+//! byte of the program, most significant first, set for those which must be moved when it's
+//! loaded elsewhere. These are the high bytes of addresses within the program, so it can only be
+//! moved by whole pages.
+//!
+//! The header is zero but for:
+//!
+//! | Bytes | Holds                                                        |
+//! |-------|--------------------------------------------------------------|
+//! | 1-2   | length of the program                                        |
+//! | 4-5   | memory needed after the program, not held in the file        |
+//!
+//! Which bytes move is found by assembling the program at 0x0000 as well as 0x0100, those one
+//! higher in the second are addresses. Any other difference is a value which depends on where the
+//! program is but can't be moved a page at a time, such as the low byte of an address times two.
+
+use std::{fmt, path::Path};
+
+use crate::ihex::Image;
+
+const HEADER_LEN: usize = 0x100;
+
+#[derive(Debug)]
+pub enum PrlError {
+    /// Offset into the program of a byte which differs by more than a page
+    NotRelocatable(usize),
+    /// Lengths of the program at 0x0000 and 0x0100
+    LengthDiffers(usize, usize),
+    Truncated,
+    /// The address asked to load at
+    NotPageAligned(u16),
+}
+
+impl std::error::Error for PrlError {}
+
+impl fmt::Display for PrlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotRelocatable(o) => write!(
+                f,
+                "Byte {:#06X} into the program changes with where it's placed, but not by a page",
+                o
+            ),
+            Self::LengthDiffers(a, b) => write!(
+                f,
+                "Program is {} bytes at 0x0000 but {} at 0x0100, its layout depends on where it is",
+                a, b
+            ),
+            Self::Truncated => write!(f, "PRL ends before its program and bitmap do"),
+            Self::NotPageAligned(a) => {
+                write!(f, "A PRL is loaded on a page boundary, not at {:#06X}", a)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Prl {
+    /// The program as assembled at 0x0100
+    pub code: Vec<u8>,
+    /// Whether each byte of the program moves with the page it's loaded at
+    pub relocated: Vec<bool>,
+    /// Memory the program needs after itself
+    pub bss: u16,
+}
+
+impl Prl {
+    /// The program from assembling it at 0x0000 and 0x0100
+    pub fn from_pair(at_0000: &[u8], at_0100: &[u8]) -> Result<Self, PrlError> {
+        if at_0000.len() != at_0100.len() {
+            return Err(PrlError::LengthDiffers(at_0000.len(), at_0100.len()));
+        }
+        let relocated = at_0000
+            .iter()
+            .zip(at_0100.iter())
+            .enumerate()
+            .map(|(idx, (low, high))| match high.wrapping_sub(*low) {
+                0 => Ok(false),
+                1 => Ok(true),
+                _ => Err(PrlError::NotRelocatable(idx)),
+            })
+            .collect::<Result<Vec<bool>, PrlError>>()?;
+        Ok(Self {
+            code: at_0100.to_vec(),
+            relocated,
+            bss: 0,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let len = self.code.len() as u16;
+        let mut out = vec![0; HEADER_LEN];
+        out[1..3].copy_from_slice(&len.to_le_bytes());
+        out[4..6].copy_from_slice(&self.bss.to_le_bytes());
+        out.extend(self.code.iter());
+        for bits in self.relocated.chunks(8) {
+            let byte = bits
+                .iter()
+                .enumerate()
+                .filter(|(_, moved)| **moved)
+                .fold(0_u8, |byte, (bit, _)| byte | 0x80 >> bit);
+            out.push(byte);
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, PrlError> {
+        let header = bytes.get(..HEADER_LEN).ok_or(PrlError::Truncated)?;
+        let len = u16::from_le_bytes([header[1], header[2]]) as usize;
+        let bss = u16::from_le_bytes([header[4], header[5]]);
+        let code = bytes
+            .get(HEADER_LEN..HEADER_LEN + len)
+            .ok_or(PrlError::Truncated)?;
+        let bitmap = bytes
+            .get(HEADER_LEN + len..HEADER_LEN + len + len.div_ceil(8))
+            .ok_or(PrlError::Truncated)?;
+        let relocated = (0..len)
+            .map(|idx| bitmap[idx / 8] & (0x80 >> (idx % 8)) != 0)
+            .collect();
+        Ok(Self {
+            code: code.to_vec(),
+            relocated,
+            bss,
+        })
+    }
+
+    /// The program moved to start at an address, which must be on a page boundary
+    pub fn relocate(&self, address: u16) -> Result<Image, PrlError> {
+        if address & 0xff != 0 {
+            return Err(PrlError::NotPageAligned(address));
+        }
+        // The program was assembled at page 1
+        let by = ((address >> 8) as u8).wrapping_sub(1);
+        let code = self
+            .code
+            .iter()
+            .zip(self.relocated.iter())
+            .map(|(byte, moved)| if *moved { byte.wrapping_add(by) } else { *byte })
+            .collect();
+        Ok(Image {
+            regions: vec![(address, code)],
+            start: Some(address),
+        })
+    }
+}
+
+pub fn is_prl_file<P: AsRef<Path>>(path: P) -> bool {
+    matches!(
+        path.as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .as_deref(),
+        Some("prl")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        // LXI H, 0x0109; JMP 0x0100; NOP x3; DB HIGH 0x0109, LOW 0x0109
+        let at_0000 = [0x21, 0x09, 0x00, 0xc3, 0x00, 0x00, 0, 0, 0, 0x00, 0x09];
+        let at_0100 = [0x21, 0x09, 0x01, 0xc3, 0x00, 0x01, 0, 0, 0, 0x01, 0x09];
+        let prl = Prl::from_pair(&at_0000, &at_0100).unwrap();
+        let bytes = prl.encode();
+        assert_eq!(&bytes[..6], &[0, 11, 0, 0, 0, 0]);
+        assert_eq!(&bytes[0x100..0x10b], &at_0100);
+        assert_eq!(&bytes[0x10b..], &[0b0010_0100, 0b0100_0000]);
+        assert_eq!(Prl::decode(&bytes).unwrap(), prl);
+
+        let image = prl.relocate(0x4000).unwrap();
+        assert_eq!(
+            image.regions,
+            vec![(
+                0x4000,
+                vec![0x21, 0x09, 0x40, 0xc3, 0x00, 0x40, 0, 0, 0, 0x40, 0x09]
+            )]
+        );
+        assert_eq!(image.start, Some(0x4000));
+        assert_eq!(prl.relocate(0).unwrap().regions[0].1, at_0000);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(
+            Prl::from_pair(&[0x00, 0x00], &[0x00, 0x02]),
+            Err(PrlError::NotRelocatable(1))
+        ));
+        assert!(matches!(
+            Prl::from_pair(&[0x00], &[0x00, 0x02]),
+            Err(PrlError::LengthDiffers(1, 2))
+        ));
+        let bytes = Prl::from_pair(&[0; 9], &[0; 9]).unwrap().encode();
+        assert!(matches!(
+            Prl::decode(&bytes[..bytes.len() - 1]),
+            Err(PrlError::Truncated)
+        ));
+        assert!(matches!(
+            Prl::decode(&bytes).unwrap().relocate(0x4010),
+            Err(PrlError::NotPageAligned(0x4010))
+        ));
+        assert!(is_prl_file("prog.PRL"));
+    }
+}
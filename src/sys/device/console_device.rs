@@ -3,13 +3,30 @@
 //! The console device takes a receiver and ideally the emulator receives the counterpart
 //! transmitter.
 //!
-//! Operation of the device is done through special characters. For example, the text passed to 
+//! Operation of the device is done through special characters. For example, the text passed to
 //! it is buffered, the null or ETB characters are used to "flush" the buffer and print the
 //! contents to the screen.
 //!
 //! Similarly end-of-tranmission (EOT) is used to close operation of the device
+//!
+//! # Modes
+//!
+//! Buffering is the default but a program which never sends a NUL or ETB shows nothing until it
+//! halts, so there are a few other modes:
+//!
+//! - Streaming, each character is written to stdout as soon as it arrives and the host terminal
+//!   deals with any control characters
+//! - VT52 and VT100, the characters are fed to a virtual [`Terminal`] which is either drawn on the
+//!   host terminal as output arrives or kept off-screen so its text can be dumped at the end
+
+use std::{
+    io::{self, Write},
+    sync::mpsc::Receiver,
+};
 
-use std::sync::mpsc::Receiver;
+use crate::cli::ConsoleMode;
+
+use super::terminal::{Terminal, ROWS};
 
 // I don't know another way to namespace some consts... maybe bad practice but who can really tell
 // these days
@@ -26,17 +43,43 @@ pub mod special_chars {
 pub struct ConsoleDevice {
     rx: Receiver<u8>,
     echo: bool,
+    mode: ConsoleMode,
+    render: bool,
 }
 
 impl ConsoleDevice {
     pub fn new(rx: Receiver<u8>, echo: bool) -> Self {
-        Self { rx, echo }
+        Self {
+            rx,
+            echo,
+            mode: ConsoleMode::Buffered,
+            render: true,
+        }
     }
 
+    pub fn with_mode(mut self, mode: ConsoleMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Whether a virtual terminal is drawn on the host terminal, has no effect in other modes
+    pub fn with_render(mut self, render: bool) -> Self {
+        self.render = render;
+        self
+    }
+
+    /// Runs until EOT is received
+    ///
+    /// The buffered mode returns everything it printed followed by whatever was left in the
+    /// buffer, the streaming mode returns everything received, and the terminal modes return the
+    /// text of the screen
     pub fn run(&self) -> Vec<u8> {
-        match self.echo {
-            true => self.run_echo(),
-            false => self.run_no_echo(),
+        match (self.echo, self.mode) {
+            (true, _) => self.run_echo(),
+            (false, ConsoleMode::Buffered) => self.run_no_echo(),
+            (false, ConsoleMode::Stream) => self.run_stream(),
+            (false, ConsoleMode::Vt52) => self.run_terminal(Terminal::vt52()),
+            (false, ConsoleMode::Vt100) => self.run_terminal(Terminal::new()),
         }
     }
 
@@ -45,6 +88,7 @@ impl ConsoleDevice {
     }
 
     fn run_no_echo(&self) -> Vec<u8> {
+        let mut printed: Vec<u8> = vec![];
         let mut buf: Vec<u8> = vec![];
         let mut idx: usize = 0;
         loop {
@@ -57,7 +101,8 @@ impl ConsoleDevice {
                     // NUL, null terminator for string (using as flush)
                     special_chars::ETB | special_chars::NUL => {
                         println!("{}", String::from_utf8_lossy(&buf));
-                        buf.clear();
+                        printed.append(&mut buf);
+                        printed.push(special_chars::LF);
                         idx = 0;
                     }
                     // BEL, device should immediately acknowledge
@@ -93,8 +138,69 @@ impl ConsoleDevice {
                 }
             }
         }
+        printed.append(&mut buf);
+        printed
+    }
+
+    fn run_stream(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = vec![];
+        let mut stdout = io::stdout();
+        while let Ok(byte) = self.rx.recv() {
+            debug!("Console device received [{}]", byte);
+            match byte {
+                special_chars::EOT => break,
+                // Nothing is held back so these only need to flush
+                special_chars::ETB | special_chars::NUL => {}
+                _ => {
+                    buf.push(byte);
+                    if let Err(e) = stdout.write_all(&[byte]) {
+                        debug!("Console device failed to write: {}", e);
+                    }
+                }
+            }
+            if let Err(e) = stdout.flush() {
+                debug!("Console device failed to flush: {}", e);
+            }
+        }
+        // Leave the host's prompt on a line of its own
+        if !buf.is_empty() && buf.last() != Some(&special_chars::LF) {
+            println!();
+        }
         buf
     }
+
+    fn run_terminal(&self, mut term: Terminal) -> Vec<u8> {
+        let mut stdout = io::stdout();
+        if self.render {
+            print!("\x1b[2J");
+        }
+        'recv: while let Ok(mut byte) = self.rx.recv() {
+            // Only redraw once everything sent so far has been taken in
+            loop {
+                debug!("Console device received [{}]", byte);
+                if byte == special_chars::EOT {
+                    break 'recv;
+                }
+                term.feed(byte);
+                match self.rx.try_recv() {
+                    Ok(next) => byte = next,
+                    Err(_) => break,
+                }
+            }
+            if self.render {
+                if let Err(e) = term.render(&mut stdout) {
+                    debug!("Console device failed to render: {}", e);
+                }
+            }
+        }
+        if self.render {
+            if let Err(e) = term.render(&mut stdout) {
+                debug!("Console device failed to render: {}", e);
+            }
+            println!("\x1b[{};1H", ROWS);
+        }
+        term.dump().into_bytes()
+    }
 }
 
 #[cfg(test)]
@@ -122,7 +228,7 @@ mod tests {
 
         assert!(err.is_none(), "{:?}", err.unwrap());
 
-        let console = ConsoleDevice { rx, echo: false };
+        let console = ConsoleDevice::new(rx, false);
 
         assert_eq!(act, String::from_utf8_lossy(&(console.run())));
     }
@@ -146,7 +252,7 @@ mod tests {
 
         assert!(err.is_none(), "{:?}", err.unwrap());
 
-        let console = ConsoleDevice { rx, echo: false };
+        let console = ConsoleDevice::new(rx, false);
 
         assert_eq!(act, String::from_utf8_lossy(&(console.run())));
     }
@@ -171,7 +277,7 @@ mod tests {
 
         assert!(err.is_none(), "{:?}", err.unwrap());
 
-        let console = ConsoleDevice { rx, echo: false };
+        let console = ConsoleDevice::new(rx, false);
 
         assert_eq!(exp, String::from_utf8_lossy(&(console.run())));
     }
@@ -195,8 +301,51 @@ mod tests {
 
         assert!(err.is_none(), "{:?}", err.unwrap());
 
-        let console = ConsoleDevice { rx, echo: false };
+        let console = ConsoleDevice::new(rx, false);
 
         assert_eq!("Hellscape", String::from_utf8_lossy(&(console.run())));
     }
+
+    fn console_with_input(mode: ConsoleMode, input: &[u8]) -> ConsoleDevice {
+        let (tx, rx): (Sender<u8>, Receiver<u8>) = mpsc::channel();
+        for byte in input.iter().chain([special_chars::EOT].iter()) {
+            tx.send(*byte).expect("receiver should be alive");
+        }
+        ConsoleDevice::new(rx, false)
+            .with_mode(mode)
+            .with_render(false)
+    }
+
+    #[test]
+    fn stream_keeps_everything() {
+        let console = console_with_input(ConsoleMode::Stream, b"one\0two\x17three");
+        assert_eq!("onetwothree", String::from_utf8_lossy(&console.run()));
+    }
+
+    #[test]
+    fn stream_ends_without_eot() {
+        let (tx, rx): (Sender<u8>, Receiver<u8>) = mpsc::channel();
+        tx.send(b'a').unwrap();
+        drop(tx);
+        let console = ConsoleDevice::new(rx, false).with_mode(ConsoleMode::Stream);
+        assert_eq!("a", String::from_utf8_lossy(&console.run()));
+    }
+
+    #[test]
+    fn vt100_returns_screen() {
+        let console = console_with_input(ConsoleMode::Vt100, b"Hello\r\nworld\x1b[1;1HJ");
+        assert_eq!("Jello\nworld", String::from_utf8_lossy(&console.run()));
+    }
+
+    #[test]
+    fn vt52_returns_screen() {
+        let console = console_with_input(ConsoleMode::Vt52, b"Hello\x1bY\x20\x22\x1bK");
+        assert_eq!("He", String::from_utf8_lossy(&console.run()));
+    }
+
+    #[test]
+    fn buffered_returns_flushed_lines() {
+        let console = console_with_input(ConsoleMode::Buffered, b"one\0two\x17thr");
+        assert_eq!("one\ntwo\nthr", String::from_utf8_lossy(&console.run()));
+    }
 }
@@ -6,6 +6,7 @@
 //! The devices here take to relevant opposites to the above.
 
 pub mod console_device;
+pub mod terminal;
 
 use std::sync::mpsc::{Receiver, Sender};
 
@@ -0,0 +1,491 @@
+//! Virtual VT52/VT100 terminal
+//!
+//! Programs written for CP/M-era machines tend to assume a smart terminal on the other end of the
+//! console, moving the cursor about and clearing parts of the screen with escape sequences. This
+//! keeps an 80x24 screen in memory and interprets the common subset of those sequences.
+//!
+//! The screen can then be rendered to the host terminal (which is assumed to understand ANSI) or
+//! dumped as plain text, the latter being handy for tests.
+//!
+//! # Supported Sequences
+//!
+//! Control characters: `BS`, `HT`, `LF` (also `VT` and `FF`), `CR` and `ESC`; the rest are ignored.
+//!
+//! In VT100 (ANSI) mode:
+//!
+//! - `ESC [ n A/B/C/D` cursor up/down/forward/back
+//! - `ESC [ r ; c H` (or `f`) cursor position, `ESC [ n G` cursor column
+//! - `ESC [ n J` erase in display, `ESC [ n K` erase in line
+//! - `ESC [ n L/M` insert/delete lines, `ESC [ n @/P` insert/delete characters
+//! - `ESC [ s/u` and `ESC 7/8` save and restore the cursor
+//! - `ESC D/M/E` index, reverse index and next line, `ESC c` reset
+//! - `ESC [ ? 2 l` switches to VT52 mode
+//!
+//! In VT52 mode:
+//!
+//! - `ESC A/B/C/D` cursor up/down/right/left, `ESC H` home, `ESC Y r c` direct cursor address
+//! - `ESC J` erase to end of screen, `ESC K` erase to end of line, `ESC E` clear screen
+//! - `ESC I` reverse line feed, `ESC L/M` insert/delete line
+//! - `ESC <` switches to VT100 mode
+//!
+//! Attributes (`ESC [ ... m`) and anything unrecognised are consumed and ignored.
+
+use std::io::{self, Write};
+
+pub const COLS: usize = 80;
+pub const ROWS: usize = 24;
+
+const ESC: u8 = 0x1b;
+const BLANK: u8 = b' ';
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum EscState {
+    Ground,
+    Escape,
+    // Private flag ('?'), parameters and the parameter currently being read
+    Csi(bool, Vec<usize>, Option<usize>),
+    // Character set designations and the like take one more byte which is dropped
+    SkipOne,
+    Vt52Row,
+    Vt52Col(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Terminal {
+    screen: Vec<[u8; COLS]>,
+    row: usize,
+    col: usize,
+    saved: (usize, usize),
+    // Writing the last column doesn't wrap until the next printable arrives
+    wrap_pending: bool,
+    vt52: bool,
+    state: EscState,
+}
+
+impl Default for Terminal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Terminal {
+    /// A terminal which starts in VT100 (ANSI) mode
+    pub fn new() -> Self {
+        Self {
+            screen: vec![[BLANK; COLS]; ROWS],
+            row: 0,
+            col: 0,
+            saved: (0, 0),
+            wrap_pending: false,
+            vt52: false,
+            state: EscState::Ground,
+        }
+    }
+
+    /// A terminal which starts in VT52 mode
+    pub fn vt52() -> Self {
+        Self {
+            vt52: true,
+            ..Self::new()
+        }
+    }
+
+    /// Row and column of the cursor, both zero-based
+    #[cfg(test)]
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+
+    #[cfg(test)]
+    pub fn feed_all(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.feed(*byte);
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) {
+        let state = std::mem::replace(&mut self.state, EscState::Ground);
+        match state {
+            EscState::Ground => self.ground(byte),
+            EscState::Escape => {
+                if self.vt52 {
+                    self.vt52_escape(byte)
+                } else {
+                    self.ansi_escape(byte)
+                }
+            }
+            EscState::Csi(private, params, current) => self.csi(byte, private, params, current),
+            EscState::SkipOne => {}
+            EscState::Vt52Row => self.state = EscState::Vt52Col(byte.saturating_sub(0x20) as usize),
+            EscState::Vt52Col(row) => {
+                self.move_to(row, byte.saturating_sub(0x20) as usize);
+            }
+        }
+    }
+
+    fn ground(&mut self, byte: u8) {
+        match byte {
+            ESC => self.state = EscState::Escape,
+            0x08 => {
+                self.wrap_pending = false;
+                self.col = self.col.saturating_sub(1);
+            }
+            b'\t' => {
+                self.wrap_pending = false;
+                self.col = ((self.col / 8 + 1) * 8).min(COLS - 1);
+            }
+            b'\n' | 0x0b | 0x0c => self.line_feed(),
+            b'\r' => {
+                self.wrap_pending = false;
+                self.col = 0;
+            }
+            0x20..=0x7e => self.put(byte),
+            _ => {}
+        }
+    }
+
+    fn ansi_escape(&mut self, byte: u8) {
+        match byte {
+            b'[' => self.state = EscState::Csi(false, vec![], None),
+            b'D' => self.line_feed(),
+            b'M' => self.reverse_line_feed(),
+            b'E' => {
+                self.col = 0;
+                self.line_feed();
+            }
+            b'7' => self.saved = (self.row, self.col),
+            b'8' => self.move_to(self.saved.0, self.saved.1),
+            b'c' => *self = Self::new(),
+            b'(' | b')' | b'#' => self.state = EscState::SkipOne,
+            _ => debug!("Terminal ignoring ESC {:?}", byte as char),
+        }
+    }
+
+    fn vt52_escape(&mut self, byte: u8) {
+        match byte {
+            b'A' => self.move_to(self.row.saturating_sub(1), self.col),
+            b'B' => self.move_to(self.row + 1, self.col),
+            b'C' => self.move_to(self.row, self.col + 1),
+            b'D' => self.move_to(self.row, self.col.saturating_sub(1)),
+            b'H' => self.move_to(0, 0),
+            b'I' => self.reverse_line_feed(),
+            b'J' => self.erase_display(0),
+            b'K' => self.erase_line(0),
+            b'E' => {
+                self.erase_display(2);
+                self.move_to(0, 0);
+            }
+            b'L' => self.insert_lines(1),
+            b'M' => self.delete_lines(1),
+            b'Y' => self.state = EscState::Vt52Row,
+            b'<' => self.vt52 = false,
+            _ => debug!("Terminal ignoring VT52 ESC {:?}", byte as char),
+        }
+    }
+
+    fn csi(&mut self, byte: u8, private: bool, mut params: Vec<usize>, current: Option<usize>) {
+        match byte {
+            b'0'..=b'9' => {
+                let digit = (byte - b'0') as usize;
+                let val = current
+                    .unwrap_or(0)
+                    .saturating_mul(10)
+                    .saturating_add(digit);
+                self.state = EscState::Csi(private, params, Some(val));
+                return;
+            }
+            b';' => {
+                params.push(current.unwrap_or(0));
+                self.state = EscState::Csi(private, params, None);
+                return;
+            }
+            b'?' => {
+                self.state = EscState::Csi(true, params, current);
+                return;
+            }
+            // Any other final or intermediate byte completes the sequence
+            _ => {}
+        }
+        if let Some(val) = current {
+            params.push(val);
+        }
+        // Zero is the same as the default for every count-like parameter
+        let count = |idx: usize| params.get(idx).copied().filter(|n| *n > 0).unwrap_or(1);
+        let mode = params.first().copied().unwrap_or(0);
+        match byte {
+            b'A' => self.move_to(self.row.saturating_sub(count(0)), self.col),
+            b'B' => self.move_to(self.row.saturating_add(count(0)), self.col),
+            b'C' => self.move_to(self.row, self.col.saturating_add(count(0))),
+            b'D' => self.move_to(self.row, self.col.saturating_sub(count(0))),
+            b'H' | b'f' => self.move_to(count(0) - 1, count(1) - 1),
+            b'G' => self.move_to(self.row, count(0) - 1),
+            b'J' => self.erase_display(mode),
+            b'K' => self.erase_line(mode),
+            b'L' => self.insert_lines(count(0)),
+            b'M' => self.delete_lines(count(0)),
+            b'@' => self.insert_chars(count(0)),
+            b'P' => self.delete_chars(count(0)),
+            b's' => self.saved = (self.row, self.col),
+            b'u' => self.move_to(self.saved.0, self.saved.1),
+            b'l' if private && params.contains(&2) => self.vt52 = true,
+            _ => trace!("Terminal ignoring CSI {:?} {:?}", params, byte as char),
+        }
+    }
+
+    fn put(&mut self, byte: u8) {
+        if self.wrap_pending {
+            self.wrap_pending = false;
+            self.col = 0;
+            self.line_feed();
+        }
+        self.screen[self.row][self.col] = byte;
+        if self.col == COLS - 1 {
+            self.wrap_pending = true;
+        } else {
+            self.col += 1;
+        }
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.wrap_pending = false;
+        self.row = row.min(ROWS - 1);
+        self.col = col.min(COLS - 1);
+    }
+
+    fn line_feed(&mut self) {
+        self.wrap_pending = false;
+        if self.row == ROWS - 1 {
+            self.screen.remove(0);
+            self.screen.push([BLANK; COLS]);
+        } else {
+            self.row += 1;
+        }
+    }
+
+    fn reverse_line_feed(&mut self) {
+        self.wrap_pending = false;
+        if self.row == 0 {
+            self.screen.pop();
+            self.screen.insert(0, [BLANK; COLS]);
+        } else {
+            self.row -= 1;
+        }
+    }
+
+    /// 0 erases from the cursor to the end, 1 from the start to the cursor, 2 everything
+    fn erase_display(&mut self, mode: usize) {
+        match mode {
+            0 => {
+                self.erase_line(0);
+                for row in self.screen.iter_mut().skip(self.row + 1) {
+                    *row = [BLANK; COLS];
+                }
+            }
+            1 => {
+                self.erase_line(1);
+                for row in self.screen.iter_mut().take(self.row) {
+                    *row = [BLANK; COLS];
+                }
+            }
+            _ => {
+                for row in self.screen.iter_mut() {
+                    *row = [BLANK; COLS];
+                }
+            }
+        }
+    }
+
+    /// As with `erase_display` but for the cursor's line
+    fn erase_line(&mut self, mode: usize) {
+        let line = &mut self.screen[self.row];
+        let range = match mode {
+            0 => self.col..COLS,
+            1 => 0..self.col + 1,
+            _ => 0..COLS,
+        };
+        for c in line[range].iter_mut() {
+            *c = BLANK;
+        }
+    }
+
+    fn insert_lines(&mut self, n: usize) {
+        for _ in 0..n.min(ROWS - self.row) {
+            self.screen.pop();
+            self.screen.insert(self.row, [BLANK; COLS]);
+        }
+        self.col = 0;
+    }
+
+    fn delete_lines(&mut self, n: usize) {
+        for _ in 0..n.min(ROWS - self.row) {
+            self.screen.remove(self.row);
+            self.screen.push([BLANK; COLS]);
+        }
+        self.col = 0;
+    }
+
+    fn insert_chars(&mut self, n: usize) {
+        let n = n.min(COLS - self.col);
+        let line = &mut self.screen[self.row];
+        line.copy_within(self.col..COLS - n, self.col + n);
+        for c in line[self.col..self.col + n].iter_mut() {
+            *c = BLANK;
+        }
+    }
+
+    fn delete_chars(&mut self, n: usize) {
+        let n = n.min(COLS - self.col);
+        let line = &mut self.screen[self.row];
+        line.copy_within(self.col + n..COLS, self.col);
+        for c in line[COLS - n..].iter_mut() {
+            *c = BLANK;
+        }
+    }
+
+    /// The text of a single row with trailing blanks removed
+    pub fn line(&self, row: usize) -> String {
+        String::from_utf8_lossy(&self.screen[row])
+            .trim_end()
+            .to_string()
+    }
+
+    /// The text on screen, trailing blanks and empty trailing lines removed
+    pub fn dump(&self) -> String {
+        let lines: Vec<String> = (0..ROWS).map(|row| self.line(row)).collect();
+        let used = lines
+            .iter()
+            .rposition(|l| !l.is_empty())
+            .map_or(0, |idx| idx + 1);
+        lines[..used].join("\n")
+    }
+
+    /// Draws the screen onto a host terminal which understands ANSI, redrawing from the top left
+    /// and leaving the host's cursor where ours is
+    pub fn render<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "\x1b[H")?;
+        for row in 0..ROWS {
+            write!(w, "{}\x1b[K", self.line(row))?;
+            if row != ROWS - 1 {
+                write!(w, "\r\n")?;
+            }
+        }
+        write!(w, "\x1b[{};{}H", self.row + 1, self.col + 1)?;
+        w.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term_with(bytes: &[u8]) -> Terminal {
+        let mut term = Terminal::new();
+        term.feed_all(bytes);
+        term
+    }
+
+    #[test]
+    fn plain_text_and_newlines() {
+        let term = term_with(b"Hello\r\nthere");
+        assert_eq!(term.dump(), "Hello\nthere");
+        assert_eq!(term.cursor(), (1, 5));
+    }
+
+    #[test]
+    fn line_feed_keeps_column() {
+        let term = term_with(b"ab\ncd");
+        assert_eq!(term.dump(), "ab\n  cd");
+    }
+
+    #[test]
+    fn backspace_and_carriage_return_overwrite() {
+        let term = term_with(b"Hello\x08p!\rJ");
+        assert_eq!(term.dump(), "Jellp!");
+    }
+
+    #[test]
+    fn wraps_and_scrolls() {
+        let mut term = Terminal::new();
+        term.feed_all(&[b'x'; COLS]);
+        assert_eq!(term.cursor(), (0, COLS - 1), "wrap is deferred");
+        term.feed(b'y');
+        assert_eq!(term.cursor(), (1, 1));
+
+        let mut term = Terminal::new();
+        for i in 0..ROWS + 2 {
+            term.feed_all(format!("{}\r\n", i).as_bytes());
+        }
+        assert_eq!(term.line(0), "3", "first three lines scrolled away");
+        assert_eq!(term.line(ROWS - 2), "25");
+    }
+
+    #[test]
+    fn ansi_cursor_movement() {
+        let term = term_with(b"\x1b[5;10Hx\x1b[2Ay\x1b[Bz\x1b[3Dw");
+        assert_eq!(term.line(2), format!("{}y", " ".repeat(10)));
+        assert_eq!(term.line(3), format!("{}w z", " ".repeat(9)));
+        assert_eq!(term.line(4), format!("{}x", " ".repeat(9)));
+    }
+
+    #[test]
+    fn ansi_clear_screen_and_line() {
+        let term = term_with(b"one\r\ntwo\r\nthree\x1b[2J\x1b[Hfour");
+        assert_eq!(term.dump(), "four");
+
+        let term = term_with(b"abcdef\x1b[3D\x1b[K");
+        assert_eq!(term.dump(), "abc");
+
+        let term = term_with(b"abcdef\x1b[3D\x1b[1K");
+        assert_eq!(term.dump(), "    ef");
+
+        let term = term_with(b"one\r\ntwo\r\nthree\x1b[2;2H\x1b[J");
+        assert_eq!(term.dump(), "one\nt");
+    }
+
+    #[test]
+    fn ansi_line_editing() {
+        let term = term_with(b"one\r\ntwo\r\nthree\x1b[2H\x1b[L");
+        assert_eq!(term.dump(), "one\n\ntwo\nthree");
+
+        let term = term_with(b"one\r\ntwo\r\nthree\x1b[1H\x1b[2M");
+        assert_eq!(term.dump(), "three");
+
+        let term = term_with(b"abcdef\x1b[1;3H\x1b[2P");
+        assert_eq!(term.dump(), "abef");
+
+        let term = term_with(b"abcdef\x1b[1;3H\x1b[2@");
+        assert_eq!(term.dump(), "ab  cdef");
+    }
+
+    #[test]
+    fn ansi_attributes_ignored() {
+        let term = term_with(b"\x1b[1;31mred\x1b[0m text");
+        assert_eq!(term.dump(), "red text");
+    }
+
+    #[test]
+    fn vt52_sequences() {
+        let mut term = Terminal::vt52();
+        term.feed_all(b"junk\x1bE\x1bY\x22\x25x\x1bAy\x1bHz");
+        assert_eq!(term.dump(), "z\n      y\n     x");
+
+        term.feed_all(b"\x1bY\x22\x20\x1bK\x1bJ");
+        assert_eq!(term.dump(), "z\n      y");
+    }
+
+    #[test]
+    fn switch_between_modes() {
+        let term = term_with(b"\x1b[?2l\x1bY\x21\x21a\x1b<\x1b[1;1Hb");
+        assert_eq!(term.dump(), "b\n a");
+    }
+
+    #[test]
+    fn render_positions_cursor() {
+        let term = term_with(b"hi");
+        let mut out: Vec<u8> = vec![];
+        term.render(&mut out).expect("render to vec");
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\x1b[Hhi\x1b[K\r\n"));
+        assert!(out.ends_with("\x1b[1;3H"));
+    }
+}
@@ -23,6 +23,7 @@ impl Flags {
             | (self.carry as u8)
     }
 
+    #[allow(clippy::wrong_self_convention)]
     pub fn from_byte(&mut self, byte: u8) {
         self.sign = util::is_bit_set(byte, 7);
         self.zero = util::is_bit_set(byte, 6);
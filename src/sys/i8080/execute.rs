@@ -600,7 +600,7 @@ impl I8080 {
     /// - val: Value to push onto the stack
     fn pop(&mut self) -> u16 {
         let result = self.memory.read_word_big_endian(self.registers.sp);
-        self.registers.sp += 2;
+        self.registers.sp = self.registers.sp.wrapping_add(2);
         result
     }
 
@@ -637,6 +637,10 @@ impl I8080 {
         self.registers.set_de(hl);
     }
 
+    /// Pushes the return address and jumps to `addr`
+    ///
+    /// The return address is that of the following instruction, unless this is an interrupt in
+    /// which case PC already points at the instruction to return to
     fn call(&mut self, addr: u16, condition: Option<bool>) {
         let mut cond = true;
         if let Some(inner_cond) = condition {
@@ -646,7 +650,13 @@ impl I8080 {
             cond = inner_cond;
         }
         if cond {
-            self.push(self.registers.pc);
+            let ret_addr = if self.in_interrupt {
+                self.registers.pc
+            } else {
+                let meta = I8080_OP_META[self.pc_inst() as usize];
+                self.registers.pc.wrapping_add(meta.width() as u16)
+            };
+            self.push(ret_addr);
             self.jmp(addr, true);
         }
     }
@@ -757,4 +767,99 @@ mod tests {
         assert_eq!(i8080.registers.sp, 0xffff, "SP is initial value");
         assert_eq!(i8080.registers.get_de(), 0xdead, "DE is 0xdead");
     }
+
+    #[test]
+    fn call_and_ret() {
+        let mut i8080 = I8080::new(vec![], vec![]);
+        i8080.load(
+            0x00,
+            vec![
+                0xcd, 0x05, 0x00, // CALL 0x0005
+                0x76, // HLT
+                0x00, // NOP
+                0x3e, 0x12, // MVI A, 0x12
+                0xc9, // RET
+            ],
+        );
+        i8080.cycle(); // CALL
+        i8080.cycle(); // MVI A, 0x12
+        i8080.cycle(); // RET
+        assert_eq!(
+            i8080.registers.pc, 0x03,
+            "returns to the instruction after CALL"
+        );
+        i8080.cycle(); // HLT
+        assert!(i8080.halted, "should halt after returning");
+        assert_eq!(i8080.registers.a, 0x12);
+        assert_eq!(i8080.registers.pc, 0x04, "PC moves past HLT");
+        assert_eq!(i8080.registers.sp, 0x00, "SP restored");
+    }
+
+    #[test]
+    fn conditional_call_and_ret() {
+        let mut i8080 = I8080::new(vec![], vec![]);
+        i8080.load(
+            0x00,
+            vec![
+                0x31, 0x00, 0x01, // LXI SP 0x0100
+                0xaf, // XRA A
+                0xc4, 0x10, 0x00, // CNZ 0x0010
+                0xcc, 0x10, 0x00, // CZ 0x0010
+                0x76, // HLT
+            ],
+        );
+        i8080.load(0x10, vec![0xc0, 0xc8]); // RNZ, RZ
+        i8080.cycle(); // LXI SP 0x0100
+        i8080.cycle(); // XRA A
+        i8080.cycle(); // CNZ
+        assert_eq!(i8080.registers.pc, 0x07, "CNZ not taken");
+        i8080.cycle(); // CZ
+        assert_eq!(i8080.registers.pc, 0x10, "CZ taken");
+        assert_eq!(
+            i8080.memory.read_word_big_endian(i8080.registers.sp),
+            0x0a,
+            "returns to the instruction after CZ"
+        );
+        i8080.cycle(); // RNZ
+        assert_eq!(i8080.registers.pc, 0x11, "RNZ not taken");
+        i8080.cycle(); // RZ
+        i8080.cycle(); // HLT
+        assert!(i8080.halted, "should halt after returning");
+        assert_eq!(i8080.registers.pc, 0x0b, "PC moves past HLT");
+        assert_eq!(i8080.registers.sp, 0x0100, "SP restored");
+    }
+
+    #[test]
+    fn interrupt_returns_to_pc() {
+        let mut i8080 = I8080::new(vec![], vec![]);
+        i8080.load(0x00, vec![0x31, 0x00, 0x01]); // LXI SP 0x0100
+        i8080.cycle();
+        i8080.issue_interrupt(0xcf); // RST 1
+        i8080.cycle();
+        assert_eq!(i8080.registers.pc, 0x08, "RST 1 jumps to 0x0008");
+        assert_eq!(
+            i8080.memory.read_word_big_endian(i8080.registers.sp),
+            0x03,
+            "returns to the interrupted instruction"
+        );
+    }
+
+    #[test]
+    fn pc_spans_the_address_space() {
+        let mut i8080 = I8080::new(vec![], vec![]);
+        i8080.load(0x0100, vec![0x3e, 0x12, 0x76]); // MVI A, 0x12; HLT
+        i8080.registers.pc = 0x0100;
+        i8080.cycle();
+        assert!(!i8080.halted, "runs past 0x00ff");
+        assert_eq!(i8080.registers.a, 0x12);
+
+        let mut i8080 = I8080::new(vec![], vec![]);
+        i8080.load(0xfffd, vec![0x00, 0x00, 0x00]); // NOP
+        i8080.registers.pc = 0xfffd;
+        i8080.cycle();
+        i8080.cycle();
+        assert!(!i8080.halted, "still within the address space");
+        i8080.cycle();
+        assert!(i8080.halted, "halts at the end of the address space");
+    }
 }
@@ -27,6 +27,7 @@ pub struct I8080 {
     pub halted: bool,
     interrupt_flip_flop: bool,
     interrupt_op_code: Option<u8>,
+    in_interrupt: bool,
     rx_devices: Vec<RxDevice>,
     tx_devices: Vec<TxDevice>,
 
@@ -50,6 +51,7 @@ impl I8080 {
             halted: false,
             interrupt_flip_flop: false,
             interrupt_op_code: None,
+            in_interrupt: false,
             rx_devices,
             tx_devices,
             interactive: false,
@@ -92,6 +94,7 @@ impl I8080 {
         };
         let meta: OpMeta = I8080_OP_META[inst as usize];
         let pc = self.registers.pc;
+        self.in_interrupt = is_interrupt;
         self.execute(inst);
         let pc_changed = self.registers.pc != pc;
         self.cycles += meta.cycles as u64;
@@ -100,7 +103,7 @@ impl I8080 {
         }
         self.log_cycle();
         if !pc_changed && !is_interrupt {
-            if self.registers.pc as u16 + meta.width() as u16 > u8::MAX.into() {
+            if self.registers.pc as u32 + meta.width() as u32 > u16::MAX.into() {
                 warn!("PC larger than address space, halting");
                 self.halt()
             } else {
@@ -109,6 +112,15 @@ impl I8080 {
         }
     }
 
+    /// Runs until halted or until `budget` cycles have passed, returning whether the CPU halted
+    pub fn run_for(&mut self, budget: u64) -> bool {
+        let until = self.cycles.saturating_add(budget);
+        while !self.halted && self.cycles < until {
+            self.cycle();
+        }
+        self.halted
+    }
+
     pub fn issue_interrupt(&mut self, inst: u8) {
         self.interrupt_flip_flop = true;
         self.interrupt_op_code = Some(inst);
@@ -132,6 +144,43 @@ impl I8080 {
         self.registers.pc
     }
 
+    pub(crate) fn set_pc(&mut self, pc: u16) {
+        self.registers.pc = pc;
+    }
+
+    /// Value of a register or register pair by name, e.g. `A`, `HL` or `SP`
+    pub(crate) fn get_register(&self, name: &str) -> Option<u16> {
+        let r = &self.registers;
+        Some(match name {
+            "A" => r.a as u16,
+            "B" => r.b as u16,
+            "C" => r.c as u16,
+            "D" => r.d as u16,
+            "E" => r.e as u16,
+            "H" => r.h as u16,
+            "L" => r.l as u16,
+            "BC" => r.get_bc(),
+            "DE" => r.get_de(),
+            "HL" => r.get_hl(),
+            "SP" => r.sp,
+            "PC" => r.pc,
+            _ => return None,
+        })
+    }
+
+    /// Value of a flag by its short or long name, e.g. `Z` or `ZERO`
+    pub(crate) fn get_flag(&self, name: &str) -> Option<bool> {
+        let f = &self.flags;
+        Some(match name {
+            "S" | "SIGN" => f.sign,
+            "Z" | "ZERO" => f.zero,
+            "AC" | "AUX" => f.aux_carry,
+            "P" | "PARITY" => f.parity,
+            "CY" | "CARRY" => f.carry,
+            _ => return None,
+        })
+    }
+
     /// Get the instruction at PC
     fn pc_inst(&self) -> u8 {
         self.memory.read_byte(self.registers.pc)
@@ -168,7 +217,7 @@ impl I8080 {
         let address = if is_interrupt {
             "n/a".to_string()
         } else {
-            format!("{:#04x}", pc as u16)
+            format!("{:#04x}", pc)
         };
         format!(
             "Inst {{ addr: {}, dis: \"{}\", hex: [{}], interrupt: {} }}",
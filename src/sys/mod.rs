@@ -10,6 +10,23 @@
 //! I've provided a simplistic console device which can be used to output text to make use of this
 //! `OUT` instruction.
 //!
+//! By default the console buffers text until a NUL or ETB, `--console stream` writes characters as
+//! they arrive, and `--console vt52` or `--console vt100` interpret cursor movement and clearing
+//! escape sequences on a virtual 80x24 screen. With `--dump-screen`, that screen is printed as
+//! plain text at halt rather than drawn as the program runs.
+//!
+//! Whatever the console produced can be saved with `--console-out`, and `--expect-output` compares
+//! it against a golden file, printing a diff and exiting with `E_OUTPUT_MISMATCH` if they differ.
+//!
+//! # Loading
+//!
+//! Files are loaded as raw binaries at `--load-at`, unless they end `.hex` or `.ihx` in which case
+//! they are read as Intel HEX. Every region of the image is loaded at its own address (so
+//! `--load-at` is ignored) and execution begins at the image's start address, if it gives one.
+//!
+//! Those ending `.prl` are page relocatable, see [prl], and are moved to run from
+//! `--load-at`, which must be on a page boundary, or from 0x0100 if it isn't given.
+//!
 //! # Interrupts
 //!
 //! Interrupts may be issued as single `u8` operation codes as per the manual I found somewhere;
@@ -19,18 +36,22 @@
 //!
 //! The emulator can be ran interactively, this will drop you into a prompt where you can
 //! cycling/debug the CPU.
+//!
+//! Given `--symbols`, or when assembling with `--assemble`, addresses in the prompt may be given
+//! as label names and labelled addresses are named as the CPU reaches them.
 
 pub mod i8080;
 
-mod device;
+pub(crate) mod device;
+
 mod flags;
 mod memory;
 mod registers;
 
 use std::{
+    collections::HashMap,
     fs,
     num::ParseIntError,
-    path::PathBuf,
     sync::mpsc::{self, Receiver, Sender},
     thread,
 };
@@ -38,9 +59,12 @@ use std::{
 use rustyline::error::ReadlineError;
 
 use crate::{
-    asm::{assemble::Assembler, disassemble::disassemble_instruction},
-    cli::{AssembleArgs, RunArgs},
-    ecodes::{E_ASSEMBLER, E_IO_ERROR, E_SUCCESS},
+    asm::{assemble::Assembler, disassemble::disassemble_instruction, label::Label, symbols},
+    cli::{AssembleArgs, ConsoleMode, RunArgs},
+    ecodes::{E_ASSEMBLER, E_IO_ERROR, E_OUTPUT_MISMATCH, E_SUCCESS},
+    ihex,
+    prl::{self, Prl},
+    util,
 };
 
 use self::{
@@ -56,7 +80,11 @@ pub fn run_system(args: RunArgs) -> i32 {
         I8080::new(vec![], vec![])
     } else {
         let (tx, rx): (Sender<u8>, Receiver<u8>) = mpsc::channel();
-        console = Some(ConsoleDevice::new(rx, false));
+        console = Some(
+            ConsoleDevice::new(rx, false)
+                .with_mode(args.console)
+                .with_render(!args.dump_screen),
+        );
         let tx_device = TxDevice::new(tx, special_chars::EOT);
         I8080::new(vec![], vec![tx_device])
     };
@@ -68,20 +96,79 @@ pub fn run_system(args: RunArgs) -> i32 {
     let load_address = args.load_at.unwrap_or(0);
     let filename_plain = args.file.as_path().display();
 
+    let mut symbols = match &args.symbols {
+        Some(path) => {
+            let read = fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| symbols::read_text(&text).map_err(|e| e.to_string()));
+            match read {
+                Ok(symbols) => symbols,
+                Err(e) => {
+                    println!("Failed to read symbols: {}\n\n{}", path.display(), e);
+                    return E_IO_ERROR;
+                }
+            }
+        }
+        None => HashMap::new(),
+    };
+
     let program = if args.assemble {
         let mut assembler = Assembler::new(AssembleArgs {
             input: args.file.clone(),
-            output: PathBuf::new(),
             load_at: load_address,
             register_definitions: true,
             hlt: true,
+            ..Default::default()
         });
         match assembler.assemble() {
-            Ok(bytes) => bytes,
+            Ok(bytes) => {
+                if args.symbols.is_none() {
+                    symbols = assembler.symbols();
+                }
+                bytes
+            }
             Err(_) => {
                 return E_ASSEMBLER;
             }
         }
+    } else if ihex::is_hex_file(&args.file) {
+        let image = match fs::read_to_string(&args.file) {
+            Ok(text) => ihex::decode(&text),
+            Err(e) => {
+                println!("Failed to read file: {}\n\n{}", filename_plain, e);
+                return E_IO_ERROR;
+            }
+        };
+        match image {
+            Ok(image) => {
+                load_image(&mut i8080, image);
+                vec![]
+            }
+            Err(e) => {
+                println!("Failed to load Intel HEX: {}\n\n{}", filename_plain, e);
+                return E_IO_ERROR;
+            }
+        }
+    } else if prl::is_prl_file(&args.file) {
+        let image = match fs::read(&args.file) {
+            Ok(bytes) => {
+                Prl::decode(&bytes).and_then(|prl| prl.relocate(args.load_at.unwrap_or(0x100)))
+            }
+            Err(e) => {
+                println!("Failed to read file: {}\n\n{}", filename_plain, e);
+                return E_IO_ERROR;
+            }
+        };
+        match image {
+            Ok(image) => {
+                load_image(&mut i8080, image);
+                vec![]
+            }
+            Err(e) => {
+                println!("Failed to load PRL: {}\n\n{}", filename_plain, e);
+                return E_IO_ERROR;
+            }
+        }
     } else {
         match fs::read(args.file.clone()) {
             Ok(bytes) => bytes,
@@ -98,19 +185,63 @@ pub fn run_system(args: RunArgs) -> i32 {
 
     if args.interactive {
         i8080.interactive = true;
-        run_interactive(&mut i8080);
+        run_interactive(&mut i8080, &symbols);
         i8080.halt();
     } else {
         i8080.run(args.emulate_clock_speed);
     }
 
     if let Some(th) = th_cons {
-        th.join().unwrap();
+        let output = th.join().unwrap();
+        if args.dump_screen && matches!(args.console, ConsoleMode::Vt52 | ConsoleMode::Vt100) {
+            println!("{}", String::from_utf8_lossy(&output));
+        }
+        return check_console_output(&args, &output);
     }
 
     E_SUCCESS
 }
 
+/// Each region is loaded at its own address, execution starts at the image's start address if it
+/// has one
+fn load_image(i8080: &mut I8080, image: ihex::Image) {
+    for (addr, bytes) in image.regions {
+        i8080.load(addr, bytes);
+    }
+    if let Some(start) = image.start {
+        i8080.set_pc(start);
+    }
+}
+
+/// Writes the console output to `--console-out` and compares it against `--expect-output`
+fn check_console_output(args: &RunArgs, output: &[u8]) -> i32 {
+    if let Some(path) = &args.console_out {
+        if let Err(e) = fs::write(path, output) {
+            println!("Failed to write file: {}\n\n{}", path.display(), e);
+            return E_IO_ERROR;
+        }
+    }
+    if let Some(path) = &args.expect_output {
+        let expected = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                println!("Failed to read file: {}\n\n{}", path.display(), e);
+                return E_IO_ERROR;
+            }
+        };
+        if expected != output {
+            println!("Console output differs from {}\n", path.display());
+            let expected = String::from_utf8_lossy(&expected);
+            let actual = String::from_utf8_lossy(output);
+            for line in util::diff_lines(&expected, &actual) {
+                println!("{}", line);
+            }
+            return E_OUTPUT_MISMATCH;
+        }
+    }
+    E_SUCCESS
+}
+
 macro_rules! continue_on_err {
     ($res:expr) => {
         match $res {
@@ -137,10 +268,13 @@ d | dis | disassemble) disassemble next instruction
 
 m | mem | memory) print values in memory
     u16: n bytes [default: 1]
-    u16: address [default: PC]\
+    u16: address [default: PC]
+
+Addresses may also be given as label names when symbols are loaded\
 ";
 
-fn run_interactive(i8080: &mut I8080) {
+fn run_interactive(i8080: &mut I8080, symbols: &HashMap<String, Label>) {
+    let labels = symbols::addresses(symbols);
     let mut rl = rustyline::Editor::<()>::with_config(
         rustyline::Config::builder()
             .edit_mode(rustyline::EditMode::Vi)
@@ -180,7 +314,7 @@ fn run_interactive(i8080: &mut I8080) {
             "h" | "?" | "help" => println!("{}", PROMPT_HELP),
             "c" | "cycle" => {
                 cycling = true;
-                if !prompt_cycle(i8080) {
+                if !prompt_cycle(i8080, &labels) {
                     break;
                 }
             }
@@ -190,7 +324,7 @@ fn run_interactive(i8080: &mut I8080) {
                     println!("Interrupt takes one arg");
                     continue;
                 }
-                let inst = continue_on_err!(parse_number(args.get(0).unwrap())) as u8;
+                let inst = continue_on_err!(parse_number(args.first().unwrap())) as u8;
                 i8080.issue_interrupt(inst);
                 println!("Interrupt issues, instruction {:#02x}", inst);
             }
@@ -199,9 +333,9 @@ fn run_interactive(i8080: &mut I8080) {
                     println!("Up to two args required: {:?}", args);
                     continue;
                 }
-                let len = continue_on_err!(parse_number(args.get(0).unwrap_or(&"1")));
+                let len = continue_on_err!(parse_number(args.first().unwrap_or(&"1")));
                 let addr = if let Some(arg) = args.get(1) {
-                    continue_on_err!(parse_number(arg))
+                    continue_on_err!(parse_address(arg, symbols))
                 } else {
                     i8080.get_pc()
                 };
@@ -212,11 +346,14 @@ fn run_interactive(i8080: &mut I8080) {
                     println!("Zero or one args required: {:?}", args);
                     continue;
                 }
-                let addr = if let Some(arg) = args.get(0) {
-                    continue_on_err!(parse_number(arg))
+                let addr = if let Some(arg) = args.first() {
+                    continue_on_err!(parse_address(arg, symbols))
                 } else {
                     i8080.get_pc()
                 };
+                if let Some(name) = labels.get(&addr) {
+                    println!("{}:", name);
+                }
                 let (s, _) =
                     continue_on_err!(disassemble_instruction(&i8080.get_memory_slice(addr, 3), 0));
                 println!("{}", s);
@@ -224,7 +361,7 @@ fn run_interactive(i8080: &mut I8080) {
             "q" | "quit" | "e" | "exit" => break,
             "" => {
                 if cycling {
-                    if !prompt_cycle(i8080) {
+                    if !prompt_cycle(i8080, &labels) {
                         break;
                     }
                 } else {
@@ -236,17 +373,28 @@ fn run_interactive(i8080: &mut I8080) {
     }
 }
 
-fn prompt_cycle(i8080: &mut I8080) -> bool {
+fn prompt_cycle(i8080: &mut I8080, labels: &HashMap<u16, String>) -> bool {
     if i8080.halted {
         println!("CPU previously halted, breaking");
         false
     } else {
+        if let Some(name) = labels.get(&i8080.get_pc()) {
+            println!("{}:", name);
+        }
         i8080.cycle();
         println!("{}", i8080.current_state);
         true
     }
 }
 
+/// A label's value if one goes by that name, otherwise a number
+fn parse_address(input: &str, symbols: &HashMap<String, Label>) -> Result<u16, ParseIntError> {
+    match symbols.get(&input.to_uppercase()).and_then(|l| l.value) {
+        Some(value) => Ok(value),
+        None => parse_number(input),
+    }
+}
+
 fn parse_number(input: &str) -> Result<u16, ParseIntError> {
     let mut s = input.to_string();
     let radix = if s.starts_with("0x") {
@@ -0,0 +1,301 @@
+//! Parsing and checking of the `;!` directives
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    asm::{
+        expressions::{
+            errors::ExpressionError,
+            parser::{parse_expression, parse_expression_u16},
+        },
+        label::Label,
+    },
+    sys::i8080::I8080,
+};
+
+const REGISTERS: [&str; 12] = [
+    "A", "B", "C", "D", "E", "H", "L", "BC", "DE", "HL", "SP", "PC",
+];
+const FLAGS: [&str; 10] = [
+    "S", "SIGN", "Z", "ZERO", "AC", "AUX", "P", "PARITY", "CY", "CARRY",
+];
+
+#[derive(Debug)]
+pub enum DirectiveError {
+    UnknownDirective(String),
+    UnknownRegister(String),
+    UnknownFlag(String),
+    MissingValue(String),
+    InvalidCycles(String),
+    Expression(ExpressionError),
+    OutsideOfTest,
+    Unresolved(String),
+}
+
+impl std::error::Error for DirectiveError {}
+
+impl fmt::Display for DirectiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownDirective(s) => write!(f, "Unknown directive: [{}]", s),
+            Self::UnknownRegister(s) => write!(f, "Unknown register: [{}]", s),
+            Self::UnknownFlag(s) => write!(f, "Unknown flag: [{}]", s),
+            Self::MissingValue(s) => write!(f, "No value given for [{}]", s),
+            Self::InvalidCycles(s) => write!(f, "Invalid cycle count: [{}]", s),
+            Self::Expression(e) => write!(f, "Expression evaluation error: {}", e),
+            Self::OutsideOfTest => write!(f, "Directive found before any test"),
+            Self::Unresolved(s) => write!(f, "Test label has no address: [{}]", s),
+        }
+    }
+}
+
+impl From<ExpressionError> for DirectiveError {
+    fn from(e: ExpressionError) -> Self {
+        DirectiveError::Expression(e)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Directive {
+    Test,
+    Cycles(u64),
+    Expect(Expectation),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expectation {
+    Register(String, u16),
+    Flag(String, bool),
+    Memory(u16, Vec<u8>),
+    Output(Vec<u8>),
+}
+
+impl Expectation {
+    /// Checks the expectation against a halted CPU and the console output it produced, giving a
+    /// description of the failure if there is one
+    pub fn check(&self, i8080: &I8080, output: &[u8]) -> Option<String> {
+        match self {
+            Self::Register(name, exp) => {
+                let act = i8080.get_register(name).unwrap_or_default();
+                (act != *exp).then(|| format!("{} expected {:#04x}, found {:#04x}", name, exp, act))
+            }
+            Self::Flag(name, exp) => {
+                let act = i8080.get_flag(name).unwrap_or_default();
+                (act != *exp)
+                    .then(|| format!("FLAG {} expected {}, found {}", name, *exp as u8, act as u8))
+            }
+            Self::Memory(addr, exp) => {
+                let act = i8080.get_memory_slice(*addr, exp.len() as u16);
+                (act != *exp).then(|| {
+                    format!(
+                        "MEM {:#06x} expected {:02x?}, found {:02x?}",
+                        addr, exp, act
+                    )
+                })
+            }
+            Self::Output(exp) => (output != exp.as_slice()).then(|| {
+                format!(
+                    "OUT expected {:?}, found {:?}",
+                    String::from_utf8_lossy(exp),
+                    String::from_utf8_lossy(output)
+                )
+            }),
+        }
+    }
+}
+
+/// Parses the text following a `;!`, expressions are resolved with the given labels
+pub fn parse_directive(
+    text: &str,
+    labels: &HashMap<String, Label>,
+) -> Result<Directive, DirectiveError> {
+    let text = uppercase_outside_quotes(text.trim());
+    let (keyword, rest) = split_word(&text);
+    match keyword {
+        "TEST" => Ok(Directive::Test),
+        "CYCLES" => rest
+            .replace('_', "")
+            .parse::<u64>()
+            .map(Directive::Cycles)
+            .map_err(|_| DirectiveError::InvalidCycles(rest.to_string())),
+        "EXPECT" => parse_expectation(rest, labels).map(Directive::Expect),
+        _ => Err(DirectiveError::UnknownDirective(text.to_string())),
+    }
+}
+
+fn parse_expectation(
+    text: &str,
+    labels: &HashMap<String, Label>,
+) -> Result<Expectation, DirectiveError> {
+    let (target, rest) = split_word(text);
+    match target {
+        "OUT" => Ok(Expectation::Output(parse_bytes(rest, labels)?)),
+        "FLAG" => {
+            let (name, value) = split_assignment(rest)?;
+            if !FLAGS.contains(&name.as_str()) {
+                return Err(DirectiveError::UnknownFlag(name));
+            }
+            let (val, _) = parse_expression_u16(value, 0, labels)?;
+            Ok(Expectation::Flag(name, val != 0))
+        }
+        "MEM" => {
+            let (addr, values) = split_assignment(rest)?;
+            let (addr, _) = parse_expression_u16(addr, 0, labels)?;
+            Ok(Expectation::Memory(addr, parse_bytes(&values, labels)?))
+        }
+        _ => {
+            let (name, value) = split_assignment(text)?;
+            if !REGISTERS.contains(&name.as_str()) {
+                return Err(DirectiveError::UnknownRegister(name));
+            }
+            let (val, _) = parse_expression_u16(value, 0, labels)?;
+            Ok(Expectation::Register(name, val))
+        }
+    }
+}
+
+/// A list of expressions laid out as `DB` would
+fn parse_bytes(text: &str, labels: &HashMap<String, Label>) -> Result<Vec<u8>, DirectiveError> {
+    let mut bytes = vec![];
+    for item in split_outside_quotes(text, ',') {
+        if item.is_empty() {
+            continue;
+        }
+        let (mut expr, flags) = parse_expression(item, 0, labels)?;
+        if flags.string {
+            bytes.append(&mut expr);
+        } else if let Some(byte) = expr.first() {
+            bytes.push(*byte);
+        }
+    }
+    Ok(bytes)
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.find(char::is_whitespace) {
+        Some(idx) => (&text[..idx], text[idx..].trim()),
+        None => (text, ""),
+    }
+}
+
+fn split_assignment(text: &str) -> Result<(String, String), DirectiveError> {
+    let mut parts = split_outside_quotes(text, '=').into_iter();
+    let name = parts.next().unwrap_or_default();
+    match parts.next() {
+        Some(value) if !value.is_empty() => Ok((name, value)),
+        _ => Err(DirectiveError::MissingValue(name)),
+    }
+}
+
+/// Splits on every `sep` found outside of quotes
+fn split_outside_quotes(text: &str, sep: char) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        if c == sep && !in_quotes {
+            parts.push(part.trim().to_string());
+            part = String::new();
+            continue;
+        }
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && in_quotes {
+            escaped = true;
+        } else if c == '\'' {
+            in_quotes = !in_quotes;
+        }
+        part.push(c);
+    }
+    parts.push(part.trim().to_string());
+    parts
+}
+
+/// Labels and keywords are uppercase to the assembler but strings must be left alone
+fn uppercase_outside_quotes(text: &str) -> String {
+    let mut out = String::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && in_quotes {
+            escaped = true;
+        } else if c == '\'' {
+            in_quotes = !in_quotes;
+        }
+        if in_quotes {
+            out.push(c);
+        } else {
+            out.extend(c.to_uppercase());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Directive, DirectiveError> {
+        let mut labels = HashMap::new();
+        labels.insert("_BUF".to_string(), Label::new_addr(Some(0x1234)));
+        parse_directive(text, &labels)
+    }
+
+    #[test]
+    fn test_and_cycles() {
+        assert_eq!(parse("test").unwrap(), Directive::Test);
+        assert_eq!(parse("CYCLES 1_000").unwrap(), Directive::Cycles(1000));
+        let e = parse("CYCLES lots").unwrap_err();
+        assert!(matches!(e, DirectiveError::InvalidCycles(_)));
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(
+            parse("EXPECT a = 0x12").unwrap(),
+            Directive::Expect(Expectation::Register("A".to_string(), 0x12))
+        );
+        assert_eq!(
+            parse("EXPECT HL = _buf + 1").unwrap(),
+            Directive::Expect(Expectation::Register("HL".to_string(), 0x1235))
+        );
+        let e = parse("EXPECT Q = 1").unwrap_err();
+        assert!(matches!(e, DirectiveError::UnknownRegister(_)));
+        let e = parse("EXPECT A").unwrap_err();
+        assert!(matches!(e, DirectiveError::MissingValue(_)));
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(
+            parse("EXPECT FLAG cy = 1").unwrap(),
+            Directive::Expect(Expectation::Flag("CY".to_string(), true))
+        );
+        let e = parse("EXPECT FLAG X = 1").unwrap_err();
+        assert!(matches!(e, DirectiveError::UnknownFlag(_)));
+    }
+
+    #[test]
+    fn memory_and_output() {
+        assert_eq!(
+            parse("EXPECT MEM _buf = 'a=b', 0x0102").unwrap(),
+            Directive::Expect(Expectation::Memory(0x1234, vec![b'a', b'=', b'b', 0x02]))
+        );
+        assert_eq!(
+            parse("EXPECT OUT 'Hi, there\\n'").unwrap(),
+            Directive::Expect(Expectation::Output(b"Hi, there\n".to_vec()))
+        );
+        let e = parse("EXPECT OUT _nope").unwrap_err();
+        assert!(matches!(e, DirectiveError::Expression(_)));
+    }
+
+    #[test]
+    fn unknown_directive() {
+        let e = parse("ASSUME A = 1").unwrap_err();
+        assert!(matches!(e, DirectiveError::UnknownDirective(_)));
+    }
+}
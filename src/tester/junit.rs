@@ -0,0 +1,93 @@
+//! JUnit XML reports
+
+use super::TestResult;
+
+pub fn report(suite: &str, results: &[TestResult]) -> String {
+    let failures = results.iter().filter(|r| !r.passed()).count();
+    let time: f64 = results.iter().map(|r| r.time.as_secs_f64()).sum();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.6}\">\n",
+        results.len(),
+        failures,
+        time
+    ));
+    xml.push_str(&format!(
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.6}\">\n",
+        escape(suite),
+        results.len(),
+        failures,
+        time
+    ));
+    for result in results.iter() {
+        xml.push_str(&format!(
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.6}\"",
+            escape(&result.name),
+            escape(suite),
+            result.time.as_secs_f64()
+        ));
+        if result.passed() {
+            xml.push_str("/>\n");
+            continue;
+        }
+        xml.push_str(">\n");
+        xml.push_str(&format!(
+            "      <failure message=\"{}\">{}</failure>\n",
+            escape(&result.failures[0]),
+            escape(&result.failures.join("\n"))
+        ));
+        xml.push_str("    </testcase>\n");
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        assert_eq!(
+            escape("a<b & 'c' > \"d\""),
+            "a&lt;b &amp; &apos;c&apos; &gt; &quot;d&quot;"
+        );
+    }
+
+    #[test]
+    fn counts_failures() {
+        let results = vec![
+            TestResult {
+                name: "TEST_OK".to_string(),
+                failures: vec![],
+                time: Duration::ZERO,
+            },
+            TestResult {
+                name: "TEST_BAD".to_string(),
+                failures: vec!["A expected 0x02, found 0x01".to_string()],
+                time: Duration::ZERO,
+            },
+        ];
+        let xml = report("suite", &results);
+        assert!(xml.contains("<testsuites tests=\"2\" failures=\"1\""));
+        assert!(xml.contains("<testcase name=\"TEST_OK\" classname=\"suite\" time=\"0.000000\"/>"));
+        assert!(xml.contains("<failure message=\"A expected 0x02, found 0x01\">"));
+    }
+}
@@ -0,0 +1,291 @@
+//! Unit tests for assembly routines
+//!
+//! Tests live alongside the code they exercise. Any label starting with `TEST_` is a test, as is
+//! any label following a `;! TEST` comment. Each test runs on a fresh CPU with the whole program
+//! loaded, starting at its label and stopping at a `HLT`. A test which doesn't halt within its
+//! cycle budget fails. Tests are found in `INCLUDE`d files and macro expansions too, and
+//! `--dialect` reads the source as `asm` would.
+//!
+//! Directives are comments starting `;!` and apply to the most recent test:
+//!
+//! ```asm
+//! TEST_ADD:
+//!     ;! CYCLES 100
+//!     ;! EXPECT A = 5
+//!     ;! EXPECT FLAG Z = 0
+//!     MVI A, 2
+//!     ADI 3
+//!     HLT
+//! ```
+//!
+//! - `;! CYCLES n` overrides the `--cycles` budget
+//! - `;! EXPECT <reg> = <expr>` checks `A`-`L`, `BC`, `DE`, `HL`, `SP` or `PC`
+//! - `;! EXPECT FLAG <flag> = <expr>` checks `S`, `Z`, `AC`, `P` or `CY`
+//! - `;! EXPECT MEM <addr> = <expr>, ...` checks memory laid out as `DB` would
+//! - `;! EXPECT OUT <expr>, ...` checks what was written to the console
+//!
+//! Expressions may use any label in the program.
+//!
+//! Results are reported much like `cargo test` and `--junit` writes a JUnit XML report for CI.
+
+mod expectation;
+mod junit;
+
+use std::{
+    fs,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    asm::assemble::Assembler,
+    cli::{AssembleArgs, TestArgs},
+    ecodes::{E_ASSEMBLER, E_IO_ERROR, E_SUCCESS, E_TEST_FAILURE},
+    sys::{
+        device::{
+            console_device::special_chars::{EOT, ETB, NUL},
+            TxDevice,
+        },
+        i8080::I8080,
+    },
+};
+
+use self::expectation::{parse_directive, Directive, DirectiveError, Expectation};
+
+const TEST_PREFIX: &str = "TEST_";
+
+#[derive(Debug)]
+struct TestCase {
+    name: String,
+    entry: u16,
+    cycles: Option<u64>,
+    /// Each with where it was written, as `file:line`
+    expectations: Vec<(String, Expectation)>,
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+    pub name: String,
+    pub failures: Vec<String>,
+    pub time: Duration,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+pub fn run_tests(args: TestArgs) -> i32 {
+    let mut assembler = assembler(&args);
+    let program = match assembler.assemble() {
+        Ok(bytes) => bytes,
+        Err(_) => return E_ASSEMBLER,
+    };
+
+    let cases = match find_tests(&assembler) {
+        Ok(cases) => cases,
+        Err((location, e)) => {
+            println!("{}: {}", location, e);
+            return E_ASSEMBLER;
+        }
+    };
+
+    let cases: Vec<TestCase> = cases
+        .into_iter()
+        .filter(|c| match &args.filter {
+            Some(f) => c.name.contains(&f.to_uppercase()),
+            None => true,
+        })
+        .collect();
+
+    println!("\nrunning {} tests", cases.len());
+    let results: Vec<TestResult> = cases
+        .iter()
+        .map(|case| {
+            let result = run_test(case, &program, args.cycles);
+            println!(
+                "test {} ... {}",
+                result.name,
+                if result.passed() { "ok" } else { "FAILED" }
+            );
+            result
+        })
+        .collect();
+
+    let failed: Vec<&TestResult> = results.iter().filter(|r| !r.passed()).collect();
+    if !failed.is_empty() {
+        println!("\nfailures:");
+        for result in failed.iter() {
+            println!("\n---- {} ----", result.name);
+            for failure in result.failures.iter() {
+                println!("    {}", failure);
+            }
+        }
+    }
+    println!(
+        "\ntest result: {}. {} passed; {} failed\n",
+        if failed.is_empty() { "ok" } else { "FAILED" },
+        results.len() - failed.len(),
+        failed.len()
+    );
+
+    if let Some(path) = &args.junit {
+        let suite = args.input.display().to_string();
+        if let Err(e) = fs::write(path, junit::report(&suite, &results)) {
+            println!("{}\n {}", e, path.display());
+            return E_IO_ERROR;
+        }
+    }
+
+    if failed.is_empty() {
+        E_SUCCESS
+    } else {
+        E_TEST_FAILURE
+    }
+}
+
+/// An assembler for the tests' source, registers are always defined
+fn assembler(args: &TestArgs) -> Assembler {
+    Assembler::new(AssembleArgs {
+        input: args.input.clone(),
+        dialect: args.dialect,
+        register_definitions: true,
+        ..Default::default()
+    })
+}
+
+/// Collects the tests and their directives from the source as the assembler read it, with
+/// `INCLUDE`s spliced in and labels placed by macros found on the line calling them
+fn find_tests(assembler: &Assembler) -> Result<Vec<TestCase>, (String, DirectiveError)> {
+    let labels = assembler.labels();
+    let mut cases: Vec<TestCase> = vec![];
+    let mut next_is_test = false;
+
+    for line in assembler.source_lines() {
+        let location = format!("{}:{}", line.file.display(), line.line_no);
+
+        for label in line.labels {
+            if next_is_test || label.starts_with(TEST_PREFIX) {
+                next_is_test = false;
+                let entry = match labels.get(&label).and_then(|l| l.value) {
+                    Some(entry) => entry,
+                    None => return Err((location, DirectiveError::Unresolved(label))),
+                };
+                cases.push(TestCase {
+                    name: label,
+                    entry,
+                    cycles: None,
+                    expectations: vec![],
+                });
+            }
+        }
+
+        let text = match line.raw_line.find(";!") {
+            Some(idx) => &line.raw_line[idx + 2..],
+            None => continue,
+        };
+        match parse_directive(text, labels) {
+            Ok(Directive::Test) => next_is_test = true,
+            Ok(directive) => match (cases.last_mut(), directive) {
+                (Some(case), Directive::Cycles(n)) => case.cycles = Some(n),
+                (Some(case), Directive::Expect(exp)) => case.expectations.push((location, exp)),
+                _ => return Err((location, DirectiveError::OutsideOfTest)),
+            },
+            Err(e) => return Err((location, e)),
+        }
+    }
+    Ok(cases)
+}
+
+fn run_test(case: &TestCase, program: &[u8], default_cycles: u64) -> TestResult {
+    let start = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    let mut i8080 = I8080::new(vec![], vec![TxDevice::new(tx, EOT)]);
+    i8080.load(0, program.to_vec());
+    i8080.set_pc(case.entry);
+
+    let mut failures = vec![];
+    let budget = case.cycles.unwrap_or(default_cycles);
+    if !i8080.run_for(budget) {
+        failures.push(format!(
+            "did not halt within {} cycles, PC at {:#06x}",
+            budget,
+            i8080.get_pc()
+        ));
+        i8080.halt();
+    }
+
+    let output: Vec<u8> = rx
+        .try_iter()
+        .take_while(|b| *b != EOT)
+        .filter(|b| *b != NUL && *b != ETB)
+        .collect();
+    for (location, exp) in case.expectations.iter() {
+        if let Some(msg) = exp.check(&i8080, &output) {
+            failures.push(format!("{}: {}", location, msg));
+        }
+    }
+
+    TestResult {
+        name: case.name.to_string(),
+        failures,
+        time: start.elapsed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cli::Dialect, util::test::rsc};
+
+    fn args(file: &str) -> TestArgs {
+        TestArgs {
+            input: rsc(file),
+            cycles: 1_000_000,
+            filter: None,
+            dialect: Dialect::Native,
+            junit: None,
+        }
+    }
+
+    #[test]
+    fn passing_tests() {
+        assert_eq!(run_tests(args("asm/tests.asm")), E_SUCCESS);
+    }
+
+    #[test]
+    fn included_and_macro_tests() {
+        let mut assembler = assembler(&args("asm/tests-included.asm"));
+        assembler.assemble().unwrap();
+        let cases = find_tests(&assembler).unwrap();
+        let names: Vec<&str> = cases.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["TEST_ADD", "TEST_CALL", "_PRINTS", "TEST_MACRO"]
+        );
+        assert!(cases[0].expectations[0].0.ends_with("tests.asm:5"));
+
+        assert_eq!(run_tests(args("asm/tests-included.asm")), E_SUCCESS);
+    }
+
+    #[test]
+    fn failing_tests() {
+        let junit = rsc("aux/tests-failing.xml");
+        let mut args = args("asm/tests-failing.asm");
+        args.junit = Some(junit.clone());
+        assert_eq!(run_tests(args), E_TEST_FAILURE);
+
+        let report = fs::read_to_string(junit).unwrap();
+        assert!(report.contains("tests=\"3\" failures=\"2\""));
+        assert!(report.contains("A expected 0x02, found 0x01"));
+        assert!(report.contains("did not halt within 50 cycles"));
+    }
+
+    #[test]
+    fn filtered_tests() {
+        let mut args = args("asm/tests-failing.asm");
+        args.filter = Some("passes".to_string());
+        assert_eq!(run_tests(args), E_SUCCESS);
+    }
+}
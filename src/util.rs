@@ -1,6 +1,6 @@
 use std::{
     fs::{self, File},
-    io::{self, BufRead, Read},
+    io::{self, Read},
     path::Path,
 };
 
@@ -30,11 +30,6 @@ pub fn vec_u8_to_u16(v: &[u8]) -> u16 {
     }
 }
 
-pub fn read_lines<P: AsRef<Path>>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>> {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
-}
-
 pub fn read_file_to_vec_u8<P: AsRef<Path>>(filename: P) -> Result<Vec<u8>, io::Error> {
     let mut f = File::open(&filename)?;
     let metadata = fs::metadata(&filename)?;
@@ -43,6 +38,61 @@ pub fn read_file_to_vec_u8<P: AsRef<Path>>(filename: P) -> Result<Vec<u8>, io::E
     Ok(buffer)
 }
 
+/// Line-by-line diff of `expected` against `actual`
+///
+/// Lines only found in `expected` are prefixed with `-`, those only in `actual` with `+`, and
+/// lines common to both with a space. When the texts only differ in their line endings, the lines
+/// are shown with `\r` and `\n` escaped so the difference is visible
+pub fn diff_lines(expected: &str, actual: &str) -> Vec<String> {
+    let out = diff(
+        &expected.lines().collect::<Vec<_>>(),
+        &actual.lines().collect::<Vec<_>>(),
+    );
+    if expected == actual || out.iter().any(|line| !line.starts_with(' ')) {
+        return out;
+    }
+    let escaped = |text: &str| -> Vec<String> {
+        text.split_inclusive('\n')
+            .map(|line| line.replace('\r', "\\r").replace('\n', "\\n"))
+            .collect()
+    };
+    let (exp, act) = (escaped(expected), escaped(actual));
+    diff(
+        &exp.iter().map(String::as_str).collect::<Vec<_>>(),
+        &act.iter().map(String::as_str).collect::<Vec<_>>(),
+    )
+}
+
+fn diff(exp: &[&str], act: &[&str]) -> Vec<String> {
+    // Longest common subsequence lengths of the suffixes
+    let mut lcs = vec![vec![0_usize; act.len() + 1]; exp.len() + 1];
+    for i in (0..exp.len()).rev() {
+        for j in (0..act.len()).rev() {
+            lcs[i][j] = if exp[i] == act[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < exp.len() || j < act.len() {
+        if i < exp.len() && j < act.len() && exp[i] == act[j] {
+            out.push(format!(" {}", exp[i]));
+            i += 1;
+            j += 1;
+        } else if i < exp.len() && (j == act.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(format!("-{}", exp[i]));
+            i += 1;
+        } else {
+            out.push(format!("+{}", act[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
 #[cfg(test)]
 pub mod test {
     use std::path::{Path, PathBuf};
@@ -72,4 +122,16 @@ mod tests {
         let v = vec_u8_to_u16(&vec);
         assert_eq!(v, 0xdead);
     }
+
+    #[test]
+    fn diff_lines_tests() {
+        assert_eq!(diff_lines("a\nb", "a\nb"), vec![" a", " b"]);
+        assert_eq!(
+            diff_lines("a\nb\nc", "a\nx\nc\nd"),
+            vec![" a", "-b", "+x", " c", "+d"]
+        );
+        assert_eq!(diff_lines("a", ""), vec!["-a"]);
+        assert_eq!(diff_lines("a\nb", "a\nb\n"), vec![" a\\n", "-b", "+b\\n"]);
+        assert_eq!(diff_lines("a\n", "a\r\n"), vec!["-a\\n", "+a\\r\\n"]);
+    }
 }